@@ -0,0 +1,239 @@
+use std::{collections::HashMap, ops::Deref, slice::Iter};
+
+use crate::amqp::transport::performative::{
+    PerformativeError, read_bool, read_error, read_fields, read_map, read_uint,
+    trim_trailing_nulls, write_bool, write_error, write_map, write_uint,
+};
+use crate::amqp::types::{constructor::Constructor, descriptor::Descriptor, primitive::Primitive};
+
+// 3.4 Delivery State. Every state but received is an outcome, i.e. terminal.
+#[derive(Debug, Clone)]
+pub enum DeliveryState {
+    // <type name="received" class="composite" source="list" provides="delivery-state">
+    // <descriptor name="amqp:received:list" code="0x00000000:0x00000023"/>
+    // The point up to which the message has been received, for resuming a delivery.
+    Received {
+        // <field name="section-number" type="uint" mandatory="true"/>
+        section_number: u32,
+        // <field name="section-offset" type="ulong" mandatory="true"/>
+        section_offset: u64,
+    },
+    // <type name="accepted" class="composite" source="list" provides="delivery-state, outcome">
+    // <descriptor name="amqp:accepted:list" code="0x00000000:0x00000024"/>
+    Accepted,
+    // <type name="rejected" class="composite" source="list" provides="delivery-state, outcome">
+    // <descriptor name="amqp:rejected:list" code="0x00000000:0x00000025"/>
+    Rejected {
+        // <field name="error" type="error"/>
+        error: Option<PerformativeError>,
+    },
+    // <type name="released" class="composite" source="list" provides="delivery-state, outcome">
+    // <descriptor name="amqp:released:list" code="0x00000000:0x00000026"/>
+    Released,
+    // <type name="modified" class="composite" source="list" provides="delivery-state, outcome">
+    // <descriptor name="amqp:modified:list" code="0x00000000:0x00000027"/>
+    Modified {
+        // <field name="delivery-failed" type="boolean"/>
+        delivery_failed: bool,
+        // <field name="undeliverable-here" type="boolean"/>
+        undeliverable_here: bool,
+        // <field name="message-annotations" type="fields"/>
+        message_annotations: HashMap<Constructor, Constructor>,
+    },
+}
+
+impl DeliveryState {
+    pub fn is_terminal(&self) -> bool {
+        !matches!(self, DeliveryState::Received { .. })
+    }
+}
+
+pub(crate) fn read_delivery_state(
+    field_iter: &mut Iter<Constructor>,
+) -> Result<Option<DeliveryState>, &'static str> {
+    let (descriptor, primitive) = match field_iter.next() {
+        Some(Constructor::DescribedType(descriptor, primitive)) => (descriptor, primitive),
+        Some(Constructor::PrimitiveType(Primitive::Null)) | None => return Ok(None),
+        _ => return Err("The delivery state is not a described type"),
+    };
+    let fields = read_fields(primitive.clone())?;
+    let mut field_iter = fields.iter();
+    Ok(Some(
+        match Descriptor::from_constructor(descriptor.deref())? {
+            Descriptor::Received => DeliveryState::Received {
+                section_number: read_uint(&mut field_iter, false, None)?
+                    .ok_or("Mandatory field: section-number")?,
+                section_offset: match field_iter.next() {
+                    Some(Constructor::PrimitiveType(Primitive::ULong(offset))) => *offset,
+                    _ => return Err("Mandatory field: section-offset"),
+                },
+            },
+            Descriptor::Accepted => DeliveryState::Accepted,
+            Descriptor::Rejected => DeliveryState::Rejected {
+                error: read_error(&mut field_iter)?,
+            },
+            Descriptor::Released => DeliveryState::Released,
+            Descriptor::Modified => DeliveryState::Modified {
+                delivery_failed: read_bool(&mut field_iter, false, None)?.unwrap_or(false),
+                undeliverable_here: read_bool(&mut field_iter, false, None)?.unwrap_or(false),
+                message_annotations: read_map(&mut field_iter)?,
+            },
+            _ => return Err("Unknown delivery state"),
+        },
+    ))
+}
+
+pub(crate) fn write_delivery_state(state: Option<&DeliveryState>) -> Constructor {
+    let Some(state) = state else {
+        return Constructor::PrimitiveType(Primitive::Null);
+    };
+    let (descriptor, fields) = match state {
+        DeliveryState::Received {
+            section_number,
+            section_offset,
+        } => (
+            Descriptor::Received,
+            vec![
+                write_uint(Some(*section_number)),
+                Constructor::PrimitiveType(Primitive::ULong(*section_offset)),
+            ],
+        ),
+        DeliveryState::Accepted => (Descriptor::Accepted, vec![]),
+        DeliveryState::Rejected { error } => {
+            (Descriptor::Rejected, vec![write_error(error.as_ref())])
+        }
+        DeliveryState::Released => (Descriptor::Released, vec![]),
+        DeliveryState::Modified {
+            delivery_failed,
+            undeliverable_here,
+            message_annotations,
+        } => (
+            Descriptor::Modified,
+            vec![
+                write_bool(Some(*delivery_failed).filter(|value| *value)),
+                write_bool(Some(*undeliverable_here).filter(|value| *value)),
+                write_map(message_annotations),
+            ],
+        ),
+    };
+    let fields = trim_trailing_nulls(fields);
+    descriptor.describe(if fields.is_empty() {
+        Primitive::EmptyList
+    } else {
+        Primitive::List(fields)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amqp::transport::condition;
+    use crate::amqp::types::format_code::FormatCode;
+
+    // Encodes the state, decodes it again and checks that it is encoded the same way,
+    // returning its encoding.
+    async fn round_trip(state: DeliveryState) -> Vec<u8> {
+        let bytes = write_delivery_state(Some(&state)).as_bytes().unwrap();
+        let mut rest = &bytes[..];
+        let code = FormatCode::read(&mut rest).await.unwrap();
+        let constructor = Constructor::new(code, &mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        let decoded = read_delivery_state(&mut std::slice::from_ref(&constructor).iter())
+            .unwrap()
+            .unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", state));
+        assert_eq!(
+            write_delivery_state(Some(&decoded)).as_bytes().unwrap(),
+            bytes
+        );
+        bytes
+    }
+
+    #[tokio::test]
+    async fn every_state_round_trips() {
+        for state in [
+            DeliveryState::Received {
+                section_number: 0,
+                section_offset: 0,
+            },
+            DeliveryState::Received {
+                section_number: 2,
+                section_offset: 1 << 40,
+            },
+            DeliveryState::Accepted,
+            DeliveryState::Rejected { error: None },
+            DeliveryState::Rejected {
+                error: Some(PerformativeError::new(
+                    condition::INTERNAL_ERROR,
+                    "Could not store the message",
+                )),
+            },
+            DeliveryState::Released,
+            DeliveryState::Modified {
+                delivery_failed: true,
+                undeliverable_here: true,
+                message_annotations: HashMap::from([(
+                    Constructor::PrimitiveType(Primitive::Symbol(b"x-opt-reason".to_vec())),
+                    Constructor::PrimitiveType(Primitive::String(String::from("busy"))),
+                )]),
+            },
+        ] {
+            round_trip(state).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn defaults_are_left_out() {
+        // An outcome without fields is an empty list.
+        assert_eq!(
+            round_trip(DeliveryState::Accepted).await,
+            [0x00, 0x53, 0x24, 0x45]
+        );
+        assert_eq!(
+            round_trip(DeliveryState::Rejected { error: None }).await,
+            [0x00, 0x53, 0x25, 0x45]
+        );
+        assert_eq!(
+            round_trip(DeliveryState::Released).await,
+            [0x00, 0x53, 0x26, 0x45]
+        );
+        assert_eq!(
+            round_trip(DeliveryState::Modified {
+                delivery_failed: false,
+                undeliverable_here: false,
+                message_annotations: HashMap::new(),
+            })
+            .await,
+            [0x00, 0x53, 0x27, 0x45]
+        );
+        // Fields after the last one set are trimmed, those before it are null.
+        assert_eq!(
+            round_trip(DeliveryState::Modified {
+                delivery_failed: true,
+                undeliverable_here: false,
+                message_annotations: HashMap::new(),
+            })
+            .await,
+            [0x00, 0x53, 0x27, 0xc0, 0x02, 0x01, 0x41]
+        );
+        assert_eq!(
+            round_trip(DeliveryState::Modified {
+                delivery_failed: false,
+                undeliverable_here: true,
+                message_annotations: HashMap::new(),
+            })
+            .await,
+            [0x00, 0x53, 0x27, 0xc0, 0x03, 0x02, 0x40, 0x41]
+        );
+
+        // No state at all is null, as is a field left out.
+        let null = write_delivery_state(None);
+        assert_eq!(null.as_bytes().unwrap(), [0x40]);
+        assert!(
+            read_delivery_state(&mut std::slice::from_ref(&null).iter())
+                .unwrap()
+                .is_none()
+        );
+        assert!(read_delivery_state(&mut [].iter()).unwrap().is_none());
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+
+use crate::amqp::messaging::message::Message;
+use crate::amqp::messaging::selector::Selector;
+use crate::amqp::types::{constructor::Constructor, descriptor::Descriptor, primitive::Primitive};
+
+// The filters of a source, which the messages sent on its link all have to pass
+// (see 3.5.8 Filter Set). Of the filters of the AMQP filter registry, we support
+// the selector filter and the legacy exact-match bindings on the subject and on
+// the application properties.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    selectors: Vec<Selector>,
+    subjects: Vec<String>,
+    headers: Vec<HeadersBinding>,
+}
+
+// The application properties a message has to have, all or any of them.
+#[derive(Clone, Debug)]
+struct HeadersBinding {
+    any: bool,
+    properties: Vec<(String, Constructor)>,
+}
+
+impl Filter {
+    // Takes the filters out of the filter-set that we support, leaving out the others,
+    // so that the source in our Attach tells the peer which ones are in place.
+    pub fn from_filter_set(
+        filter_set: &mut HashMap<Constructor, Constructor>,
+    ) -> Result<Self, &'static str> {
+        let mut filter = Filter::default();
+        let mut unsupported = vec![];
+        for (name, value) in filter_set.iter() {
+            let Constructor::DescribedType(descriptor, value) = value else {
+                unsupported.push(name.clone());
+                continue;
+            };
+            match (Descriptor::from_constructor(descriptor.deref()), value) {
+                (Ok(Descriptor::SelectorFilter), Primitive::String(selector)) => {
+                    filter.selectors.push(Selector::parse(selector)?);
+                }
+                (Ok(Descriptor::LegacyDirectBinding), Primitive::String(subject)) => {
+                    filter.subjects.push(subject.clone());
+                }
+                (Ok(Descriptor::LegacyHeadersBinding), Primitive::Map(map)) => {
+                    filter.headers.push(HeadersBinding::new(&map.value)?);
+                }
+                _ => unsupported.push(name.clone()),
+            }
+        }
+        for name in unsupported {
+            filter_set.remove(&name);
+        }
+        Ok(filter)
+    }
+
+    pub fn matches(&self, message: &Message) -> bool {
+        let subject = message
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.subject.as_ref());
+        self.subjects
+            .iter()
+            .all(|expected| subject == Some(expected))
+            && self.headers.iter().all(|headers| headers.matches(message))
+            && self
+                .selectors
+                .iter()
+                .all(|selector| selector.matches(message))
+    }
+}
+
+impl HeadersBinding {
+    // The x-match entry says whether all of the others have to match, the default,
+    // or any one of them. Other keys starting with x- are ignored, as they are by the
+    // headers exchanges of AMQP 0-9-1.
+    fn new(map: &HashMap<Constructor, Constructor>) -> Result<Self, &'static str> {
+        let mut binding = HeadersBinding {
+            any: false,
+            properties: vec![],
+        };
+        for (key, value) in map {
+            let Constructor::PrimitiveType(Primitive::String(key)) = key else {
+                return Err("The keys of a headers binding must be strings");
+            };
+            if !key.starts_with("x-") {
+                binding.properties.push((key.clone(), value.clone()));
+                continue;
+            }
+            if key != "x-match" {
+                continue;
+            }
+            binding.any = match value {
+                Constructor::PrimitiveType(Primitive::String(mode)) if mode == "all" => false,
+                Constructor::PrimitiveType(Primitive::String(mode)) if mode == "any" => true,
+                _ => return Err("The x-match of a headers binding must be all or any"),
+            };
+        }
+        Ok(binding)
+    }
+
+    // A binding without properties to match lets every message through, whether
+    // all or any of them have to match.
+    fn matches(&self, message: &Message) -> bool {
+        if self.properties.is_empty() {
+            return true;
+        }
+        let mut matching = self
+            .properties
+            .iter()
+            .map(|(key, value)| message.application_properties.get(key) == Some(value));
+        match self.any {
+            true => matching.any(|matches| matches),
+            false => matching.all(|matches| matches),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &str) -> Constructor {
+        Constructor::PrimitiveType(Primitive::String(value.to_string()))
+    }
+
+    fn binding(entries: &[(&str, &str)]) -> HeadersBinding {
+        let map = entries
+            .iter()
+            .map(|(key, value)| (string(key), string(value)))
+            .collect();
+        HeadersBinding::new(&map).unwrap()
+    }
+
+    fn message(application_properties: &[(&str, &str)]) -> Message {
+        Message {
+            application_properties: application_properties
+                .iter()
+                .map(|(key, value)| (key.to_string(), string(value)))
+                .collect(),
+            ..Message::default()
+        }
+    }
+
+    #[test]
+    fn headers_binding_all() {
+        let binding = binding(&[("color", "red"), ("size", "large")]);
+        assert!(binding.matches(&message(&[("color", "red"), ("size", "large")])));
+        assert!(!binding.matches(&message(&[("color", "red")])));
+        assert!(!binding.matches(&message(&[("color", "red"), ("size", "small")])));
+    }
+
+    #[test]
+    fn headers_binding_any() {
+        let binding = binding(&[("x-match", "any"), ("color", "red"), ("size", "large")]);
+        assert!(binding.matches(&message(&[("color", "red")])));
+        assert!(binding.matches(&message(&[("color", "blue"), ("size", "large")])));
+        assert!(!binding.matches(&message(&[("color", "blue")])));
+    }
+
+    #[test]
+    fn headers_binding_without_properties_matches_everything() {
+        assert!(binding(&[]).matches(&message(&[])));
+        assert!(binding(&[("x-match", "all")]).matches(&message(&[])));
+        assert!(binding(&[("x-match", "any")]).matches(&message(&[("color", "red")])));
+    }
+
+    #[test]
+    fn headers_binding_ignores_other_x_keys() {
+        let binding = binding(&[("x-match", "any"), ("x-priority", "high"), ("color", "red")]);
+        assert!(binding.matches(&message(&[("color", "red")])));
+        assert!(!binding.matches(&message(&[("x-priority", "high")])));
+    }
+
+    #[test]
+    fn headers_binding_rejects_bad_x_match() {
+        let map = HashMap::from([(string("x-match"), string("some"))]);
+        assert!(HeadersBinding::new(&map).is_err());
+        let map = HashMap::from([(Constructor::PrimitiveType(Primitive::Int(1)), string("red"))]);
+        assert!(HeadersBinding::new(&map).is_err());
+    }
+}
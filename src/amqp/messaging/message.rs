@@ -0,0 +1,493 @@
+use std::{collections::HashMap, ops::Deref, slice::Iter, time::Duration};
+
+use crate::amqp::transport::performative::{
+    read_any, read_bool, read_fields, read_map, read_string, read_ubyte, read_uint,
+    trim_trailing_nulls, write_binary, write_bool, write_string, write_ubyte, write_uint,
+};
+use crate::amqp::types::{
+    constructor::Constructor,
+    descriptor::Descriptor,
+    format_code::FormatCode,
+    primitive::{InnerMap, Primitive},
+};
+
+// <type name="header" class="composite" source="list" provides="section">
+// <descriptor name="amqp:header:list" code="0x00000000:0x00000070"/>
+// </type>
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    // <field name="durable" type="boolean" default="false"/>
+    pub durable: bool,
+    // <field name="priority" type="ubyte" default="4"/>
+    pub priority: u8,
+    // <field name="ttl" type="milliseconds"/>
+    pub ttl: Option<Duration>,
+    // <field name="first-acquirer" type="boolean" default="false"/>
+    pub first_acquirer: bool,
+    // <field name="delivery-count" type="uint" default="0"/>
+    pub delivery_count: u32,
+}
+
+impl Default for Header {
+    fn default() -> Self {
+        Self {
+            durable: false,
+            priority: 4,
+            ttl: None,
+            first_acquirer: false,
+            delivery_count: 0,
+        }
+    }
+}
+
+// <type name="properties" class="composite" source="list" provides="section">
+// <descriptor name="amqp:properties:list" code="0x00000000:0x00000073"/>
+// </type>
+// The message-id and correlation-id may be a ulong, uuid, binary or string,
+// and are null if not set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Properties {
+    // <field name="message-id" type="*" requires="message-id"/>
+    pub message_id: Constructor,
+    // <field name="user-id" type="binary"/>
+    pub user_id: Option<Vec<u8>>,
+    // <field name="to" type="*" requires="address"/>
+    pub to: Option<String>,
+    // <field name="subject" type="string"/>
+    pub subject: Option<String>,
+    // <field name="reply-to" type="*" requires="address"/>
+    pub reply_to: Option<String>,
+    // <field name="correlation-id" type="*" requires="message-id"/>
+    pub correlation_id: Constructor,
+    // <field name="content-type" type="symbol"/>
+    pub content_type: Option<Vec<u8>>,
+    // <field name="content-encoding" type="symbol"/>
+    pub content_encoding: Option<Vec<u8>>,
+    // <field name="absolute-expiry-time" type="timestamp"/>
+    pub absolute_expiry_time: Option<i64>,
+    // <field name="creation-time" type="timestamp"/>
+    pub creation_time: Option<i64>,
+    // <field name="group-id" type="string"/>
+    pub group_id: Option<String>,
+    // <field name="group-sequence" type="sequence-no"/>
+    pub group_sequence: Option<u32>,
+    // <field name="reply-to-group-id" type="string"/>
+    pub reply_to_group_id: Option<String>,
+}
+
+impl Default for Properties {
+    fn default() -> Self {
+        Self {
+            message_id: Constructor::PrimitiveType(Primitive::Null),
+            user_id: None,
+            to: None,
+            subject: None,
+            reply_to: None,
+            correlation_id: Constructor::PrimitiveType(Primitive::Null),
+            content_type: None,
+            content_encoding: None,
+            absolute_expiry_time: None,
+            creation_time: None,
+            group_id: None,
+            group_sequence: None,
+            reply_to_group_id: None,
+        }
+    }
+}
+
+// The body consists of one of the following three choices: one or more data sections,
+// one or more amqp-sequence sections, or a single amqp-value section (see 3.2).
+// A message without a body section has no data sections.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Body {
+    Data(Vec<Vec<u8>>),
+    AmqpSequence(Vec<Vec<Constructor>>),
+    AmqpValue(Primitive),
+}
+
+// 3.2 Message Format. The bare message is immutable once sent, the annotated
+// message around it may be changed by intermediaries.
+// Annotation keys are symbols or ulongs, application property keys are strings.
+// Absent annotations, application properties and footers are empty.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub header: Option<Header>,
+    pub delivery_annotations: HashMap<Constructor, Constructor>,
+    pub message_annotations: HashMap<Constructor, Constructor>,
+    pub properties: Option<Properties>,
+    pub application_properties: HashMap<String, Constructor>,
+    pub body: Body,
+    pub footer: HashMap<Constructor, Constructor>,
+    // The properties, application-properties and body sections as they were received,
+    // which are sent on as they are, so that the bare message stays the same however
+    // its sections would be encoded again.
+    pub bare_message: Option<Vec<u8>>,
+    // Where the node stored the message if it is durable, which isn't part of it.
+    pub store_id: Option<u64>,
+}
+
+impl Default for Message {
+    fn default() -> Self {
+        Self {
+            header: None,
+            delivery_annotations: HashMap::new(),
+            message_annotations: HashMap::new(),
+            properties: None,
+            application_properties: HashMap::new(),
+            body: Body::Data(vec![]),
+            footer: HashMap::new(),
+            bare_message: None,
+            store_id: None,
+        }
+    }
+}
+
+impl Message {
+    // Decodes the payload of a delivery, i.e. its sections in the order
+    //     [header] [delivery-annotations] [message-annotations] [properties]
+    //     [application-properties] body [footer]
+    pub async fn new(mut payload: &[u8]) -> Result<Self, &'static str> {
+        let received = payload;
+        let mut bare_message = None;
+        let mut message = Message::default();
+        let mut last_position = None;
+        while !payload.is_empty() {
+            let start = received.len() - payload.len();
+            let code = FormatCode::read(&mut payload).await?;
+            let Constructor::DescribedType(descriptor, value) =
+                Constructor::new(code, &mut payload).await?
+            else {
+                return Err("Message section is not a described type");
+            };
+            let descriptor = Descriptor::from_constructor(descriptor.deref())?;
+            let position = section_position(descriptor)?;
+            // Only the body may consist of several sections.
+            if last_position > Some(position)
+                || (last_position == Some(position) && position != BODY_POSITION)
+            {
+                return Err("Message sections are out of order");
+            }
+            let first_body_section = last_position != Some(BODY_POSITION);
+            last_position = Some(position);
+            if (BARE_MESSAGE_POSITION..=BODY_POSITION).contains(&position) {
+                let end = received.len() - payload.len();
+                let bare_start = bare_message.map_or(start, |(bare_start, _)| bare_start);
+                bare_message = Some((bare_start, end));
+            }
+
+            match descriptor {
+                Descriptor::Header => {
+                    let fields = read_fields(value)?;
+                    let mut field_iter = fields.iter();
+                    message.header = Some(Header {
+                        durable: read_bool(&mut field_iter, false, None)?.unwrap_or(false),
+                        priority: read_ubyte(&mut field_iter, false, None)?.unwrap_or(4),
+                        ttl: read_uint(&mut field_iter, false, None)?
+                            .map(|ttl| Duration::from_millis(ttl.into())),
+                        first_acquirer: read_bool(&mut field_iter, false, None)?.unwrap_or(false),
+                        delivery_count: read_uint(&mut field_iter, false, None)?.unwrap_or(0),
+                    });
+                }
+                Descriptor::DeliveryAnnotations => {
+                    message.delivery_annotations = read_section_map(value)?;
+                }
+                Descriptor::MessageAnnotations => {
+                    message.message_annotations = read_section_map(value)?;
+                }
+                Descriptor::Properties => {
+                    let fields = read_fields(value)?;
+                    let mut field_iter = fields.iter();
+                    message.properties = Some(Properties {
+                        message_id: read_any(&mut field_iter),
+                        user_id: read_optional_binary(&mut field_iter)?,
+                        to: read_string(&mut field_iter, false)?,
+                        subject: read_string(&mut field_iter, false)?,
+                        reply_to: read_string(&mut field_iter, false)?,
+                        correlation_id: read_any(&mut field_iter),
+                        content_type: read_optional_symbol(&mut field_iter)?,
+                        content_encoding: read_optional_symbol(&mut field_iter)?,
+                        absolute_expiry_time: read_timestamp(&mut field_iter)?,
+                        creation_time: read_timestamp(&mut field_iter)?,
+                        group_id: read_string(&mut field_iter, false)?,
+                        group_sequence: read_uint(&mut field_iter, false, None)?,
+                        reply_to_group_id: read_string(&mut field_iter, false)?,
+                    });
+                }
+                Descriptor::ApplicationProperties => {
+                    for (key, value) in read_section_map(value)? {
+                        let Constructor::PrimitiveType(Primitive::String(key)) = key else {
+                            return Err("Application property key is not a string");
+                        };
+                        if !is_simple(&value) {
+                            return Err("Application property value is not of a simple type");
+                        }
+                        message.application_properties.insert(key, value);
+                    }
+                }
+                Descriptor::Data => {
+                    let Primitive::Binary(data) = value else {
+                        return Err("Data section is not binary");
+                    };
+                    match message.body {
+                        Body::Data(ref mut sections) => sections.push(data),
+                        _ => return Err("Data section follows a different body section"),
+                    }
+                }
+                Descriptor::AmqpSequence => {
+                    let sequence = read_fields(value)?;
+                    match message.body {
+                        Body::AmqpSequence(ref mut sections) => sections.push(sequence),
+                        _ if first_body_section => {
+                            message.body = Body::AmqpSequence(vec![sequence])
+                        }
+                        _ => return Err("AMQP sequence section follows a different body section"),
+                    }
+                }
+                Descriptor::AmqpValue => {
+                    if !first_body_section {
+                        return Err("AMQP value section follows another body section");
+                    }
+                    message.body = Body::AmqpValue(value);
+                }
+                Descriptor::Footer => {
+                    message.footer = read_section_map(value)?;
+                }
+                _ => unreachable!("section_position only accepts message sections"),
+            }
+        }
+        message.bare_message = bare_message.map(|(start, end)| received[start..end].to_vec());
+        Ok(message)
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, &'static str> {
+        let mut sections = vec![];
+        if let Some(ref header) = self.header {
+            sections.push(Descriptor::Header.describe(list(vec![
+                write_bool(Some(header.durable)),
+                write_ubyte(Some(header.priority)),
+                write_uint(
+                    header
+                        .ttl
+                        .map(|ttl| ttl.as_millis().try_into().unwrap_or(u32::MAX)),
+                ),
+                write_bool(Some(header.first_acquirer)),
+                write_uint(Some(header.delivery_count)),
+            ])));
+        }
+        if !self.delivery_annotations.is_empty() {
+            sections.push(
+                Descriptor::DeliveryAnnotations.describe(section_map(&self.delivery_annotations)),
+            );
+        }
+        if !self.message_annotations.is_empty() {
+            sections.push(
+                Descriptor::MessageAnnotations.describe(section_map(&self.message_annotations)),
+            );
+        }
+        let mut buf = vec![];
+        for section in sections {
+            section.encode(&mut buf)?;
+        }
+        match self.bare_message {
+            Some(ref bare_message) => buf.extend_from_slice(bare_message),
+            None => {
+                for section in self.bare_message_sections() {
+                    section.encode(&mut buf)?;
+                }
+            }
+        }
+        if !self.footer.is_empty() {
+            Descriptor::Footer
+                .describe(section_map(&self.footer))
+                .encode(&mut buf)?;
+        }
+        Ok(buf)
+    }
+
+    // The properties, application-properties and body sections of a message that
+    // wasn't received as it is.
+    fn bare_message_sections(&self) -> Vec<Constructor> {
+        let mut sections = vec![];
+        if let Some(ref properties) = self.properties {
+            sections.push(Descriptor::Properties.describe(list(vec![
+                properties.message_id.clone(),
+                properties
+                    .user_id
+                    .as_deref()
+                    .map_or(Constructor::PrimitiveType(Primitive::Null), write_binary),
+                write_string(properties.to.as_ref()),
+                write_string(properties.subject.as_ref()),
+                write_string(properties.reply_to.as_ref()),
+                properties.correlation_id.clone(),
+                write_symbol(properties.content_type.as_ref()),
+                write_symbol(properties.content_encoding.as_ref()),
+                write_timestamp(properties.absolute_expiry_time),
+                write_timestamp(properties.creation_time),
+                write_string(properties.group_id.as_ref()),
+                write_uint(properties.group_sequence),
+                write_string(properties.reply_to_group_id.as_ref()),
+            ])));
+        }
+        if !self.application_properties.is_empty() {
+            let application_properties = self
+                .application_properties
+                .iter()
+                .map(|(key, value)| {
+                    (
+                        Constructor::PrimitiveType(Primitive::String(key.clone())),
+                        value.clone(),
+                    )
+                })
+                .collect();
+            sections.push(
+                Descriptor::ApplicationProperties.describe(section_map(&application_properties)),
+            );
+        }
+        match self.body {
+            Body::Data(ref data) => sections.extend(
+                data.iter()
+                    .map(|data| Descriptor::Data.describe(Primitive::Binary(data.clone()))),
+            ),
+            Body::AmqpSequence(ref sequences) => {
+                sections.extend(sequences.iter().map(|sequence| {
+                    Descriptor::AmqpSequence.describe(Primitive::List(sequence.clone()))
+                }))
+            }
+            Body::AmqpValue(ref value) => {
+                sections.push(Descriptor::AmqpValue.describe(value.clone()))
+            }
+        }
+        sections
+    }
+}
+
+// The bare message starts with the properties and ends with the body (see 3.2).
+const BARE_MESSAGE_POSITION: usize = 3;
+const BODY_POSITION: usize = 5;
+
+// Where the section goes in a message, see Message::new.
+fn section_position(descriptor: Descriptor) -> Result<usize, &'static str> {
+    match descriptor {
+        Descriptor::Header => Ok(0),
+        Descriptor::DeliveryAnnotations => Ok(1),
+        Descriptor::MessageAnnotations => Ok(2),
+        Descriptor::Properties => Ok(3),
+        Descriptor::ApplicationProperties => Ok(4),
+        Descriptor::Data | Descriptor::AmqpSequence | Descriptor::AmqpValue => Ok(BODY_POSITION),
+        Descriptor::Footer => Ok(6),
+        _ => Err("Unknown message section"),
+    }
+}
+
+// Annotations, application properties and footers are maps, which may be null.
+fn read_section_map(value: Primitive) -> Result<HashMap<Constructor, Constructor>, &'static str> {
+    read_map(&mut [Constructor::PrimitiveType(value)].iter())
+}
+
+fn section_map(value: &HashMap<Constructor, Constructor>) -> Primitive {
+    Primitive::Map(InnerMap {
+        value: value.clone(),
+    })
+}
+
+fn list(fields: Vec<Constructor>) -> Primitive {
+    match trim_trailing_nulls(fields) {
+        fields if fields.is_empty() => Primitive::EmptyList,
+        fields => Primitive::List(fields),
+    }
+}
+
+// Values of application properties are restricted to be of simple types only,
+// that is excluding map, list, and array types.
+fn is_simple(value: &Constructor) -> bool {
+    !matches!(
+        value,
+        Constructor::DescribedType(_, _)
+            | Constructor::PrimitiveType(
+                Primitive::List(_) | Primitive::EmptyList | Primitive::Map(_) | Primitive::Array(_)
+            )
+    )
+}
+
+fn read_optional_binary(
+    field_iter: &mut Iter<Constructor>,
+) -> Result<Option<Vec<u8>>, &'static str> {
+    match field_iter.next() {
+        Some(Constructor::PrimitiveType(Primitive::Binary(value))) => Ok(Some(value.clone())),
+        Some(Constructor::PrimitiveType(Primitive::Null)) | None => Ok(None),
+        _ => Err("Invalid field type: binary expected"),
+    }
+}
+
+fn read_optional_symbol(
+    field_iter: &mut Iter<Constructor>,
+) -> Result<Option<Vec<u8>>, &'static str> {
+    match field_iter.next() {
+        Some(Constructor::PrimitiveType(Primitive::Symbol(value))) => Ok(Some(value.clone())),
+        Some(Constructor::PrimitiveType(Primitive::Null)) | None => Ok(None),
+        _ => Err("Invalid field type: symbol expected"),
+    }
+}
+
+fn read_timestamp(field_iter: &mut Iter<Constructor>) -> Result<Option<i64>, &'static str> {
+    match field_iter.next() {
+        Some(Constructor::PrimitiveType(Primitive::Timestamp(value))) => Ok(Some(*value)),
+        Some(Constructor::PrimitiveType(Primitive::Null)) | None => Ok(None),
+        _ => Err("Invalid field type: timestamp expected"),
+    }
+}
+
+fn write_symbol(value: Option<&Vec<u8>>) -> Constructor {
+    Constructor::PrimitiveType(
+        value.map_or(Primitive::Null, |value| Primitive::Symbol(value.clone())),
+    )
+}
+
+fn write_timestamp(value: Option<i64>) -> Constructor {
+    Constructor::PrimitiveType(value.map_or(Primitive::Null, Primitive::Timestamp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn bare_message_is_sent_as_received() {
+        let header = [0x00, 0x53, 0x70, 0x45];
+        // The subject is a str32 where we would write a str8.
+        let bare_message = [
+            0x00, 0x53, 0x73, 0xd0, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x00, 0x00, 0x04, 0x40, 0x40,
+            0x40, 0xb1, 0x00, 0x00, 0x00, 0x01, b'a', 0x00, 0x53, 0x77, 0xa1, 0x01, b'x',
+        ];
+        let mut message = Message::new(&[&header[..], &bare_message[..]].concat())
+            .await
+            .unwrap();
+        assert_eq!(
+            message
+                .properties
+                .as_ref()
+                .and_then(|properties| properties.subject.as_deref()),
+            Some("a")
+        );
+        assert_eq!(
+            message.body,
+            Body::AmqpValue(Primitive::String(String::from("x")))
+        );
+
+        // The annotated message around it may change.
+        message.header.as_mut().unwrap().delivery_count += 1;
+        let payload = message.encode().unwrap();
+        assert!(payload.ends_with(&bare_message));
+        let resent = Message::new(&payload).await.unwrap();
+        assert_eq!(resent.header.unwrap().delivery_count, 1);
+
+        // Without the bytes received the sections are encoded anew.
+        message.bare_message = None;
+        let payload = message.encode().unwrap();
+        assert!(!payload.ends_with(&bare_message));
+        assert_eq!(
+            Message::new(&payload).await.unwrap().properties,
+            message.properties
+        );
+    }
+}
@@ -0,0 +1,5 @@
+pub mod delivery_state;
+pub mod filter;
+pub mod message;
+pub mod selector;
+pub mod terminus;
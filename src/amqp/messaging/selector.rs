@@ -0,0 +1,895 @@
+use std::cmp::Ordering;
+
+use crate::amqp::messaging::message::Message;
+use crate::amqp::types::constructor::Constructor;
+use crate::amqp::types::primitive::Primitive;
+
+// How deeply a selector may nest, so evaluating it can't exhaust the stack.
+const MAX_DEPTH: usize = 100;
+
+const KEYWORDS: &[&str] = &[
+    "AND", "OR", "NOT", "BETWEEN", "IN", "LIKE", "ESCAPE", "IS", "NULL", "TRUE", "FALSE",
+];
+
+// The value of an expression. Missing values are unknown, as is whatever is made of
+// them or of values of the wrong type, which a selector doesn't match.
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+impl Value {
+    fn as_float(&self) -> Option<f64> {
+        match self {
+            Value::Int(value) => Some(*value as f64),
+            Value::Float(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    fn from_constructor(value: &Constructor) -> Option<Self> {
+        let Constructor::PrimitiveType(value) = value else {
+            return None;
+        };
+        match value {
+            Primitive::Boolean(value) => Some(Value::Bool(*value)),
+            Primitive::UByte(value) => Some(Value::Int(*value as i64)),
+            Primitive::UShort(value) => Some(Value::Int(*value as i64)),
+            Primitive::UInt(value) => Some(Value::Int(*value as i64)),
+            Primitive::ULong(value) => i64::try_from(*value).ok().map(Value::Int),
+            Primitive::Byte(value) => Some(Value::Int(*value as i64)),
+            Primitive::Short(value) => Some(Value::Int(*value as i64)),
+            Primitive::Int(value) => Some(Value::Int(*value as i64)),
+            Primitive::Long(value) | Primitive::Timestamp(value) => Some(Value::Int(*value)),
+            Primitive::Float(value) => Some(Value::Float(value.value as f64)),
+            Primitive::Double(value) => Some(Value::Float(value.value)),
+            Primitive::Char(value) => char::from_u32(u32::from_be_bytes(*value))
+                .map(|value| Value::String(value.to_string())),
+            Primitive::String(value) => Some(Value::String(value.clone())),
+            Primitive::Symbol(value) => String::from_utf8(value.clone()).ok().map(Value::String),
+            Primitive::UUID(value) => {
+                let hex: String = value.iter().map(|byte| format!("{:02x}", byte)).collect();
+                Some(Value::String(format!(
+                    "{}-{}-{}-{}-{}",
+                    &hex[..8],
+                    &hex[8..12],
+                    &hex[12..16],
+                    &hex[16..20],
+                    &hex[20..]
+                )))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessOrEqual,
+    GreaterOrEqual,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+// A LIKE pattern, where % stands for any number of characters and _ for any one.
+#[derive(Clone, Debug, PartialEq)]
+enum PatternPart {
+    Any,
+    One,
+    Char(char),
+}
+
+#[derive(Clone, Debug)]
+enum Expression {
+    Literal(Value),
+    Identifier(String),
+    Not(Box<Expression>),
+    And(Vec<Expression>),
+    Or(Vec<Expression>),
+    Compare(Comparison, Box<Expression>, Box<Expression>),
+    // A chain of operators of the same precedence, applied from left to right, which
+    // is kept flat like AND and OR however long it is.
+    Arithmetic(Box<Expression>, Vec<(Operator, Expression)>),
+    Negate(Box<Expression>),
+    Between(Box<Expression>, Box<Expression>, Box<Expression>),
+    In(Box<Expression>, Vec<String>),
+    Like(Box<Expression>, Vec<PatternPart>),
+    IsNull(Box<Expression>),
+}
+
+impl Expression {
+    // None is unknown, i.e. SQL NULL, with AND, OR and NOT following three-valued logic.
+    fn evaluate(&self, message: &Message) -> Option<Value> {
+        match self {
+            Expression::Literal(value) => Some(value.clone()),
+            Expression::Identifier(name) => identifier(message, name),
+            Expression::Not(expression) => {
+                Some(Value::Bool(!expression.evaluate(message)?.as_bool()?))
+            }
+            Expression::And(expressions) => {
+                let mut result = Some(true);
+                for expression in expressions {
+                    match expression
+                        .evaluate(message)
+                        .and_then(|value| value.as_bool())
+                    {
+                        Some(false) => return Some(Value::Bool(false)),
+                        Some(true) => {}
+                        None => result = None,
+                    }
+                }
+                result.map(Value::Bool)
+            }
+            Expression::Or(expressions) => {
+                let mut result = Some(false);
+                for expression in expressions {
+                    match expression
+                        .evaluate(message)
+                        .and_then(|value| value.as_bool())
+                    {
+                        Some(true) => return Some(Value::Bool(true)),
+                        Some(false) => {}
+                        None => result = None,
+                    }
+                }
+                result.map(Value::Bool)
+            }
+            Expression::Compare(comparison, left, right) => compare(
+                *comparison,
+                &left.evaluate(message)?,
+                &right.evaluate(message)?,
+            )
+            .map(Value::Bool),
+            Expression::Arithmetic(first, rest) => rest
+                .iter()
+                .try_fold(first.evaluate(message)?, |left, (operator, right)| {
+                    arithmetic(*operator, left, right.evaluate(message)?)
+                }),
+            Expression::Negate(expression) => match expression.evaluate(message)? {
+                Value::Int(value) => value.checked_neg().map(Value::Int),
+                Value::Float(value) => Some(Value::Float(-value)),
+                _ => None,
+            },
+            Expression::Between(expression, low, high) => {
+                let value = expression.evaluate(message)?;
+                let low = low
+                    .evaluate(message)
+                    .and_then(|low| compare(Comparison::GreaterOrEqual, &value, &low));
+                let high = high
+                    .evaluate(message)
+                    .and_then(|high| compare(Comparison::LessOrEqual, &value, &high));
+                match (low, high) {
+                    (Some(false), _) | (_, Some(false)) => Some(Value::Bool(false)),
+                    (Some(true), Some(true)) => Some(Value::Bool(true)),
+                    _ => None,
+                }
+            }
+            Expression::In(expression, list) => match expression.evaluate(message)? {
+                Value::String(value) => Some(Value::Bool(list.contains(&value))),
+                _ => None,
+            },
+            Expression::Like(expression, pattern) => match expression.evaluate(message)? {
+                Value::String(value) => {
+                    let value: Vec<char> = value.chars().collect();
+                    Some(Value::Bool(like(&value, pattern)))
+                }
+                _ => None,
+            },
+            Expression::IsNull(expression) => {
+                Some(Value::Bool(expression.evaluate(message).is_none()))
+            }
+        }
+    }
+}
+
+// The JMS header fields and properties stand for the fields of the header and
+// properties sections they map to, any other identifier for an application property.
+fn identifier(message: &Message, name: &str) -> Option<Value> {
+    let header = message.header.as_ref();
+    let properties = message.properties.as_ref();
+    let delivery_count = header.map_or(0, |header| header.delivery_count) as i64;
+    match name {
+        "JMSMessageID" => Value::from_constructor(&properties?.message_id),
+        "JMSCorrelationID" => Value::from_constructor(&properties?.correlation_id),
+        "JMSType" => properties?.subject.clone().map(Value::String),
+        "JMSTimestamp" => properties?.creation_time.map(Value::Int),
+        "JMSExpiration" => properties?.absolute_expiry_time.map(Value::Int),
+        "JMSPriority" => Some(Value::Int(header.map_or(4, |header| header.priority) as i64)),
+        "JMSDeliveryMode" => Some(Value::String(String::from(
+            if header.is_some_and(|header| header.durable) {
+                "PERSISTENT"
+            } else {
+                "NON_PERSISTENT"
+            },
+        ))),
+        "JMSRedelivered" => Some(Value::Bool(delivery_count > 0)),
+        "JMSXDeliveryCount" => Some(Value::Int(delivery_count + 1)),
+        "JMSXUserID" => properties?
+            .user_id
+            .clone()
+            .and_then(|user_id| String::from_utf8(user_id).ok())
+            .map(Value::String),
+        "JMSXGroupID" => properties?.group_id.clone().map(Value::String),
+        "JMSXGroupSeq" => properties?
+            .group_sequence
+            .map(|group_sequence| Value::Int(group_sequence as i64)),
+        _ => Value::from_constructor(message.application_properties.get(name)?),
+    }
+}
+
+// Numbers compare with each other, strings and booleans only for (in)equality.
+fn compare(comparison: Comparison, left: &Value, right: &Value) -> Option<bool> {
+    let equality = matches!(comparison, Comparison::Equal | Comparison::NotEqual);
+    let ordering = match (left, right) {
+        (Value::Int(left), Value::Int(right)) => Some(left.cmp(right)),
+        (Value::String(left), Value::String(right)) if equality => Some(left.cmp(right)),
+        (Value::Bool(left), Value::Bool(right)) if equality => Some(left.cmp(right)),
+        (left, right) => left.as_float()?.partial_cmp(&right.as_float()?),
+    }?;
+    Some(match comparison {
+        Comparison::Equal => ordering == Ordering::Equal,
+        Comparison::NotEqual => ordering != Ordering::Equal,
+        Comparison::Less => ordering == Ordering::Less,
+        Comparison::Greater => ordering == Ordering::Greater,
+        Comparison::LessOrEqual => ordering != Ordering::Greater,
+        Comparison::GreaterOrEqual => ordering != Ordering::Less,
+    })
+}
+
+fn arithmetic_chain(first: Expression, rest: Vec<(Operator, Expression)>) -> Expression {
+    match rest.is_empty() {
+        true => first,
+        false => Expression::Arithmetic(Box::new(first), rest),
+    }
+}
+
+// Integers stay integers unless they overflow, which makes the result unknown.
+fn arithmetic(operator: Operator, left: Value, right: Value) -> Option<Value> {
+    if let (Value::Int(left), Value::Int(right)) = (&left, &right) {
+        return match operator {
+            Operator::Add => left.checked_add(*right),
+            Operator::Subtract => left.checked_sub(*right),
+            Operator::Multiply => left.checked_mul(*right),
+            Operator::Divide => left.checked_div(*right),
+        }
+        .map(Value::Int);
+    }
+    let (left, right) = (left.as_float()?, right.as_float()?);
+    Some(Value::Float(match operator {
+        Operator::Add => left + right,
+        Operator::Subtract => left - right,
+        Operator::Multiply => left * right,
+        Operator::Divide => left / right,
+    }))
+}
+
+// Goes through the pattern keeping track of how much of the value it may match so
+// far, which takes no backtracking.
+fn like(value: &[char], pattern: &[PatternPart]) -> bool {
+    // Whether the pattern so far matches the first so many characters of the value.
+    let mut matched = vec![false; value.len() + 1];
+    matched[0] = true;
+    for part in pattern {
+        let mut next = vec![false; value.len() + 1];
+        match part {
+            PatternPart::Any => {
+                let mut any = false;
+                for (next, matched) in next.iter_mut().zip(matched.iter()) {
+                    any |= matched;
+                    *next = any;
+                }
+            }
+            PatternPart::One | PatternPart::Char(_) => {
+                for (index, char) in value.iter().enumerate() {
+                    next[index + 1] = matched[index]
+                        && match part {
+                            PatternPart::Char(expected) => char == expected,
+                            _ => true,
+                        };
+                }
+            }
+        }
+        matched = next;
+    }
+    matched[value.len()]
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    // A keyword or identifier, keywords being case insensitive.
+    Word(String),
+    // An identifier in double quotes, which may be any string.
+    Identifier(String),
+    String(String),
+    Int(i64),
+    Float(f64),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: &[&str] = &[
+    "<>", "<=", ">=", "=", "<", ">", "+", "-", "*", "/", "(", ")", ",",
+];
+
+fn tokenize(selector: &str) -> Result<Vec<Token>, &'static str> {
+    let chars: Vec<char> = selector.chars().collect();
+    let mut tokens = vec![];
+    let mut index = 0;
+    while let Some(char) = chars.get(index).copied() {
+        if char.is_whitespace() {
+            index += 1;
+        } else if char == '\'' || char == '"' {
+            // The quote is escaped by doubling it.
+            let mut value = String::new();
+            index += 1;
+            loop {
+                match chars.get(index) {
+                    None => return Err("Invalid selector: unterminated quote"),
+                    Some(quote) if *quote == char && chars.get(index + 1) == Some(&char) => {
+                        value.push(char);
+                        index += 2;
+                    }
+                    Some(quote) if *quote == char => {
+                        index += 1;
+                        break;
+                    }
+                    Some(other) => {
+                        value.push(*other);
+                        index += 1;
+                    }
+                }
+            }
+            tokens.push(match char {
+                '\'' => Token::String(value),
+                _ => Token::Identifier(value),
+            });
+        } else if char.is_ascii_digit()
+            || (char == '.' && chars.get(index + 1).is_some_and(char::is_ascii_digit))
+        {
+            let start = index;
+            let mut float = false;
+            while chars.get(index).is_some_and(char::is_ascii_digit) {
+                index += 1;
+            }
+            if chars.get(index) == Some(&'.') {
+                float = true;
+                index += 1;
+                while chars.get(index).is_some_and(char::is_ascii_digit) {
+                    index += 1;
+                }
+            }
+            if matches!(chars.get(index), Some('e' | 'E')) {
+                let digits = match chars.get(index + 1) {
+                    Some('+' | '-') => index + 2,
+                    _ => index + 1,
+                };
+                if chars.get(digits).is_some_and(char::is_ascii_digit) {
+                    float = true;
+                    index = digits;
+                    while chars.get(index).is_some_and(char::is_ascii_digit) {
+                        index += 1;
+                    }
+                }
+            }
+            let number: String = chars[start..index].iter().collect();
+            // The type suffixes of Java literals.
+            match chars.get(index) {
+                Some('l' | 'L') if !float => index += 1,
+                Some('f' | 'F' | 'd' | 'D') => {
+                    float = true;
+                    index += 1;
+                }
+                _ => {}
+            }
+            tokens.push(if float {
+                Token::Float(number.parse().map_err(|_| "Invalid selector: bad number")?)
+            } else {
+                Token::Int(number.parse().map_err(|_| "Invalid selector: bad number")?)
+            });
+        } else if char.is_alphabetic() || char == '_' || char == '$' {
+            let start = index;
+            while chars
+                .get(index)
+                .is_some_and(|char| char.is_alphanumeric() || *char == '_' || *char == '$')
+            {
+                index += 1;
+            }
+            tokens.push(Token::Word(chars[start..index].iter().collect()));
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| {
+                    symbol
+                        .chars()
+                        .enumerate()
+                        .all(|(offset, char)| chars.get(index + offset) == Some(&char))
+                })
+                .ok_or("Invalid selector: unexpected character")?;
+            index += symbol.len();
+            tokens.push(Token::Symbol(symbol));
+        }
+    }
+    Ok(tokens)
+}
+
+// A recursive descent parser of the selector grammar, from the loosest binding
+// operator to the tightest:
+//     OR, AND, NOT, comparisons (= <> < > <= >= BETWEEN IN LIKE IS NULL),
+//     + -, * /, unary + -, then literals, identifiers and parentheses.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.tokens.get(self.position) {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword) => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn symbol(&mut self, symbol: &str) -> bool {
+        match self.tokens.get(self.position) {
+            Some(Token::Symbol(token)) if *token == symbol => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), &'static str> {
+        match self.symbol(symbol) {
+            true => Ok(()),
+            false => Err("Invalid selector: unexpected token"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, &'static str> {
+        match self.next() {
+            Some(Token::String(value)) => Ok(value),
+            _ => Err("Invalid selector: string literal expected"),
+        }
+    }
+
+    fn deeper(&mut self) -> Result<(), &'static str> {
+        self.depth += 1;
+        match self.depth > MAX_DEPTH {
+            true => Err("Invalid selector: nested too deeply"),
+            false => Ok(()),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expression, &'static str> {
+        let mut expressions = vec![self.and()?];
+        while self.keyword("OR") {
+            expressions.push(self.and()?);
+        }
+        Ok(match expressions.len() {
+            1 => expressions.remove(0),
+            _ => Expression::Or(expressions),
+        })
+    }
+
+    fn and(&mut self) -> Result<Expression, &'static str> {
+        let mut expressions = vec![self.not()?];
+        while self.keyword("AND") {
+            expressions.push(self.not()?);
+        }
+        Ok(match expressions.len() {
+            1 => expressions.remove(0),
+            _ => Expression::And(expressions),
+        })
+    }
+
+    fn not(&mut self) -> Result<Expression, &'static str> {
+        if !self.keyword("NOT") {
+            return self.comparison();
+        }
+        let depth = self.depth;
+        self.deeper()?;
+        let expression = Expression::Not(Box::new(self.not()?));
+        self.depth = depth;
+        Ok(expression)
+    }
+
+    fn comparison(&mut self) -> Result<Expression, &'static str> {
+        let left = Box::new(self.additive()?);
+        for (symbol, comparison) in [
+            ("=", Comparison::Equal),
+            ("<>", Comparison::NotEqual),
+            ("<", Comparison::Less),
+            (">", Comparison::Greater),
+            ("<=", Comparison::LessOrEqual),
+            (">=", Comparison::GreaterOrEqual),
+        ] {
+            if self.symbol(symbol) {
+                return Ok(Expression::Compare(
+                    comparison,
+                    left,
+                    Box::new(self.additive()?),
+                ));
+            }
+        }
+        if self.keyword("IS") {
+            let negated = self.keyword("NOT");
+            if !self.keyword("NULL") {
+                return Err("Invalid selector: NULL expected");
+            }
+            return Ok(negate(negated, Expression::IsNull(left)));
+        }
+        let negated = self.keyword("NOT");
+        let expression = if self.keyword("BETWEEN") {
+            let low = self.additive()?;
+            if !self.keyword("AND") {
+                return Err("Invalid selector: AND expected");
+            }
+            Expression::Between(left, Box::new(low), Box::new(self.additive()?))
+        } else if self.keyword("IN") {
+            self.expect_symbol("(")?;
+            let mut list = vec![self.string()?];
+            while self.symbol(",") {
+                list.push(self.string()?);
+            }
+            self.expect_symbol(")")?;
+            Expression::In(left, list)
+        } else if self.keyword("LIKE") {
+            let pattern = self.string()?;
+            let escape = match self.keyword("ESCAPE") {
+                true => {
+                    let escape: Vec<char> = self.string()?.chars().collect();
+                    match escape.as_slice() {
+                        [escape] => Some(*escape),
+                        _ => return Err("Invalid selector: the escape is not one character"),
+                    }
+                }
+                false => None,
+            };
+            Expression::Like(left, like_pattern(&pattern, escape))
+        } else if negated {
+            return Err("Invalid selector: BETWEEN, IN or LIKE expected");
+        } else {
+            return Ok(*left);
+        };
+        Ok(negate(negated, expression))
+    }
+
+    fn additive(&mut self) -> Result<Expression, &'static str> {
+        let first = self.multiplicative()?;
+        let mut rest = vec![];
+        loop {
+            let operator = if self.symbol("+") {
+                Operator::Add
+            } else if self.symbol("-") {
+                Operator::Subtract
+            } else {
+                break;
+            };
+            rest.push((operator, self.multiplicative()?));
+        }
+        Ok(arithmetic_chain(first, rest))
+    }
+
+    fn multiplicative(&mut self) -> Result<Expression, &'static str> {
+        let first = self.unary()?;
+        let mut rest = vec![];
+        loop {
+            let operator = if self.symbol("*") {
+                Operator::Multiply
+            } else if self.symbol("/") {
+                Operator::Divide
+            } else {
+                break;
+            };
+            rest.push((operator, self.unary()?));
+        }
+        Ok(arithmetic_chain(first, rest))
+    }
+
+    fn unary(&mut self) -> Result<Expression, &'static str> {
+        let negated = if self.symbol("-") {
+            true
+        } else if self.symbol("+") {
+            false
+        } else {
+            return self.primary();
+        };
+        let depth = self.depth;
+        self.deeper()?;
+        let expression = self.unary()?;
+        self.depth = depth;
+        Ok(match negated {
+            true => Expression::Negate(Box::new(expression)),
+            false => expression,
+        })
+    }
+
+    fn primary(&mut self) -> Result<Expression, &'static str> {
+        match self.next() {
+            Some(Token::Symbol("(")) => {
+                let depth = self.depth;
+                self.deeper()?;
+                let expression = self.or()?;
+                self.depth = depth;
+                self.expect_symbol(")")?;
+                Ok(expression)
+            }
+            Some(Token::String(value)) => Ok(Expression::Literal(Value::String(value))),
+            Some(Token::Int(value)) => Ok(Expression::Literal(Value::Int(value))),
+            Some(Token::Float(value)) => Ok(Expression::Literal(Value::Float(value))),
+            Some(Token::Identifier(name)) => Ok(Expression::Identifier(name)),
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("TRUE") => {
+                Ok(Expression::Literal(Value::Bool(true)))
+            }
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("FALSE") => {
+                Ok(Expression::Literal(Value::Bool(false)))
+            }
+            Some(Token::Word(word))
+                if !KEYWORDS
+                    .iter()
+                    .any(|keyword| word.eq_ignore_ascii_case(keyword)) =>
+            {
+                Ok(Expression::Identifier(word))
+            }
+            Some(_) => Err("Invalid selector: unexpected token"),
+            None => Err("Invalid selector: unexpected end"),
+        }
+    }
+}
+
+fn negate(negated: bool, expression: Expression) -> Expression {
+    match negated {
+        true => Expression::Not(Box::new(expression)),
+        false => expression,
+    }
+}
+
+fn like_pattern(pattern: &str, escape: Option<char>) -> Vec<PatternPart> {
+    let mut parts = vec![];
+    let mut chars = pattern.chars();
+    while let Some(char) = chars.next() {
+        parts.push(match char {
+            _ if Some(char) == escape => match chars.next() {
+                Some(escaped) => PatternPart::Char(escaped),
+                None => PatternPart::Char(char),
+            },
+            '%' => PatternPart::Any,
+            '_' => PatternPart::One,
+            _ => PatternPart::Char(char),
+        });
+    }
+    parts
+}
+
+// A message selector in the SQL-92 based language of JMS: a conditional expression
+// over the fields of the header and properties sections and the application
+// properties of a message, which the message matches if it evaluates to true.
+#[derive(Clone, Debug)]
+pub struct Selector(Expression);
+
+impl Selector {
+    pub fn parse(selector: &str) -> Result<Self, &'static str> {
+        let mut parser = Parser {
+            tokens: tokenize(selector)?,
+            position: 0,
+            depth: 0,
+        };
+        let expression = parser.or()?;
+        if parser.position != parser.tokens.len() {
+            return Err("Invalid selector: unexpected token");
+        }
+        Ok(Self(expression))
+    }
+
+    pub fn matches(&self, message: &Message) -> bool {
+        self.0.evaluate(message) == Some(Value::Bool(true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amqp::messaging::message::{Header, Properties};
+    use crate::amqp::types::primitive::InnerDouble;
+
+    fn message(application_properties: &[(&str, Primitive)]) -> Message {
+        Message {
+            application_properties: application_properties
+                .iter()
+                .map(|(name, value)| (name.to_string(), Constructor::PrimitiveType(value.clone())))
+                .collect(),
+            ..Message::default()
+        }
+    }
+
+    fn matches(selector: &str, message: &Message) -> bool {
+        Selector::parse(selector).unwrap().matches(message)
+    }
+
+    #[test]
+    fn comparisons() {
+        let message = message(&[
+            ("color", Primitive::String(String::from("red"))),
+            ("weight", Primitive::Int(25)),
+            ("price", Primitive::Double(InnerDouble { value: 9.5 })),
+            ("express", Primitive::Boolean(true)),
+        ]);
+        assert!(matches("color = 'red'", &message));
+        assert!(!matches("color <> 'red'", &message));
+        assert!(matches("weight > 20 AND weight <= 25", &message));
+        assert!(matches("price < 10", &message));
+        assert!(matches("weight = 25.0", &message));
+        assert!(matches("express", &message));
+        assert!(matches("express = TRUE", &message));
+        // Strings and booleans only compare for (in)equality.
+        assert!(!matches("color > 'blue'", &message));
+        assert!(!matches("weight = '25'", &message));
+    }
+
+    #[test]
+    fn missing_properties_are_unknown() {
+        let message = message(&[("weight", Primitive::Int(25))]);
+        assert!(!matches("color = 'red'", &message));
+        assert!(!matches("color <> 'red'", &message));
+        assert!(!matches("NOT (color = 'red')", &message));
+        assert!(matches("color IS NULL", &message));
+        assert!(!matches("color IS NOT NULL", &message));
+        assert!(matches("weight IS NOT NULL", &message));
+    }
+
+    #[test]
+    fn three_valued_logic() {
+        let message = message(&[("weight", Primitive::Int(25))]);
+        // unknown AND false is false, unknown AND true is unknown.
+        assert!(matches("NOT (color = 'red' AND weight = 0)", &message));
+        assert!(!matches("NOT (color = 'red' AND weight = 25)", &message));
+        // unknown OR true is true, unknown OR false is unknown.
+        assert!(matches("color = 'red' OR weight = 25", &message));
+        assert!(!matches("color = 'red' OR weight = 0", &message));
+        assert!(!matches("NOT (color = 'red' OR weight = 0)", &message));
+    }
+
+    #[test]
+    fn arithmetic() {
+        let message = message(&[
+            ("weight", Primitive::Int(25)),
+            ("large", Primitive::Long(i64::MAX)),
+        ]);
+        assert!(matches("weight * 2 + 1 = 51", &message));
+        assert!(matches("weight - 5 * 2 = 15", &message));
+        assert!(matches("(weight - 5) * 2 = 40", &message));
+        assert!(matches("weight / 2 = 12", &message));
+        assert!(matches("weight / 2.0 = 12.5", &message));
+        assert!(matches("-weight = -25", &message));
+        // Overflowing makes the result unknown, as does dividing by zero.
+        assert!(!matches("large + 1 > 0", &message));
+        assert!(!matches("large + 1 <= 0", &message));
+        assert!(!matches("weight / 0 = 0", &message));
+    }
+
+    #[test]
+    fn between_in_and_like() {
+        let message = message(&[
+            ("weight", Primitive::Int(25)),
+            ("color", Primitive::String(String::from("dark_red"))),
+        ]);
+        assert!(matches("weight BETWEEN 20 AND 30", &message));
+        assert!(!matches("weight NOT BETWEEN 20 AND 30", &message));
+        assert!(matches("color IN ('red', 'dark_red')", &message));
+        assert!(matches("color NOT IN ('red', 'blue')", &message));
+        assert!(matches("color LIKE 'dark%'", &message));
+        assert!(matches("color LIKE 'dark_re_'", &message));
+        assert!(!matches("color LIKE 'dark'", &message));
+        assert!(matches("color LIKE 'dark!_%' ESCAPE '!'", &message));
+        assert!(!matches("color LIKE 'darkest!_%' ESCAPE '!'", &message));
+        assert!(matches("color NOT LIKE '%blue%'", &message));
+        assert!(!matches("shade IN ('red')", &message));
+        assert!(!matches("shade NOT IN ('red')", &message));
+    }
+
+    #[test]
+    fn header_and_properties_fields() {
+        let mut message = message(&[]);
+        assert!(matches("JMSPriority = 4", &message));
+        assert!(matches("JMSDeliveryMode = 'NON_PERSISTENT'", &message));
+        assert!(matches("JMSRedelivered = FALSE", &message));
+        message.header = Some(Header {
+            durable: true,
+            priority: 7,
+            delivery_count: 2,
+            ..Header::default()
+        });
+        message.properties = Some(Properties {
+            subject: Some(String::from("order")),
+            ..Properties::default()
+        });
+        assert!(matches("JMSPriority > 5", &message));
+        assert!(matches("JMSDeliveryMode = 'PERSISTENT'", &message));
+        assert!(matches(
+            "JMSRedelivered AND JMSXDeliveryCount = 3",
+            &message
+        ));
+        assert!(matches("JMSType = 'order'", &message));
+    }
+
+    #[test]
+    fn quoting() {
+        let message = message(&[
+            ("name", Primitive::String(String::from("it's"))),
+            ("and", Primitive::Int(1)),
+        ]);
+        assert!(matches("name = 'it''s'", &message));
+        assert!(matches("\"and\" = 1", &message));
+        assert!(matches(
+            "name like 'it%' and \"and\" between 0 and 2",
+            &message
+        ));
+    }
+
+    #[test]
+    fn invalid_selectors() {
+        for selector in [
+            "",
+            "color =",
+            "color = 'red",
+            "color = 'red')",
+            "(color = 'red'",
+            "color # 'red'",
+            "AND = 1",
+            "weight BETWEEN 1",
+            "color IN ()",
+            "color IS 'red'",
+            "color LIKE 'a' ESCAPE 'ab'",
+        ] {
+            assert!(Selector::parse(selector).is_err(), "{}", selector);
+        }
+    }
+
+    #[test]
+    fn long_chains_are_not_nesting() {
+        let message = message(&[("weight", Primitive::Int(1))]);
+        let sum = vec!["weight"; 1000].join(" + ");
+        assert!(matches(&format!("{} = 1000", sum), &message));
+        let conditions = vec!["weight = 1"; 1000].join(" AND ");
+        assert!(matches(&conditions, &message));
+    }
+
+    #[test]
+    fn deep_nesting_is_rejected() {
+        let nested = |depth| format!("{}weight = 1{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(Selector::parse(&nested(50)).is_ok());
+        assert!(Selector::parse(&nested(1000)).is_err());
+        assert!(Selector::parse(&format!("{}TRUE", "NOT ".repeat(1000))).is_err());
+        assert!(Selector::parse(&format!("{}1 = -1", "-".repeat(1000))).is_err());
+    }
+}
@@ -0,0 +1,496 @@
+use std::{collections::HashMap, ops::Deref, slice::Iter};
+
+use crate::amqp::messaging::delivery_state::{
+    DeliveryState, read_delivery_state, write_delivery_state,
+};
+use crate::amqp::transport::performative::{
+    read_bool, read_fields, read_map, read_string, read_symbol_array, read_uint,
+    trim_trailing_nulls, write_bool, write_map, write_string, write_symbol_array, write_uint,
+};
+use crate::amqp::types::{constructor::Constructor, descriptor::Descriptor, primitive::Primitive};
+
+// <type name="terminus-durability" class="restricted" source="uint">
+//     <choice name="none" value="0"/>
+//     <choice name="configuration" value="1"/>
+//     <choice name="unsettled-state" value="2"/>
+// </type>
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TerminusDurability {
+    #[default]
+    None,
+    Configuration,
+    UnsettledState,
+}
+
+impl TerminusDurability {
+    fn from_uint(value: u32) -> Result<Self, &'static str> {
+        match value {
+            0 => Ok(TerminusDurability::None),
+            1 => Ok(TerminusDurability::Configuration),
+            2 => Ok(TerminusDurability::UnsettledState),
+            _ => Err("Unknown terminus-durability"),
+        }
+    }
+
+    fn as_uint(self) -> u32 {
+        self as u32
+    }
+}
+
+// <type name="terminus-expiry-policy" class="restricted" source="symbol">
+//     <choice name="link-detach" value="link-detach"/>
+//     <choice name="session-end" value="session-end"/>
+//     <choice name="connection-close" value="connection-close"/>
+//     <choice name="never" value="never"/>
+// </type>
+// The terminus is kept for the timeout of its source or target once the event
+// happened (see 3.5.6).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TerminusExpiryPolicy {
+    LinkDetach,
+    #[default]
+    SessionEnd,
+    ConnectionClose,
+    Never,
+}
+
+impl TerminusExpiryPolicy {
+    fn from_symbol(value: &[u8]) -> Result<Self, &'static str> {
+        match value {
+            b"link-detach" => Ok(TerminusExpiryPolicy::LinkDetach),
+            b"session-end" => Ok(TerminusExpiryPolicy::SessionEnd),
+            b"connection-close" => Ok(TerminusExpiryPolicy::ConnectionClose),
+            b"never" => Ok(TerminusExpiryPolicy::Never),
+            _ => Err("Unknown terminus-expiry-policy"),
+        }
+    }
+
+    fn as_symbol(self) -> &'static [u8] {
+        match self {
+            TerminusExpiryPolicy::LinkDetach => b"link-detach",
+            TerminusExpiryPolicy::SessionEnd => b"session-end",
+            TerminusExpiryPolicy::ConnectionClose => b"connection-close",
+            TerminusExpiryPolicy::Never => b"never",
+        }
+    }
+}
+
+// <type name="std-dist-mode" class="restricted" source="symbol" provides="distribution-mode">
+//     <choice name="move" value="move"/>
+//     <choice name="copy" value="copy"/>
+// </type>
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DistributionMode {
+    // Every message goes to one of the links attached to the node.
+    Move,
+    // Every link attached to the node gets its own copy of every message.
+    Copy,
+}
+
+impl DistributionMode {
+    fn from_symbol(value: &[u8]) -> Result<Self, &'static str> {
+        match value {
+            b"move" => Ok(DistributionMode::Move),
+            b"copy" => Ok(DistributionMode::Copy),
+            _ => Err("Unknown distribution-mode"),
+        }
+    }
+
+    fn as_symbol(self) -> &'static [u8] {
+        match self {
+            DistributionMode::Move => b"move",
+            DistributionMode::Copy => b"copy",
+        }
+    }
+}
+
+// 3.5.10 Lifetime Policies, the lifetime-policy entry of the node-properties of a
+// dynamic node. A node never goes before the link that created it, the policy
+// telling what else it waits for.
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LifetimePolicy {
+    // <descriptor name="amqp:delete-on-close:list" code="0x00000000:0x0000002b"/>
+    #[default]
+    DeleteOnClose,
+    // <descriptor name="amqp:delete-on-no-links:list" code="0x00000000:0x0000002c"/>
+    DeleteOnNoLinks,
+    // <descriptor name="amqp:delete-on-no-messages:list" code="0x00000000:0x0000002d"/>
+    DeleteOnNoMessages,
+    // <descriptor name="amqp:delete-on-no-links-or-messages:list" code="0x00000000:0x0000002e"/>
+    DeleteOnNoLinksOrMessages,
+}
+
+impl LifetimePolicy {
+    // The policy in the node-properties, delete-on-close if they have none (see 3.5.9).
+    pub fn from_node_properties(
+        properties: &HashMap<Constructor, Constructor>,
+    ) -> Result<Self, &'static str> {
+        let key = Constructor::PrimitiveType(Primitive::Symbol(b"lifetime-policy".to_vec()));
+        match properties.get(&key) {
+            Some(Constructor::DescribedType(descriptor, _)) => {
+                match Descriptor::from_constructor(descriptor.deref())? {
+                    Descriptor::DeleteOnClose => Ok(LifetimePolicy::DeleteOnClose),
+                    Descriptor::DeleteOnNoLinks => Ok(LifetimePolicy::DeleteOnNoLinks),
+                    Descriptor::DeleteOnNoMessages => Ok(LifetimePolicy::DeleteOnNoMessages),
+                    Descriptor::DeleteOnNoLinksOrMessages => {
+                        Ok(LifetimePolicy::DeleteOnNoLinksOrMessages)
+                    }
+                    _ => Err("Unknown lifetime-policy"),
+                }
+            }
+            Some(Constructor::PrimitiveType(Primitive::Null)) | None => {
+                Ok(LifetimePolicy::default())
+            }
+            _ => Err("The lifetime-policy is not a described type"),
+        }
+    }
+}
+
+// <type name="source" class="composite" source="list" provides="source">
+// <descriptor name="amqp:source:list" code="0x00000000:0x00000028"/>
+#[derive(Debug, Clone, Default)]
+pub struct Source {
+    // <field name="address" type="*" requires="address"/>
+    pub address: Option<String>,
+    // <field name="durable" type="terminus-durability" default="none"/>
+    pub durable: TerminusDurability,
+    // <field name="expiry-policy" type="terminus-expiry-policy" default="session-end"/>
+    pub expiry_policy: TerminusExpiryPolicy,
+    // <field name="timeout" type="seconds" default="0"/>
+    pub timeout: u32,
+    // <field name="dynamic" type="boolean" default="false"/>
+    pub dynamic: bool,
+    // <field name="dynamic-node-properties" type="node-properties"/>
+    pub dynamic_node_properties: HashMap<Constructor, Constructor>,
+    // <field name="distribution-mode" type="symbol" requires="distribution-mode"/>
+    pub distribution_mode: Option<DistributionMode>,
+    // <type name="filter-set" class="restricted" source="map"/>
+    // <field name="filter" type="filter-set"/>
+    pub filter: HashMap<Constructor, Constructor>,
+    // <field name="default-outcome" type="*" requires="outcome"/>
+    pub default_outcome: Option<DeliveryState>,
+    // <field name="outcomes" type="symbol" multiple="true"/>
+    pub outcomes: Vec<Vec<u8>>,
+    // <field name="capabilities" type="symbol" multiple="true"/>
+    pub capabilities: Vec<Vec<u8>>,
+}
+// </type>
+
+// <type name="target" class="composite" source="list" provides="target">
+// <descriptor name="amqp:target:list" code="0x00000000:0x00000029"/>
+#[derive(Debug, Clone, Default)]
+pub struct Target {
+    // <field name="address" type="*" requires="address"/>
+    pub address: Option<String>,
+    // <field name="durable" type="terminus-durability" default="none"/>
+    pub durable: TerminusDurability,
+    // <field name="expiry-policy" type="terminus-expiry-policy" default="session-end"/>
+    pub expiry_policy: TerminusExpiryPolicy,
+    // <field name="timeout" type="seconds" default="0"/>
+    pub timeout: u32,
+    // <field name="dynamic" type="boolean" default="false"/>
+    pub dynamic: bool,
+    // <field name="dynamic-node-properties" type="node-properties"/>
+    pub dynamic_node_properties: HashMap<Constructor, Constructor>,
+    // <field name="capabilities" type="symbol" multiple="true"/>
+    pub capabilities: Vec<Vec<u8>>,
+}
+// </type>
+
+pub(crate) fn read_source(
+    field_iter: &mut Iter<Constructor>,
+) -> Result<Option<Source>, &'static str> {
+    let Some(fields) = read_terminus(field_iter, Descriptor::Source)? else {
+        return Ok(None);
+    };
+    let mut field_iter = fields.iter();
+    Ok(Some(Source {
+        address: read_string(&mut field_iter, false)?,
+        durable: read_durability(&mut field_iter)?,
+        expiry_policy: read_expiry_policy(&mut field_iter)?,
+        timeout: read_uint(&mut field_iter, true, Some(0))?.unwrap_or(0),
+        dynamic: read_bool(&mut field_iter, true, Some(false))?.unwrap_or(false),
+        dynamic_node_properties: read_map(&mut field_iter)?,
+        distribution_mode: match field_iter.next() {
+            Some(Constructor::PrimitiveType(Primitive::Symbol(mode))) => {
+                Some(DistributionMode::from_symbol(mode)?)
+            }
+            Some(Constructor::PrimitiveType(Primitive::Null)) | None => None,
+            _ => return Err("Invalid field type: symbol expected"),
+        },
+        filter: read_map(&mut field_iter)?,
+        default_outcome: read_delivery_state(&mut field_iter)?,
+        outcomes: read_symbol_array(&mut field_iter)?,
+        capabilities: read_symbol_array(&mut field_iter)?,
+    }))
+}
+
+pub(crate) fn read_target(
+    field_iter: &mut Iter<Constructor>,
+) -> Result<Option<Target>, &'static str> {
+    let Some(fields) = read_terminus(field_iter, Descriptor::Target)? else {
+        return Ok(None);
+    };
+    let mut field_iter = fields.iter();
+    Ok(Some(Target {
+        address: read_string(&mut field_iter, false)?,
+        durable: read_durability(&mut field_iter)?,
+        expiry_policy: read_expiry_policy(&mut field_iter)?,
+        timeout: read_uint(&mut field_iter, true, Some(0))?.unwrap_or(0),
+        dynamic: read_bool(&mut field_iter, true, Some(false))?.unwrap_or(false),
+        dynamic_node_properties: read_map(&mut field_iter)?,
+        capabilities: read_symbol_array(&mut field_iter)?,
+    }))
+}
+
+pub(crate) fn write_source(source: Option<&Source>) -> Constructor {
+    let Some(source) = source else {
+        return Constructor::PrimitiveType(Primitive::Null);
+    };
+    write_terminus(
+        Descriptor::Source,
+        vec![
+            write_string(source.address.as_ref()),
+            write_durability(source.durable),
+            write_expiry_policy(source.expiry_policy),
+            write_uint(Some(source.timeout).filter(|timeout| *timeout > 0)),
+            write_bool(Some(source.dynamic).filter(|value| *value)),
+            write_map(&source.dynamic_node_properties),
+            Constructor::PrimitiveType(source.distribution_mode.map_or(Primitive::Null, |mode| {
+                Primitive::Symbol(mode.as_symbol().to_vec())
+            })),
+            write_map(&source.filter),
+            write_delivery_state(source.default_outcome.as_ref()),
+            write_symbol_array(&source.outcomes),
+            write_symbol_array(&source.capabilities),
+        ],
+    )
+}
+
+pub(crate) fn write_target(target: Option<&Target>) -> Constructor {
+    let Some(target) = target else {
+        return Constructor::PrimitiveType(Primitive::Null);
+    };
+    write_terminus(
+        Descriptor::Target,
+        vec![
+            write_string(target.address.as_ref()),
+            write_durability(target.durable),
+            write_expiry_policy(target.expiry_policy),
+            write_uint(Some(target.timeout).filter(|timeout| *timeout > 0)),
+            write_bool(Some(target.dynamic).filter(|value| *value)),
+            write_map(&target.dynamic_node_properties),
+            write_symbol_array(&target.capabilities),
+        ],
+    )
+}
+
+// The fields of the source or the target, which the attach of a link that is
+// being refused may leave out (see 2.6.3 Establishing Or Resuming A Link).
+fn read_terminus(
+    field_iter: &mut Iter<Constructor>,
+    expected: Descriptor,
+) -> Result<Option<Vec<Constructor>>, &'static str> {
+    match field_iter.next() {
+        Some(Constructor::DescribedType(descriptor, primitive)) => {
+            if Descriptor::from_constructor(descriptor.deref())? != expected {
+                return Err("Unsupported terminus type");
+            }
+            Ok(Some(read_fields(primitive.clone())?))
+        }
+        Some(Constructor::PrimitiveType(Primitive::Null)) | None => Ok(None),
+        _ => Err("The terminus is not a described type"),
+    }
+}
+
+fn write_terminus(descriptor: Descriptor, fields: Vec<Constructor>) -> Constructor {
+    let fields = trim_trailing_nulls(fields);
+    descriptor.describe(if fields.is_empty() {
+        Primitive::EmptyList
+    } else {
+        Primitive::List(fields)
+    })
+}
+
+fn read_durability(field_iter: &mut Iter<Constructor>) -> Result<TerminusDurability, &'static str> {
+    TerminusDurability::from_uint(read_uint(field_iter, true, Some(0))?.unwrap_or(0))
+}
+
+fn write_durability(durable: TerminusDurability) -> Constructor {
+    write_uint(Some(durable.as_uint()).filter(|value| *value > 0))
+}
+
+fn read_expiry_policy(
+    field_iter: &mut Iter<Constructor>,
+) -> Result<TerminusExpiryPolicy, &'static str> {
+    match field_iter.next() {
+        Some(Constructor::PrimitiveType(Primitive::Symbol(policy))) => {
+            TerminusExpiryPolicy::from_symbol(policy)
+        }
+        Some(Constructor::PrimitiveType(Primitive::Null)) | None => {
+            Ok(TerminusExpiryPolicy::default())
+        }
+        _ => Err("Invalid field type: symbol expected"),
+    }
+}
+
+fn write_expiry_policy(policy: TerminusExpiryPolicy) -> Constructor {
+    if policy == TerminusExpiryPolicy::default() {
+        return Constructor::PrimitiveType(Primitive::Null);
+    }
+    Constructor::PrimitiveType(Primitive::Symbol(policy.as_symbol().to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amqp::types::format_code::FormatCode;
+
+    fn symbol(value: &str) -> Constructor {
+        Constructor::PrimitiveType(Primitive::Symbol(value.as_bytes().to_vec()))
+    }
+
+    async fn decode(bytes: &[u8]) -> Constructor {
+        let mut rest = bytes;
+        let code = FormatCode::read(&mut rest).await.unwrap();
+        let constructor = Constructor::new(code, &mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        constructor
+    }
+
+    // Encodes the source, decodes it again and checks that it is encoded the same way,
+    // returning its encoding.
+    async fn source_round_trip(source: Source) -> Vec<u8> {
+        let bytes = write_source(Some(&source)).as_bytes().unwrap();
+        let constructor = decode(&bytes).await;
+        let decoded = read_source(&mut std::slice::from_ref(&constructor).iter())
+            .unwrap()
+            .unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", source));
+        assert_eq!(write_source(Some(&decoded)).as_bytes().unwrap(), bytes);
+        bytes
+    }
+
+    async fn target_round_trip(target: Target) -> Vec<u8> {
+        let bytes = write_target(Some(&target)).as_bytes().unwrap();
+        let constructor = decode(&bytes).await;
+        let decoded = read_target(&mut std::slice::from_ref(&constructor).iter())
+            .unwrap()
+            .unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", target));
+        assert_eq!(write_target(Some(&decoded)).as_bytes().unwrap(), bytes);
+        bytes
+    }
+
+    #[tokio::test]
+    async fn every_field_round_trips() {
+        source_round_trip(Source {
+            address: Some(String::from("orders")),
+            durable: TerminusDurability::UnsettledState,
+            expiry_policy: TerminusExpiryPolicy::Never,
+            timeout: 60,
+            dynamic: true,
+            dynamic_node_properties: HashMap::from([(
+                symbol("lifetime-policy"),
+                Descriptor::DeleteOnNoLinks.describe(Primitive::EmptyList),
+            )]),
+            distribution_mode: Some(DistributionMode::Copy),
+            filter: HashMap::from([(
+                symbol("selector"),
+                Descriptor::SelectorFilter
+                    .describe(Primitive::String(String::from("priority > 4"))),
+            )]),
+            default_outcome: Some(DeliveryState::Released),
+            outcomes: vec![
+                b"amqp:accepted:list".to_vec(),
+                b"amqp:released:list".to_vec(),
+            ],
+            capabilities: vec![b"queue".to_vec()],
+        })
+        .await;
+        target_round_trip(Target {
+            address: Some(String::from("orders")),
+            durable: TerminusDurability::Configuration,
+            expiry_policy: TerminusExpiryPolicy::LinkDetach,
+            timeout: 5,
+            dynamic: true,
+            dynamic_node_properties: HashMap::from([(
+                symbol("lifetime-policy"),
+                Descriptor::DeleteOnClose.describe(Primitive::EmptyList),
+            )]),
+            capabilities: vec![b"topic".to_vec()],
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn defaults_are_left_out() {
+        assert_eq!(
+            source_round_trip(Source::default()).await,
+            [0x00, 0x53, 0x28, 0x45]
+        );
+        assert_eq!(
+            target_round_trip(Target::default()).await,
+            [0x00, 0x53, 0x29, 0x45]
+        );
+        assert_eq!(
+            source_round_trip(Source {
+                address: Some(String::from("q")),
+                ..Source::default()
+            })
+            .await,
+            [0x00, 0x53, 0x28, 0xc0, 0x04, 0x01, 0xa1, 0x01, b'q']
+        );
+        // Fields before the last one set are null.
+        let mut expected = vec![0x00, 0x53, 0x29, 0xc0, 0x0a, 0x03, 0x40, 0x40, 0xa3, 0x05];
+        expected.extend_from_slice(b"never");
+        assert_eq!(
+            target_round_trip(Target {
+                expiry_policy: TerminusExpiryPolicy::Never,
+                ..Target::default()
+            })
+            .await,
+            expected
+        );
+
+        // Defaults sent explicitly read the same as left out ones.
+        let explicit = Descriptor::Target.describe(Primitive::List(vec![
+            Constructor::PrimitiveType(Primitive::String(String::from("q"))),
+            Constructor::PrimitiveType(Primitive::UInt(0)),
+            symbol("session-end"),
+            Constructor::PrimitiveType(Primitive::UInt(0)),
+            Constructor::PrimitiveType(Primitive::Boolean(false)),
+        ]));
+        let target = read_target(&mut std::slice::from_ref(&explicit).iter())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            write_target(Some(&target)).as_bytes().unwrap(),
+            [0x00, 0x53, 0x29, 0xc0, 0x04, 0x01, 0xa1, 0x01, b'q']
+        );
+
+        // No terminus at all is null.
+        assert_eq!(write_source(None).as_bytes().unwrap(), [0x40]);
+        assert!(read_target(&mut [].iter()).unwrap().is_none());
+    }
+
+    #[test]
+    fn lifetime_policy_defaults_to_delete_on_close() {
+        let properties = |policy: Descriptor| {
+            HashMap::from([(
+                symbol("lifetime-policy"),
+                policy.describe(Primitive::EmptyList),
+            )])
+        };
+        assert_eq!(
+            LifetimePolicy::from_node_properties(&HashMap::new()),
+            Ok(LifetimePolicy::DeleteOnClose)
+        );
+        assert_eq!(
+            LifetimePolicy::from_node_properties(&properties(Descriptor::DeleteOnNoMessages)),
+            Ok(LifetimePolicy::DeleteOnNoMessages)
+        );
+        assert!(LifetimePolicy::from_node_properties(&properties(Descriptor::Accepted)).is_err());
+    }
+}
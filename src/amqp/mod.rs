@@ -1,2 +1,3 @@
+pub mod messaging;
 pub mod transport;
 pub mod types;
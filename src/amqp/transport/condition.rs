@@ -0,0 +1,40 @@
+// Error conditions carried by the error field of Close, End, Detach and Rejected
+// (see 2.8.15 - 2.8.18). Only the ones defined by the specification are listed here.
+#![allow(dead_code)]
+
+// <type name="amqp-error" class="restricted" source="symbol" provides="error-condition">
+pub const INTERNAL_ERROR: &[u8] = b"amqp:internal-error";
+pub const NOT_FOUND: &[u8] = b"amqp:not-found";
+pub const UNAUTHORIZED_ACCESS: &[u8] = b"amqp:unauthorized-access";
+pub const DECODE_ERROR: &[u8] = b"amqp:decode-error";
+pub const RESOURCE_LIMIT_EXCEEDED: &[u8] = b"amqp:resource-limit-exceeded";
+pub const NOT_ALLOWED: &[u8] = b"amqp:not-allowed";
+pub const INVALID_FIELD: &[u8] = b"amqp:invalid-field";
+pub const NOT_IMPLEMENTED: &[u8] = b"amqp:not-implemented";
+pub const RESOURCE_LOCKED: &[u8] = b"amqp:resource-locked";
+pub const PRECONDITION_FAILED: &[u8] = b"amqp:precondition-failed";
+pub const RESOURCE_DELETED: &[u8] = b"amqp:resource-deleted";
+pub const ILLEGAL_STATE: &[u8] = b"amqp:illegal-state";
+pub const FRAME_SIZE_TOO_SMALL: &[u8] = b"amqp:frame-size-too-small";
+// </type>
+
+// <type name="connection-error" class="restricted" source="symbol" provides="error-condition">
+pub const CONNECTION_FORCED: &[u8] = b"amqp:connection:forced";
+pub const CONNECTION_FRAMING_ERROR: &[u8] = b"amqp:connection:framing-error";
+pub const CONNECTION_REDIRECT: &[u8] = b"amqp:connection:redirect";
+// </type>
+
+// <type name="session-error" class="restricted" source="symbol" provides="error-condition">
+pub const SESSION_WINDOW_VIOLATION: &[u8] = b"amqp:session:window-violation";
+pub const SESSION_ERRANT_LINK: &[u8] = b"amqp:session:errant-link";
+pub const SESSION_HANDLE_IN_USE: &[u8] = b"amqp:session:handle-in-use";
+pub const SESSION_UNATTACHED_HANDLE: &[u8] = b"amqp:session:unattached-handle";
+// </type>
+
+// <type name="link-error" class="restricted" source="symbol" provides="error-condition">
+pub const LINK_DETACH_FORCED: &[u8] = b"amqp:link:detach-forced";
+pub const LINK_TRANSFER_LIMIT_EXCEEDED: &[u8] = b"amqp:link:transfer-limit-exceeded";
+pub const LINK_MESSAGE_SIZE_EXCEEDED: &[u8] = b"amqp:link:message-size-exceeded";
+pub const LINK_REDIRECT: &[u8] = b"amqp:link:redirect";
+pub const LINK_STOLEN: &[u8] = b"amqp:link:stolen";
+// </type>
@@ -1,13 +1,28 @@
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc::Sender;
 
-use crate::amqp::types::frame::Frame;
+use crate::amqp::messaging::delivery_state::DeliveryState;
+use crate::amqp::messaging::message::Message;
+use crate::amqp::transport::condition;
+use crate::amqp::transport::link::{Link, LinkEvent, Role};
+use crate::amqp::transport::performative::{Performative, PerformativeError};
+use crate::amqp::transport::session::Session;
+use crate::amqp::transport::suspended::SuspendedLinks;
+use crate::amqp::types::frame::{Frame, FrameError, FrameType, MIN_MAX_FRAME_SIZE};
+use crate::config::Config;
+use crate::frame_bus::ConnectionId;
 
-enum ConnectionState {
+// See 2.4.6 Connection States.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
     Start,
     HdrRcvd,
     HdrSent,
+    HdrExch,
     OpenPipe,
     OcPipe,
     OpenRcvd,
@@ -20,22 +35,987 @@ enum ConnectionState {
     End,
 }
 
-struct Connection {
+// Everything that moves a connection from one state to another.
+#[derive(Clone, Copy, Debug)]
+enum ConnectionEvent {
+    HeaderSent,
+    HeaderReceived,
+    OpenSent,
+    OpenReceived,
+    // A close sent because of an error makes us discard everything
+    // but the peer's close (see 2.4.6, DISCARDING).
+    CloseSent { error: bool },
+    CloseReceived,
+}
+
+impl ConnectionState {
+    // The connection state diagram (see 2.4.7 Connection State Diagram).
+    fn next(self, event: ConnectionEvent) -> Option<Self> {
+        use ConnectionEvent as E;
+        use ConnectionState as S;
+        match (self, event) {
+            (S::Start, E::HeaderSent) => Some(S::HdrSent),
+            (S::Start, E::HeaderReceived) => Some(S::HdrRcvd),
+            (S::HdrRcvd, E::HeaderSent) => Some(S::HdrExch),
+            (S::HdrSent, E::HeaderReceived) => Some(S::HdrExch),
+            (S::HdrSent, E::OpenSent) => Some(S::OpenPipe),
+            (S::HdrExch, E::OpenSent) => Some(S::OpenSent),
+            (S::HdrExch, E::OpenReceived) => Some(S::OpenRcvd),
+            (S::OpenPipe, E::HeaderReceived) => Some(S::OpenSent),
+            (S::OpenPipe, E::CloseSent { .. }) => Some(S::OcPipe),
+            (S::OcPipe, E::HeaderReceived) => Some(S::ClosePipe),
+            (S::ClosePipe, E::OpenReceived) => Some(S::CloseSent),
+            (S::ClosePipe, E::CloseReceived) => Some(S::End),
+            (S::OpenRcvd, E::OpenSent) => Some(S::Opened),
+            (S::OpenSent, E::OpenReceived) => Some(S::Opened),
+            (S::OpenSent, E::CloseSent { .. }) => Some(S::ClosePipe),
+            (S::Opened, E::CloseReceived) => Some(S::CloseRcvd),
+            (S::Opened, E::CloseSent { error: false }) => Some(S::CloseSent),
+            (S::Opened, E::CloseSent { error: true }) => Some(S::Discarding),
+            (S::CloseRcvd, E::CloseSent { .. }) => Some(S::End),
+            (S::CloseSent, E::CloseReceived) => Some(S::End),
+            (S::Discarding, E::CloseReceived) => Some(S::End),
+            _ => None,
+        }
+    }
+
+    // Whether we have already sent our close frame.
+    fn is_closing(self) -> bool {
+        matches!(
+            self,
+            ConnectionState::OcPipe
+                | ConnectionState::ClosePipe
+                | ConnectionState::CloseSent
+                | ConnectionState::Discarding
+                | ConnectionState::End
+        )
+    }
+}
+
+// A Connection is a full-duplex, reliably ordered sequence of Frames (see 2.4 Connections).
+pub struct Connection {
+    connection_id: ConnectionId,
     state: ConnectionState,
-    incoming_channels: HashMap<u16, Receiver<Frame>>,
-    outgoing_channels: HashMap<u16, Sender<Frame>>,
-    max_frame_size: u32,
-    max_channel_number: u16,
+    config: Arc<Config>,
+    client_tx: Sender<Frame>,
+    remote_container_id: Option<String>,
+    // What the peer told us in its Open, i.e. the limits for the frames we send.
+    remote_max_frame_size: u32,
+    remote_channel_max: u16,
+    remote_idle_time_out: Option<Duration>,
+    // When we last heard from the peer and last sent it anything (see 2.4.5).
+    last_received: Instant,
+    last_sent: Instant,
+    // Sessions by our channel, and our channel for each of the peer's (see 2.5.1).
+    sessions: HashMap<u16, Session>,
+    remote_channels: HashMap<u16, u16>,
+    // What happened on the links of the sessions, by our channel, for the frame bus
+    // to pass on to the nodes.
+    events: Vec<(u16, LinkEvent)>,
+    // The links suspended on any connection, which the peer may resume on this one.
+    suspended: Arc<Mutex<SuspendedLinks>>,
 }
 
 impl Connection {
-    pub fn new() -> Self {
+    pub fn new(
+        connection_id: ConnectionId,
+        client_tx: Sender<Frame>,
+        config: Arc<Config>,
+        suspended: Arc<Mutex<SuspendedLinks>>,
+    ) -> Self {
         Self {
+            connection_id,
             state: ConnectionState::Start,
-            incoming_channels: HashMap::new(),
-            outgoing_channels: HashMap::new(),
-            max_frame_size: 512,
-            max_channel_number: 0,
+            config,
+            client_tx,
+            remote_container_id: None,
+            remote_max_frame_size: MIN_MAX_FRAME_SIZE,
+            remote_channel_max: 0,
+            remote_idle_time_out: None,
+            last_received: Instant::now(),
+            last_sent: Instant::now(),
+            sessions: HashMap::new(),
+            remote_channels: HashMap::new(),
+            events: vec![],
+            suspended,
+        }
+    }
+
+    pub fn is_ended(&self) -> bool {
+        self.state == ConnectionState::End
+    }
+
+    pub fn remote_container_id(&self) -> Option<&str> {
+        self.remote_container_id.as_deref()
+    }
+
+    pub fn take_events(&mut self) -> Vec<(u16, LinkEvent)> {
+        std::mem::take(&mut self.events)
+    }
+
+    // Detaches the links of every session once the connection is gone.
+    pub fn detach_links(&mut self) {
+        let mut links = vec![];
+        for (local_channel, session) in self.sessions.iter_mut() {
+            session.detach_links();
+            self.events.extend(
+                session
+                    .take_events()
+                    .into_iter()
+                    .map(|event| (*local_channel, event)),
+            );
+            links.push((*local_channel, session.take_suspended()));
+        }
+        for (local_channel, links) in links {
+            self.suspend_links(local_channel, links);
+        }
+        self.suspended
+            .lock()
+            .unwrap()
+            .connection_closed(self.connection_id, Instant::now());
+    }
+
+    // Whether the link with our channel and handle may send the peer a message.
+    pub fn can_send(&self, channel: u16, handle: u32) -> bool {
+        self.state == ConnectionState::Opened
+            && self
+                .sessions
+                .get(&channel)
+                .is_some_and(|session| session.can_send(handle))
+    }
+
+    // Sends a message on the link with our channel and handle, handing it back
+    // if the link can't take it.
+    pub async fn send_message(
+        &mut self,
+        channel: u16,
+        handle: u32,
+        message: Message,
+    ) -> Result<(), Message> {
+        if !self.can_send(channel, handle) {
+            return Err(message);
+        }
+        // A message we can't encode goes back to its node as rejected, which takes it
+        // out of the store and dead-letters it.
+        let payload = match message.encode() {
+            Ok(payload) => payload,
+            Err(err) => {
+                self.events.push((
+                    channel,
+                    LinkEvent::Outcome {
+                        handle,
+                        message: Box::new(message),
+                        state: DeliveryState::Rejected {
+                            error: Some(PerformativeError::new(condition::INTERNAL_ERROR, err)),
+                        },
+                    },
+                ));
+                return Ok(());
+            }
+        };
+        let Some(session) = self.sessions.get_mut(&channel) else {
+            return Err(message);
+        };
+        // A message too large for the peer goes back to its node to be sent elsewhere.
+        if !session.fits(handle, payload.len()) {
+            self.events.push((
+                channel,
+                LinkEvent::Outcome {
+                    handle,
+                    message: Box::new(message),
+                    state: DeliveryState::Modified {
+                        delivery_failed: false,
+                        undeliverable_here: true,
+                        message_annotations: HashMap::new(),
+                    },
+                },
+            ));
+            return Ok(());
+        }
+        let transfers = match session.transfer(handle, message, &payload) {
+            Ok(transfers) => transfers,
+            Err(err) => {
+                self.close(Some(PerformativeError::new(condition::INTERNAL_ERROR, err)))
+                    .await;
+                return Ok(());
+            }
+        };
+        self.events.extend(
+            session
+                .take_events()
+                .into_iter()
+                .map(|event| (channel, event)),
+        );
+        for (transfer, chunk) in transfers {
+            if let Err(error) = self.send(channel, transfer, &chunk).await {
+                self.close(Some(error)).await;
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Settles a message the peer sent us with the outcome its node decided on.
+    pub async fn settle(&mut self, channel: u16, delivery_id: u32, state: DeliveryState) {
+        let Some(disposition) = self
+            .sessions
+            .get_mut(&channel)
+            .filter(|session| !session.is_ending())
+            .and_then(|session| session.settle(delivery_id, state))
+        else {
+            return;
+        };
+        if let Err(error) = self.send(channel, disposition, &[]).await {
+            self.close(Some(error)).await;
+        }
+    }
+
+    // Tells a draining receiver that we have nothing left to send it.
+    pub async fn drain_credit(&mut self, channel: u16, handle: u32) {
+        let Some(flow) = self
+            .sessions
+            .get_mut(&channel)
+            .and_then(|session| session.drain_credit(handle))
+        else {
+            return;
+        };
+        if let Err(error) = self.send(channel, flow, &[]).await {
+            self.close(Some(error)).await;
+        }
+    }
+
+    // Closes one of our links, telling the peer why.
+    pub async fn detach_link(&mut self, channel: u16, handle: u32, error: PerformativeError) {
+        let Some(detach) = self
+            .sessions
+            .get_mut(&channel)
+            .filter(|session| !session.is_ending())
+            .and_then(|session| session.detach_link(handle, error))
+        else {
+            return;
+        };
+        if let Err(error) = self.send(channel, detach, &[]).await {
+            self.close(Some(error)).await;
+        }
+    }
+
+    // The protocol headers are exchanged before any frames (see negotiate_amqp_version).
+    pub fn header_received(&mut self) -> Result<(), PerformativeError> {
+        self.transition(ConnectionEvent::HeaderReceived)
+    }
+
+    pub fn header_sent(&mut self) -> Result<(), PerformativeError> {
+        self.transition(ConnectionEvent::HeaderSent)
+    }
+
+    pub async fn handle_frame(&mut self, frame: Frame) {
+        self.last_received = Instant::now();
+        if self.state == ConnectionState::End || frame.is_heartbeat() {
+            return;
+        }
+        if frame.frame_type != FrameType::AMQP {
+            self.close(Some(PerformativeError::new(
+                condition::CONNECTION_FRAMING_ERROR,
+                "Unexpected non-AMQP frame",
+            )))
+            .await;
+            return;
+        }
+
+        let channel = frame.channel();
+        let (performative, payload) = match get_performative_and_payload(&frame.frame_body).await {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                if self.state != ConnectionState::Discarding {
+                    self.close(Some(PerformativeError::new(
+                        condition::CONNECTION_FRAMING_ERROR,
+                        err,
+                    )))
+                    .await;
+                }
+                return;
+            }
+        };
+        if self.state == ConnectionState::Discarding
+            && !matches!(performative, Performative::Close { .. })
+        {
+            return;
         }
+        if let Err(error) = self
+            .handle_performative(channel, performative, payload)
+            .await
+        {
+            self.close(Some(error)).await;
+        }
+    }
+
+    // Called periodically by the frame bus to keep the connection alive on behalf of
+    // the peer and to give up on a peer that has gone silent (see 2.4.5).
+    pub async fn tick(&mut self, now: Instant) {
+        if self.state == ConnectionState::End {
+            return;
+        }
+        if let Some(idle_time_out) = self.config.idle_time_out
+            && now.duration_since(self.last_received) > idle_time_out
+        {
+            if self.state.is_closing() {
+                // The peer didn't even answer our close, so there is nobody left to wait for.
+                self.state = ConnectionState::End;
+            } else {
+                self.close(Some(PerformativeError::new(
+                    condition::RESOURCE_LIMIT_EXCEEDED,
+                    "local-idle-timeout expired",
+                )))
+                .await;
+            }
+            return;
+        }
+        // Sending at half the peer's interval keeps it from timing out on us
+        // even if a frame is delayed on its way.
+        if let Some(remote_idle_time_out) = self.remote_idle_time_out
+            && now.duration_since(self.last_sent) >= remote_idle_time_out / 2
+        {
+            self.send_frame(Frame::heartbeat()).await;
+        }
+    }
+
+    // The frame stream can't be recovered after a malformed frame, so all we can
+    // do is tell the peer why before the socket goes away.
+    pub async fn framing_error(&mut self, err: FrameError) {
+        self.close(Some(PerformativeError::new(
+            condition::CONNECTION_FRAMING_ERROR,
+            err.to_string(),
+        )))
+        .await;
+    }
+
+    async fn handle_performative(
+        &mut self,
+        channel: u16,
+        performative: Performative,
+        payload: Vec<u8>,
+    ) -> Result<(), PerformativeError> {
+        match performative {
+            Performative::Open {
+                container_id,
+                max_frame_size,
+                channel_max,
+                idle_time_out,
+                ..
+            } => {
+                self.transition(ConnectionEvent::OpenReceived)?;
+                self.remote_container_id = Some(container_id);
+                self.remote_max_frame_size = max_frame_size.max(MIN_MAX_FRAME_SIZE);
+                self.remote_channel_max = channel_max;
+                self.remote_idle_time_out = idle_time_out.filter(|timeout| !timeout.is_zero());
+                if self.state == ConnectionState::OpenRcvd {
+                    self.send_open().await?;
+                }
+            }
+            Performative::Close { error } => {
+                if let Some(error) = error {
+                    println!(
+                        "Connection closed by {:?} with {}: {:?}",
+                        self.remote_container_id,
+                        String::from_utf8_lossy(&error.condition),
+                        error.description
+                    );
+                }
+                self.transition(ConnectionEvent::CloseReceived)?;
+                if self.state == ConnectionState::CloseRcvd {
+                    self.send_close(None).await?;
+                }
+            }
+            _ => {
+                if self.state != ConnectionState::Opened {
+                    return Err(PerformativeError::new(
+                        condition::ILLEGAL_STATE,
+                        format!(
+                            "Performative received in the {:?} connection state",
+                            self.state
+                        ),
+                    ));
+                }
+                self.handle_session_performative(channel, performative, payload)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_session_performative(
+        &mut self,
+        channel: u16,
+        performative: Performative,
+        payload: Vec<u8>,
+    ) -> Result<(), PerformativeError> {
+        match performative {
+            Performative::Begin {
+                remote_channel: None,
+                next_outgoing_id,
+                incoming_window,
+                handle_max,
+                ..
+            } => {
+                if channel > self.config.channel_max {
+                    return Err(PerformativeError::new(
+                        condition::CONNECTION_FRAMING_ERROR,
+                        format!("Channel {} exceeds the negotiated channel-max", channel),
+                    ));
+                }
+                if self.remote_channels.contains_key(&channel) {
+                    return Err(PerformativeError::new(
+                        condition::ILLEGAL_STATE,
+                        format!("Begin received on the mapped channel {}", channel),
+                    ));
+                }
+                let local_channel = self.free_channel().ok_or_else(|| {
+                    PerformativeError::new(
+                        condition::RESOURCE_LIMIT_EXCEEDED,
+                        "No channel left to map the session to",
+                    )
+                })?;
+                let session = Session::new(
+                    local_channel,
+                    channel,
+                    next_outgoing_id,
+                    incoming_window,
+                    handle_max,
+                    self.remote_max_frame_size,
+                    &self.config,
+                );
+                let begin = session.begin();
+                self.remote_channels.insert(channel, local_channel);
+                self.sessions.insert(local_channel, session);
+                self.send(local_channel, begin, &[]).await?;
+            }
+            // We never begin sessions ourselves, so there is nothing this could answer.
+            Performative::Begin { .. } => {
+                return Err(PerformativeError::new(
+                    condition::ILLEGAL_STATE,
+                    "Begin answers a session we never began",
+                ));
+            }
+            Performative::End { error } => {
+                let mut session = self
+                    .remote_channels
+                    .remove(&channel)
+                    .and_then(|local_channel| self.sessions.remove(&local_channel))
+                    .ok_or_else(|| unmapped_channel(channel))?;
+                if let Some(error) = error {
+                    println!(
+                        "Session on channel {} ended by {:?} with {}: {:?}",
+                        channel,
+                        self.remote_container_id,
+                        String::from_utf8_lossy(&error.condition),
+                        error.description
+                    );
+                }
+                session.detach_links();
+                let local_channel = session.local_channel();
+                self.events.extend(
+                    session
+                        .take_events()
+                        .into_iter()
+                        .map(|event| (local_channel, event)),
+                );
+                self.suspend_links(local_channel, session.take_suspended());
+                self.suspended.lock().unwrap().session_ended(
+                    self.connection_id,
+                    local_channel,
+                    Instant::now(),
+                );
+                if !session.is_ending() {
+                    self.send(
+                        session.local_channel(),
+                        Performative::End { error: None },
+                        &[],
+                    )
+                    .await?;
+                }
+            }
+            performative => {
+                let session = self
+                    .remote_channels
+                    .get(&channel)
+                    .and_then(|local_channel| self.sessions.get_mut(local_channel))
+                    .ok_or_else(|| unmapped_channel(channel))?;
+                if session.is_ending() {
+                    return Ok(());
+                }
+                let local_channel = session.local_channel();
+                // A handle beyond the handle-max we announced is an error of the
+                // connection, not just of the session (see 2.7.2 Begin).
+                if let Performative::Attach { handle, .. } = &performative
+                    && *handle > session.handle_max()
+                {
+                    return Err(PerformativeError::new(
+                        condition::CONNECTION_FRAMING_ERROR,
+                        format!("Handle {} exceeds the session handle-max", handle),
+                    ));
+                }
+                // A link suspended with the peer is resumed by attaching it by name,
+                // wherever it was suspended (see 2.6.3 Establishing Or Resuming A Link).
+                if let Performative::Attach { name, role, .. } = &performative {
+                    let container_id = self.remote_container_id.as_deref().unwrap_or_default();
+                    let link = self.suspended.lock().unwrap().resume(
+                        container_id,
+                        name,
+                        Role::from_bool(!role),
+                    );
+                    if let Some(link) = link {
+                        session.offer(link);
+                    }
+                }
+                let replies = match session.handle(performative, payload) {
+                    Ok(replies) => replies,
+                    Err(error) => vec![session.end(Some(error))],
+                };
+                self.events.extend(
+                    session
+                        .take_events()
+                        .into_iter()
+                        .map(|event| (local_channel, event)),
+                );
+                // A Flow may have opened the peer's window for the rest of a delivery.
+                let transfers = session.take_transfers();
+                let links = session.take_suspended();
+                self.suspend_links(local_channel, links);
+                for reply in replies {
+                    self.send(local_channel, reply, &[]).await?;
+                }
+                for (transfer, chunk) in transfers {
+                    self.send(local_channel, transfer, &chunk).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Keeps the links the peer suspended on the session with our channel for it to resume.
+    fn suspend_links(&self, local_channel: u16, links: Vec<Link>) {
+        let container_id = self.remote_container_id.as_deref().unwrap_or_default();
+        let mut suspended = self.suspended.lock().unwrap();
+        for link in links {
+            suspended.suspend(
+                container_id,
+                self.connection_id,
+                local_channel,
+                link,
+                Instant::now(),
+            );
+        }
+    }
+
+    // The lowest channel neither we nor the peer have ruled out (see 2.7.1 Open, channel-max).
+    fn free_channel(&self) -> Option<u16> {
+        let channel_max = self.config.channel_max.min(self.remote_channel_max);
+        (0..=channel_max).find(|channel| !self.sessions.contains_key(channel))
+    }
+
+    // Closes the connection, sending an Open first if we haven't yet since
+    // the peer expects one before anything else (see 2.4.4 Closing A Connection).
+    pub async fn close(&mut self, error: Option<PerformativeError>) {
+        if self.state.is_closing() {
+            return;
+        }
+        if let Some(ref error) = error {
+            println!(
+                "Closing connection to {:?} with {}: {:?}",
+                self.remote_container_id,
+                String::from_utf8_lossy(&error.condition),
+                error.description
+            );
+        }
+        if matches!(
+            self.state,
+            ConnectionState::HdrExch | ConnectionState::OpenRcvd
+        ) && self.send_open().await.is_err()
+        {
+            return;
+        }
+        if self.send_close(error).await.is_err() {
+            self.state = ConnectionState::End;
+        }
+    }
+
+    async fn send_open(&mut self) -> Result<(), PerformativeError> {
+        self.transition(ConnectionEvent::OpenSent)?;
+        let open = Performative::Open {
+            container_id: self.config.container_id.clone(),
+            hostname: None,
+            max_frame_size: self.config.max_frame_size,
+            channel_max: self.config.channel_max,
+            // Advertising half our timeout leaves the peer room for delayed frames (see 2.4.5).
+            idle_time_out: self.config.idle_time_out.map(|timeout| timeout / 2),
+            outgoing_locales: vec![],
+            incoming_locales: vec![],
+            offered_capabilities: vec![],
+            desired_capabilities: vec![],
+            properties: HashMap::new(),
+        };
+        self.send(0, open, &[]).await
+    }
+
+    async fn send_close(
+        &mut self,
+        error: Option<PerformativeError>,
+    ) -> Result<(), PerformativeError> {
+        self.transition(ConnectionEvent::CloseSent {
+            error: error.is_some(),
+        })?;
+        self.send(0, Performative::Close { error }, &[]).await
+    }
+
+    async fn send(
+        &mut self,
+        channel: u16,
+        performative: Performative,
+        payload: &[u8],
+    ) -> Result<(), PerformativeError> {
+        let frame = performative
+            .to_frame(channel, payload)
+            .map_err(|err| PerformativeError::new(condition::INTERNAL_ERROR, err))?;
+        self.send_frame(frame).await;
+        Ok(())
+    }
+
+    async fn send_frame(&mut self, frame: Frame) {
+        self.last_sent = Instant::now();
+        if self.client_tx.send(frame).await.is_err() {
+            // The socket writer is gone, so there is nobody left to talk to.
+            self.state = ConnectionState::End;
+        }
+    }
+
+    fn transition(&mut self, event: ConnectionEvent) -> Result<(), PerformativeError> {
+        match self.state.next(event) {
+            Some(state) => {
+                self.state = state;
+                Ok(())
+            }
+            None => Err(PerformativeError::new(
+                condition::ILLEGAL_STATE,
+                format!("{:?} in the {:?} connection state", event, self.state),
+            )),
+        }
+    }
+}
+
+fn unmapped_channel(channel: u16) -> PerformativeError {
+    PerformativeError::new(
+        condition::ILLEGAL_STATE,
+        format!("Frame received on the unmapped channel {}", channel),
+    )
+}
+
+// The frame body is defined as a performative followed by an opaque payload.
+// The performative MUST be one of those defined in section 2.7 Performatives
+// and is encoded as a described type in the AMQP type system.
+// The remaining bytes in the frame body form the payload for that frame.
+// The presence and format of the payload is defined by the semantics
+// of the given performative.
+pub(crate) async fn get_performative_and_payload(
+    mut frame_body: &[u8],
+) -> Result<(Performative, Vec<u8>), &'static str> {
+    let performative = Performative::new(&mut frame_body).await?;
+    let mut payload = vec![];
+    frame_body
+        .read_to_end(&mut payload)
+        .await
+        .map_err(|_| "Could not read payload")?;
+    Ok((performative, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::{self, Receiver};
+
+    use super::*;
+    use crate::amqp::messaging::message::Body;
+    use crate::amqp::messaging::terminus::Source;
+    use crate::amqp::types::constructor::Constructor;
+    use crate::amqp::types::primitive::Primitive;
+
+    fn connection() -> (Connection, Receiver<Frame>) {
+        let (client_tx, client_rx) = mpsc::channel(16);
+        let config = Config {
+            channel_max: 3,
+            ..Config::default()
+        };
+        let mut connection = Connection::new(
+            0,
+            client_tx,
+            Arc::new(config),
+            Arc::new(Mutex::new(SuspendedLinks::default())),
+        );
+        connection.header_received().unwrap();
+        connection.header_sent().unwrap();
+        (connection, client_rx)
+    }
+
+    fn open() -> Performative {
+        Performative::Open {
+            container_id: String::from("client"),
+            hostname: None,
+            max_frame_size: 4096,
+            channel_max: 7,
+            idle_time_out: None,
+            outgoing_locales: vec![],
+            incoming_locales: vec![],
+            offered_capabilities: vec![],
+            desired_capabilities: vec![],
+            properties: HashMap::new(),
+        }
+    }
+
+    fn begin(channel: u16) -> Frame {
+        Performative::Begin {
+            remote_channel: None,
+            next_outgoing_id: 0,
+            incoming_window: 100,
+            outgoing_window: 100,
+            handle_max: 7,
+            offered_capabilities: vec![],
+            desired_capabilities: vec![],
+            properties: HashMap::new(),
+        }
+        .to_frame(channel, &[])
+        .unwrap()
+    }
+
+    async fn sent(client_rx: &mut Receiver<Frame>) -> Performative {
+        let frame = client_rx.try_recv().expect("nothing sent");
+        get_performative_and_payload(&frame.frame_body)
+            .await
+            .unwrap()
+            .0
+    }
+
+    fn close_condition(performative: Performative) -> Option<Vec<u8>> {
+        match performative {
+            Performative::Close { error } => error.map(|error| error.condition),
+            performative => panic!("expected a Close, got {:?}", performative),
+        }
+    }
+
+    #[test]
+    fn state_diagram() {
+        use ConnectionEvent as E;
+        use ConnectionState as S;
+        let walk = |events: &[ConnectionEvent]| {
+            events
+                .iter()
+                .try_fold(S::Start, |state, event| state.next(*event))
+        };
+        // The peer speaks first, then we answer in turn.
+        let events = [
+            E::HeaderReceived,
+            E::HeaderSent,
+            E::OpenReceived,
+            E::OpenSent,
+        ];
+        assert_eq!(walk(&events), Some(S::Opened));
+        let events = [
+            E::HeaderSent,
+            E::HeaderReceived,
+            E::OpenSent,
+            E::OpenReceived,
+        ];
+        assert_eq!(walk(&events), Some(S::Opened));
+        // Everything pipelined, without waiting for the peer.
+        let events = [
+            E::HeaderSent,
+            E::OpenSent,
+            E::CloseSent { error: false },
+            E::HeaderReceived,
+            E::OpenReceived,
+            E::CloseReceived,
+        ];
+        assert_eq!(walk(&events[..3]), Some(S::OcPipe));
+        assert_eq!(walk(&events[..4]), Some(S::ClosePipe));
+        assert_eq!(walk(&events[..5]), Some(S::CloseSent));
+        assert_eq!(walk(&events), Some(S::End));
+        // Closing from either end.
+        let opened = [
+            E::HeaderSent,
+            E::HeaderReceived,
+            E::OpenSent,
+            E::OpenReceived,
+        ];
+        for closing in [
+            [E::CloseReceived, E::CloseSent { error: false }],
+            [E::CloseSent { error: false }, E::CloseReceived],
+            [E::CloseSent { error: true }, E::CloseReceived],
+        ] {
+            assert_eq!(walk(&[&opened[..], &closing[..]].concat()), Some(S::End));
+        }
+        assert_eq!(
+            walk(&[&opened[..], &[E::CloseSent { error: true }]].concat()),
+            Some(S::Discarding)
+        );
+        // Nothing happens twice or out of turn.
+        assert_eq!(walk(&[E::OpenSent]), None);
+        assert_eq!(walk(&[&opened[..], &[E::OpenReceived]].concat()), None);
+        assert_eq!(walk(&[E::HeaderReceived, E::HeaderReceived]), None);
+        assert_eq!(S::End.next(E::CloseReceived), None);
+    }
+
+    #[tokio::test]
+    async fn open_and_close() {
+        let (mut connection, mut client_rx) = connection();
+        connection
+            .handle_frame(open().to_frame(0, &[]).unwrap())
+            .await;
+        assert!(matches!(
+            sent(&mut client_rx).await,
+            Performative::Open { .. }
+        ));
+        assert_eq!(connection.state, ConnectionState::Opened);
+        assert_eq!(connection.remote_container_id(), Some("client"));
+        assert_eq!(connection.remote_max_frame_size, 4096);
+
+        let close = Performative::Close { error: None };
+        connection
+            .handle_frame(close.to_frame(0, &[]).unwrap())
+            .await;
+        assert_eq!(close_condition(sent(&mut client_rx).await), None);
+        assert!(connection.is_ended());
+    }
+
+    #[tokio::test]
+    async fn performatives_before_open_close_the_connection() {
+        let (mut connection, mut client_rx) = connection();
+        connection.handle_frame(begin(0)).await;
+        // The peer expects our Open before the Close (see 2.4.4).
+        assert!(matches!(
+            sent(&mut client_rx).await,
+            Performative::Open { .. }
+        ));
+        assert_eq!(
+            close_condition(sent(&mut client_rx).await),
+            Some(condition::ILLEGAL_STATE.to_vec())
+        );
+        assert_eq!(connection.state, ConnectionState::ClosePipe);
+    }
+
+    #[tokio::test]
+    async fn framing_errors_discard_all_but_the_close() {
+        let (mut connection, mut client_rx) = connection();
+        connection
+            .handle_frame(open().to_frame(0, &[]).unwrap())
+            .await;
+        sent(&mut client_rx).await;
+        connection
+            .framing_error(FrameError::InvalidDataOffset(1))
+            .await;
+        assert_eq!(
+            close_condition(sent(&mut client_rx).await),
+            Some(condition::CONNECTION_FRAMING_ERROR.to_vec())
+        );
+        assert_eq!(connection.state, ConnectionState::Discarding);
+
+        connection.handle_frame(begin(0)).await;
+        assert!(client_rx.try_recv().is_err());
+        let close = Performative::Close { error: None };
+        connection
+            .handle_frame(close.to_frame(0, &[]).unwrap())
+            .await;
+        assert!(connection.is_ended());
+    }
+
+    #[tokio::test]
+    async fn channels_beyond_channel_max_are_framing_errors() {
+        let (mut connection, mut client_rx) = connection();
+        connection
+            .handle_frame(open().to_frame(0, &[]).unwrap())
+            .await;
+        sent(&mut client_rx).await;
+        connection.handle_frame(begin(3)).await;
+        assert!(matches!(
+            sent(&mut client_rx).await,
+            Performative::Begin { .. }
+        ));
+        connection.handle_frame(begin(4)).await;
+        assert_eq!(
+            close_condition(sent(&mut client_rx).await),
+            Some(condition::CONNECTION_FRAMING_ERROR.to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_that_cannot_be_encoded_are_rejected() {
+        let (mut connection, mut client_rx) = connection();
+        for frame in [
+            open().to_frame(0, &[]).unwrap(),
+            begin(0),
+            Performative::Attach {
+                name: String::from("receiver"),
+                handle: 0,
+                role: true,
+                snd_settle_mode: 0,
+                rcv_settle_mode: 0,
+                source: Some(Box::new(Source {
+                    address: Some(String::from("queue")),
+                    ..Source::default()
+                })),
+                target: None,
+                unsettled: HashMap::new(),
+                incomplete_unsettled: false,
+                initial_delivery_count: None,
+                max_message_size: None,
+                offered_capabilities: vec![],
+                desired_capabilities: vec![],
+                properties: HashMap::new(),
+            }
+            .to_frame(0, &[])
+            .unwrap(),
+            Performative::Flow {
+                next_incoming_id: Some(0),
+                incoming_window: 100,
+                next_outgoing_id: 0,
+                outgoing_window: 100,
+                handle: Some(0),
+                delivery_count: Some(0),
+                link_credit: Some(1),
+                available: None,
+                drain: false,
+                echo: false,
+                properties: HashMap::new(),
+            }
+            .to_frame(0, &[])
+            .unwrap(),
+        ] {
+            connection.handle_frame(frame).await;
+        }
+        while client_rx.try_recv().is_ok() {}
+        connection.take_events();
+
+        // Array elements of different types have no encoding.
+        let message = Message {
+            body: Body::AmqpValue(Primitive::Array(vec![
+                Constructor::PrimitiveType(Primitive::Int(1)),
+                Constructor::PrimitiveType(Primitive::String(String::from("a"))),
+            ])),
+            ..Message::default()
+        };
+        assert!(connection.send_message(0, 0, message).await.is_ok());
+        assert!(client_rx.try_recv().is_err());
+        let events = connection.take_events();
+        let [
+            (
+                0,
+                LinkEvent::Outcome {
+                    handle: 0, state, ..
+                },
+            ),
+        ] = &events[..]
+        else {
+            panic!("expected an Outcome");
+        };
+        let DeliveryState::Rejected { error: Some(error) } = state else {
+            panic!("expected a Rejected outcome, got {:?}", state);
+        };
+        assert_eq!(error.condition, condition::INTERNAL_ERROR);
     }
 }
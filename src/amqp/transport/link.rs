@@ -0,0 +1,1103 @@
+use std::collections::HashMap;
+
+use crate::amqp::messaging::delivery_state::{
+    DeliveryState, read_delivery_state, write_delivery_state,
+};
+use crate::amqp::messaging::filter::Filter;
+use crate::amqp::messaging::message::Message;
+use crate::amqp::messaging::terminus::{
+    LifetimePolicy, Source, Target, TerminusDurability, TerminusExpiryPolicy,
+};
+use crate::amqp::transport::condition;
+use crate::amqp::transport::performative::{Performative, PerformativeError};
+use crate::amqp::types::constructor::Constructor;
+use crate::amqp::types::primitive::Primitive;
+
+// What the addresses we make up for dynamic nodes start with.
+pub const DYNAMIC_ADDRESS_PREFIX: &str = "dynamic/";
+
+// <type name="role" class="restricted" source="boolean">
+//     <choice name="sender" value="false"/>
+//     <choice name="receiver" value="true"/>
+// </type>
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Role {
+    Sender,
+    Receiver,
+}
+
+impl Role {
+    pub fn from_bool(value: bool) -> Self {
+        if value { Role::Receiver } else { Role::Sender }
+    }
+
+    pub fn as_bool(self) -> bool {
+        self == Role::Receiver
+    }
+}
+
+// <type name="sender-settle-mode" class="restricted" source="ubyte">
+//     <choice name="unsettled" value="0"/>
+//     <choice name="settled" value="1"/>
+//     <choice name="mixed" value="2"/>
+// </type>
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SenderSettleMode {
+    Unsettled,
+    Settled,
+    Mixed,
+}
+
+impl SenderSettleMode {
+    pub fn from_ubyte(value: u8) -> Self {
+        match value {
+            0 => SenderSettleMode::Unsettled,
+            1 => SenderSettleMode::Settled,
+            _ => SenderSettleMode::Mixed,
+        }
+    }
+
+    pub fn as_ubyte(self) -> u8 {
+        self as u8
+    }
+}
+
+// <type name="receiver-settle-mode" class="restricted" source="ubyte">
+//     <choice name="first" value="0"/>
+//     <choice name="second" value="1"/>
+// </type>
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReceiverSettleMode {
+    // The receiver settles as soon as it has an outcome.
+    #[default]
+    First,
+    // The receiver only settles once the sender has settled, after seeing its outcome.
+    Second,
+}
+
+impl ReceiverSettleMode {
+    pub fn from_ubyte(value: u8) -> Self {
+        if value == 1 {
+            ReceiverSettleMode::Second
+        } else {
+            ReceiverSettleMode::First
+        }
+    }
+
+    pub fn as_ubyte(self) -> u8 {
+        self as u8
+    }
+}
+
+// What happens on our links that the nodes they are attached to have to act on.
+// Handles are ours.
+pub enum LinkEvent {
+    // The peer attached a link to receive the messages of the node at the address.
+    // A durable source asks for the node to outlive the link. A dynamic source has
+    // the link create the node, which lives on as its lifetime policy says. Only the
+    // messages passing the filter of the source are sent on the link.
+    Attached {
+        handle: u32,
+        address: String,
+        name: String,
+        durable: bool,
+        dynamic: Option<LifetimePolicy>,
+        filter: Filter,
+    },
+    // The peer attached a link to send messages to the node at the address.
+    TargetAttached {
+        handle: u32,
+        address: String,
+    },
+    // The link is gone, handing back the messages sent on it that the peer never settled.
+    // Unless the peer closed it, it may come back for the same node, which a suspended
+    // link does under the same name unless its terminus expires first.
+    Detached {
+        handle: u32,
+        closed: bool,
+        suspended: bool,
+        unsettled: Vec<Message>,
+    },
+    // The peer sent a message in full, for the node at the address of the link if it
+    // has one. Unless the peer settled it already, it waits for our outcome.
+    Delivery {
+        delivery_id: u32,
+        settled: bool,
+        address: Option<String>,
+        payload: Vec<u8>,
+    },
+    // The peer settled a message we sent.
+    Outcome {
+        handle: u32,
+        message: Box<Message>,
+        state: DeliveryState,
+    },
+}
+
+// A delivery as received so far, collected across its Transfers
+// (see 2.6.14 Transferring A Message).
+#[derive(Default)]
+pub struct IncomingDelivery {
+    pub delivery_id: u32,
+    pub delivery_tag: Vec<u8>,
+    pub settled: bool,
+    // Whether the peer sends the delivery again after resuming the link (see 2.6.13).
+    pub resume: bool,
+    // The link's receiver settle mode, unless the Transfer asked for second.
+    pub rcv_settle_mode: ReceiverSettleMode,
+    pub payload: Vec<u8>,
+}
+
+// A Link is a unidirectional route between a source and a target, one at each end
+// (see 2.6 Links). This is our end of it, so the role is the opposite of the peer's.
+pub struct Link {
+    name: String,
+    role: Role,
+    // Our handle for the link (see 2.6.2).
+    handle: u32,
+    snd_settle_mode: SenderSettleMode,
+    rcv_settle_mode: ReceiverSettleMode,
+    source: Option<Box<Source>>,
+    target: Option<Box<Target>>,
+    // See 2.6.7 Flow Control. The sender owns the delivery-count and available,
+    // the receiver owns the link-credit and drain.
+    delivery_count: u32,
+    link_credit: u32,
+    available: u32,
+    drain: bool,
+    // The link-credit we grant every time the peer has used up half of it.
+    max_link_credit: u32,
+    // The largest message we take in, and the largest the peer takes in if it has a limit.
+    max_message_size: u64,
+    remote_max_message_size: Option<u64>,
+    // Whether the last Transfer had more=true, so the next one continues its delivery.
+    incomplete: bool,
+    delivery: IncomingDelivery,
+    // The deliveries the peer sent us that aren't settled at both ends yet by tag,
+    // with our state for them, exchanged on attach to recover the link (see 2.6.13).
+    unsettled: HashMap<Vec<u8>, Option<DeliveryState>>,
+    // The messages we sent that the peer hadn't settled when the link was suspended,
+    // by tag, until it is resumed or expires.
+    held: Vec<(Vec<u8>, Message)>,
+    // The outcomes the peer had for messages we sent when it resumed the link, for
+    // the resumed Transfers settling them once it gives us credit again.
+    settlements: Vec<(Vec<u8>, DeliveryState)>,
+    // The messages we sent that the peer may have a record of but no outcome for when
+    // it resumed the link, sent again under their tag once it gives us credit again.
+    resends: Vec<(Vec<u8>, Message)>,
+    detach_sent: bool,
+}
+
+impl Link {
+    // Creates our endpoint for the peer's Attach. A receiver adopts the sender's
+    // delivery-count, while as the sender we pick our own (see 2.7.3 Attach).
+    pub fn attach(
+        handle: u32,
+        attach: Performative,
+        max_link_credit: u32,
+        max_message_size: u64,
+    ) -> Option<Self> {
+        let Performative::Attach {
+            name,
+            role,
+            snd_settle_mode,
+            rcv_settle_mode,
+            source,
+            target,
+            initial_delivery_count,
+            max_message_size: remote_max_message_size,
+            ..
+        } = attach
+        else {
+            return None;
+        };
+        let role = Role::from_bool(!role);
+        Some(Self {
+            name,
+            role,
+            handle,
+            snd_settle_mode: SenderSettleMode::from_ubyte(snd_settle_mode),
+            rcv_settle_mode: ReceiverSettleMode::from_ubyte(rcv_settle_mode),
+            source,
+            target,
+            delivery_count: match role {
+                Role::Receiver => initial_delivery_count.unwrap_or(0),
+                Role::Sender => 0,
+            },
+            link_credit: 0,
+            available: 0,
+            drain: false,
+            max_link_credit,
+            max_message_size,
+            // Zero means there is no limit, same as leaving it out.
+            remote_max_message_size: remote_max_message_size.filter(|size| *size > 0),
+            incomplete: false,
+            delivery: IncomingDelivery::default(),
+            unsettled: HashMap::new(),
+            held: vec![],
+            settlements: vec![],
+            resends: vec![],
+            detach_sent: false,
+        })
+    }
+
+    // Keeps the messages the peer hasn't settled with the link while it is suspended,
+    // along with those it was yet to get again since it last resumed the link.
+    pub fn suspend(&mut self, held: Vec<(Vec<u8>, Message)>) {
+        self.held = std::mem::take(&mut self.resends);
+        self.held.extend(held);
+    }
+
+    // The messages held by a suspended link or yet to be sent again by a resumed one,
+    // which go back to their node if the link expires or is closed.
+    pub fn take_held(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.held)
+            .into_iter()
+            .chain(std::mem::take(&mut self.resends))
+            .map(|(_, message)| message)
+            .collect()
+    }
+
+    // Re-attaches a suspended link under a new handle, the peer's new endpoint starting
+    // out with no credit (see 2.6.13 Resuming A Link). The unsettled map of its Attach
+    // tells what became of the deliveries neither end settled: as the receiver, we
+    // forget those the sender has forgotten, and as the sender, we settle those the
+    // receiver has an outcome for, send again those it may have a record of, and
+    // return the outcome of every message that goes back to its node.
+    pub fn resume(&mut self, handle: u32, attach: &Performative) -> Vec<(Message, DeliveryState)> {
+        let Performative::Attach {
+            unsettled,
+            incomplete_unsettled,
+            initial_delivery_count,
+            ..
+        } = attach
+        else {
+            return vec![];
+        };
+        self.handle = handle;
+        self.detach_sent = false;
+        self.link_credit = 0;
+        self.drain = false;
+        self.incomplete = false;
+        self.delivery = IncomingDelivery::default();
+        let remote_unsettled: HashMap<Vec<u8>, Option<DeliveryState>> = unsettled
+            .iter()
+            .filter_map(|(delivery_tag, state)| match delivery_tag {
+                Constructor::PrimitiveType(Primitive::Binary(delivery_tag)) => Some((
+                    delivery_tag.clone(),
+                    read_delivery_state(&mut std::slice::from_ref(state).iter())
+                        .ok()
+                        .flatten(),
+                )),
+                _ => None,
+            })
+            .collect();
+        match self.role {
+            Role::Receiver => {
+                if let Some(delivery_count) = initial_delivery_count {
+                    self.delivery_count = *delivery_count;
+                }
+                // With an incomplete map, the sender may still know the deliveries it leaves out.
+                if !incomplete_unsettled {
+                    self.unsettled
+                        .retain(|delivery_tag, _| remote_unsettled.contains_key(delivery_tag));
+                }
+                vec![]
+            }
+            Role::Sender => {
+                let mut outcomes = vec![];
+                for (delivery_tag, message) in std::mem::take(&mut self.held) {
+                    match remote_unsettled.get(&delivery_tag) {
+                        Some(Some(state)) if state.is_terminal() => {
+                            self.settlements.push((delivery_tag, state.clone()));
+                            outcomes.push((message, state.clone()));
+                        }
+                        // The peer has no outcome for what it got of it, if anything, so
+                        // it gets it again under the same tag, which it won't take for a
+                        // new delivery. With an incomplete map, the peer may still know
+                        // the deliveries it leaves out.
+                        Some(_) => self.resends.push((delivery_tag, message)),
+                        None if *incomplete_unsettled => self.resends.push((delivery_tag, message)),
+                        // The peer may have settled it without us hearing about it,
+                        // so it counts as a delivery attempt.
+                        None => outcomes.push((
+                            message,
+                            DeliveryState::Modified {
+                                delivery_failed: true,
+                                undeliverable_here: false,
+                                message_annotations: HashMap::new(),
+                            },
+                        )),
+                    }
+                }
+                outcomes
+            }
+        }
+    }
+
+    pub fn handle(&self) -> u32 {
+        self.handle
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    // The address of the node at our end, i.e. of the source we send from
+    // or the target we receive into.
+    pub fn address(&self) -> Option<String> {
+        match self.role {
+            Role::Sender => self.source.as_ref()?.address.clone(),
+            Role::Receiver => self.target.as_ref()?.address.clone(),
+        }
+    }
+
+    // A receiver asking for a dynamic source gets a node of its own, whose address we
+    // make up and return in the source of our Attach (see 3.5.3 Source). Returns the
+    // lifetime policy of the node if the link created one.
+    pub fn create_dynamic_node(&mut self) -> Result<Option<LifetimePolicy>, &'static str> {
+        let Some(source) = self.source.as_mut() else {
+            return Ok(None);
+        };
+        if self.role != Role::Sender || !source.dynamic || source.address.is_some() {
+            return Ok(None);
+        }
+        let lifetime_policy =
+            LifetimePolicy::from_node_properties(&source.dynamic_node_properties)?;
+        source.address = Some(format!(
+            "{}{:016x}",
+            DYNAMIC_ADDRESS_PREFIX,
+            rand::random::<u64>()
+        ));
+        Ok(Some(lifetime_policy))
+    }
+
+    // The filters of the source we send from, dropping those we don't apply, so that
+    // our Attach only echoes the ones in place (see 3.5.8 Filter Set).
+    pub fn filter(&mut self) -> Result<Filter, &'static str> {
+        match (self.role, self.source.as_mut()) {
+            (Role::Sender, Some(source)) => Filter::from_filter_set(&mut source.filter),
+            _ => Ok(Filter::default()),
+        }
+    }
+
+    // The outcome of the messages we sent that the peer settles without one
+    // (see 3.5.3 Source).
+    pub fn default_outcome(&self) -> Option<DeliveryState> {
+        self.source.as_ref()?.default_outcome.clone()
+    }
+
+    // Whether the terminus at our end is gone as soon as the link is detached, so
+    // the link can't be resumed (see 3.5.6 Terminus Expiry Policy). Any other
+    // expiry policy keeps it at least until the session ends.
+    pub fn expires_on_detach(&self) -> bool {
+        let (expiry_policy, timeout) = self.expiry();
+        expiry_policy == TerminusExpiryPolicy::LinkDetach && timeout == 0
+    }
+
+    // Whether the terminus at our end is durable (see 3.5.5 Terminus Durability).
+    pub fn is_durable(&self) -> bool {
+        match self.role {
+            Role::Sender => self.source.as_ref().map(|source| source.durable),
+            Role::Receiver => self.target.as_ref().map(|target| target.durable),
+        }
+        .is_some_and(|durable| durable != TerminusDurability::None)
+    }
+
+    // The expiry policy and timeout of the terminus at our end.
+    pub fn expiry(&self) -> (TerminusExpiryPolicy, u32) {
+        match self.role {
+            Role::Sender => self
+                .source
+                .as_ref()
+                .map(|source| (source.expiry_policy, source.timeout)),
+            Role::Receiver => self
+                .target
+                .as_ref()
+                .map(|target| (target.expiry_policy, target.timeout)),
+        }
+        .unwrap_or_default()
+    }
+
+    // Whether the messages we send on the link go out settled, i.e. at most once.
+    pub fn sends_settled(&self) -> bool {
+        self.snd_settle_mode == SenderSettleMode::Settled
+    }
+
+    // Whether the peer takes in a message of the size.
+    pub fn fits(&self, message_size: usize) -> bool {
+        self.remote_max_message_size
+            .is_none_or(|max_message_size| message_size as u64 <= max_message_size)
+    }
+
+    // The outcome we already have for a delivery the peer sends again.
+    pub fn known_outcome(&self, delivery_tag: &[u8]) -> Option<DeliveryState> {
+        self.unsettled.get(delivery_tag).cloned().flatten()
+    }
+
+    // Remembers a delivery the peer hasn't settled along with our state for it,
+    // or forgets it once settled at both ends.
+    pub fn set_unsettled(&mut self, delivery_tag: &[u8], state: Option<DeliveryState>) {
+        self.unsettled.insert(delivery_tag.to_vec(), state);
+    }
+
+    pub fn settled(&mut self, delivery_tag: &[u8]) {
+        self.unsettled.remove(delivery_tag);
+    }
+
+    pub fn is_detaching(&self) -> bool {
+        self.detach_sent
+    }
+
+    // Our answer to the peer's Attach.
+    pub fn attach_reply(&self) -> Performative {
+        Performative::Attach {
+            name: self.name.clone(),
+            handle: self.handle,
+            role: self.role.as_bool(),
+            snd_settle_mode: self.snd_settle_mode.as_ubyte(),
+            rcv_settle_mode: self.rcv_settle_mode.as_ubyte(),
+            source: self.source.clone(),
+            target: self.target.clone(),
+            unsettled: self
+                .unsettled
+                .iter()
+                .map(|(delivery_tag, state)| (delivery_tag, state.as_ref()))
+                .chain(
+                    self.settlements
+                        .iter()
+                        .map(|(delivery_tag, state)| (delivery_tag, Some(state))),
+                )
+                .map(|(delivery_tag, state)| {
+                    (
+                        Constructor::PrimitiveType(Primitive::Binary(delivery_tag.clone())),
+                        write_delivery_state(state),
+                    )
+                })
+                .collect(),
+            incomplete_unsettled: false,
+            initial_delivery_count: match self.role {
+                Role::Sender => Some(self.delivery_count),
+                Role::Receiver => None,
+            },
+            max_message_size: Some(self.max_message_size),
+            offered_capabilities: vec![],
+            desired_capabilities: vec![],
+            properties: HashMap::new(),
+        }
+    }
+
+    pub fn detach(&mut self, closed: bool, error: Option<PerformativeError>) -> Performative {
+        self.detach_sent = true;
+        Performative::Detach {
+            handle: self.handle,
+            closed,
+            error,
+        }
+    }
+
+    // As the receiver we hand out link-credit as soon as the link is attached.
+    pub fn grant_credit(&mut self) -> bool {
+        if self.role != Role::Receiver || self.link_credit > self.max_link_credit / 2 {
+            return false;
+        }
+        self.link_credit = self.max_link_credit;
+        true
+    }
+
+    // Applies the link flow state from the peer's Flow, returning whether we owe it
+    // a Flow with ours (see 2.6.7 Flow Control).
+    pub fn flow_received(&mut self, flow: &Performative) -> bool {
+        let Performative::Flow {
+            delivery_count,
+            link_credit,
+            available,
+            drain,
+            echo,
+            ..
+        } = *flow
+        else {
+            return false;
+        };
+        match self.role {
+            Role::Sender => {
+                // link-credit = delivery-count(rcv) + link-credit(rcv) - delivery-count(snd),
+                // where a receiver that hasn't seen our Attach yet counts from the initial one.
+                let remote_delivery_count = delivery_count.unwrap_or(self.delivery_count);
+                self.link_credit = remote_delivery_count
+                    .wrapping_add(link_credit.unwrap_or(0))
+                    .wrapping_sub(self.delivery_count);
+                // The credit left over once the node has nothing more to send is used up
+                // by drain_credit.
+                self.drain = drain;
+            }
+            Role::Receiver => {
+                if let Some(delivery_count) = delivery_count {
+                    self.delivery_count = delivery_count;
+                }
+                self.available = available.unwrap_or(0);
+            }
+        }
+        echo
+    }
+
+    // Whether we may send the peer a delivery on the link.
+    pub fn can_send(&self) -> bool {
+        self.role == Role::Sender && !self.detach_sent && self.link_credit > 0
+    }
+
+    // Accounts for a delivery we send, returning its tag. The delivery-count is unique
+    // on the link for as long as the delivery may be unsettled, so it does as the tag.
+    pub fn transfer_sent(&mut self) -> Vec<u8> {
+        let delivery_tag = self.delivery_count.to_be_bytes().to_vec();
+        self.delivery_count = self.delivery_count.wrapping_add(1);
+        self.link_credit -= 1;
+        delivery_tag
+    }
+
+    // The outcomes left to settle after resuming the link, as many as the credit allows.
+    pub fn take_settlements(&mut self) -> Vec<(Vec<u8>, DeliveryState)> {
+        if !self.can_send() {
+            return vec![];
+        }
+        let count = self.settlements.len().min(self.link_credit as usize);
+        self.delivery_count = self.delivery_count.wrapping_add(count as u32);
+        self.link_credit -= count as u32;
+        self.settlements.drain(..count).collect()
+    }
+
+    // The messages to send again after resuming the link, as many as the credit
+    // allows once the settlements took theirs.
+    pub fn take_resends(&mut self) -> Vec<(Vec<u8>, Message)> {
+        if !self.can_send() {
+            return vec![];
+        }
+        let count = self.resends.len().min(self.link_credit as usize);
+        self.delivery_count = self.delivery_count.wrapping_add(count as u32);
+        self.link_credit -= count as u32;
+        self.resends.drain(..count).collect()
+    }
+
+    // A draining receiver wants the credit we have no deliveries for used up,
+    // returning whether it is owed a Flow telling it so.
+    pub fn drain_credit(&mut self) -> bool {
+        if self.role != Role::Sender || !self.drain || self.link_credit == 0 {
+            return false;
+        }
+        self.delivery_count = self.delivery_count.wrapping_add(self.link_credit);
+        self.link_credit = 0;
+        true
+    }
+
+    // Accounts for a Transfer, each delivery using up one link-credit however many
+    // frames it spans, and adds its payload to the delivery being received. Returns
+    // whether the credit has to be replenished, and the delivery once its final
+    // Transfer is in (see 2.6.14 Transferring A Message).
+    pub fn transfer_received(
+        &mut self,
+        transfer: &Performative,
+        payload: &[u8],
+    ) -> Result<(bool, Option<IncomingDelivery>), PerformativeError> {
+        let Performative::Transfer {
+            delivery_id,
+            ref delivery_tag,
+            settled,
+            more,
+            rcv_settle_mode,
+            resume,
+            aborted,
+            ..
+        } = *transfer
+        else {
+            return Ok((false, None));
+        };
+        if self.role != Role::Receiver {
+            return Err(PerformativeError::new(
+                condition::ILLEGAL_STATE,
+                "Transfer received on a link we are the sender of",
+            ));
+        }
+        let incomplete = self.incomplete;
+        // An aborted delivery is over, whatever the more flag says.
+        self.incomplete = more && !aborted;
+        let mut replenish = false;
+        if incomplete {
+            // Any of the frames may settle the delivery.
+            self.delivery.settled |= settled.unwrap_or(false);
+        } else {
+            replenish =
+                self.delivery_started(delivery_id, delivery_tag, settled, rcv_settle_mode)?;
+            self.delivery.resume = resume;
+        }
+        if aborted {
+            // The sender gave up on the delivery, so whatever it sent of it is dropped.
+            self.delivery = IncomingDelivery::default();
+            return Ok((replenish, None));
+        }
+        if self.delivery.payload.len() as u64 + payload.len() as u64 > self.max_message_size {
+            self.incomplete = false;
+            self.delivery = IncomingDelivery::default();
+            return Err(PerformativeError::new(
+                condition::LINK_MESSAGE_SIZE_EXCEEDED,
+                format!(
+                    "Message larger than the max-message-size of {} octets",
+                    self.max_message_size
+                ),
+            ));
+        }
+        self.delivery.payload.extend_from_slice(payload);
+        if self.incomplete {
+            return Ok((replenish, None));
+        }
+        Ok((replenish, Some(std::mem::take(&mut self.delivery))))
+    }
+
+    fn delivery_started(
+        &mut self,
+        delivery_id: Option<u32>,
+        delivery_tag: &[u8],
+        settled: Option<bool>,
+        rcv_settle_mode: Option<u8>,
+    ) -> Result<bool, PerformativeError> {
+        if self.link_credit == 0 {
+            self.incomplete = false;
+            return Err(PerformativeError::new(
+                condition::LINK_TRANSFER_LIMIT_EXCEEDED,
+                "Transfer received without link credit",
+            ));
+        }
+        // The delivery-id may only be omitted on the frames continuing a delivery.
+        let delivery_id = delivery_id.ok_or_else(|| {
+            PerformativeError::new(
+                condition::INVALID_FIELD,
+                "Transfer starting a delivery without a delivery-id",
+            )
+        })?;
+        // A Transfer may only ask for a stricter receiver settle mode than the link's.
+        let rcv_settle_mode = match rcv_settle_mode.map(ReceiverSettleMode::from_ubyte) {
+            Some(ReceiverSettleMode::Second)
+                if self.rcv_settle_mode == ReceiverSettleMode::First =>
+            {
+                return Err(PerformativeError::new(
+                    condition::INVALID_FIELD,
+                    "Transfer asking for the second receiver settle mode on a link settling first",
+                ));
+            }
+            mode => mode.unwrap_or(self.rcv_settle_mode),
+        };
+        self.delivery_count = self.delivery_count.wrapping_add(1);
+        self.link_credit -= 1;
+        self.delivery = IncomingDelivery {
+            delivery_id,
+            delivery_tag: delivery_tag.to_vec(),
+            settled: settled.unwrap_or(false),
+            resume: false,
+            rcv_settle_mode,
+            payload: vec![],
+        };
+        Ok(self.grant_credit())
+    }
+
+    // Fills the link fields of a Flow carrying the session flow state (see 2.7.4 Flow).
+    pub fn flow(&self, mut flow: Performative) -> Performative {
+        if let Performative::Flow {
+            handle,
+            delivery_count,
+            link_credit,
+            available,
+            drain,
+            ..
+        } = &mut flow
+        {
+            *handle = Some(self.handle);
+            *delivery_count = Some(self.delivery_count);
+            *link_credit = Some(self.link_credit);
+            *available = Some(self.available);
+            *drain = self.drain;
+        }
+        flow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amqp::messaging::message::Properties;
+
+    // The peer's Attach of a link with its role and unsettled map.
+    fn attach(
+        role: Role,
+        unsettled: &[(&[u8], Option<DeliveryState>)],
+        incomplete_unsettled: bool,
+    ) -> Performative {
+        Performative::Attach {
+            name: String::from("link"),
+            handle: 0,
+            role: role.as_bool(),
+            snd_settle_mode: SenderSettleMode::Unsettled.as_ubyte(),
+            rcv_settle_mode: ReceiverSettleMode::First.as_ubyte(),
+            source: Some(Box::new(Source {
+                address: Some(String::from("queue")),
+                ..Source::default()
+            })),
+            target: Some(Box::new(Target {
+                address: Some(String::from("queue")),
+                ..Target::default()
+            })),
+            unsettled: unsettled
+                .iter()
+                .map(|(delivery_tag, state)| {
+                    (
+                        Constructor::PrimitiveType(Primitive::Binary(delivery_tag.to_vec())),
+                        write_delivery_state(state.as_ref()),
+                    )
+                })
+                .collect(),
+            incomplete_unsettled,
+            initial_delivery_count: Some(0),
+            max_message_size: None,
+            offered_capabilities: vec![],
+            desired_capabilities: vec![],
+            properties: HashMap::new(),
+        }
+    }
+
+    fn flow(delivery_count: u32, link_credit: u32) -> Performative {
+        Performative::Flow {
+            next_incoming_id: Some(0),
+            incoming_window: 100,
+            next_outgoing_id: 0,
+            outgoing_window: 100,
+            handle: Some(0),
+            delivery_count: Some(delivery_count),
+            link_credit: Some(link_credit),
+            available: None,
+            drain: false,
+            echo: false,
+            properties: HashMap::new(),
+        }
+    }
+
+    // A Transfer of the peer's delivery, or of part of it with more=true.
+    fn transfer(delivery_id: u32, more: bool, aborted: bool) -> Performative {
+        Performative::Transfer {
+            handle: 0,
+            delivery_id: Some(delivery_id),
+            delivery_tag: delivery_id.to_be_bytes().to_vec(),
+            message_format: Some(0),
+            settled: Some(false),
+            more,
+            rcv_settle_mode: None,
+            state: None,
+            resume: false,
+            aborted,
+            batchable: false,
+        }
+    }
+
+    fn message(subject: &str) -> Message {
+        Message {
+            properties: Some(Properties {
+                subject: Some(subject.to_string()),
+                ..Properties::default()
+            }),
+            ..Message::default()
+        }
+    }
+
+    fn subject(message: &Message) -> &str {
+        message
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.subject.as_deref())
+            .unwrap_or_default()
+    }
+
+    // Our sending end of a link suspended with the messages of the tags unsettled.
+    fn suspended_sender(delivery_tags: &[&[u8]]) -> Link {
+        let mut link = Link::attach(0, attach(Role::Receiver, &[], false), 10, 1024).unwrap();
+        link.suspend(
+            delivery_tags
+                .iter()
+                .map(|delivery_tag| {
+                    let subject = String::from_utf8_lossy(delivery_tag).to_string();
+                    (delivery_tag.to_vec(), message(&subject))
+                })
+                .collect(),
+        );
+        link
+    }
+
+    #[test]
+    fn resumed_sender_settles_resends_and_gives_back() {
+        let mut link = suspended_sender(&[b"a", b"b", b"c", b"d"]);
+        let unsettled: &[(&[u8], Option<DeliveryState>)] = &[
+            (b"a", Some(DeliveryState::Accepted)),
+            (
+                b"b",
+                Some(DeliveryState::Received {
+                    section_number: 0,
+                    section_offset: 0,
+                }),
+            ),
+            (b"c", None),
+        ];
+        let outcomes = link.resume(1, &attach(Role::Receiver, unsettled, false));
+        assert_eq!(link.handle(), 1);
+
+        // The peer's outcome goes to the node and is settled with the peer.
+        // The peer has no record of d, so it goes back to the node.
+        assert_eq!(outcomes.len(), 2);
+        assert_eq!(subject(&outcomes[0].0), "a");
+        assert!(matches!(outcomes[0].1, DeliveryState::Accepted));
+        assert_eq!(subject(&outcomes[1].0), "d");
+        assert!(matches!(
+            outcomes[1].1,
+            DeliveryState::Modified {
+                delivery_failed: true,
+                ..
+            }
+        ));
+        let Performative::Attach { unsettled, .. } = link.attach_reply() else {
+            unreachable!();
+        };
+        assert_eq!(unsettled.len(), 1);
+
+        // b and c go out again under their tags, once there is credit for them.
+        assert!(link.take_resends().is_empty());
+        link.flow_received(&flow(0, 2));
+        assert_eq!(link.take_settlements().len(), 1);
+        let resends = link.take_resends();
+        assert_eq!(resends.len(), 1);
+        assert_eq!(resends[0].0, b"b");
+        link.flow_received(&flow(2, 5));
+        let resends = link.take_resends();
+        assert_eq!(resends.len(), 1);
+        assert_eq!(resends[0].0, b"c");
+        assert!(link.can_send());
+        assert_eq!(link.transfer_sent(), 3u32.to_be_bytes());
+    }
+
+    #[test]
+    fn incomplete_map_keeps_what_it_leaves_out() {
+        let mut link = suspended_sender(&[b"a", b"b"]);
+        let unsettled: &[(&[u8], Option<DeliveryState>)] = &[(b"a", None)];
+        let outcomes = link.resume(0, &attach(Role::Receiver, unsettled, true));
+        assert!(outcomes.is_empty());
+        link.flow_received(&flow(0, 10));
+        let delivery_tags: Vec<Vec<u8>> = link
+            .take_resends()
+            .into_iter()
+            .map(|(delivery_tag, _)| delivery_tag)
+            .collect();
+        assert_eq!(delivery_tags, [b"a", b"b"]);
+    }
+
+    #[test]
+    fn messages_to_resend_stay_with_the_link() {
+        let mut link = suspended_sender(&[b"a"]);
+        let unsettled: &[(&[u8], Option<DeliveryState>)] = &[(b"a", None)];
+        link.resume(0, &attach(Role::Receiver, unsettled, false));
+        // Suspended again before the peer gave credit, the message is still held.
+        link.suspend(vec![(b"b".to_vec(), message("b"))]);
+        let held: Vec<String> = link
+            .take_held()
+            .iter()
+            .map(|message| subject(message).to_string())
+            .collect();
+        assert_eq!(held, ["a", "b"]);
+    }
+
+    #[test]
+    fn resumed_receiver_forgets_what_the_sender_forgot() {
+        let receiver = || {
+            let mut link = Link::attach(0, attach(Role::Sender, &[], false), 10, 1024).unwrap();
+            link.set_unsettled(b"a", None);
+            link.set_unsettled(b"b", Some(DeliveryState::Accepted));
+            link
+        };
+        let unsettled: &[(&[u8], Option<DeliveryState>)] = &[(b"a", None)];
+
+        let mut link = receiver();
+        assert!(
+            link.resume(0, &attach(Role::Sender, unsettled, false))
+                .is_empty()
+        );
+        let Performative::Attach {
+            unsettled: ours, ..
+        } = link.attach_reply()
+        else {
+            unreachable!();
+        };
+        assert_eq!(ours.len(), 1);
+        assert!(link.known_outcome(b"b").is_none());
+
+        let mut link = receiver();
+        link.resume(0, &attach(Role::Sender, unsettled, true));
+        assert!(matches!(
+            link.known_outcome(b"b"),
+            Some(DeliveryState::Accepted)
+        ));
+    }
+
+    #[test]
+    fn sender_credit_follows_the_receiver_flow() {
+        let mut link = Link::attach(0, attach(Role::Receiver, &[], false), 10, 1024).unwrap();
+        assert!(!link.can_send());
+        assert!(!link.flow_received(&flow(0, 10)));
+        assert_eq!(link.link_credit, 10);
+        for _ in 0..3 {
+            link.transfer_sent();
+        }
+        assert_eq!((link.delivery_count, link.link_credit), (3, 7));
+
+        // A Flow sent before the receiver saw our last deliveries counts them too.
+        link.flow_received(&flow(1, 5));
+        assert_eq!(link.link_credit, 3);
+        // One sent before it saw our Attach counts from our delivery-count.
+        let mut early = flow(0, 4);
+        if let Performative::Flow { delivery_count, .. } = &mut early {
+            *delivery_count = None;
+        }
+        link.flow_received(&early);
+        assert_eq!(link.link_credit, 4);
+
+        // Asked to echo, the receiver is owed our flow state.
+        let mut echo = flow(3, 4);
+        if let Performative::Flow { echo, .. } = &mut echo {
+            *echo = true;
+        }
+        assert!(link.flow_received(&echo));
+        assert!(matches!(
+            link.flow(flow(0, 0)),
+            Performative::Flow {
+                delivery_count: Some(3),
+                link_credit: Some(4),
+                drain: false,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn draining_uses_up_the_credit_left() {
+        let mut link = Link::attach(0, attach(Role::Receiver, &[], false), 10, 1024).unwrap();
+        assert!(!link.drain_credit());
+        let mut drain = flow(0, 5);
+        if let Performative::Flow { drain, .. } = &mut drain {
+            *drain = true;
+        }
+        link.flow_received(&drain);
+        link.transfer_sent();
+        assert!(link.drain_credit());
+        assert!(matches!(
+            link.flow(flow(0, 0)),
+            Performative::Flow {
+                delivery_count: Some(5),
+                link_credit: Some(0),
+                drain: true,
+                ..
+            }
+        ));
+        assert!(!link.can_send());
+        assert!(!link.drain_credit());
+    }
+
+    #[test]
+    fn receiver_grants_credit_again_at_half() {
+        let mut link = Link::attach(0, attach(Role::Sender, &[], false), 4, 1024).unwrap();
+        assert!(link.grant_credit());
+        assert!(!link.grant_credit());
+        let (replenish, _) = link
+            .transfer_received(&transfer(0, false, false), b"a")
+            .unwrap();
+        assert!(!replenish);
+        let (replenish, _) = link
+            .transfer_received(&transfer(1, false, false), b"b")
+            .unwrap();
+        assert!(replenish);
+        assert_eq!((link.delivery_count, link.link_credit), (2, 4));
+
+        // The sender's Flow tells us its delivery-count and what it has available.
+        let mut available = flow(6, 0);
+        if let Performative::Flow { available, .. } = &mut available {
+            *available = Some(3);
+        }
+        link.flow_received(&available);
+        assert_eq!((link.delivery_count, link.available), (6, 3));
+
+        link.link_credit = 0;
+        let Err(error) = link.transfer_received(&transfer(2, false, false), b"c") else {
+            panic!("expected an error");
+        };
+        assert_eq!(error.condition, condition::LINK_TRANSFER_LIMIT_EXCEEDED);
+    }
+
+    #[test]
+    fn deliveries_come_together_across_their_transfers() {
+        let mut link = Link::attach(0, attach(Role::Sender, &[], false), 10, 6).unwrap();
+        link.grant_credit();
+        let (_, delivery) = link
+            .transfer_received(&transfer(0, true, false), b"ab")
+            .unwrap();
+        assert!(delivery.is_none());
+        // Continuing the delivery, a Transfer may leave out the delivery-id and settle it.
+        let mut rest = transfer(0, true, false);
+        if let Performative::Transfer {
+            delivery_id,
+            settled,
+            ..
+        } = &mut rest
+        {
+            *delivery_id = None;
+            *settled = Some(true);
+        }
+        let (_, delivery) = link.transfer_received(&rest, b"cd").unwrap();
+        assert!(delivery.is_none());
+        let (_, delivery) = link
+            .transfer_received(&transfer(0, false, false), b"ef")
+            .unwrap();
+        let delivery = delivery.unwrap();
+        assert_eq!(delivery.payload, b"abcdef");
+        assert!(delivery.settled);
+        assert_eq!((link.delivery_count, link.link_credit), (1, 9));
+    }
+
+    #[test]
+    fn aborted_deliveries_are_dropped() {
+        let mut link = Link::attach(0, attach(Role::Sender, &[], false), 10, 6).unwrap();
+        link.grant_credit();
+        link.transfer_received(&transfer(0, true, false), b"ab")
+            .unwrap();
+        let (_, delivery) = link
+            .transfer_received(&transfer(0, true, true), b"")
+            .unwrap();
+        assert!(delivery.is_none());
+        // The credit stays used up, and the next Transfer starts a delivery of its own.
+        let (_, delivery) = link
+            .transfer_received(&transfer(1, false, false), b"cd")
+            .unwrap();
+        let delivery = delivery.unwrap();
+        assert_eq!(delivery.delivery_id, 1);
+        assert_eq!(delivery.payload, b"cd");
+        assert_eq!(link.link_credit, 8);
+    }
+
+    #[test]
+    fn deliveries_larger_than_the_max_message_size_detach_the_link() {
+        let mut link = Link::attach(0, attach(Role::Sender, &[], false), 10, 4).unwrap();
+        link.grant_credit();
+        link.transfer_received(&transfer(0, true, false), b"abc")
+            .unwrap();
+        let Err(error) = link.transfer_received(&transfer(0, false, false), b"de") else {
+            panic!("expected an error");
+        };
+        assert_eq!(error.condition, condition::LINK_MESSAGE_SIZE_EXCEEDED);
+        assert!(!link.incomplete);
+        assert!(link.delivery.payload.is_empty());
+    }
+}
@@ -1,30 +1,98 @@
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+use sasl::Sasl;
+use tls::{Tls, Transport};
+
+pub mod condition;
 pub mod connection;
 pub mod link;
-pub mod session;
 pub mod performative;
+pub mod sasl;
+pub mod session;
+pub mod suspended;
+pub mod tls;
+
+// "AMQP" followed by the protocol id and the major, minor and revision version numbers
+// (see 2.2 Version Negotiation, 5.2.1 TLS Negotiation and 5.3.1 SASL Negotiation).
+const AMQP_HEADER: [u8; 8] = *b"AMQP\x00\x01\x00\x00";
+const TLS_HEADER: [u8; 8] = *b"AMQP\x02\x01\x00\x00";
+const SASL_HEADER: [u8; 8] = *b"AMQP\x03\x01\x00\x00";
 
-pub async fn negotiate_amqp_version(socket: &mut TcpStream) -> Result<&'static str, &'static str> {
-    let mut valid_version = true;
-    for ch in b"AMQP\x00\x01\x00\x00" {
-        let buf = socket.read_u8().await.unwrap_or_else(|_| 255);
-        if buf != *ch {
-            valid_version = false;
-        }
+// Exchanges the protocol headers with the client, going through the TLS and SASL
+// layers first if the client asks for them, and returns the stream to carry on with
+// along with the user the client authenticated as, if any.
+pub async fn negotiate_amqp_version(
+    mut stream: Transport,
+    tls: &Tls,
+    sasl: &Sasl,
+) -> Result<(Transport, Option<String>), &'static str> {
+    let mut header = read_protocol_header(&mut stream).await?;
+    if header == TLS_HEADER && tls.is_enabled() && !stream.is_tls() {
+        write_protocol_header(&mut stream, TLS_HEADER).await?;
+        stream = tls.accept(stream).await?;
+        header = read_protocol_header(&mut stream).await?;
     }
-    for ch in b"AMQP\x00\x01\x00\x00" {
-        match socket.write_u8(*ch).await {
-            Ok(_) => {}
-            Err(_) => return Err("Could not write to socket"),
-        }
+    // A header we can't accept is answered with the one we would have accepted
+    // in its place before the connection is dropped.
+    if tls.required && !stream.is_tls() {
+        write_protocol_header(&mut stream, TLS_HEADER).await?;
+        return Err("Client did not establish TLS");
     }
-    if valid_version {
-        Ok("Client protocol version is 1.0.0")
+    let mut user = None;
+    let encrypted = stream.is_tls();
+    let client_certificate = stream.client_certificate().map(<[u8]>::to_vec);
+    if header == SASL_HEADER && sasl.can_offer(encrypted, client_certificate.as_deref()) {
+        write_protocol_header(&mut stream, SASL_HEADER).await?;
+        user = Some(
+            sasl.authenticate(&mut stream, encrypted, client_certificate.as_deref())
+                .await?,
+        );
+        header = read_protocol_header(&mut stream).await?;
+    }
+    if user.is_none() && sasl.required {
+        write_protocol_header(&mut stream, SASL_HEADER).await?;
+        return Err("Client did not authenticate");
+    }
+    write_protocol_header(&mut stream, AMQP_HEADER).await?;
+    if header == AMQP_HEADER {
+        Ok((stream, user))
     } else {
         Err("Invalid client protocol version")
     }
 }
+
+// Sends our protocol header to a node we connect to, which has to answer with the same
+// one since we don't go through TLS or SASL with it.
+pub async fn request_amqp_version<S>(stream: &mut S) -> Result<(), &'static str>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    write_protocol_header(stream, AMQP_HEADER).await?;
+    if read_protocol_header(stream).await? == AMQP_HEADER {
+        Ok(())
+    } else {
+        Err("The peer does not accept AMQP without TLS or SASL")
+    }
+}
+
+async fn read_protocol_header<S>(stream: &mut S) -> Result<[u8; 8], &'static str>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0; 8];
+    stream
+        .read_exact(&mut header)
+        .await
+        .map_err(|_| "Could not read protocol header")?;
+    Ok(header)
+}
+
+async fn write_protocol_header<S>(stream: &mut S, header: [u8; 8]) -> Result<(), &'static str>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream
+        .write_all(&header)
+        .await
+        .map_err(|_| "Could not write to socket")
+}
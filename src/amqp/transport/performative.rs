@@ -2,20 +2,44 @@ use std::{collections::HashMap, ops::Deref, slice::Iter, time::Duration};
 
 use tokio::io::AsyncReadExt;
 
-use crate::amqp::types::{constructor::Constructor, format_code::FormatCode, primitive::Primitive};
+use crate::amqp::messaging::delivery_state::{
+    DeliveryState, read_delivery_state, write_delivery_state,
+};
+use crate::amqp::messaging::terminus::{
+    Source, Target, read_source, read_target, write_source, write_target,
+};
+use crate::amqp::types::{
+    constructor::Constructor,
+    descriptor::Descriptor,
+    format_code::FormatCode,
+    frame::Frame,
+    primitive::{InnerMap, Primitive},
+};
 
 // <type name="error" class="composite" source="list">
 // <descriptor name="amqp:error:list" code="0x00000000:0x0000001d"/>
 // </type>
-struct PerformativeError {
+#[derive(Debug, Clone)]
+pub struct PerformativeError {
     // <field name="condition" type="symbol" requires="error-condition" mandatory="true"/>
-    condition: Vec<Vec<u8>>,
+    pub condition: Vec<u8>,
     // <field name="description" type="string"/>
-    description: Option<String>,
+    pub description: Option<String>,
     // <field name="info" type="fields"/>
-    info: HashMap<Constructor, Constructor>,
+    pub info: HashMap<Constructor, Constructor>,
 }
 
+impl PerformativeError {
+    pub fn new(condition: &[u8], description: impl Into<String>) -> Self {
+        Self {
+            condition: condition.to_vec(),
+            description: Some(description.into()),
+            info: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Performative {
     Open {
         container_id: String,
@@ -45,12 +69,12 @@ pub enum Performative {
         role: bool,
         snd_settle_mode: u8,
         rcv_settle_mode: u8,
-        source: Constructor,
-        target: Constructor,
+        source: Option<Box<Source>>,
+        target: Option<Box<Target>>,
         unsettled: HashMap<Constructor, Constructor>,
         incomplete_unsettled: bool,
-        initial_delivery_count: u32,
-        max_message_size: u64,
+        initial_delivery_count: Option<u32>,
+        max_message_size: Option<u64>,
         offered_capabilities: Vec<Vec<u8>>,
         desired_capabilities: Vec<Vec<u8>>,
         properties: HashMap<Constructor, Constructor>,
@@ -62,8 +86,8 @@ pub enum Performative {
         outgoing_window: u32,
         handle: Option<u32>,
         delivery_count: Option<u32>,
-        link_credit: u32,
-        available: u32,
+        link_credit: Option<u32>,
+        available: Option<u32>,
         drain: bool,
         echo: bool,
         properties: HashMap<Constructor, Constructor>,
@@ -76,7 +100,7 @@ pub enum Performative {
         settled: Option<bool>,
         more: bool,
         rcv_settle_mode: Option<u8>,
-        state: Constructor,
+        state: Option<DeliveryState>,
         resume: bool,
         aborted: bool,
         batchable: bool,
@@ -86,7 +110,7 @@ pub enum Performative {
         first: u32,
         last: Option<u32>,
         settled: bool,
-        state: Constructor,
+        state: Option<DeliveryState>,
         batchable: bool,
     },
     Detach {
@@ -104,62 +128,35 @@ pub enum Performative {
 
 impl Performative {
     pub async fn new(buf_reader: &mut (impl AsyncReadExt + Unpin)) -> Result<Self, &'static str> {
-        let mut read_buf = [0u8; 2];
-        buf_reader
-            .read_exact(&mut read_buf)
-            .await
-            .unwrap_or_else(|_| 0);
-        let fcode = FormatCode::try_from(u16::from_be_bytes(read_buf))?;
-        let constructor = Constructor::new(fcode, buf_reader).await.unwrap();
+        let fcode = FormatCode::read(buf_reader).await?;
+        let constructor = Constructor::new(fcode, buf_reader).await?;
         match constructor {
             Constructor::PrimitiveType(_) => {
                 Err("Constructor for a performative is a primitive type")
             }
             Constructor::DescribedType(descriptor, constructor_primitive) => {
-                let descriptor: Constructor = descriptor.deref().clone();
-                match descriptor {
-                    Constructor::PrimitiveType(primitive) => match primitive {
-                        Primitive::String(prim_body) => {
-                            Self::decode_descriptor(prim_body, constructor_primitive).await
-                        }
-                        _ => Err("Performative constructor descriptor is not a string"),
-                    },
-                    Constructor::DescribedType(_, _) => {
-                        Err("Performative constructor descriptor is not a primitive type")
-                    }
-                }
+                let descriptor = Descriptor::from_constructor(descriptor.deref())?;
+                Self::decode_descriptor(descriptor, constructor_primitive).await
             }
         }
     }
 
     async fn decode_descriptor(
-        type_name: String,
+        descriptor: Descriptor,
         primitive: Primitive,
     ) -> Result<Self, &'static str> {
-        let fields = match primitive {
-            Primitive::List(boxed_fields) => {
-                let mut fields = Vec::with_capacity(boxed_fields.len());
-                for field in boxed_fields.iter() {
-                    let field = field.deref().clone();
-                    fields.push(field);
-                }
-                fields
-            }
-            _ => {
-                return Err("Performative descriptor is not a list");
-            }
-        };
-        match type_name.as_str() {
-            "amqp:open:list" => Self::open(fields),
-            "amqp:begin:list" => Self::begin(fields),
-            "amqp:attach:list" => Self::attach(fields),
-            "amqp:flow:list" => Self::flow(fields),
-            "amqp:transfer:list" => Self::transfer(fields),
-            "amqp:disposition:list" => Self::disposition(fields),
-            "amqp:detach:list" => Self::detach(fields),
-            "amqp:end:list" => Self::end(fields),
-            "amqp:close:list" => Self::close(fields),
-            _ => Err("Unknown performative type name"),
+        let fields = read_fields(primitive)?;
+        match descriptor {
+            Descriptor::Open => Self::open(fields),
+            Descriptor::Begin => Self::begin(fields),
+            Descriptor::Attach => Self::attach(fields),
+            Descriptor::Flow => Self::flow(fields),
+            Descriptor::Transfer => Self::transfer(fields),
+            Descriptor::Disposition => Self::disposition(fields),
+            Descriptor::Detach => Self::detach(fields),
+            Descriptor::End => Self::end(fields),
+            Descriptor::Close => Self::close(fields),
+            _ => Err("Descriptor does not belong to a performative"),
         }
     }
 
@@ -180,11 +177,8 @@ impl Performative {
             channel_max: read_ushort(&mut field_iter, true, Some(65535))?
                 .ok_or("Mandatory field: channel_max")?,
             // <field name="idle-time-out" type="milliseconds"/>
-            idle_time_out: if let Some(ms) = read_uint(&mut field_iter, false, None)? {
-                Some(Duration::from_millis(ms as u64))
-            } else {
-                None
-            },
+            idle_time_out: read_uint(&mut field_iter, false, None)?
+                .map(|ms| Duration::from_millis(ms as u64)),
             // <type name="ietf-language-tag" class="restricted" source="symbol"/>
             // <field name="outgoing-locales" type="ietf-language-tag" multiple="true"/>
             outgoing_locales: read_symbol_array(&mut field_iter)?,
@@ -263,22 +257,19 @@ impl Performative {
             // <field name="rcv-settle-mode" type="receiver-settle-mode" default="first"/>
             rcv_settle_mode: read_ubyte(&mut field_iter, true, Some(0))?
                 .ok_or("Mandatory field: rcv_settle_mode")?,
-            // spec:wildcard[*]: A value of any type is permitted.
             // <field name="source" type="*" requires="source"/>
-            source: field_iter.next().ok_or("Mandatory field: source")?.clone(),
+            source: read_source(&mut field_iter)?.map(Box::new),
             // <field name="target" type="*" requires="target"/>
-            target: field_iter.next().ok_or("Mandatory field: target")?.clone(),
+            target: read_target(&mut field_iter)?.map(Box::new),
             // <field name="unsettled" type="map"/>
             unsettled: read_map(&mut field_iter)?,
             // <field name="incomplete-unsettled" type="boolean" default="false"/>
             incomplete_unsettled: read_bool(&mut field_iter, true, Some(false))?
                 .ok_or("Mandatory field: incomplete_unsettled")?,
             // <field name="initial-delivery-count" type="sequence-no"/>
-            initial_delivery_count: read_uint(&mut field_iter, false, None)?
-                .ok_or("Mandatory field: initial_delivery_count")?,
+            initial_delivery_count: read_uint(&mut field_iter, false, None)?,
             // <field name="max-message-size" type="ulong"/>
-            max_message_size: read_ulong(&mut field_iter, false, None)?
-                .ok_or("Mandatory field: max_message_size")?,
+            max_message_size: read_ulong(&mut field_iter, false, None)?,
             // <field name="offered-capabilities" type="symbol" multiple="true"/>
             offered_capabilities: read_symbol_array(&mut field_iter)?,
             // <field name="desired-capabilities" type="symbol" multiple="true"/>
@@ -308,17 +299,17 @@ impl Performative {
             // <field name="handle" type="handle"/>
             handle: read_uint(&mut field_iter, false, None)?,
             // <field name="delivery-count" type="sequence-no"/>
-            delivery_count: read_uint(&mut field_iter, true, None)?,
+            delivery_count: read_uint(&mut field_iter, false, None)?,
             // <field name="link-credit" type="uint"/>
-            link_credit: read_uint(&mut field_iter, true, None)?
-                .ok_or("Mandatory field: link_credit")?,
+            link_credit: read_uint(&mut field_iter, false, None)?,
             // <field name="available" type="uint"/>
-            available: read_uint(&mut field_iter, true, None)?
-                .ok_or("Mandatory field: available")?,
+            available: read_uint(&mut field_iter, false, None)?,
             // <field name="drain" type="boolean" default="false"/>
-            drain: read_bool(&mut field_iter, true, None)?.ok_or("Mandatory field: drain")?,
+            drain: read_bool(&mut field_iter, true, Some(false))?
+                .ok_or("the field drain is null unexpectedly")?,
             // <field name="echo" type="boolean" default="false"/>
-            echo: read_bool(&mut field_iter, true, None)?.ok_or("Mandatory field: echo")?,
+            echo: read_bool(&mut field_iter, true, Some(false))?
+                .ok_or("the field echo is null unexpectedly")?,
             // <field name="properties" type="fields"/>
             properties: read_map(&mut field_iter)?,
         })
@@ -356,7 +347,7 @@ impl Performative {
             // <field name="rcv-settle-mode" type="receiver-settle-mode"/>
             rcv_settle_mode: read_ubyte(&mut field_iter, false, None)?,
             // <field name="state" type="*" requires="delivery-state"/>
-            state: field_iter.next().ok_or("Mandatory field: state")?.clone(),
+            state: read_delivery_state(&mut field_iter)?,
             // <field name="resume" type="boolean" default="false"/>
             resume: read_bool(&mut field_iter, true, Some(false))?
                 .ok_or("the field resume is null unexpectedly")?,
@@ -385,7 +376,7 @@ impl Performative {
             settled: read_bool(&mut field_iter, true, Some(false))?
                 .ok_or("the field settled is null unexpectedly")?,
             // <field name="state" type="*" requires="delivery-state"/>
-            state: field_iter.next().ok_or("Mandatory field: state")?.clone(),
+            state: read_delivery_state(&mut field_iter)?,
             // <field name="batchable" type="boolean" default="false"/>
             batchable: read_bool(&mut field_iter, true, Some(false))?
                 .ok_or("the field batchable is null unexpectedly")?,
@@ -432,47 +423,231 @@ impl Performative {
     // </type>
 }
 
-fn read_bool(
+impl Performative {
+    pub fn descriptor(&self) -> Descriptor {
+        match self {
+            Performative::Open { .. } => Descriptor::Open,
+            Performative::Begin { .. } => Descriptor::Begin,
+            Performative::Attach { .. } => Descriptor::Attach,
+            Performative::Flow { .. } => Descriptor::Flow,
+            Performative::Transfer { .. } => Descriptor::Transfer,
+            Performative::Disposition { .. } => Descriptor::Disposition,
+            Performative::Detach { .. } => Descriptor::Detach,
+            Performative::End { .. } => Descriptor::End,
+            Performative::Close { .. } => Descriptor::Close,
+        }
+    }
+
+    pub fn to_constructor(&self) -> Constructor {
+        let fields = match self {
+            Performative::Open {
+                container_id,
+                hostname,
+                max_frame_size,
+                channel_max,
+                idle_time_out,
+                outgoing_locales,
+                incoming_locales,
+                offered_capabilities,
+                desired_capabilities,
+                properties,
+            } => vec![
+                write_string(Some(container_id)),
+                write_string(hostname.as_ref()),
+                write_uint(Some(*max_frame_size)),
+                write_ushort(Some(*channel_max)),
+                write_uint(idle_time_out.map(|timeout| timeout.as_millis() as u32)),
+                write_symbol_array(outgoing_locales),
+                write_symbol_array(incoming_locales),
+                write_symbol_array(offered_capabilities),
+                write_symbol_array(desired_capabilities),
+                write_map(properties),
+            ],
+            Performative::Begin {
+                remote_channel,
+                next_outgoing_id,
+                incoming_window,
+                outgoing_window,
+                handle_max,
+                offered_capabilities,
+                desired_capabilities,
+                properties,
+            } => vec![
+                write_ushort(*remote_channel),
+                write_uint(Some(*next_outgoing_id)),
+                write_uint(Some(*incoming_window)),
+                write_uint(Some(*outgoing_window)),
+                write_uint(Some(*handle_max)),
+                write_symbol_array(offered_capabilities),
+                write_symbol_array(desired_capabilities),
+                write_map(properties),
+            ],
+            Performative::Attach {
+                name,
+                handle,
+                role,
+                snd_settle_mode,
+                rcv_settle_mode,
+                source,
+                target,
+                unsettled,
+                incomplete_unsettled,
+                initial_delivery_count,
+                max_message_size,
+                offered_capabilities,
+                desired_capabilities,
+                properties,
+            } => vec![
+                write_string(Some(name)),
+                write_uint(Some(*handle)),
+                write_bool(Some(*role)),
+                write_ubyte(Some(*snd_settle_mode)),
+                write_ubyte(Some(*rcv_settle_mode)),
+                write_source(source.as_deref()),
+                write_target(target.as_deref()),
+                write_map(unsettled),
+                write_bool(Some(*incomplete_unsettled).filter(|value| *value)),
+                write_uint(*initial_delivery_count),
+                write_ulong(*max_message_size),
+                write_symbol_array(offered_capabilities),
+                write_symbol_array(desired_capabilities),
+                write_map(properties),
+            ],
+            Performative::Flow {
+                next_incoming_id,
+                incoming_window,
+                next_outgoing_id,
+                outgoing_window,
+                handle,
+                delivery_count,
+                link_credit,
+                available,
+                drain,
+                echo,
+                properties,
+            } => vec![
+                write_uint(*next_incoming_id),
+                write_uint(Some(*incoming_window)),
+                write_uint(Some(*next_outgoing_id)),
+                write_uint(Some(*outgoing_window)),
+                write_uint(*handle),
+                write_uint(*delivery_count),
+                write_uint(*link_credit),
+                write_uint(*available),
+                write_bool(Some(*drain).filter(|value| *value)),
+                write_bool(Some(*echo).filter(|value| *value)),
+                write_map(properties),
+            ],
+            Performative::Transfer {
+                handle,
+                delivery_id,
+                delivery_tag,
+                message_format,
+                settled,
+                more,
+                rcv_settle_mode,
+                state,
+                resume,
+                aborted,
+                batchable,
+            } => vec![
+                write_uint(Some(*handle)),
+                write_uint(*delivery_id),
+                write_binary(delivery_tag),
+                write_uint(*message_format),
+                write_bool(*settled),
+                write_bool(Some(*more).filter(|value| *value)),
+                write_ubyte(*rcv_settle_mode),
+                write_delivery_state(state.as_ref()),
+                write_bool(Some(*resume).filter(|value| *value)),
+                write_bool(Some(*aborted).filter(|value| *value)),
+                write_bool(Some(*batchable).filter(|value| *value)),
+            ],
+            Performative::Disposition {
+                role,
+                first,
+                last,
+                settled,
+                state,
+                batchable,
+            } => vec![
+                write_bool(Some(*role)),
+                write_uint(Some(*first)),
+                write_uint(*last),
+                write_bool(Some(*settled).filter(|value| *value)),
+                write_delivery_state(state.as_ref()),
+                write_bool(Some(*batchable).filter(|value| *value)),
+            ],
+            Performative::Detach {
+                handle,
+                closed,
+                error,
+            } => vec![
+                write_uint(Some(*handle)),
+                write_bool(Some(*closed).filter(|value| *value)),
+                write_error(error.as_ref()),
+            ],
+            Performative::End { error } => vec![write_error(error.as_ref())],
+            Performative::Close { error } => vec![write_error(error.as_ref())],
+        };
+        self.descriptor()
+            .describe(Primitive::List(trim_trailing_nulls(fields)))
+    }
+
+    // Builds an AMQP frame carrying the performative followed by the (possibly empty) payload.
+    pub fn to_frame(&self, channel: u16, payload: &[u8]) -> Result<Frame, &'static str> {
+        let mut frame_body = self.to_constructor().as_bytes()?;
+        frame_body.extend_from_slice(payload);
+        Ok(Frame::amqp(channel, frame_body))
+    }
+}
+
+// Trailing fields which are null can be omitted from the encoded list
+// (see 1.4 Composite Types), which keeps the frames small.
+pub(crate) fn trim_trailing_nulls(mut fields: Vec<Constructor>) -> Vec<Constructor> {
+    while let Some(Constructor::PrimitiveType(Primitive::Null)) = fields.last() {
+        fields.pop();
+    }
+    fields
+}
+
+pub(crate) fn read_bool(
     field_iter: &mut Iter<Constructor>,
     mandatory: bool,
     default: Option<bool>,
 ) -> Result<Option<bool>, &'static str> {
     match field_iter.next() {
         Some(Constructor::PrimitiveType(Primitive::Boolean(value))) => Ok(Some(*value)),
-        Some(Constructor::PrimitiveType(Primitive::Null)) => {
+        Some(Constructor::PrimitiveType(Primitive::Null)) | None => {
             if !mandatory {
                 Ok(None)
             } else if default.is_some() {
                 Ok(default)
             } else {
-                return Err("Mandatory ushort field is null");
+                Err("Mandatory ushort field is null")
             }
         }
-        _ => {
-            return Err("Invalid field type, expected boolean");
-        }
+        _ => Err("Invalid field type, expected boolean"),
     }
 }
 
-fn read_ubyte(
+pub(crate) fn read_ubyte(
     field_iter: &mut Iter<Constructor>,
     mandatory: bool,
     default: Option<u8>,
 ) -> Result<Option<u8>, &'static str> {
     match field_iter.next() {
         Some(Constructor::PrimitiveType(Primitive::UByte(value))) => Ok(Some(*value)),
-        Some(Constructor::PrimitiveType(Primitive::Null)) => {
+        Some(Constructor::PrimitiveType(Primitive::Null)) | None => {
             if !mandatory {
                 Ok(None)
             } else if default.is_some() {
                 Ok(default)
             } else {
-                return Err("Mandatory ubyte field is null");
+                Err("Mandatory ubyte field is null")
             }
         }
-        _ => {
-            return Err("Invalid field type, expected ubyte");
-        }
+        _ => Err("Invalid field type, expected ubyte"),
     }
 }
 
@@ -483,40 +658,36 @@ fn read_ushort(
 ) -> Result<Option<u16>, &'static str> {
     match field_iter.next() {
         Some(Constructor::PrimitiveType(Primitive::UShort(value))) => Ok(Some(*value)),
-        Some(Constructor::PrimitiveType(Primitive::Null)) => {
+        Some(Constructor::PrimitiveType(Primitive::Null)) | None => {
             if !mandatory {
                 Ok(None)
             } else if default.is_some() {
                 Ok(default)
             } else {
-                return Err("Mandatory ushort field is null");
+                Err("Mandatory ushort field is null")
             }
         }
-        _ => {
-            return Err("Invalid field type, expected ushort");
-        }
+        _ => Err("Invalid field type, expected ushort"),
     }
 }
 
-fn read_uint(
+pub(crate) fn read_uint(
     field_iter: &mut Iter<Constructor>,
     mandatory: bool,
     default: Option<u32>,
 ) -> Result<Option<u32>, &'static str> {
     match field_iter.next() {
         Some(Constructor::PrimitiveType(Primitive::UInt(value))) => Ok(Some(*value)),
-        Some(Constructor::PrimitiveType(Primitive::Null)) => {
+        Some(Constructor::PrimitiveType(Primitive::Null)) | None => {
             if !mandatory {
                 Ok(None)
             } else if default.is_some() {
                 Ok(default)
             } else {
-                return Err("Mandatory uint field is null");
+                Err("Mandatory uint field is null")
             }
         }
-        _ => {
-            return Err("Invalid field type, expected uint");
-        }
+        _ => Err("Invalid field type, expected uint"),
     }
 }
 
@@ -527,41 +698,46 @@ fn read_ulong(
 ) -> Result<Option<u64>, &'static str> {
     match field_iter.next() {
         Some(Constructor::PrimitiveType(Primitive::ULong(value))) => Ok(Some(*value)),
-        Some(Constructor::PrimitiveType(Primitive::Null)) => {
+        Some(Constructor::PrimitiveType(Primitive::Null)) | None => {
             if !mandatory {
                 Ok(None)
             } else if default.is_some() {
                 Ok(default)
             } else {
-                return Err("Mandatory ulong field is null");
+                Err("Mandatory ulong field is null")
             }
         }
-        _ => {
-            return Err("Invalid field type, expected ulong");
-        }
+        _ => Err("Invalid field type, expected ulong"),
     }
 }
 
-fn read_string(
+pub(crate) fn read_string(
     field_iter: &mut Iter<Constructor>,
     mandatory: bool,
 ) -> Result<Option<String>, &'static str> {
     match (*field_iter).next() {
         Some(Constructor::PrimitiveType(Primitive::String(value))) => Ok(Some(value.clone())),
-        Some(Constructor::PrimitiveType(Primitive::Null)) => {
+        Some(Constructor::PrimitiveType(Primitive::Null)) | None => {
             if mandatory {
-                return Err("Mandatory string is null");
+                Err("Mandatory string is null")
             } else {
                 Ok(None)
             }
         }
-        _ => {
-            return Err("Invalid type: string expected");
-        }
+        _ => Err("Invalid type: string expected"),
     }
 }
 
-fn read_symbol_array(field_iter: &mut Iter<Constructor>) -> Result<Vec<Vec<u8>>, &'static str> {
+pub(super) fn read_symbol(field_iter: &mut Iter<Constructor>) -> Result<Vec<u8>, &'static str> {
+    match field_iter.next() {
+        Some(Constructor::PrimitiveType(Primitive::Symbol(value))) => Ok(value.clone()),
+        _ => Err("Invalid field type: symbol expected"),
+    }
+}
+
+pub(crate) fn read_symbol_array(
+    field_iter: &mut Iter<Constructor>,
+) -> Result<Vec<Vec<u8>>, &'static str> {
     match field_iter.next() {
         Some(Constructor::PrimitiveType(Primitive::Array(value))) => {
             let mut result = vec![];
@@ -577,82 +753,141 @@ fn read_symbol_array(field_iter: &mut Iter<Constructor>) -> Result<Vec<Vec<u8>>,
             }
             Ok(result)
         }
-        Some(Constructor::PrimitiveType(Primitive::EmptyList)) => Ok(vec![]),
+        // A field with multiple="true" may also carry a single value instead of an array.
+        Some(Constructor::PrimitiveType(Primitive::Symbol(symbol))) => Ok(vec![symbol.clone()]),
+        Some(Constructor::PrimitiveType(Primitive::EmptyList | Primitive::Null)) | None => {
+            Ok(vec![])
+        }
         _ => Err("Invalid field type: symbol array expected"),
     }
 }
 
-fn read_map(
+pub(crate) fn read_map(
     field_iter: &mut Iter<Constructor>,
 ) -> Result<HashMap<Constructor, Constructor>, &'static str> {
     match field_iter.next() {
         Some(Constructor::PrimitiveType(Primitive::Map(value))) => {
             let mut result = HashMap::with_capacity(value.value.len());
             for (key, val) in value.value.iter() {
-                result.insert(key.deref().clone(), val.deref().clone());
+                result.insert(key.clone(), val.clone());
             }
             Ok(result)
         }
+        Some(Constructor::PrimitiveType(Primitive::Null)) | None => Ok(HashMap::new()),
         _ => Err("Invalid field type: map expected"),
     }
 }
 
-fn read_binary(field_iter: &mut Iter<Constructor>) -> Result<Vec<u8>, &'static str> {
+pub(crate) fn read_binary(field_iter: &mut Iter<Constructor>) -> Result<Vec<u8>, &'static str> {
     match field_iter.next() {
         Some(Constructor::PrimitiveType(Primitive::Binary(value))) => Ok(value.clone()),
+        Some(Constructor::PrimitiveType(Primitive::Null)) | None => Ok(vec![]),
         _ => Err("Invalid field type: binary expected"),
     }
 }
 
-fn read_error(
+// spec:wildcard[*]: A value of any type is permitted, an omitted field is null.
+pub(crate) fn read_any(field_iter: &mut Iter<Constructor>) -> Constructor {
+    field_iter
+        .next()
+        .cloned()
+        .unwrap_or(Constructor::PrimitiveType(Primitive::Null))
+}
+
+pub(crate) fn read_error(
     field_iter: &mut Iter<Constructor>,
 ) -> Result<Option<PerformativeError>, &'static str> {
     match field_iter.next() {
-        Some(constructor) => match constructor {
-            Constructor::DescribedType(descriptor, list_primitive) => {
-                match descriptor.deref().clone() {
-                    Constructor::PrimitiveType(constructor_primitive) => {
-                        match constructor_primitive {
-                            Primitive::String(prim_body) => match prim_body.as_str() {
-                                "amqp:error:list" => {
-                                    let fields = match list_primitive {
-                                        Primitive::List(boxed_fields) => {
-                                            let mut fields = Vec::with_capacity(boxed_fields.len());
-                                            for field in boxed_fields.iter() {
-                                                let field = field.deref().clone();
-                                                fields.push(field);
-                                            }
-                                            fields
-                                        }
-                                        _ => {
-                                            return Err("Performative descriptor is not a list");
-                                        }
-                                    };
-                                    let mut field_iter = fields.iter();
-
-                                    Ok(Some(PerformativeError {
-                                        condition: read_symbol_array(&mut field_iter)?,
-                                        description: read_string(&mut field_iter, true)?,
-                                        info: read_map(&mut field_iter)?,
-                                    }))
-                                }
-                                _ => return Err("Unknown error type"),
-                            },
-                            _ => {
-                                return Err("Performative constructor descriptor is not a string");
-                            }
-                        }
-                    }
-                    Constructor::DescribedType(_, _) => {
-                        return Err("Performative constructor descriptor is not a primitive type");
-                    }
+        Some(Constructor::DescribedType(descriptor, list_primitive)) => {
+            match Descriptor::from_constructor(descriptor.deref())? {
+                Descriptor::Error => {
+                    let fields = read_fields(list_primitive.clone())?;
+                    let mut field_iter = fields.iter();
+
+                    Ok(Some(PerformativeError {
+                        condition: read_symbol(&mut field_iter)?,
+                        description: read_string(&mut field_iter, false)?,
+                        info: read_map(&mut field_iter)?,
+                    }))
                 }
+                _ => Err("Unknown error type"),
             }
-            Constructor::PrimitiveType(Primitive::Null) => Ok(None),
-            _ => {
-                return Err("The error field is of an unexpected type");
-            }
-        },
-        None => Ok(None),
+        }
+        Some(Constructor::PrimitiveType(Primitive::Null)) | None => Ok(None),
+        _ => Err("The error field is of an unexpected type"),
+    }
+}
+
+// The fields of a composite type are encoded as a list, where trailing null
+// fields may be omitted altogether, so the empty list is valid as well.
+pub(crate) fn read_fields(primitive: Primitive) -> Result<Vec<Constructor>, &'static str> {
+    match primitive {
+        Primitive::List(fields) => Ok(fields),
+        Primitive::EmptyList => Ok(vec![]),
+        _ => Err("Composite type is not encoded as a list"),
+    }
+}
+
+pub(crate) fn write_bool(value: Option<bool>) -> Constructor {
+    Constructor::PrimitiveType(value.map_or(Primitive::Null, Primitive::Boolean))
+}
+
+pub(crate) fn write_ubyte(value: Option<u8>) -> Constructor {
+    Constructor::PrimitiveType(value.map_or(Primitive::Null, Primitive::UByte))
+}
+
+fn write_ushort(value: Option<u16>) -> Constructor {
+    Constructor::PrimitiveType(value.map_or(Primitive::Null, Primitive::UShort))
+}
+
+pub(crate) fn write_uint(value: Option<u32>) -> Constructor {
+    Constructor::PrimitiveType(value.map_or(Primitive::Null, Primitive::UInt))
+}
+
+fn write_ulong(value: Option<u64>) -> Constructor {
+    Constructor::PrimitiveType(value.map_or(Primitive::Null, Primitive::ULong))
+}
+
+pub(crate) fn write_string(value: Option<&String>) -> Constructor {
+    Constructor::PrimitiveType(
+        value.map_or(Primitive::Null, |value| Primitive::String(value.clone())),
+    )
+}
+
+pub(crate) fn write_symbol_array(value: &[Vec<u8>]) -> Constructor {
+    if value.is_empty() {
+        return Constructor::PrimitiveType(Primitive::Null);
+    }
+    Constructor::PrimitiveType(Primitive::Array(
+        value
+            .iter()
+            .map(|symbol| Constructor::PrimitiveType(Primitive::Symbol(symbol.clone())))
+            .collect(),
+    ))
+}
+
+pub(crate) fn write_map(value: &HashMap<Constructor, Constructor>) -> Constructor {
+    if value.is_empty() {
+        return Constructor::PrimitiveType(Primitive::Null);
+    }
+    Constructor::PrimitiveType(Primitive::Map(InnerMap {
+        value: value.clone(),
+    }))
+}
+
+pub(crate) fn write_binary(value: &[u8]) -> Constructor {
+    Constructor::PrimitiveType(Primitive::Binary(value.to_vec()))
+}
+
+// <type name="error" class="composite" source="list">
+// <descriptor name="amqp:error:list" code="0x00000000:0x0000001d"/>
+pub(crate) fn write_error(error: Option<&PerformativeError>) -> Constructor {
+    match error {
+        Some(error) => Descriptor::Error.describe(Primitive::List(trim_trailing_nulls(vec![
+            Constructor::PrimitiveType(Primitive::Symbol(error.condition.clone())),
+            write_string(error.description.as_ref()),
+            write_map(&error.info),
+        ]))),
+        None => Constructor::PrimitiveType(Primitive::Null),
     }
 }
@@ -0,0 +1,297 @@
+use std::sync::Arc;
+
+use x509_parser::extensions::GeneralName;
+use x509_parser::parse_x509_certificate;
+
+use crate::amqp::transport::sasl::mechanism::{Exchange, Mechanism, Step};
+
+// The certificate fields a user name can be taken from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CertificateField {
+    // The common name of the subject.
+    Cn,
+    // The whole subject distinguished name, e.g. "CN=svc-orders, O=estate".
+    Subject,
+    // Subject alternative names of the given kind.
+    SanDns,
+    SanEmail,
+    SanUri,
+}
+
+impl CertificateField {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "cn" => Some(CertificateField::Cn),
+            "subject" => Some(CertificateField::Subject),
+            "san-dns" => Some(CertificateField::SanDns),
+            "san-email" => Some(CertificateField::SanEmail),
+            "san-uri" => Some(CertificateField::SanUri),
+            _ => None,
+        }
+    }
+}
+
+// Maps a certificate field to a user name. Rules are written as
+//     field[:pattern[=user]]
+// where the pattern may contain a single * matching anything, and the user name may
+// use * to refer to what it matched. Without a pattern any value matches, and without
+// a user name the value itself is the user name, so "san-email:*@example.com=*" maps
+// orders@example.com to orders, and "cn" maps a certificate to its common name.
+#[derive(Clone, Debug)]
+pub struct MappingRule {
+    field: CertificateField,
+    pattern: Option<String>,
+    user: Option<String>,
+}
+
+impl MappingRule {
+    // Parses the comma-separated rules from UEXRS_EXTERNAL_RULES.
+    pub fn parse_rules(rules: &str) -> Result<Vec<Self>, String> {
+        rules
+            .split(',')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(|rule| {
+                let (field, rest) = match rule.split_once(':') {
+                    Some((field, rest)) => (field, Some(rest)),
+                    None => (rule, None),
+                };
+                let (pattern, user) = match rest.map(|rest| rest.split_once('=')) {
+                    Some(Some((pattern, user))) => (Some(pattern), Some(user)),
+                    Some(None) => (rest, None),
+                    None => (None, None),
+                };
+                if pattern.is_some_and(|pattern| pattern.matches('*').count() > 1) {
+                    return Err(format!("Only a single * is allowed in rule {}", rule));
+                }
+                Ok(Self {
+                    field: CertificateField::from_name(field)
+                        .ok_or_else(|| format!("Unknown certificate field in rule {}", rule))?,
+                    pattern: pattern.map(String::from),
+                    user: user.map(String::from),
+                })
+            })
+            .collect()
+    }
+
+    fn apply(&self, value: &str) -> Option<String> {
+        let matched = match self.pattern {
+            Some(ref pattern) => match pattern.split_once('*') {
+                Some((prefix, suffix)) if value.len() >= prefix.len() + suffix.len() => {
+                    value.strip_prefix(prefix)?.strip_suffix(suffix)?
+                }
+                Some(_) => return None,
+                None if pattern == value => value,
+                None => return None,
+            },
+            None => value,
+        };
+        match self.user {
+            Some(ref user) => Some(user.replacen('*', matched, 1)),
+            None => Some(value.to_string()),
+        }
+    }
+}
+
+// RFC 4422 Appendix A. The client is whoever its TLS certificate says it is; rustls has
+// already verified that certificate against UEXRS_TLS_CLIENT_CA_FILE, since no client
+// certificates are asked for without it.
+pub struct External {
+    rules: Arc<Vec<MappingRule>>,
+}
+
+impl External {
+    pub fn new(rules: Vec<MappingRule>) -> Self {
+        Self {
+            rules: Arc::new(rules),
+        }
+    }
+}
+
+impl Mechanism for External {
+    fn name(&self) -> &'static [u8] {
+        b"EXTERNAL"
+    }
+
+    fn needs_client_certificate(&self) -> bool {
+        true
+    }
+
+    fn start(&self, client_certificate: Option<&[u8]>) -> Box<dyn Exchange + Send> {
+        Box::new(ExternalExchange {
+            rules: self.rules.clone(),
+            client_certificate: client_certificate.map(<[u8]>::to_vec),
+        })
+    }
+}
+
+struct ExternalExchange {
+    rules: Arc<Vec<MappingRule>>,
+    client_certificate: Option<Vec<u8>>,
+}
+
+impl Exchange for ExternalExchange {
+    // The response is the authorization identity, which has to be empty or
+    // the user name the certificate maps to.
+    fn step(&mut self, response: &[u8]) -> Step {
+        let Some(user) = self
+            .client_certificate
+            .as_deref()
+            .and_then(|certificate| map_certificate(certificate, &self.rules))
+        else {
+            return Step::Failure;
+        };
+        if !response.is_empty() && response != user.as_bytes() {
+            return Step::Failure;
+        }
+        Step::Success {
+            user,
+            additional_data: vec![],
+        }
+    }
+}
+
+// The user name given by the first rule that matches any value of its field.
+fn map_certificate(certificate: &[u8], rules: &[MappingRule]) -> Option<String> {
+    let (_, certificate) = parse_x509_certificate(certificate).ok()?;
+    let subject = certificate.subject();
+    let alternative_names = certificate
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .map(|extension| extension.value.general_names.clone())
+        .unwrap_or_default();
+    rules.iter().find_map(|rule| {
+        let values: Vec<String> = match rule.field {
+            CertificateField::Cn => subject
+                .iter_common_name()
+                .filter_map(|cn| cn.as_str().ok().map(String::from))
+                .collect(),
+            CertificateField::Subject => vec![subject.to_string()],
+            field => alternative_names
+                .iter()
+                .filter_map(|name| match (field, name) {
+                    (CertificateField::SanDns, GeneralName::DNSName(value))
+                    | (CertificateField::SanEmail, GeneralName::RFC822Name(value))
+                    | (CertificateField::SanUri, GeneralName::URI(value)) => {
+                        Some(value.to_string())
+                    }
+                    _ => None,
+                })
+                .collect(),
+        };
+        values.iter().find_map(|value| rule.apply(value))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+
+    // A self-signed certificate for "O=estate, CN=svc-orders" with the subject
+    // alternative names DNS:orders.example.com, email:orders@example.com and
+    // URI:spiffe://example.com/orders.
+    const CERTIFICATE: &str = concat!(
+        "MIIB9TCCAZugAwIBAgIUfo78lEyI2EdPfq87RA3Y7uBzmcowCgYIKoZIzj0EAwIwJjEPMA0GA1UECgwGZXN0YXRl",
+        "MRMwEQYDVQQDDApzdmMtb3JkZXJzMCAXDTI2MTAxNzE3NDUwOFoYDzIxMjYwOTIzMTc0NTA4WjAmMQ8wDQYDVQQK",
+        "DAZlc3RhdGUxEzARBgNVBAMMCnN2Yy1vcmRlcnMwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAARzt37eZCCFJ2PA",
+        "tbnlqkuZhmhsyWPuBgUhtaWc0zbJ2rQTAGbfzIQiG7Id2dJ1i5IrnBAEwMo+BQ/IrzBjQsoXo4GkMIGhMB0GA1Ud",
+        "DgQWBBRgITYdhgALLdL7+JO9TG5oCLp1YDAfBgNVHSMEGDAWgBRgITYdhgALLdL7+JO9TG5oCLp1YDAPBgNVHRMB",
+        "Af8EBTADAQH/ME4GA1UdEQRHMEWCEm9yZGVycy5leGFtcGxlLmNvbYESb3JkZXJzQGV4YW1wbGUuY29thhtzcGlm",
+        "ZmU6Ly9leGFtcGxlLmNvbS9vcmRlcnMwCgYIKoZIzj0EAwIDSAAwRQIgDfyOBY6n592HyOY6J5BTKnFzE3Cbb/Ae",
+        "pQGHH21GMIECIQDjsitJvqnWTbYhfsSijRB2r7I5iuBIWa6xSHr3PCWT4Q==",
+    );
+
+    fn certificate() -> Vec<u8> {
+        BASE64.decode(CERTIFICATE).unwrap()
+    }
+
+    fn user(rules: &str) -> Option<String> {
+        map_certificate(&certificate(), &MappingRule::parse_rules(rules).unwrap())
+    }
+
+    #[test]
+    fn rules_are_parsed() {
+        let rules =
+            MappingRule::parse_rules(" cn , san-email:*@example.com=*,san-dns:*.example.com,")
+                .unwrap();
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[0].field, CertificateField::Cn);
+        assert_eq!(
+            (rules[0].pattern.as_deref(), rules[0].user.as_deref()),
+            (None, None)
+        );
+        assert_eq!(rules[1].field, CertificateField::SanEmail);
+        assert_eq!(rules[1].pattern.as_deref(), Some("*@example.com"));
+        assert_eq!(rules[1].user.as_deref(), Some("*"));
+        assert_eq!(rules[2].field, CertificateField::SanDns);
+        assert_eq!(rules[2].pattern.as_deref(), Some("*.example.com"));
+        assert_eq!(rules[2].user, None);
+
+        assert!(MappingRule::parse_rules("email").is_err());
+        assert!(MappingRule::parse_rules("san-dns:*.*").is_err());
+    }
+
+    #[test]
+    fn patterns_match_and_name_the_user() {
+        let rule = |rule| MappingRule::parse_rules(rule).unwrap().remove(0);
+        assert_eq!(
+            rule("san-email:*@example.com=*").apply("orders@example.com"),
+            Some(String::from("orders"))
+        );
+        assert_eq!(rule("san-email:*@example.com=*").apply("@example.co"), None);
+        assert_eq!(
+            rule("cn:svc-*=service-*").apply("svc-orders"),
+            Some(String::from("service-orders"))
+        );
+        assert_eq!(
+            rule("cn:admin=root").apply("admin"),
+            Some(String::from("root"))
+        );
+        assert_eq!(rule("cn:admin=root").apply("administrator"), None);
+    }
+
+    #[test]
+    fn certificates_map_to_the_first_matching_rule() {
+        assert_eq!(user("cn"), Some(String::from("svc-orders")));
+        assert_eq!(
+            user("subject"),
+            Some(String::from("O=estate, CN=svc-orders"))
+        );
+        assert_eq!(
+            user("san-dns:*.example.com=*"),
+            Some(String::from("orders"))
+        );
+        assert_eq!(
+            user("san-uri:spiffe://example.com/*=*"),
+            Some(String::from("orders"))
+        );
+        assert_eq!(
+            user("san-email:*@elsewhere.com=*, san-email, cn"),
+            Some(String::from("orders@example.com"))
+        );
+        assert_eq!(user("cn:svc-billing"), None);
+    }
+
+    #[test]
+    fn authorization_identity_has_to_be_the_mapped_user() {
+        let external = External::new(MappingRule::parse_rules("cn").unwrap());
+        let certificate = certificate();
+        let success = |step| matches!(step, Step::Success { user, .. } if user == "svc-orders");
+        assert!(success(external.start(Some(&certificate)).step(b"")));
+        assert!(success(
+            external.start(Some(&certificate)).step(b"svc-orders")
+        ));
+        assert!(matches!(
+            external.start(Some(&certificate)).step(b"admin"),
+            Step::Failure
+        ));
+        assert!(matches!(external.start(None).step(b""), Step::Failure));
+        assert!(matches!(
+            external.start(Some(b"not a certificate")).step(b""),
+            Step::Failure
+        ));
+    }
+}
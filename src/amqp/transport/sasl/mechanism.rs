@@ -0,0 +1,196 @@
+use std::sync::Arc;
+
+use crate::amqp::transport::sasl::users::Users;
+
+// What a mechanism makes of the client's latest response.
+pub enum Step {
+    // Send the challenge and feed the client's response back into the exchange.
+    Challenge(Vec<u8>),
+    // The client is authenticated as the given user; the additional data, if any,
+    // goes out with the sasl-outcome.
+    Success {
+        user: String,
+        additional_data: Vec<u8>,
+    },
+    Failure,
+}
+
+// A SASL mechanism we can offer to clients. Each authentication attempt gets its own
+// Exchange so mechanisms needing several rounds can keep their state there.
+pub trait Mechanism: Send + Sync {
+    fn name(&self) -> &'static [u8];
+
+    // Mechanisms that need a client certificate are only offered on TLS connections
+    // where the client presented one.
+    fn needs_client_certificate(&self) -> bool {
+        false
+    }
+
+    // Mechanisms sending the password itself are only offered on TLS connections.
+    fn needs_tls(&self) -> bool {
+        false
+    }
+
+    fn start(&self, client_certificate: Option<&[u8]>) -> Box<dyn Exchange + Send>;
+}
+
+pub trait Exchange {
+    // Called with the initial response from sasl-init first, which is empty if the client
+    // didn't send one, and then with every sasl-response.
+    fn step(&mut self, response: &[u8]) -> Step;
+}
+
+// RFC 4505. The optional trace information the client sends is of no use to us.
+pub struct Anonymous;
+
+impl Mechanism for Anonymous {
+    fn name(&self) -> &'static [u8] {
+        b"ANONYMOUS"
+    }
+
+    fn start(&self, _client_certificate: Option<&[u8]>) -> Box<dyn Exchange + Send> {
+        Box::new(AnonymousExchange)
+    }
+}
+
+struct AnonymousExchange;
+
+impl Exchange for AnonymousExchange {
+    fn step(&mut self, _response: &[u8]) -> Step {
+        Step::Success {
+            user: String::from("anonymous"),
+            additional_data: vec![],
+        }
+    }
+}
+
+// RFC 4616, checked against the credentials in the user file.
+pub struct Plain {
+    users: Arc<Users>,
+}
+
+impl Plain {
+    pub fn new(users: Arc<Users>) -> Self {
+        Self { users }
+    }
+}
+
+impl Mechanism for Plain {
+    fn name(&self) -> &'static [u8] {
+        b"PLAIN"
+    }
+
+    fn needs_tls(&self) -> bool {
+        true
+    }
+
+    fn start(&self, _client_certificate: Option<&[u8]>) -> Box<dyn Exchange + Send> {
+        Box::new(PlainExchange {
+            users: self.users.clone(),
+            challenged: false,
+        })
+    }
+}
+
+struct PlainExchange {
+    users: Arc<Users>,
+    // A client without an initial response gets an empty challenge to send it in reply.
+    challenged: bool,
+}
+
+impl Exchange for PlainExchange {
+    fn step(&mut self, response: &[u8]) -> Step {
+        if response.is_empty() && !self.challenged {
+            self.challenged = true;
+            return Step::Challenge(vec![]);
+        }
+        // message = [authzid] UTF8NUL authcid UTF8NUL passwd
+        let mut parts = response.split(|byte| *byte == 0);
+        let (Some(authzid), Some(authcid), Some(passwd), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Step::Failure;
+        };
+        // Acting on behalf of someone else isn't supported.
+        if !authzid.is_empty() && authzid != authcid {
+            return Step::Failure;
+        }
+        let (Ok(user), Ok(password)) = (str::from_utf8(authcid), str::from_utf8(passwd)) else {
+            return Step::Failure;
+        };
+        if self.users.check_password(user, password) {
+            Step::Success {
+                user: user.to_string(),
+                additional_data: vec![],
+            }
+        } else {
+            Step::Failure
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amqp::transport::sasl::users::add_user;
+
+    // PLAIN checking against a users file holding only "user" with the password "pencil".
+    fn plain(name: &str) -> Plain {
+        let path =
+            std::env::temp_dir().join(format!("uexrs-users-{}-{}", name, std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        add_user(path, "user", "pencil", 16).unwrap();
+        let users = Users::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        Plain::new(Arc::new(users))
+    }
+
+    fn user(step: Step) -> Option<String> {
+        match step {
+            Step::Success { user, .. } => Some(user),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn plain_checks_the_password() {
+        let plain = plain("plain");
+        assert!(plain.needs_tls());
+        assert_eq!(
+            user(plain.start(None).step(b"\0user\0pencil")),
+            Some(String::from("user"))
+        );
+        assert_eq!(user(plain.start(None).step(b"\0user\0crayon")), None);
+        assert_eq!(user(plain.start(None).step(b"\0nobody\0pencil")), None);
+        assert_eq!(user(plain.start(None).step(b"user\0pencil")), None);
+
+        // Without an initial response the client is asked for it.
+        let mut exchange = plain.start(None);
+        assert!(
+            matches!(exchange.step(b""), Step::Challenge(ref challenge) if challenge.is_empty())
+        );
+        assert_eq!(
+            user(exchange.step(b"\0user\0pencil")),
+            Some(String::from("user"))
+        );
+    }
+
+    #[test]
+    fn plain_rejects_acting_for_someone_else() {
+        let plain = plain("plain-authzid");
+        assert_eq!(user(plain.start(None).step(b"admin\0user\0pencil")), None);
+        assert_eq!(
+            user(plain.start(None).step(b"user\0user\0pencil")),
+            Some(String::from("user"))
+        );
+    }
+
+    #[test]
+    fn anonymous_lets_anyone_in() {
+        assert_eq!(
+            user(Anonymous.start(None).step(b"trace")),
+            Some(String::from("anonymous"))
+        );
+    }
+}
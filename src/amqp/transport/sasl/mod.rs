@@ -0,0 +1,388 @@
+use std::io;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::amqp::transport::performative::{
+    read_binary, read_fields, read_symbol, trim_trailing_nulls, write_binary, write_symbol_array,
+    write_ubyte,
+};
+use crate::amqp::types::constructor::Constructor;
+use crate::amqp::types::descriptor::Descriptor;
+use crate::amqp::types::format_code::FormatCode;
+use crate::amqp::types::frame::{Frame, FrameCodec, FrameType, MIN_MAX_FRAME_SIZE};
+use crate::amqp::types::primitive::Primitive;
+use crate::config::Config;
+
+pub mod external;
+pub mod mechanism;
+pub mod scram;
+pub mod users;
+
+use external::{External, MappingRule};
+use mechanism::{Anonymous, Exchange, Mechanism, Plain, Step};
+use scram::{Scram, ScramHash};
+use users::Users;
+
+// <type name="sasl-code" class="restricted" source="ubyte">
+//     <choice name="ok" value="0"/>
+//     <choice name="auth" value="1"/>
+//     <choice name="sys" value="2"/>
+//     <choice name="sys-perm" value="3"/>
+//     <choice name="sys-temp" value="4"/>
+// </type>
+// We never fail for system reasons, so only ok and auth are ever sent.
+#[derive(Clone, Copy, Debug)]
+pub enum SaslCode {
+    Ok = 0,
+    Auth = 1,
+}
+
+// The frames of the SASL negotiation (see 5.3.3 Security Frames).
+// Only the ones a server receives are decoded and only the ones it sends are encoded.
+#[derive(Debug)]
+pub enum SaslFrame {
+    Mechanisms {
+        mechanisms: Vec<Vec<u8>>,
+    },
+    Init {
+        mechanism: Vec<u8>,
+        initial_response: Vec<u8>,
+    },
+    Challenge {
+        challenge: Vec<u8>,
+    },
+    Response {
+        response: Vec<u8>,
+    },
+    Outcome {
+        code: SaslCode,
+        additional_data: Vec<u8>,
+    },
+}
+
+impl SaslFrame {
+    pub async fn new(mut frame_body: &[u8]) -> Result<Self, &'static str> {
+        let fcode = FormatCode::read(&mut frame_body).await?;
+        match Constructor::new(fcode, &mut frame_body).await? {
+            Constructor::PrimitiveType(_) => {
+                Err("Constructor for a SASL frame is a primitive type")
+            }
+            Constructor::DescribedType(descriptor, primitive) => {
+                let fields = read_fields(primitive)?;
+                let mut field_iter = fields.iter();
+                match Descriptor::from_constructor(descriptor.deref())? {
+                    // <type name="sasl-init" class="composite" source="list" provides="sasl-frame">
+                    // <descriptor name="amqp:sasl-init:list" code="0x00000000:0x00000041"/>
+                    Descriptor::SaslInit => Ok(SaslFrame::Init {
+                        // <field name="mechanism" type="symbol" mandatory="true"/>
+                        mechanism: read_symbol(&mut field_iter)?,
+                        // <field name="initial-response" type="binary"/>
+                        initial_response: read_binary(&mut field_iter)?,
+                        // <field name="hostname" type="string"/>
+                        // We don't do virtual hosting, so the hostname is left alone.
+                    }),
+                    // <type name="sasl-response" class="composite" source="list" provides="sasl-frame">
+                    // <descriptor name="amqp:sasl-response:list" code="0x00000000:0x00000043"/>
+                    Descriptor::SaslResponse => Ok(SaslFrame::Response {
+                        // <field name="response" type="binary" mandatory="true"/>
+                        response: read_binary(&mut field_iter)?,
+                    }),
+                    _ => Err("Descriptor does not belong to a SASL frame a server receives"),
+                }
+            }
+        }
+    }
+
+    pub fn to_frame(&self) -> Result<Frame, &'static str> {
+        let (descriptor, fields) = match self {
+            // <field name="sasl-server-mechanisms" type="symbol" multiple="true" mandatory="true"/>
+            SaslFrame::Mechanisms { mechanisms } => (
+                Descriptor::SaslMechanisms,
+                vec![write_symbol_array(mechanisms)],
+            ),
+            // <field name="challenge" type="binary" mandatory="true"/>
+            SaslFrame::Challenge { challenge } => {
+                (Descriptor::SaslChallenge, vec![write_binary(challenge)])
+            }
+            SaslFrame::Outcome {
+                code,
+                additional_data,
+            } => (
+                Descriptor::SaslOutcome,
+                vec![
+                    // <field name="code" type="sasl-code" mandatory="true"/>
+                    write_ubyte(Some(*code as u8)),
+                    // <field name="additional-data" type="binary"/>
+                    if additional_data.is_empty() {
+                        Constructor::PrimitiveType(Primitive::Null)
+                    } else {
+                        write_binary(additional_data)
+                    },
+                ],
+            ),
+            SaslFrame::Init { .. } | SaslFrame::Response { .. } => {
+                return Err("SASL frame is only ever sent by clients");
+            }
+        };
+        let body = descriptor
+            .describe(Primitive::List(trim_trailing_nulls(fields)))
+            .as_bytes()?;
+        Ok(Frame::sasl(body))
+    }
+}
+
+// The mechanisms we offer, in order of preference (see 5.3 SASL).
+pub struct Sasl {
+    mechanisms: Vec<Box<dyn Mechanism>>,
+    // Whether clients have to authenticate before they may open a connection.
+    pub required: bool,
+}
+
+impl Sasl {
+    pub fn from_config(config: &Config) -> io::Result<Self> {
+        let rules = MappingRule::parse_rules(&config.external_rules)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let mut mechanisms: Vec<Box<dyn Mechanism>> = vec![];
+        // Only certificates verified against our CAs may stand for a user.
+        if config.tls_cert_file.is_some() && config.tls_client_ca_file.is_some() {
+            mechanisms.push(Box::new(External::new(rules)));
+        }
+        if let Some(ref users_file) = config.users_file {
+            let users = Arc::new(Users::load(users_file)?);
+            mechanisms.push(Box::new(Scram::new(
+                ScramHash::Sha256,
+                users.clone(),
+                config.scram_iterations,
+            )));
+            mechanisms.push(Box::new(Scram::new(
+                ScramHash::Sha1,
+                users.clone(),
+                config.scram_iterations,
+            )));
+            mechanisms.push(Box::new(Plain::new(users)));
+        }
+        if config.allow_anonymous {
+            mechanisms.push(Box::new(Anonymous));
+        }
+        Ok(Self {
+            mechanisms,
+            required: config.require_sasl,
+        })
+    }
+
+    // Whether any mechanism can be offered on a stream, without which the client is
+    // answered with the AMQP header rather than left with no mechanism to choose.
+    pub fn can_offer(&self, tls: bool, client_certificate: Option<&[u8]>) -> bool {
+        !self.offered(tls, client_certificate).is_empty()
+    }
+
+    fn offered(&self, tls: bool, client_certificate: Option<&[u8]>) -> Vec<&dyn Mechanism> {
+        self.mechanisms
+            .iter()
+            .map(Box::as_ref)
+            .filter(|mechanism| {
+                (!mechanism.needs_client_certificate() || client_certificate.is_some())
+                    && (!mechanism.needs_tls() || tls)
+            })
+            .collect()
+    }
+
+    // Runs the SASL negotiation after the SASL protocol headers have been exchanged
+    // and returns the name of the authenticated user (see 5.3.2 SASL Negotiation).
+    pub async fn authenticate<S>(
+        &self,
+        stream: &mut S,
+        tls: bool,
+        client_certificate: Option<&[u8]>,
+    ) -> Result<String, &'static str>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let offered = self.offered(tls, client_certificate);
+        let mechanisms = offered
+            .iter()
+            .map(|mechanism| mechanism.name().to_vec())
+            .collect();
+        write_sasl_frame(stream, SaslFrame::Mechanisms { mechanisms }).await?;
+
+        let SaslFrame::Init {
+            mechanism,
+            initial_response,
+        } = read_sasl_frame(stream).await?
+        else {
+            return Err("Expected sasl-init");
+        };
+        let Some(mechanism) = offered
+            .iter()
+            .find(|offered| offered.name() == mechanism.as_slice())
+        else {
+            write_sasl_frame(stream, auth_failure()).await?;
+            return Err("Client chose a mechanism we don't offer");
+        };
+
+        let exchange = mechanism.start(client_certificate);
+        let (mut exchange, mut step) = run_step(exchange, initial_response).await?;
+        loop {
+            match step {
+                Step::Challenge(challenge) => {
+                    write_sasl_frame(stream, SaslFrame::Challenge { challenge }).await?;
+                    let SaslFrame::Response { response } = read_sasl_frame(stream).await? else {
+                        return Err("Expected sasl-response");
+                    };
+                    (exchange, step) = run_step(exchange, response).await?;
+                }
+                Step::Success {
+                    user,
+                    additional_data,
+                } => {
+                    let outcome = SaslFrame::Outcome {
+                        code: SaslCode::Ok,
+                        additional_data,
+                    };
+                    write_sasl_frame(stream, outcome).await?;
+                    return Ok(user);
+                }
+                Step::Failure => {
+                    write_sasl_frame(stream, auth_failure()).await?;
+                    return Err("Authentication failed");
+                }
+            }
+        }
+    }
+}
+
+// Mechanisms may derive keys from passwords, which takes long enough to hold up
+// everything else on the executor, so their steps run on the blocking thread pool.
+async fn run_step(
+    mut exchange: Box<dyn Exchange + Send>,
+    response: Vec<u8>,
+) -> Result<(Box<dyn Exchange + Send>, Step), &'static str> {
+    tokio::task::spawn_blocking(move || {
+        let step = exchange.step(&response);
+        (exchange, step)
+    })
+    .await
+    .map_err(|_| "Authentication step failed")
+}
+
+fn auth_failure() -> SaslFrame {
+    SaslFrame::Outcome {
+        code: SaslCode::Auth,
+        additional_data: vec![],
+    }
+}
+
+// SASL frames are read one at a time straight off the stream rather than through a
+// FramedRead, since the AMQP protocol header that follows them must not be buffered away.
+async fn read_sasl_frame<S>(stream: &mut S) -> Result<SaslFrame, &'static str>
+where
+    S: AsyncRead + Unpin,
+{
+    let size = stream
+        .read_u32()
+        .await
+        .map_err(|_| "Could not read SASL frame")?;
+    // Until the connection is open only the minimum max-frame-size can be relied upon.
+    if !(8..=MIN_MAX_FRAME_SIZE).contains(&size) {
+        return Err("Invalid SASL frame size");
+    }
+    let mut buf = BytesMut::zeroed(size as usize);
+    buf[..4].copy_from_slice(&size.to_be_bytes());
+    stream
+        .read_exact(&mut buf[4..])
+        .await
+        .map_err(|_| "Could not read SASL frame")?;
+    let frame = FrameCodec::new(MIN_MAX_FRAME_SIZE)
+        .decode(&mut buf)
+        .map_err(|_| "Invalid SASL frame")?
+        .ok_or("Incomplete SASL frame")?;
+    if frame.frame_type != FrameType::SASL {
+        return Err("Expected a SASL frame");
+    }
+    SaslFrame::new(&frame.frame_body).await
+}
+
+async fn write_sasl_frame<S>(stream: &mut S, sasl_frame: SaslFrame) -> Result<(), &'static str>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut buf = BytesMut::new();
+    FrameCodec::new(MIN_MAX_FRAME_SIZE)
+        .encode(sasl_frame.to_frame()?, &mut buf)
+        .map_err(|_| "Could not encode SASL frame")?;
+    stream
+        .write_all(&buf)
+        .await
+        .map_err(|_| "Could not write to socket")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(sasl: &Sasl, tls: bool, client_certificate: Option<&[u8]>) -> Vec<&'static [u8]> {
+        sasl.offered(tls, client_certificate)
+            .iter()
+            .map(|mechanism| mechanism.name())
+            .collect()
+    }
+
+    #[test]
+    fn nothing_to_offer_without_anonymous_users_or_certificates() {
+        let config = Config {
+            allow_anonymous: false,
+            ..Config::default()
+        };
+        let sasl = Sasl::from_config(&config).unwrap();
+        assert!(!sasl.can_offer(false, None));
+        assert!(!sasl.can_offer(true, Some(b"certificate")));
+    }
+
+    #[test]
+    fn external_needs_verified_client_certificates() {
+        let config = Config {
+            tls_cert_file: Some(String::from("server.pem")),
+            tls_key_file: Some(String::from("server.key")),
+            ..Config::default()
+        };
+        let sasl = Sasl::from_config(&config).unwrap();
+        assert_eq!(names(&sasl, true, Some(b"certificate")), [b"ANONYMOUS"]);
+
+        let config = Config {
+            tls_client_ca_file: Some(String::from("ca.pem")),
+            allow_anonymous: false,
+            ..config
+        };
+        let sasl = Sasl::from_config(&config).unwrap();
+        assert!(!sasl.can_offer(false, None));
+        assert!(!sasl.can_offer(true, None));
+        assert_eq!(names(&sasl, true, Some(b"certificate")), [b"EXTERNAL"]);
+    }
+
+    #[test]
+    fn plain_is_only_offered_with_tls() {
+        let path = std::env::temp_dir().join(format!("uexrs-users-sasl-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        users::add_user(path, "user", "pencil", 16).unwrap();
+        let config = Config {
+            users_file: Some(path.to_string()),
+            ..Config::default()
+        };
+        let sasl = Sasl::from_config(&config).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let scram: [&[u8]; 2] = [b"SCRAM-SHA-256", b"SCRAM-SHA-1"];
+        assert_eq!(
+            names(&sasl, false, None),
+            [scram[0], scram[1], b"ANONYMOUS"]
+        );
+        assert_eq!(
+            names(&sasl, true, None),
+            [scram[0], scram[1], b"PLAIN", b"ANONYMOUS"]
+        );
+    }
+}
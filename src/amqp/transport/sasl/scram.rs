@@ -0,0 +1,535 @@
+use std::sync::Arc;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::amqp::transport::sasl::mechanism::{Exchange, Mechanism, Step};
+use crate::amqp::transport::sasl::users::Users;
+
+// The hash functions SCRAM is offered with (see RFC 5802 and RFC 7677).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ScramHash {
+    Sha1,
+    Sha256,
+}
+
+impl ScramHash {
+    pub fn name(self) -> &'static str {
+        match self {
+            ScramHash::Sha1 => "SCRAM-SHA-1",
+            ScramHash::Sha256 => "SCRAM-SHA-256",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [ScramHash::Sha1, ScramHash::Sha256]
+            .into_iter()
+            .find(|hash| hash.name() == name)
+    }
+
+    fn hash(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            ScramHash::Sha1 => Sha1::digest(data).to_vec(),
+            ScramHash::Sha256 => Sha256::digest(data).to_vec(),
+        }
+    }
+
+    fn hmac(self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            ScramHash::Sha1 => {
+                let mut mac =
+                    Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes keys of any size");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            ScramHash::Sha256 => {
+                let mut mac =
+                    Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    // SaltedPassword := Hi(Normalize(password), salt, i), where Hi is PBKDF2 with HMAC.
+    fn salted_password(self, password: &str, salt: &[u8], iterations: u32) -> Vec<u8> {
+        match self {
+            ScramHash::Sha1 => {
+                pbkdf2::pbkdf2_hmac_array::<Sha1, 20>(password.as_bytes(), salt, iterations)
+                    .to_vec()
+            }
+            ScramHash::Sha256 => {
+                pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(password.as_bytes(), salt, iterations)
+                    .to_vec()
+            }
+        }
+    }
+}
+
+// What the server keeps of a password: enough to verify a client's proof
+// and to prove itself in return, but not enough to log in with.
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+}
+
+impl Credentials {
+    pub fn new(hash: ScramHash, password: &str, salt: Vec<u8>, iterations: u32) -> Self {
+        let salted_password = hash.salted_password(password, &salt, iterations);
+        // ClientKey := HMAC(SaltedPassword, "Client Key"), StoredKey := H(ClientKey)
+        let client_key = hash.hmac(&salted_password, b"Client Key");
+        Self {
+            salt,
+            iterations,
+            stored_key: hash.hash(&client_key),
+            // ServerKey := HMAC(SaltedPassword, "Server Key")
+            server_key: hash.hmac(&salted_password, b"Server Key"),
+        }
+    }
+
+    // Derives fresh credentials for a password with a random salt.
+    pub fn generate(hash: ScramHash, password: &str, iterations: u32) -> Self {
+        Self::new(hash, password, random_bytes(16), iterations)
+    }
+
+    pub fn check_password(&self, hash: ScramHash, password: &str) -> bool {
+        let derived = Self::new(hash, password, self.salt.clone(), self.iterations);
+        constant_time_eq(&derived.stored_key, &self.stored_key)
+    }
+}
+
+pub struct Scram {
+    hash: ScramHash,
+    users: Arc<Users>,
+    // The iteration count made-up credentials for unknown users get, and the key their
+    // salt is derived from, so that it stays the same from one attempt to the next.
+    iterations: u32,
+    secret: Arc<[u8]>,
+}
+
+impl Scram {
+    pub fn new(hash: ScramHash, users: Arc<Users>, iterations: u32) -> Self {
+        Self {
+            hash,
+            users,
+            iterations,
+            secret: random_bytes(32).into(),
+        }
+    }
+}
+
+impl Mechanism for Scram {
+    fn name(&self) -> &'static [u8] {
+        self.hash.name().as_bytes()
+    }
+
+    fn start(&self, _client_certificate: Option<&[u8]>) -> Box<dyn Exchange + Send> {
+        Box::new(ScramExchange {
+            hash: self.hash,
+            users: self.users.clone(),
+            iterations: self.iterations,
+            secret: self.secret.clone(),
+            state: ScramState::ClientFirst { challenged: false },
+        })
+    }
+}
+
+enum ScramState {
+    // A client without an initial response gets an empty challenge to send it in reply.
+    ClientFirst {
+        challenged: bool,
+    },
+    ClientFinal {
+        user: Option<String>,
+        credentials: Credentials,
+        gs2_header: String,
+        nonce: String,
+        // client-first-message-bare "," server-first-message
+        auth_message: String,
+    },
+    Done,
+}
+
+struct ScramExchange {
+    hash: ScramHash,
+    users: Arc<Users>,
+    iterations: u32,
+    secret: Arc<[u8]>,
+    state: ScramState,
+}
+
+impl Exchange for ScramExchange {
+    fn step(&mut self, response: &[u8]) -> Step {
+        let Ok(response) = str::from_utf8(response) else {
+            return Step::Failure;
+        };
+        match std::mem::replace(&mut self.state, ScramState::Done) {
+            ScramState::ClientFirst { challenged } => {
+                if response.is_empty() && !challenged {
+                    self.state = ScramState::ClientFirst { challenged: true };
+                    return Step::Challenge(vec![]);
+                }
+                self.client_first(response).unwrap_or(Step::Failure)
+            }
+            ScramState::ClientFinal {
+                user,
+                credentials,
+                gs2_header,
+                nonce,
+                auth_message,
+            } => self
+                .client_final(
+                    response,
+                    user,
+                    &credentials,
+                    &gs2_header,
+                    &nonce,
+                    &auth_message,
+                )
+                .unwrap_or(Step::Failure),
+            ScramState::Done => Step::Failure,
+        }
+    }
+}
+
+impl ScramExchange {
+    // client-first-message = gs2-header client-first-message-bare
+    // gs2-header = gs2-cbind-flag "," [ authzid ] ","
+    // client-first-message-bare = [reserved-mext ","] username "," nonce ["," extensions]
+    fn client_first(&mut self, response: &str) -> Option<Step> {
+        let mut parts = response.splitn(3, ',');
+        let cbind_flag = parts.next()?;
+        let authzid = parts.next()?;
+        let client_first_bare = parts.next()?;
+        // Channel binding needs TLS, and a client that wants it must not be served without it.
+        if cbind_flag != "n" && cbind_flag != "y" {
+            return None;
+        }
+        let mut attributes = client_first_bare.split(',');
+        let user = decode_saslname(attributes.next()?.strip_prefix("n=")?)?;
+        let client_nonce = attributes.next()?.strip_prefix("r=")?;
+        // Acting on behalf of someone else isn't supported.
+        if !authzid.is_empty() && decode_saslname(authzid.strip_prefix("a=")?)? != user {
+            return None;
+        }
+
+        // Unknown users go through the same motions with made-up credentials,
+        // so the exchange doesn't tell which user names exist.
+        let (user, credentials) = match self.users.credentials(&user, self.hash) {
+            Some(credentials) => (Some(user), credentials.clone()),
+            None => {
+                let credentials = self.unknown_user_credentials(&user);
+                (None, credentials)
+            }
+        };
+        let nonce = format!("{}{}", client_nonce, BASE64.encode(random_bytes(18)));
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce,
+            BASE64.encode(&credentials.salt),
+            credentials.iterations
+        );
+        self.state = ScramState::ClientFinal {
+            user,
+            credentials,
+            gs2_header: format!("{},{},", cbind_flag, authzid),
+            nonce,
+            auth_message: format!("{},{}", client_first_bare, server_first),
+        };
+        Some(Step::Challenge(server_first.into_bytes()))
+    }
+
+    // Like a real user's, the salt is the same on every attempt, being derived from
+    // the user name. No proof matches the random keys.
+    fn unknown_user_credentials(&self, user: &str) -> Credentials {
+        let mut salt = self.hash.hmac(&self.secret, user.as_bytes());
+        salt.truncate(16);
+        Credentials {
+            salt,
+            iterations: self.iterations,
+            stored_key: self.hash.hash(&random_bytes(16)),
+            server_key: self.hash.hash(&random_bytes(16)),
+        }
+    }
+
+    // client-final-message = channel-binding "," nonce ["," extensions] "," proof
+    fn client_final(
+        &self,
+        response: &str,
+        user: Option<String>,
+        credentials: &Credentials,
+        gs2_header: &str,
+        nonce: &str,
+        auth_message: &str,
+    ) -> Option<Step> {
+        let (without_proof, proof) = response.rsplit_once(",p=")?;
+        let mut attributes = without_proof.split(',');
+        let channel_binding = BASE64.decode(attributes.next()?.strip_prefix("c=")?).ok()?;
+        if channel_binding != gs2_header.as_bytes()
+            || attributes.next()?.strip_prefix("r=")? != nonce
+        {
+            return None;
+        }
+        let proof = BASE64.decode(proof).ok()?;
+
+        // AuthMessage := client-first-message-bare + "," + server-first-message + ","
+        //                + client-final-message-without-proof
+        let auth_message = format!("{},{}", auth_message, without_proof);
+        // ClientKey := ClientProof XOR HMAC(StoredKey, AuthMessage)
+        let client_signature = self
+            .hash
+            .hmac(&credentials.stored_key, auth_message.as_bytes());
+        if proof.len() != client_signature.len() {
+            return None;
+        }
+        let client_key: Vec<u8> = proof
+            .iter()
+            .zip(&client_signature)
+            .map(|(a, b)| a ^ b)
+            .collect();
+        if !constant_time_eq(&self.hash.hash(&client_key), &credentials.stored_key) {
+            return None;
+        }
+
+        // server-final-message = "v=" base64(HMAC(ServerKey, AuthMessage)), which
+        // AMQP carries in the additional-data of the sasl-outcome.
+        let server_signature = self
+            .hash
+            .hmac(&credentials.server_key, auth_message.as_bytes());
+        Some(Step::Success {
+            user: user?,
+            additional_data: format!("v={}", BASE64.encode(server_signature)).into_bytes(),
+        })
+    }
+}
+
+// The characters ',' and '=' in user names are sent as "=2C" and "=3D".
+fn decode_saslname(name: &str) -> Option<String> {
+    let mut decoded = String::new();
+    let mut rest = name;
+    while let Some(index) = rest.find('=') {
+        decoded.push_str(&rest[..index]);
+        match rest.get(index..index + 3)? {
+            "=2C" => decoded.push(','),
+            "=3D" => decoded.push('='),
+            _ => return None,
+        }
+        rest = &rest[index + 3..];
+    }
+    decoded.push_str(rest);
+    Some(decoded)
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+// Compares without returning early, so the time taken doesn't tell how much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // A users file holding only "user", whose password is "pencil" as in the RFC examples.
+    fn users(name: &str, hash: ScramHash, salt: &str) -> Arc<Users> {
+        let credentials = Credentials::new(hash, "pencil", BASE64.decode(salt).unwrap(), 4096);
+        let path =
+            std::env::temp_dir().join(format!("uexrs-users-{}-{}", name, std::process::id()));
+        let line = format!(
+            "user:{}:{}:{}:{}:{}\n",
+            hash.name(),
+            credentials.iterations,
+            BASE64.encode(&credentials.salt),
+            BASE64.encode(&credentials.stored_key),
+            BASE64.encode(&credentials.server_key),
+        );
+        fs::write(&path, line).unwrap();
+        let users = Users::load(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        Arc::new(users)
+    }
+
+    fn exchange(hash: ScramHash, users: Arc<Users>) -> ScramExchange {
+        ScramExchange {
+            hash,
+            users,
+            iterations: 4096,
+            secret: random_bytes(32).into(),
+            state: ScramState::ClientFirst { challenged: false },
+        }
+    }
+
+    fn challenge(step: Step) -> String {
+        let Step::Challenge(challenge) = step else {
+            panic!("expected a challenge");
+        };
+        String::from_utf8(challenge).unwrap()
+    }
+
+    // What a client knowing the password answers to the server-first-message.
+    fn client_final(
+        hash: ScramHash,
+        password: &str,
+        client_first_bare: &str,
+        server_first: &str,
+    ) -> String {
+        let attribute = |name| {
+            server_first
+                .split(',')
+                .find_map(|attribute| attribute.strip_prefix(name))
+                .unwrap()
+        };
+        let salted_password = hash.salted_password(
+            password,
+            &BASE64.decode(attribute("s=")).unwrap(),
+            attribute("i=").parse().unwrap(),
+        );
+        let client_key = hash.hmac(&salted_password, b"Client Key");
+        let without_proof = format!("c=biws,r={}", attribute("r="));
+        let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+        let client_signature = hash.hmac(&hash.hash(&client_key), auth_message.as_bytes());
+        let proof: Vec<u8> = client_key
+            .iter()
+            .zip(&client_signature)
+            .map(|(a, b)| a ^ b)
+            .collect();
+        format!("{},p={}", without_proof, BASE64.encode(proof))
+    }
+
+    // Goes through the example exchange of the RFC, whose server nonce stands in for
+    // the random one we make up.
+    fn rfc_exchange(hash: ScramHash, salt: &str, messages: [&str; 4]) {
+        let [client_first, server_first, client_final, server_final] = messages;
+        let mut exchange = exchange(hash, users(hash.name(), hash, salt));
+        let challenge = challenge(exchange.step(client_first.as_bytes()));
+        let client_nonce = client_first.rsplit_once("r=").unwrap().1;
+        assert!(challenge.starts_with(&format!("r={}", client_nonce)));
+        assert!(challenge.ends_with(&format!(",s={},i=4096", salt)));
+        let ScramState::ClientFinal {
+            nonce,
+            auth_message,
+            ..
+        } = &mut exchange.state
+        else {
+            panic!("expected the client-final-message next");
+        };
+        *nonce = server_first[2..].split(',').next().unwrap().to_string();
+        *auth_message = format!("{},{}", &client_first[3..], server_first);
+
+        let Step::Success {
+            user,
+            additional_data,
+        } = exchange.step(client_final.as_bytes())
+        else {
+            panic!("expected the exchange to succeed");
+        };
+        assert_eq!(user, "user");
+        assert_eq!(additional_data, server_final.as_bytes());
+    }
+
+    #[test]
+    fn rfc_5802_example() {
+        rfc_exchange(
+            ScramHash::Sha1,
+            "QSXCR+Q6sek8bf92",
+            [
+                "n,,n=user,r=fyko+d2lbbFgONRv9qkxdawL",
+                "r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4096",
+                "c=biws,r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,p=v0X8v3Bz2T0CJGbJQyF0X+HI4Ts=",
+                "v=rmF9pqV8S7suAoZWja4dJRkFsKQ=",
+            ],
+        );
+    }
+
+    #[test]
+    fn rfc_7677_example() {
+        rfc_exchange(
+            ScramHash::Sha256,
+            "W22ZaJ0SNY7soEsUEjb6gQ==",
+            [
+                "n,,n=user,r=rOprNGfwEbeRWgbNEkqO",
+                "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+                "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+                "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=",
+            ],
+        );
+    }
+
+    #[test]
+    fn only_the_right_password_is_proven() {
+        let hash = ScramHash::Sha256;
+        let users = users("password", hash, "W22ZaJ0SNY7soEsUEjb6gQ==");
+        let client_first_bare = "n=user,r=nonce";
+        for (password, succeeds) in [("pencil", true), ("crayon", false)] {
+            let mut exchange = exchange(hash, users.clone());
+            // Without an initial response the client is asked for it.
+            assert!(challenge(exchange.step(b"")).is_empty());
+            let server_first =
+                challenge(exchange.step(format!("n,,{}", client_first_bare).as_bytes()));
+            let client_final = client_final(hash, password, client_first_bare, &server_first);
+            let step = exchange.step(client_final.as_bytes());
+            assert_eq!(matches!(step, Step::Success { .. }), succeeds);
+        }
+        assert!(users.check_password("user", "pencil"));
+        assert!(!users.check_password("user", "crayon"));
+    }
+
+    #[test]
+    fn others_may_not_be_acted_for() {
+        let hash = ScramHash::Sha1;
+        let users = users("authzid", hash, "QSXCR+Q6sek8bf92");
+        let mut acting_for_admin = exchange(hash, users.clone());
+        assert!(matches!(
+            acting_for_admin.step(b"n,a=admin,n=user,r=nonce"),
+            Step::Failure
+        ));
+        let mut acting_for_self = exchange(hash, users);
+        assert!(matches!(
+            acting_for_self.step(b"n,a=user,n=user,r=nonce"),
+            Step::Challenge(_)
+        ));
+    }
+
+    #[test]
+    fn unknown_users_get_a_stable_made_up_salt() {
+        let hash = ScramHash::Sha256;
+        let scram = Scram::new(
+            hash,
+            users("unknown", hash, "W22ZaJ0SNY7soEsUEjb6gQ=="),
+            4096,
+        );
+        let server_first = |user: &str| {
+            let mut exchange = scram.start(None);
+            let server_first =
+                challenge(exchange.step(format!("n,,n={},r=nonce", user).as_bytes()));
+            (exchange, server_first)
+        };
+        let salt = |server_first: &str| server_first.split(',').nth(1).unwrap().to_string();
+
+        let (mut exchange, first) = server_first("nobody");
+        let (_, again) = server_first("nobody");
+        let (_, other) = server_first("somebody");
+        assert_eq!(salt(&first), salt(&again));
+        assert_ne!(salt(&first), salt(&other));
+        assert!(first.ends_with(",i=4096"));
+        // Whatever the password, there is no proving it.
+        let client_final = client_final(hash, "pencil", "n=nobody,r=nonce", &first);
+        assert!(matches!(
+            exchange.step(client_final.as_bytes()),
+            Step::Failure
+        ));
+    }
+}
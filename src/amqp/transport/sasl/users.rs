@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+
+use crate::amqp::transport::sasl::scram::{Credentials, ScramHash};
+
+// The users allowed to authenticate, read from the file named by UEXRS_USERS_FILE.
+// Passwords are never stored, only the salted SCRAM credentials derived from them,
+// one line per user and hash function:
+//     user:SCRAM-SHA-256:iterations:salt:stored-key:server-key
+// with the binary values base64-encoded. Empty lines and lines starting with # are skipped.
+// Lines are added with `uexrs add-user <name>`, see add_user.
+pub struct Users {
+    credentials: HashMap<String, HashMap<ScramHash, Credentials>>,
+}
+
+impl Users {
+    pub fn load(path: &str) -> io::Result<Self> {
+        let mut credentials: HashMap<String, HashMap<ScramHash, Credentials>> = HashMap::new();
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, hash, user_credentials) = parse_line(line).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid line in users file {}: {}", path, line),
+                )
+            })?;
+            credentials
+                .entry(user.to_string())
+                .or_default()
+                .insert(hash, user_credentials);
+        }
+        Ok(Self { credentials })
+    }
+
+    pub fn credentials(&self, user: &str, hash: ScramHash) -> Option<&Credentials> {
+        self.credentials.get(user)?.get(&hash)
+    }
+
+    // PLAIN sends the password itself, so it can be checked against any of the credentials.
+    pub fn check_password(&self, user: &str, password: &str) -> bool {
+        self.credentials.get(user).is_some_and(|credentials| {
+            credentials
+                .iter()
+                .next()
+                .is_some_and(|(hash, credentials)| credentials.check_password(*hash, password))
+        })
+    }
+}
+
+// Derives credentials for every hash function and writes them to the users file,
+// replacing whatever the user had before.
+pub fn add_user(path: &str, user: &str, password: &str, iterations: u32) -> io::Result<()> {
+    if user.is_empty() || user.contains([':', '\n', '\r']) || user.starts_with('#') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "User names must not be empty, start with # or contain colons or line breaks",
+        ));
+    }
+    let existing = match fs::read_to_string(path) {
+        Ok(existing) => existing,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+    let prefix = format!("{}:", user);
+    let mut lines: Vec<String> = existing
+        .lines()
+        .filter(|line| !line.trim().starts_with(&prefix))
+        .map(String::from)
+        .collect();
+    for hash in [ScramHash::Sha256, ScramHash::Sha1] {
+        let credentials = Credentials::generate(hash, password, iterations);
+        lines.push(format!(
+            "{}:{}:{}:{}:{}:{}",
+            user,
+            hash.name(),
+            credentials.iterations,
+            BASE64.encode(&credentials.salt),
+            BASE64.encode(&credentials.stored_key),
+            BASE64.encode(&credentials.server_key),
+        ));
+    }
+    lines.push(String::new());
+    fs::write(path, lines.join("\n"))
+}
+
+fn parse_line(line: &str) -> Option<(&str, ScramHash, Credentials)> {
+    let mut fields = line.split(':');
+    let user = fields.next()?;
+    let hash = ScramHash::from_name(fields.next()?)?;
+    let credentials = Credentials {
+        iterations: fields.next()?.parse().ok()?,
+        salt: BASE64.decode(fields.next()?).ok()?,
+        stored_key: BASE64.decode(fields.next()?).ok()?,
+        server_key: BASE64.decode(fields.next()?).ok()?,
+    };
+    if fields.next().is_some() {
+        return None;
+    }
+    Some((user, hash, credentials))
+}
@@ -0,0 +1,1196 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::amqp::messaging::delivery_state::DeliveryState;
+use crate::amqp::messaging::message::Message;
+use crate::amqp::transport::condition;
+use crate::amqp::transport::link::{Link, LinkEvent, ReceiverSettleMode, Role};
+use crate::amqp::transport::performative::{Performative, PerformativeError};
+use crate::config::Config;
+
+// See 2.5.5 Session States. Sessions are only ever begun by the peer and mapped as soon
+// as we answer its Begin, so the BEGIN_SENT and BEGIN_RCVD states are never observable,
+// and END_RCVD only lasts until we answer the peer's End.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionState {
+    Mapped,
+    EndSent,
+    // We ended the session because of an error and ignore everything but the peer's End.
+    Discarding,
+}
+
+// A Session is a bidirectional sequential conversation between two containers
+// that provides a grouping for related links (see 2.5 Sessions).
+pub struct Session {
+    state: SessionState,
+    local_channel: u16,
+    remote_channel: u16,
+    // See 2.5.6 Session Flow Control.
+    next_incoming_id: u32,
+    incoming_window: u32,
+    // The incoming window we grant the peer every time it has used up half of it.
+    max_incoming_window: u32,
+    next_outgoing_id: u32,
+    outgoing_window: u32,
+    handle_max: u32,
+    remote_handle_max: u32,
+    // Links by our handle, and our handle for each of the peer's (see 2.6.2).
+    links: HashMap<u32, Link>,
+    remote_handles: HashMap<u32, u32>,
+    // Suspended links the peer is attaching again by name, which the connection
+    // takes out of the SuspendedLinks for us, and the links the peer suspended since
+    // the connection last took them.
+    resumable: HashMap<String, Link>,
+    suspended: Vec<Link>,
+    link_credit: u32,
+    max_message_size: u64,
+    // The delivery-id of the next message we send, which unlike the transfer-id
+    // doesn't count the frames continuing a delivery (see 2.6.14).
+    next_delivery_id: u32,
+    // How many more Transfers the peer takes in (see 2.5.6).
+    remote_incoming_window: u32,
+    // The largest frame the peer takes in, which the messages we send are split to fit.
+    remote_max_frame_size: u32,
+    // The frames of a delivery that didn't fit in the peer's incoming window, with our
+    // handle for their link, sent once the peer's next Flow opens the window again.
+    outgoing: VecDeque<(u32, Performative, Vec<u8>)>,
+    // The messages we sent that the peer hasn't settled yet by delivery-id,
+    // with our handle for the link they went out on and their tag.
+    unsettled: BTreeMap<u32, (u32, Vec<u8>, Message)>,
+    // The messages the peer sent that aren't settled at both ends yet by delivery-id,
+    // with the name of their link, their tag and how we settle them. Delivery-ids are
+    // unique within the session, so they outlive a suspended link.
+    incoming_unsettled: BTreeMap<u32, (String, Vec<u8>, ReceiverSettleMode)>,
+    // What happened on the links since the connection last took it.
+    events: Vec<LinkEvent>,
+}
+
+impl Session {
+    pub fn new(
+        local_channel: u16,
+        remote_channel: u16,
+        remote_next_outgoing_id: u32,
+        remote_incoming_window: u32,
+        remote_handle_max: u32,
+        remote_max_frame_size: u32,
+        config: &Config,
+    ) -> Self {
+        Self {
+            state: SessionState::Mapped,
+            local_channel,
+            remote_channel,
+            next_incoming_id: remote_next_outgoing_id,
+            incoming_window: config.incoming_window,
+            max_incoming_window: config.incoming_window,
+            next_outgoing_id: 0,
+            outgoing_window: config.outgoing_window,
+            handle_max: config.handle_max,
+            remote_handle_max,
+            links: HashMap::new(),
+            remote_handles: HashMap::new(),
+            resumable: HashMap::new(),
+            suspended: vec![],
+            link_credit: config.link_credit,
+            max_message_size: config.max_message_size,
+            next_delivery_id: 0,
+            remote_incoming_window,
+            remote_max_frame_size,
+            outgoing: VecDeque::new(),
+            unsettled: BTreeMap::new(),
+            incoming_unsettled: BTreeMap::new(),
+            events: vec![],
+        }
+    }
+
+    pub fn local_channel(&self) -> u16 {
+        self.local_channel
+    }
+
+    pub fn is_ending(&self) -> bool {
+        self.state != SessionState::Mapped
+    }
+
+    pub fn handle_max(&self) -> u32 {
+        self.handle_max
+    }
+
+    // Our answer to the peer's Begin.
+    pub fn begin(&self) -> Performative {
+        Performative::Begin {
+            remote_channel: Some(self.remote_channel),
+            next_outgoing_id: self.next_outgoing_id,
+            incoming_window: self.incoming_window,
+            outgoing_window: self.outgoing_window,
+            handle_max: self.handle_max,
+            offered_capabilities: vec![],
+            desired_capabilities: vec![],
+            properties: HashMap::new(),
+        }
+    }
+
+    pub fn end(&mut self, error: Option<PerformativeError>) -> Performative {
+        self.state = if error.is_some() {
+            SessionState::Discarding
+        } else {
+            SessionState::EndSent
+        };
+        Performative::End { error }
+    }
+
+    // Handles the frames sent on the session's channel other than Begin and End,
+    // returning whatever has to be sent back on it.
+    pub fn handle(
+        &mut self,
+        performative: Performative,
+        payload: Vec<u8>,
+    ) -> Result<Vec<Performative>, PerformativeError> {
+        let mut replies = vec![];
+        match performative {
+            Performative::Attach {
+                handle: remote_handle,
+                role,
+                ref name,
+                ..
+            } => {
+                if self.remote_handles.contains_key(&remote_handle) {
+                    return Err(PerformativeError::new(
+                        condition::SESSION_HANDLE_IN_USE,
+                        format!("Attach received on the used handle {}", remote_handle),
+                    ));
+                }
+                let handle = self.free_handle().ok_or_else(|| {
+                    PerformativeError::new(
+                        condition::RESOURCE_LIMIT_EXCEEDED,
+                        "No handle left to attach the link to",
+                    )
+                })?;
+                let (mut link, outcomes) = match self.resumable.remove(name) {
+                    Some(mut link) if link.role() == Role::from_bool(!role) => {
+                        let outcomes = link.resume(handle, &performative);
+                        (link, outcomes)
+                    }
+                    _ => (
+                        Link::attach(
+                            handle,
+                            performative,
+                            self.link_credit,
+                            self.max_message_size,
+                        )
+                        .ok_or_else(|| {
+                            PerformativeError::new(condition::INTERNAL_ERROR, "Not an Attach")
+                        })?,
+                        vec![],
+                    ),
+                };
+                let terminus = link
+                    .create_dynamic_node()
+                    .and_then(|dynamic| Ok((dynamic, link.filter()?)));
+                replies.push(link.attach_reply());
+                if link.role() == Role::Sender {
+                    match (link.address(), terminus) {
+                        (_, Err(err)) => replies.push(link.detach(
+                            true,
+                            Some(PerformativeError::new(condition::INVALID_FIELD, err)),
+                        )),
+                        (Some(address), Ok((dynamic, filter))) => {
+                            self.events.push(LinkEvent::Attached {
+                                handle,
+                                address,
+                                name: link.name().to_string(),
+                                durable: link.is_durable(),
+                                dynamic,
+                                filter,
+                            });
+                            // Requeued messages go in front, so in reverse they stay in order.
+                            for (message, state) in outcomes.into_iter().rev() {
+                                self.events.push(LinkEvent::Outcome {
+                                    handle,
+                                    message: Box::new(message),
+                                    state,
+                                });
+                            }
+                        }
+                        (None, _) => replies.push(link.detach(
+                            true,
+                            Some(PerformativeError::new(
+                                condition::NOT_FOUND,
+                                "The source has no address",
+                            )),
+                        )),
+                    }
+                } else if let Some(address) = link.address() {
+                    self.events
+                        .push(LinkEvent::TargetAttached { handle, address });
+                }
+                if link.grant_credit() {
+                    replies.push(link.flow(self.flow()));
+                }
+                self.remote_handles.insert(remote_handle, handle);
+                self.links.insert(handle, link);
+            }
+            Performative::Detach {
+                handle: remote_handle,
+                closed,
+                error,
+            } => {
+                let mut link = self
+                    .remote_handles
+                    .remove(&remote_handle)
+                    .and_then(|handle| self.links.remove(&handle))
+                    .ok_or_else(|| unattached_handle(remote_handle))?;
+                if let Some(error) = error {
+                    println!(
+                        "Link {} detached with {}: {:?}",
+                        link.name(),
+                        String::from_utf8_lossy(&error.condition),
+                        error.description
+                    );
+                }
+                if !link.is_detaching() {
+                    replies.push(link.detach(closed, None));
+                }
+                if closed || link.expires_on_detach() {
+                    self.detached(&mut link, closed);
+                    self.incoming_unsettled
+                        .retain(|_, (name, _, _)| name != link.name());
+                } else {
+                    self.suspend(link);
+                }
+            }
+            Performative::Flow {
+                next_incoming_id,
+                incoming_window,
+                handle,
+                echo,
+                ..
+            } => {
+                // remote-incoming-window = next-incoming-id(flow) + incoming-window(flow)
+                //                          - next-outgoing-id(endpoint),
+                // where a peer that hasn't seen our Begin yet counts from our initial
+                // next-outgoing-id of 0.
+                self.remote_incoming_window = next_incoming_id
+                    .unwrap_or(0)
+                    .wrapping_add(incoming_window)
+                    .wrapping_sub(self.next_outgoing_id);
+                match handle {
+                    None if echo => replies.push(self.flow()),
+                    None => {}
+                    Some(remote_handle) => {
+                        let session_flow = self.flow();
+                        let link = self.link(remote_handle)?;
+                        if !link.is_detaching() && link.flow_received(&performative) {
+                            replies.push(link.flow(session_flow));
+                        }
+                        let handle = link.handle();
+                        let settlements = link.take_settlements();
+                        let resends = link.take_resends();
+                        replies.extend(self.settlement_transfers(handle, settlements));
+                        for (delivery_tag, message) in resends {
+                            self.resend(handle, delivery_tag, message);
+                        }
+                    }
+                }
+            }
+            Performative::Transfer {
+                handle: remote_handle,
+                ..
+            } => {
+                if self.incoming_window == 0 {
+                    return Err(PerformativeError::new(
+                        condition::SESSION_WINDOW_VIOLATION,
+                        "Transfer received while the incoming window is closed",
+                    ));
+                }
+                self.next_incoming_id = self.next_incoming_id.wrapping_add(1);
+                self.incoming_window -= 1;
+                if self.incoming_window <= self.max_incoming_window / 2 {
+                    self.incoming_window = self.max_incoming_window;
+                    replies.push(self.flow());
+                }
+                let session_flow = self.flow();
+                let link = self.link(remote_handle)?;
+                if link.is_detaching() {
+                    return Ok(replies);
+                }
+                match link.transfer_received(&performative, &payload) {
+                    Ok((replenish, delivery)) => {
+                        if replenish {
+                            replies.push(link.flow(session_flow));
+                        }
+                        if let Some(delivery) = delivery {
+                            let name = link.name().to_string();
+                            let address = link.address();
+                            // A delivery resumed after we decided on it isn't passed on
+                            // again, the peer only hears of our outcome (see 2.6.13).
+                            let known_outcome = delivery
+                                .resume
+                                .then(|| link.known_outcome(&delivery.delivery_tag))
+                                .flatten();
+                            if delivery.settled {
+                                link.settled(&delivery.delivery_tag);
+                            } else {
+                                link.set_unsettled(&delivery.delivery_tag, known_outcome.clone());
+                                self.incoming_unsettled.insert(
+                                    delivery.delivery_id,
+                                    (
+                                        name,
+                                        delivery.delivery_tag.clone(),
+                                        delivery.rcv_settle_mode,
+                                    ),
+                                );
+                            }
+                            match known_outcome {
+                                Some(state) if !delivery.settled => {
+                                    replies.extend(self.settle(delivery.delivery_id, state));
+                                }
+                                Some(_) => {}
+                                // Resumed only to be settled, there is nothing to pass on.
+                                None if delivery.resume
+                                    && delivery.settled
+                                    && delivery.payload.is_empty() => {}
+                                None => self.events.push(LinkEvent::Delivery {
+                                    delivery_id: delivery.delivery_id,
+                                    settled: delivery.settled,
+                                    address,
+                                    payload: delivery.payload,
+                                }),
+                            }
+                        }
+                    }
+                    Err(error) => replies.push(link.detach(true, Some(error))),
+                }
+            }
+            // The peer settling the messages we sent (see 2.6.12 Transferring A Message).
+            Performative::Disposition {
+                role: true,
+                first,
+                last,
+                settled,
+                state,
+                ..
+            } => {
+                // A message settled without an outcome gets the default outcome
+                // of the source of its link (see 3.5.3).
+                let state = match state {
+                    Some(state) if state.is_terminal() => Some(state),
+                    _ if settled => None,
+                    _ => return Ok(replies),
+                };
+                let last = last.unwrap_or(first);
+                let delivery_ids: Vec<u32> = self
+                    .unsettled
+                    .keys()
+                    .filter(|id| id.wrapping_sub(first) <= last.wrapping_sub(first))
+                    .copied()
+                    .collect();
+                if delivery_ids.is_empty() {
+                    return Ok(replies);
+                }
+                for delivery_id in delivery_ids {
+                    if let Some((handle, _, message)) = self.unsettled.remove(&delivery_id) {
+                        let state = state
+                            .clone()
+                            .or_else(|| self.links.get(&handle).and_then(Link::default_outcome))
+                            .unwrap_or(DeliveryState::Modified {
+                                delivery_failed: true,
+                                undeliverable_here: false,
+                                message_annotations: HashMap::new(),
+                            });
+                        self.events.push(LinkEvent::Outcome {
+                            handle,
+                            message: Box::new(message),
+                            state,
+                        });
+                    }
+                }
+                // With an outcome, there is nothing left for us to remember.
+                if !settled {
+                    replies.push(Performative::Disposition {
+                        role: Role::Sender.as_bool(),
+                        first,
+                        last: Some(last),
+                        settled: true,
+                        state,
+                        batchable: false,
+                    });
+                }
+            }
+            // The peer settling the messages it sent us, which those we settle second
+            // have been waiting for.
+            Performative::Disposition {
+                role: false,
+                first,
+                last,
+                settled: true,
+                ..
+            } => {
+                let last = last.unwrap_or(first);
+                let delivery_ids: Vec<u32> = self
+                    .incoming_unsettled
+                    .keys()
+                    .filter(|id| id.wrapping_sub(first) <= last.wrapping_sub(first))
+                    .copied()
+                    .collect();
+                for delivery_id in delivery_ids {
+                    if let Some((name, delivery_tag, _)) =
+                        self.incoming_unsettled.remove(&delivery_id)
+                        && let Some(link) = self.link_named(&name)
+                    {
+                        link.settled(&delivery_tag);
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(replies)
+    }
+
+    pub fn take_events(&mut self) -> Vec<LinkEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    // Whether the link with our handle may send the peer a message, which waits
+    // for the rest of any delivery the peer's incoming window held back.
+    pub fn can_send(&self, handle: u32) -> bool {
+        !self.is_ending()
+            && self.remote_incoming_window > 0
+            && self.outgoing.is_empty()
+            && self.links.get(&handle).is_some_and(Link::can_send)
+    }
+
+    // Whether the peer takes in a message of the size on the link with our handle.
+    pub fn fits(&self, handle: u32, message_size: usize) -> bool {
+        self.links
+            .get(&handle)
+            .is_some_and(|link| link.fits(message_size))
+    }
+
+    // The Transfers sending a message on the link with our handle, which the peer
+    // has to settle unless the link sends them settled. Only as many frames as the
+    // peer's incoming window allows are returned, the rest wait in take_transfers.
+    pub fn transfer(
+        &mut self,
+        handle: u32,
+        message: Message,
+        payload: &[u8],
+    ) -> Result<Vec<(Performative, Vec<u8>)>, &'static str> {
+        let link = self.links.get_mut(&handle).ok_or("The link is gone")?;
+        let delivery_tag = link.transfer_sent();
+        self.queue_delivery(handle, delivery_tag, false, message, payload)?;
+        Ok(self.take_transfers())
+    }
+
+    // Sends a message again under the tag the peer knows it by after resuming its link
+    // (see 2.6.13 Resuming Deliveries). One we can't encode goes back to its node.
+    fn resend(&mut self, handle: u32, delivery_tag: Vec<u8>, message: Message) {
+        let payload = match message.encode() {
+            Ok(payload) => payload,
+            Err(err) => {
+                self.events.push(LinkEvent::Outcome {
+                    handle,
+                    message: Box::new(message),
+                    state: DeliveryState::Rejected {
+                        error: Some(PerformativeError::new(condition::INTERNAL_ERROR, err)),
+                    },
+                });
+                return;
+            }
+        };
+        if let Err(err) = self.queue_delivery(handle, delivery_tag, true, message, &payload) {
+            println!("Could not send a message again: {}", err);
+        }
+    }
+
+    // Queues the frames of a delivery, splitting the payload so that no frame is larger
+    // than the max-frame-size, each frame but the last having more=true
+    // (see 2.6.14 Transferring A Message).
+    fn queue_delivery(
+        &mut self,
+        handle: u32,
+        delivery_tag: Vec<u8>,
+        resume: bool,
+        message: Message,
+        payload: &[u8],
+    ) -> Result<(), &'static str> {
+        let settled = self
+            .links
+            .get(&handle)
+            .ok_or("The link is gone")?
+            .sends_settled();
+        let delivery_id = self.next_delivery_id;
+        let transfer = |more| Performative::Transfer {
+            handle,
+            delivery_id: Some(delivery_id),
+            delivery_tag: delivery_tag.clone(),
+            message_format: Some(0),
+            settled: Some(settled),
+            more,
+            rcv_settle_mode: None,
+            state: None,
+            resume,
+            aborted: false,
+            batchable: false,
+        };
+        // The frame header takes 8 octets, and the Transfer is the same on every frame
+        // but for the more flag, which takes up as much room either way.
+        let overhead = 8 + transfer(true).to_constructor().as_bytes()?.len();
+        let chunk_size = (self.remote_max_frame_size as usize)
+            .checked_sub(overhead)
+            .filter(|size| *size > 0)
+            .unwrap_or(1);
+        let chunks: Vec<&[u8]> = if payload.is_empty() {
+            vec![payload]
+        } else {
+            payload.chunks(chunk_size).collect()
+        };
+        self.next_delivery_id = self.next_delivery_id.wrapping_add(1);
+        if settled {
+            // Sent settled, the message is as good as accepted, and done with at its node.
+            self.events.push(LinkEvent::Outcome {
+                handle,
+                message: Box::new(message),
+                state: DeliveryState::Accepted,
+            });
+        } else {
+            self.unsettled
+                .insert(delivery_id, (handle, delivery_tag.clone(), message));
+        }
+        let last = chunks.len() - 1;
+        self.outgoing.extend(
+            chunks
+                .into_iter()
+                .enumerate()
+                .map(|(index, chunk)| (handle, transfer(index < last), chunk.to_vec())),
+        );
+        Ok(())
+    }
+
+    // The held back frames that fit in the peer's incoming window, each taking up
+    // one transfer-id of it (see 2.5.6 Session Flow Control).
+    pub fn take_transfers(&mut self) -> Vec<(Performative, Vec<u8>)> {
+        let mut transfers = vec![];
+        while self.remote_incoming_window > 0
+            && !self.is_ending()
+            && let Some((_, transfer, chunk)) = self.outgoing.pop_front()
+        {
+            self.next_outgoing_id = self.next_outgoing_id.wrapping_add(1);
+            self.remote_incoming_window -= 1;
+            transfers.push((transfer, chunk));
+        }
+        transfers
+    }
+
+    // The Disposition with the outcome of a message the peer sent. Settling first, we
+    // are done with it, while settling second we wait for the peer to settle it.
+    pub fn settle(&mut self, delivery_id: u32, state: DeliveryState) -> Option<Performative> {
+        let (name, delivery_tag, rcv_settle_mode) =
+            self.incoming_unsettled.get(&delivery_id)?.clone();
+        let link = self.link_named(&name)?;
+        let settled = rcv_settle_mode == ReceiverSettleMode::First;
+        if settled {
+            link.settled(&delivery_tag);
+            self.incoming_unsettled.remove(&delivery_id);
+        } else {
+            link.set_unsettled(&delivery_tag, Some(state.clone()));
+        }
+        Some(Performative::Disposition {
+            role: Role::Receiver.as_bool(),
+            first: delivery_id,
+            last: None,
+            settled,
+            state: Some(state),
+            batchable: false,
+        })
+    }
+
+    // The Flow owed to a draining receiver once nothing is left to send it.
+    pub fn drain_credit(&mut self, handle: u32) -> Option<Performative> {
+        let session_flow = self.flow();
+        let link = self.links.get_mut(&handle)?;
+        link.drain_credit().then(|| link.flow(session_flow))
+    }
+
+    // Closes the link with our handle from our end, e.g. once its node is deleted.
+    pub fn detach_link(&mut self, handle: u32, error: PerformativeError) -> Option<Performative> {
+        let link = self.links.get_mut(&handle)?;
+        (!link.is_detaching()).then(|| link.detach(true, Some(error)))
+    }
+
+    // A suspended link the peer is about to attach again, see SuspendedLinks.
+    pub fn offer(&mut self, link: Link) {
+        self.resumable.insert(link.name().to_string(), link);
+    }
+
+    pub fn take_suspended(&mut self) -> Vec<Link> {
+        std::mem::take(&mut self.suspended)
+    }
+
+    // Lets the nodes know the links are gone along with the session. Ending the session
+    // doesn't close the links, so those whose terminus outlives them are suspended.
+    pub fn detach_links(&mut self) {
+        let links: Vec<Link> = self.links.drain().map(|(_, link)| link).collect();
+        for mut link in links {
+            if link.expires_on_detach() {
+                self.detached(&mut link, false);
+            } else {
+                self.suspend(link);
+            }
+        }
+        let resumable: Vec<Link> = self.resumable.drain().map(|(_, link)| link).collect();
+        self.suspended.extend(resumable);
+        self.remote_handles.clear();
+        self.incoming_unsettled.clear();
+        self.outgoing.clear();
+    }
+
+    // The messages the peer hasn't settled go back to their node, as do those a
+    // resumed link was yet to send again.
+    fn detached(&mut self, link: &mut Link, closed: bool) {
+        let handle = link.handle();
+        self.outgoing
+            .retain(|(link_handle, _, _)| *link_handle != handle);
+        let unsettled = self
+            .take_unsettled(handle)
+            .into_iter()
+            .map(|(_, message)| message)
+            .chain(link.take_held())
+            .collect();
+        self.events.push(LinkEvent::Detached {
+            handle,
+            closed,
+            suspended: false,
+            unsettled,
+        });
+    }
+
+    // The messages the peer hasn't settled stay with the link for when it resumes it.
+    fn suspend(&mut self, mut link: Link) {
+        let handle = link.handle();
+        self.outgoing
+            .retain(|(link_handle, _, _)| *link_handle != handle);
+        link.suspend(self.take_unsettled(handle));
+        self.events.push(LinkEvent::Detached {
+            handle,
+            closed: false,
+            suspended: true,
+            unsettled: vec![],
+        });
+        self.suspended.push(link);
+    }
+
+    // The messages sent on the link with our handle that the peer hasn't settled, by tag.
+    fn take_unsettled(&mut self, handle: u32) -> Vec<(Vec<u8>, Message)> {
+        let delivery_ids: Vec<u32> = self
+            .unsettled
+            .iter()
+            .filter(|(_, (link_handle, _, _))| *link_handle == handle)
+            .map(|(delivery_id, _)| *delivery_id)
+            .collect();
+        delivery_ids
+            .iter()
+            .filter_map(|delivery_id| self.unsettled.remove(delivery_id))
+            .map(|(_, delivery_tag, message)| (delivery_tag, message))
+            .collect()
+    }
+
+    // Resumed Transfers settling the messages the peer had outcomes for when it resumed
+    // the link, with no payload since it got them already (see 2.6.13).
+    fn settlement_transfers(
+        &mut self,
+        handle: u32,
+        settlements: Vec<(Vec<u8>, DeliveryState)>,
+    ) -> Vec<Performative> {
+        let mut transfers = vec![];
+        for (delivery_tag, state) in settlements {
+            transfers.push(Performative::Transfer {
+                handle,
+                delivery_id: Some(self.next_delivery_id),
+                delivery_tag,
+                message_format: Some(0),
+                settled: Some(true),
+                more: false,
+                rcv_settle_mode: None,
+                state: Some(state),
+                resume: true,
+                aborted: false,
+                batchable: false,
+            });
+            self.next_delivery_id = self.next_delivery_id.wrapping_add(1);
+            self.next_outgoing_id = self.next_outgoing_id.wrapping_add(1);
+            self.remote_incoming_window = self.remote_incoming_window.saturating_sub(1);
+        }
+        transfers
+    }
+
+    fn link(&mut self, remote_handle: u32) -> Result<&mut Link, PerformativeError> {
+        self.remote_handles
+            .get(&remote_handle)
+            .and_then(|handle| self.links.get_mut(handle))
+            .ok_or_else(|| unattached_handle(remote_handle))
+    }
+
+    // The attached link with the name that we receive on.
+    fn link_named(&mut self, name: &str) -> Option<&mut Link> {
+        self.links
+            .values_mut()
+            .find(|link| link.name() == name && link.role() == Role::Receiver)
+    }
+
+    // The lowest handle the peer's handle-max allows that isn't in use (see 2.7.2 Begin).
+    fn free_handle(&self) -> Option<u32> {
+        (0..=self.remote_handle_max).find(|handle| !self.links.contains_key(handle))
+    }
+
+    // A Flow carrying only the session flow state (see 2.7.4 Flow).
+    fn flow(&self) -> Performative {
+        Performative::Flow {
+            next_incoming_id: Some(self.next_incoming_id),
+            incoming_window: self.incoming_window,
+            next_outgoing_id: self.next_outgoing_id,
+            outgoing_window: self.outgoing_window,
+            handle: None,
+            delivery_count: None,
+            link_credit: None,
+            available: None,
+            drain: false,
+            echo: false,
+            properties: HashMap::new(),
+        }
+    }
+}
+
+fn unattached_handle(handle: u32) -> PerformativeError {
+    PerformativeError::new(
+        condition::SESSION_UNATTACHED_HANDLE,
+        format!("Frame received on the unattached handle {}", handle),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amqp::messaging::delivery_state::write_delivery_state;
+    use crate::amqp::messaging::message::Properties;
+    use crate::amqp::messaging::terminus::{Source, Target};
+    use crate::amqp::transport::link::SenderSettleMode;
+    use crate::amqp::types::constructor::Constructor;
+    use crate::amqp::types::primitive::Primitive;
+
+    fn session() -> Session {
+        Session::new(0, 0, 0, 100, 7, 512, &Config::default())
+    }
+
+    // The peer's Attach of a link to or from the queue, by the peer's role.
+    fn attach(handle: u32, role: Role) -> Performative {
+        Performative::Attach {
+            name: String::from("link"),
+            handle,
+            role: role.as_bool(),
+            snd_settle_mode: 0,
+            rcv_settle_mode: 0,
+            source: Some(Box::new(Source {
+                address: Some(String::from("queue")),
+                ..Source::default()
+            })),
+            target: Some(Box::new(Target {
+                address: Some(String::from("queue")),
+                ..Target::default()
+            })),
+            unsettled: HashMap::new(),
+            incomplete_unsettled: false,
+            initial_delivery_count: Some(0),
+            max_message_size: None,
+            offered_capabilities: vec![],
+            desired_capabilities: vec![],
+            properties: HashMap::new(),
+        }
+    }
+
+    // The Attach asking for the settle modes.
+    fn settling(
+        mut attach: Performative,
+        sender: SenderSettleMode,
+        receiver: ReceiverSettleMode,
+    ) -> Performative {
+        if let Performative::Attach {
+            snd_settle_mode,
+            rcv_settle_mode,
+            ..
+        } = &mut attach
+        {
+            *snd_settle_mode = sender.as_ubyte();
+            *rcv_settle_mode = receiver.as_ubyte();
+        }
+        attach
+    }
+
+    fn flow(handle: u32, delivery_count: u32, link_credit: u32) -> Performative {
+        Performative::Flow {
+            next_incoming_id: Some(0),
+            incoming_window: 100,
+            next_outgoing_id: 0,
+            outgoing_window: 100,
+            handle: Some(handle),
+            delivery_count: Some(delivery_count),
+            link_credit: Some(link_credit),
+            available: None,
+            drain: false,
+            echo: false,
+            properties: HashMap::new(),
+        }
+    }
+
+    // The peer's Transfer of the whole of a delivery on its link of handle 0.
+    fn transfer(delivery_id: u32) -> Performative {
+        Performative::Transfer {
+            handle: 0,
+            delivery_id: Some(delivery_id),
+            delivery_tag: delivery_id.to_be_bytes().to_vec(),
+            message_format: Some(0),
+            settled: Some(false),
+            more: false,
+            rcv_settle_mode: None,
+            state: None,
+            resume: false,
+            aborted: false,
+            batchable: false,
+        }
+    }
+
+    fn message(subject: &str) -> Message {
+        Message {
+            properties: Some(Properties {
+                subject: Some(subject.to_string()),
+                ..Properties::default()
+            }),
+            ..Message::default()
+        }
+    }
+
+    #[test]
+    fn resumed_links_send_unsettled_deliveries_again_under_their_tag() {
+        let mut session = session();
+        session.handle(attach(0, Role::Receiver), vec![]).unwrap();
+        session.handle(flow(0, 0, 10), vec![]).unwrap();
+        let payload = message("a").encode().unwrap();
+        let transfers = session.transfer(0, message("a"), &payload).unwrap();
+        let [(Performative::Transfer { delivery_tag, .. }, _)] = &transfers[..] else {
+            panic!("expected one Transfer");
+        };
+        let delivery_tag = delivery_tag.clone();
+
+        let detach = Performative::Detach {
+            handle: 0,
+            closed: false,
+            error: None,
+        };
+        session.handle(detach, vec![]).unwrap();
+        session.take_events();
+        for link in session.take_suspended() {
+            session.offer(link);
+        }
+        let mut resume = attach(1, Role::Receiver);
+        if let Performative::Attach { unsettled, .. } = &mut resume {
+            unsettled.insert(
+                Constructor::PrimitiveType(Primitive::Binary(delivery_tag.clone())),
+                write_delivery_state(None),
+            );
+        }
+        session.handle(resume, vec![]).unwrap();
+        // The message stays with the link rather than going back to the queue.
+        let events = session.take_events();
+        assert!(matches!(events[..], [LinkEvent::Attached { .. }]));
+        assert!(session.take_transfers().is_empty());
+
+        session.handle(flow(1, 1, 10), vec![]).unwrap();
+        let transfers = session.take_transfers();
+        let [
+            (
+                Performative::Transfer {
+                    delivery_id,
+                    delivery_tag: resent_tag,
+                    resume: true,
+                    ..
+                },
+                resent_payload,
+            ),
+        ] = &transfers[..]
+        else {
+            panic!("expected one resumed Transfer");
+        };
+        assert_eq!(*delivery_id, Some(1));
+        assert_eq!(*resent_tag, delivery_tag);
+        assert_eq!(*resent_payload, payload);
+    }
+
+    #[test]
+    fn transfers_beyond_the_incoming_window_end_the_session() {
+        let config = Config {
+            incoming_window: 4,
+            ..Config::default()
+        };
+        let mut session = Session::new(0, 0, 0, 100, 7, 512, &config);
+        session.handle(attach(0, Role::Sender), vec![]).unwrap();
+        assert!(session.handle(transfer(0), vec![]).unwrap().is_empty());
+        // Half of the window used up, the peer gets all of it again.
+        let replies = session.handle(transfer(1), vec![]).unwrap();
+        assert!(matches!(
+            replies[..],
+            [Performative::Flow {
+                next_incoming_id: Some(2),
+                incoming_window: 4,
+                handle: None,
+                ..
+            }]
+        ));
+
+        let config = Config {
+            incoming_window: 0,
+            ..Config::default()
+        };
+        let mut session = Session::new(0, 0, 0, 100, 7, 512, &config);
+        session.handle(attach(0, Role::Sender), vec![]).unwrap();
+        let error = session.handle(transfer(0), vec![]).unwrap_err();
+        assert_eq!(error.condition, condition::SESSION_WINDOW_VIOLATION);
+    }
+
+    #[test]
+    fn frames_wait_for_the_remote_incoming_window() {
+        let mut session = session();
+        session.handle(attach(0, Role::Receiver), vec![]).unwrap();
+        // The peer takes in a single Transfer to begin with.
+        let mut credit = flow(0, 0, 10);
+        if let Performative::Flow {
+            incoming_window, ..
+        } = &mut credit
+        {
+            *incoming_window = 1;
+        }
+        session.handle(credit, vec![]).unwrap();
+        let payload = vec![0; 1200];
+        let transfers = session.transfer(0, message("a"), &payload).unwrap();
+        assert_eq!(transfers.len(), 1);
+        assert!(!session.can_send(0));
+        assert!(session.take_transfers().is_empty());
+
+        let session_flow = Performative::Flow {
+            next_incoming_id: Some(1),
+            incoming_window: 5,
+            next_outgoing_id: 0,
+            outgoing_window: 100,
+            handle: None,
+            delivery_count: None,
+            link_credit: None,
+            available: None,
+            drain: false,
+            echo: false,
+            properties: HashMap::new(),
+        };
+        assert!(session.handle(session_flow, vec![]).unwrap().is_empty());
+        let transfers = session.take_transfers();
+        assert_eq!(transfers.len(), 2);
+        assert!(matches!(
+            transfers[1].0,
+            Performative::Transfer { more: false, .. }
+        ));
+        assert_eq!(session.remote_incoming_window, 3);
+        assert!(session.can_send(0));
+    }
+
+    #[test]
+    fn messages_sent_settled_or_waiting_for_the_outcome() {
+        let sender = |mode| {
+            let mut session = session();
+            let attach = settling(attach(0, Role::Receiver), mode, ReceiverSettleMode::First);
+            session.handle(attach, vec![]).unwrap();
+            session.handle(flow(0, 0, 10), vec![]).unwrap();
+            session.take_events();
+            session
+        };
+        let disposition = |first, settled, state| Performative::Disposition {
+            role: Role::Receiver.as_bool(),
+            first,
+            last: None,
+            settled,
+            state,
+            batchable: false,
+        };
+
+        // At most once, the message is done with as soon as it is sent.
+        let mut session = sender(SenderSettleMode::Settled);
+        let transfers = session.transfer(0, message("a"), b"a").unwrap();
+        assert!(matches!(
+            transfers[..],
+            [(
+                Performative::Transfer {
+                    settled: Some(true),
+                    ..
+                },
+                _
+            )]
+        ));
+        let events = session.take_events();
+        assert!(matches!(
+            events[..],
+            [LinkEvent::Outcome {
+                state: DeliveryState::Accepted,
+                ..
+            }]
+        ));
+        let replies = session
+            .handle(disposition(0, false, Some(DeliveryState::Accepted)), vec![])
+            .unwrap();
+        assert!(replies.is_empty());
+
+        // At least once, it waits for the peer's outcome, which we settle.
+        let mut session = sender(SenderSettleMode::Unsettled);
+        session.transfer(0, message("a"), b"a").unwrap();
+        session.transfer(0, message("b"), b"b").unwrap();
+        assert!(session.take_events().is_empty());
+        let replies = session
+            .handle(disposition(0, false, Some(DeliveryState::Accepted)), vec![])
+            .unwrap();
+        assert!(matches!(
+            replies[..],
+            [Performative::Disposition {
+                role: false,
+                first: 0,
+                settled: true,
+                state: Some(DeliveryState::Accepted),
+                ..
+            }]
+        ));
+        // Settled without an outcome, the message gets the default one of its source.
+        let replies = session.handle(disposition(1, true, None), vec![]).unwrap();
+        assert!(replies.is_empty());
+        let events = session.take_events();
+        assert!(matches!(
+            events[..],
+            [
+                LinkEvent::Outcome {
+                    state: DeliveryState::Accepted,
+                    ..
+                },
+                LinkEvent::Outcome {
+                    state: DeliveryState::Modified {
+                        delivery_failed: true,
+                        ..
+                    },
+                    ..
+                }
+            ]
+        ));
+    }
+
+    #[test]
+    fn receivers_settle_first_or_once_the_sender_has() {
+        let receiver = |mode| {
+            let mut session = session();
+            let attach = settling(attach(0, Role::Sender), SenderSettleMode::Unsettled, mode);
+            session.handle(attach, vec![]).unwrap();
+            session.handle(transfer(0), vec![]).unwrap();
+            session
+        };
+        let tag = 0u32.to_be_bytes();
+
+        let mut session = receiver(ReceiverSettleMode::First);
+        assert!(matches!(
+            session.take_events()[..],
+            [
+                LinkEvent::TargetAttached { .. },
+                LinkEvent::Delivery {
+                    delivery_id: 0,
+                    settled: false,
+                    ..
+                }
+            ]
+        ));
+        assert!(matches!(
+            session.settle(0, DeliveryState::Accepted),
+            Some(Performative::Disposition {
+                role: true,
+                first: 0,
+                settled: true,
+                ..
+            })
+        ));
+        assert!(session.links[&0].known_outcome(&tag).is_none());
+        assert!(session.settle(0, DeliveryState::Accepted).is_none());
+        // A Transfer may not ask for the second mode on a link settling first.
+        let mut second = transfer(1);
+        if let Performative::Transfer {
+            rcv_settle_mode, ..
+        } = &mut second
+        {
+            *rcv_settle_mode = Some(ReceiverSettleMode::Second.as_ubyte());
+        }
+        let replies = session.handle(second, vec![]).unwrap();
+        assert!(matches!(
+            &replies[..],
+            [Performative::Detach {
+                closed: true,
+                error: Some(error),
+                ..
+            }] if error.condition == condition::INVALID_FIELD
+        ));
+
+        // Exactly once, our outcome is kept until the sender settles the delivery.
+        let mut session = receiver(ReceiverSettleMode::Second);
+        assert!(matches!(
+            session.settle(0, DeliveryState::Accepted),
+            Some(Performative::Disposition { settled: false, .. })
+        ));
+        assert!(matches!(
+            session.links[&0].known_outcome(&tag),
+            Some(DeliveryState::Accepted)
+        ));
+        let settled = Performative::Disposition {
+            role: Role::Sender.as_bool(),
+            first: 0,
+            last: None,
+            settled: true,
+            state: Some(DeliveryState::Accepted),
+            batchable: false,
+        };
+        assert!(session.handle(settled, vec![]).unwrap().is_empty());
+        assert!(session.links[&0].known_outcome(&tag).is_none());
+        assert!(session.incoming_unsettled.is_empty());
+    }
+
+    #[test]
+    fn messages_are_split_at_the_max_frame_size() {
+        let mut session = session();
+        session.handle(attach(0, Role::Receiver), vec![]).unwrap();
+        session.handle(flow(0, 0, 10), vec![]).unwrap();
+        let payload: Vec<u8> = (0..1200).map(|octet| octet as u8).collect();
+        let transfers = session.transfer(0, message("a"), &payload).unwrap();
+        assert_eq!(transfers.len(), 3);
+        for (index, (transfer, chunk)) in transfers.iter().enumerate() {
+            let Performative::Transfer {
+                delivery_id, more, ..
+            } = transfer
+            else {
+                panic!("expected a Transfer");
+            };
+            assert_eq!(*delivery_id, Some(0));
+            assert_eq!(*more, index < 2);
+            let frame = transfer.to_frame(0, chunk).unwrap();
+            assert!(8 + frame.frame_body.len() <= 512);
+        }
+        let reassembled: Vec<u8> = transfers.into_iter().flat_map(|(_, chunk)| chunk).collect();
+        assert_eq!(reassembled, payload);
+
+        // An empty payload still takes a Transfer.
+        let transfers = session.transfer(0, message("b"), &[]).unwrap();
+        assert!(matches!(
+            transfers[..],
+            [(
+                Performative::Transfer {
+                    delivery_id: Some(1),
+                    more: false,
+                    ..
+                },
+                _
+            )]
+        ));
+    }
+}
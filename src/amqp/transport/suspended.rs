@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::amqp::messaging::message::Message;
+use crate::amqp::messaging::terminus::TerminusExpiryPolicy;
+use crate::amqp::transport::link::{Link, Role};
+use crate::frame_bus::ConnectionId;
+
+// A suspended link, along with where it was last attached, for its terminus to
+// expire with the session or connection if its expiry policy says so.
+struct Suspended {
+    link: Link,
+    connection_id: ConnectionId,
+    channel: u16,
+    // When the terminus expires, once the event of its expiry policy happened
+    // and its timeout started.
+    expires_at: Option<Instant>,
+}
+
+// A link whose terminus expired, with the messages sent on it the peer never settled,
+// if any.
+pub struct Expired {
+    pub container_id: String,
+    pub name: String,
+    pub address: String,
+    pub unsettled: Vec<Message>,
+}
+
+// Links detached with closed=false until the peer attaches them again or their
+// terminus expires (see 2.6.3 and 3.5.6 Terminus Expiry Policy). A link belongs to
+// the pair of containers rather than to a session, so it may be resumed on another
+// connection, by the container-id of the peer, the link name and our role.
+#[derive(Default)]
+pub struct SuspendedLinks {
+    links: HashMap<(String, String, Role), Suspended>,
+    // The links that expired, for their nodes to take back the messages the peer
+    // never settled and to know the links are gone.
+    expired: Vec<Expired>,
+}
+
+impl SuspendedLinks {
+    pub fn suspend(
+        &mut self,
+        container_id: &str,
+        connection_id: ConnectionId,
+        channel: u16,
+        link: Link,
+        now: Instant,
+    ) {
+        let key = (
+            container_id.to_string(),
+            link.name().to_string(),
+            link.role(),
+        );
+        // Detaching the link is the expiry event of link-detach, which only gets here
+        // with a timeout.
+        let (expiry_policy, timeout) = link.expiry();
+        let suspended = Suspended {
+            link,
+            connection_id,
+            channel,
+            expires_at: (expiry_policy == TerminusExpiryPolicy::LinkDetach)
+                .then(|| now + Duration::from_secs(timeout as u64)),
+        };
+        // A link of the same name can only be suspended once, so the older one is gone.
+        if let Some(replaced) = self.links.insert(key.clone(), suspended) {
+            self.expire_link(key.0, replaced.link);
+        }
+    }
+
+    pub fn resume(&mut self, container_id: &str, name: &str, role: Role) -> Option<Link> {
+        self.links
+            .remove(&(container_id.to_string(), name.to_string(), role))
+            .map(|suspended| suspended.link)
+    }
+
+    pub fn session_ended(&mut self, connection_id: ConnectionId, channel: u16, now: Instant) {
+        self.expiry_event(now, |suspended, expiry_policy| {
+            expiry_policy == TerminusExpiryPolicy::SessionEnd
+                && suspended.connection_id == connection_id
+                && suspended.channel == channel
+        });
+    }
+
+    pub fn connection_closed(&mut self, connection_id: ConnectionId, now: Instant) {
+        self.expiry_event(now, |suspended, expiry_policy| {
+            matches!(
+                expiry_policy,
+                TerminusExpiryPolicy::SessionEnd | TerminusExpiryPolicy::ConnectionClose
+            ) && suspended.connection_id == connection_id
+        });
+    }
+
+    // Drops the links whose terminus timed out.
+    pub fn expire(&mut self, now: Instant) {
+        let keys: Vec<(String, String, Role)> = self
+            .links
+            .iter()
+            .filter(|(_, suspended)| suspended.expires_at.is_some_and(|at| at <= now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            if let Some(suspended) = self.links.remove(&key) {
+                self.expire_link(key.0, suspended.link);
+            }
+        }
+    }
+
+    pub fn take_expired(&mut self) -> Vec<Expired> {
+        std::mem::take(&mut self.expired)
+    }
+
+    // Starts the timeout of the links whose expiry policy names the event, dropping
+    // those without one right away.
+    fn expiry_event(
+        &mut self,
+        now: Instant,
+        applies: impl Fn(&Suspended, TerminusExpiryPolicy) -> bool,
+    ) {
+        for suspended in self.links.values_mut() {
+            let (expiry_policy, timeout) = suspended.link.expiry();
+            if suspended.expires_at.is_none() && applies(suspended, expiry_policy) {
+                suspended.expires_at = Some(now + Duration::from_secs(timeout as u64));
+            }
+        }
+        self.expire(now);
+    }
+
+    fn expire_link(&mut self, container_id: String, mut link: Link) {
+        let unsettled = link.take_held();
+        if let Some(address) = link.address() {
+            self.expired.push(Expired {
+                container_id,
+                name: link.name().to_string(),
+                address,
+                unsettled,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::amqp::messaging::terminus::Source;
+    use crate::amqp::transport::performative::Performative;
+
+    // Our sending end of a link from the queue, holding a message the peer never settled.
+    fn link(name: &str, expiry_policy: TerminusExpiryPolicy, timeout: u32) -> Link {
+        let attach = Performative::Attach {
+            name: name.to_string(),
+            handle: 0,
+            role: Role::Receiver.as_bool(),
+            snd_settle_mode: 0,
+            rcv_settle_mode: 0,
+            source: Some(Box::new(Source {
+                address: Some(String::from("queue")),
+                expiry_policy,
+                timeout,
+                ..Source::default()
+            })),
+            target: None,
+            unsettled: HashMap::new(),
+            incomplete_unsettled: false,
+            initial_delivery_count: None,
+            max_message_size: None,
+            offered_capabilities: vec![],
+            desired_capabilities: vec![],
+            properties: HashMap::new(),
+        };
+        let mut link = Link::attach(0, attach, 10, 1024).unwrap();
+        link.suspend(vec![(vec![0], Message::default())]);
+        link
+    }
+
+    fn expired_names(links: &mut SuspendedLinks) -> Vec<String> {
+        let mut names: Vec<String> = links
+            .take_expired()
+            .into_iter()
+            .map(|expired| {
+                assert_eq!(expired.address, "queue");
+                assert_eq!(expired.unsettled.len(), 1);
+                expired.name
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn link_detach_expires_after_its_timeout() {
+        let now = Instant::now();
+        let mut links = SuspendedLinks::default();
+        links.suspend(
+            "client",
+            1,
+            0,
+            link("a", TerminusExpiryPolicy::LinkDetach, 5),
+            now,
+        );
+        links.expire(now + Duration::from_secs(4));
+        assert!(expired_names(&mut links).is_empty());
+        links.expire(now + Duration::from_secs(5));
+        assert_eq!(expired_names(&mut links), ["a"]);
+        assert!(links.resume("client", "a", Role::Sender).is_none());
+    }
+
+    #[test]
+    fn session_end_expires_with_its_session_or_connection() {
+        let now = Instant::now();
+        let mut links = SuspendedLinks::default();
+        for (name, connection_id, channel) in [("a", 1, 0), ("b", 1, 1), ("c", 2, 0)] {
+            let link = link(name, TerminusExpiryPolicy::SessionEnd, 0);
+            links.suspend("client", connection_id, channel, link, now);
+        }
+        links.session_ended(1, 0, now);
+        assert_eq!(expired_names(&mut links), ["a"]);
+        links.connection_closed(1, now);
+        assert_eq!(expired_names(&mut links), ["b"]);
+        assert!(links.resume("client", "c", Role::Sender).is_some());
+    }
+
+    #[test]
+    fn connection_close_outlives_the_session() {
+        let now = Instant::now();
+        let mut links = SuspendedLinks::default();
+        let link = link("a", TerminusExpiryPolicy::ConnectionClose, 10);
+        links.suspend("client", 1, 0, link, now);
+        links.session_ended(1, 0, now);
+        links.expire(now + Duration::from_secs(60));
+        assert!(expired_names(&mut links).is_empty());
+        // The timeout starts once the connection is closed.
+        links.connection_closed(1, now + Duration::from_secs(60));
+        assert!(expired_names(&mut links).is_empty());
+        links.expire(now + Duration::from_secs(70));
+        assert_eq!(expired_names(&mut links), ["a"]);
+    }
+
+    #[test]
+    fn never_expires_and_resumes_by_container_name_and_role() {
+        let now = Instant::now();
+        let mut links = SuspendedLinks::default();
+        links.suspend(
+            "client",
+            1,
+            0,
+            link("a", TerminusExpiryPolicy::Never, 0),
+            now,
+        );
+        links.connection_closed(1, now);
+        links.expire(now + Duration::from_secs(3600));
+        assert!(expired_names(&mut links).is_empty());
+        assert!(links.resume("other", "a", Role::Sender).is_none());
+        assert!(links.resume("client", "a", Role::Receiver).is_none());
+        let mut link = links.resume("client", "a", Role::Sender).unwrap();
+        assert_eq!(link.take_held().len(), 1);
+    }
+
+    #[test]
+    fn suspending_a_link_again_expires_the_older_one() {
+        let now = Instant::now();
+        let mut links = SuspendedLinks::default();
+        links.suspend(
+            "client",
+            1,
+            0,
+            link("a", TerminusExpiryPolicy::Never, 0),
+            now,
+        );
+        links.suspend(
+            "client",
+            2,
+            0,
+            link("a", TerminusExpiryPolicy::Never, 0),
+            now,
+        );
+        assert_eq!(expired_names(&mut links), ["a"]);
+        assert!(links.resume("client", "a", Role::Sender).is_some());
+    }
+}
@@ -0,0 +1,174 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::RootCertStore;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::server::TlsStream;
+
+use crate::config::Config;
+
+// The byte stream a connection runs over: the accepted socket, or the TLS session
+// established on top of it (see 5.2 TLS).
+pub enum Transport {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Transport {
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Transport::Tls(_))
+    }
+
+    // The DER-encoded certificate the client authenticated the TLS session with, if any.
+    pub fn client_certificate(&self) -> Option<&[u8]> {
+        match self {
+            Transport::Plain(_) => None,
+            Transport::Tls(stream) => stream
+                .get_ref()
+                .1
+                .peer_certificates()?
+                .first()
+                .map(|certificate| certificate.as_ref()),
+        }
+    }
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Transport::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Transport::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Transport::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Transport::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+// TLS is only available if a certificate and key are configured.
+pub struct Tls {
+    acceptor: Option<TlsAcceptor>,
+    // Whether clients have to establish TLS before anything else.
+    pub required: bool,
+}
+
+impl Tls {
+    pub fn from_config(config: &Config) -> io::Result<Self> {
+        // Client certificates can't be required without the CAs to verify them against.
+        if config.tls_require_client_cert && config.tls_client_ca_file.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "UEXRS_TLS_REQUIRE_CLIENT_CERT needs UEXRS_TLS_CLIENT_CA_FILE",
+            ));
+        }
+        let acceptor = match (&config.tls_cert_file, &config.tls_key_file) {
+            (Some(cert_file), Some(key_file)) => Some(TlsAcceptor::from(Arc::new(server_config(
+                config, cert_file, key_file,
+            )?))),
+            (None, None) => None,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "UEXRS_TLS_CERT_FILE and UEXRS_TLS_KEY_FILE have to be set together",
+                ));
+            }
+        };
+        Ok(Self {
+            acceptor,
+            required: config.require_tls,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.acceptor.is_some()
+    }
+
+    // Runs the TLS handshake on a plain stream.
+    pub async fn accept(&self, stream: Transport) -> Result<Transport, &'static str> {
+        let (Some(acceptor), Transport::Plain(stream)) = (&self.acceptor, stream) else {
+            return Err("TLS is not available on this stream");
+        };
+        let stream = acceptor
+            .accept(stream)
+            .await
+            .map_err(|_| "TLS handshake failed")?;
+        Ok(Transport::Tls(Box::new(stream)))
+    }
+}
+
+fn server_config(config: &Config, cert_file: &str, key_file: &str) -> io::Result<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| pem_error(cert_file, err))?;
+    let key = PrivateKeyDer::from_pem_file(key_file).map_err(|err| pem_error(key_file, err))?;
+
+    let builder = ServerConfig::builder();
+    let builder = match config.tls_client_ca_file {
+        Some(ref ca_file) => {
+            let mut roots = RootCertStore::empty();
+            for cert in
+                CertificateDer::pem_file_iter(ca_file).map_err(|err| pem_error(ca_file, err))?
+            {
+                roots
+                    .add(cert.map_err(|err| pem_error(ca_file, err))?)
+                    .map_err(io::Error::other)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            // Without a required client certificate, clients may still present one
+            // and have it verified.
+            let verifier = if config.tls_require_client_cert {
+                verifier.build()
+            } else {
+                verifier.allow_unauthenticated().build()
+            };
+            builder.with_client_cert_verifier(verifier.map_err(io::Error::other)?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    builder
+        .with_single_cert(certs, key)
+        .map_err(io::Error::other)
+}
+
+fn pem_error(path: &str, err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Could not read PEM file {}: {}", path, err),
+    )
+}
@@ -19,18 +19,9 @@ impl Constructor {
     ) -> Result<Self, &'static str> {
         match code {
             FormatCode::NonPrimitive => {
-                let mut read_buf = [0u8; 2];
-                buf_reader
-                    .read_exact(&mut read_buf)
-                    .await
-                    .unwrap_or_else(|_| 0);
-                let descriptor_code = FormatCode::try_from(u16::from_be_bytes(read_buf))?;
+                let descriptor_code = FormatCode::read(buf_reader).await?;
                 let descriptor = Box::pin(Constructor::new(descriptor_code, buf_reader)).await?;
-                buf_reader
-                    .read_exact(&mut read_buf)
-                    .await
-                    .unwrap_or_else(|_| 0);
-                let primitive_code = FormatCode::try_from(u16::from_be_bytes(read_buf))?;
+                let primitive_code = FormatCode::read(buf_reader).await?;
                 match primitive_code {
                     FormatCode::NonPrimitive => {
                         Err("Non-primitive used as a described constructor primitive")
@@ -64,24 +55,36 @@ async fn read_primitive(
         FormatCode::Null => Ok(Primitive::Null),
         FormatCode::Boolean => {
             let mut buf = [0u8; 1];
-            buf_reader.read_exact(&mut buf).await.unwrap_or_else(|_| 0);
+            buf_reader
+                .read_exact(&mut buf)
+                .await
+                .map_err(|_| "Unexpected end of data")?;
             Ok(Primitive::Boolean(buf[0] != 0))
         }
         FormatCode::BooleanTrue => Ok(Primitive::Boolean(true)),
         FormatCode::BooleanFalse => Ok(Primitive::Boolean(false)),
         FormatCode::Ubyte => {
             let mut buf = [0u8; 1];
-            buf_reader.read_exact(&mut buf).await.unwrap_or_else(|_| 0);
+            buf_reader
+                .read_exact(&mut buf)
+                .await
+                .map_err(|_| "Unexpected end of data")?;
             Ok(Primitive::UByte(buf[0]))
         }
         FormatCode::Ushort => {
             let mut buf = [0u8; 2];
-            buf_reader.read_exact(&mut buf).await.unwrap_or_else(|_| 0);
+            buf_reader
+                .read_exact(&mut buf)
+                .await
+                .map_err(|_| "Unexpected end of data")?;
             Ok(Primitive::UShort(u16::from_be_bytes(buf)))
         }
         FormatCode::Uint => {
             let mut buf = [0u8; 4];
-            buf_reader.read_exact(&mut buf).await.unwrap_or_else(|_| 0);
+            buf_reader
+                .read_exact(&mut buf)
+                .await
+                .map_err(|_| "Unexpected end of data")?;
             Ok(Primitive::UInt(u32::from_be_bytes(buf)))
         }
         FormatCode::Smalluint => {
@@ -89,13 +92,16 @@ async fn read_primitive(
             buf_reader
                 .read_exact(&mut buf[3..])
                 .await
-                .unwrap_or_else(|_| 0);
+                .map_err(|_| "Unexpected end of data")?;
             Ok(Primitive::UInt(u32::from_be_bytes(buf)))
         }
         FormatCode::Uint0 => Ok(Primitive::UInt(0)),
         FormatCode::Ulong => {
             let mut buf = [0u8; 8];
-            buf_reader.read_exact(&mut buf).await.unwrap_or_else(|_| 0);
+            buf_reader
+                .read_exact(&mut buf)
+                .await
+                .map_err(|_| "Unexpected end of data")?;
             Ok(Primitive::ULong(u64::from_be_bytes(buf)))
         }
         FormatCode::Smallulong => {
@@ -103,88 +109,126 @@ async fn read_primitive(
             buf_reader
                 .read_exact(&mut buf[7..])
                 .await
-                .unwrap_or_else(|_| 0);
+                .map_err(|_| "Unexpected end of data")?;
             Ok(Primitive::ULong(u64::from_be_bytes(buf)))
         }
         FormatCode::Ulong0 => Ok(Primitive::ULong(0)),
         FormatCode::Byte => {
             let mut buf = [0u8; 1];
-            buf_reader.read_exact(&mut buf).await.unwrap_or_else(|_| 0);
+            buf_reader
+                .read_exact(&mut buf)
+                .await
+                .map_err(|_| "Unexpected end of data")?;
             Ok(Primitive::Byte(i8::from_be_bytes(buf)))
         }
         FormatCode::Short => {
             let mut buf = [0u8; 2];
-            buf_reader.read_exact(&mut buf).await.unwrap_or_else(|_| 0);
+            buf_reader
+                .read_exact(&mut buf)
+                .await
+                .map_err(|_| "Unexpected end of data")?;
             Ok(Primitive::Short(i16::from_be_bytes(buf)))
         }
         FormatCode::Int => {
             let mut buf = [0u8; 4];
-            buf_reader.read_exact(&mut buf).await.unwrap_or_else(|_| 0);
+            buf_reader
+                .read_exact(&mut buf)
+                .await
+                .map_err(|_| "Unexpected end of data")?;
             Ok(Primitive::Int(i32::from_be_bytes(buf)))
         }
         FormatCode::Smallint => {
-            let mut buf = [0u8; 4];
+            let mut buf = [0u8; 1];
             buf_reader
-                .read_exact(&mut buf[3..])
+                .read_exact(&mut buf)
                 .await
-                .unwrap_or_else(|_| 0);
-            Ok(Primitive::Int(i32::from_be_bytes(buf)))
+                .map_err(|_| "Unexpected end of data")?;
+            // The value is signed, so it has to be sign-extended rather than zero-padded.
+            Ok(Primitive::Int(i8::from_be_bytes(buf) as i32))
         }
         FormatCode::Long => {
             let mut buf = [0u8; 8];
-            buf_reader.read_exact(&mut buf).await.unwrap_or_else(|_| 0);
+            buf_reader
+                .read_exact(&mut buf)
+                .await
+                .map_err(|_| "Unexpected end of data")?;
             Ok(Primitive::Long(i64::from_be_bytes(buf)))
         }
         FormatCode::Smalllong => {
-            let mut buf = [0u8; 8];
+            let mut buf = [0u8; 1];
             buf_reader
-                .read_exact(&mut buf[7..])
+                .read_exact(&mut buf)
                 .await
-                .unwrap_or_else(|_| 0);
-            Ok(Primitive::Long(i64::from_be_bytes(buf)))
+                .map_err(|_| "Unexpected end of data")?;
+            // The value is signed, so it has to be sign-extended rather than zero-padded.
+            Ok(Primitive::Long(i8::from_be_bytes(buf) as i64))
         }
         FormatCode::Float => {
             let mut buf = [0u8; 4];
-            buf_reader.read_exact(&mut buf).await.unwrap_or_else(|_| 0);
+            buf_reader
+                .read_exact(&mut buf)
+                .await
+                .map_err(|_| "Unexpected end of data")?;
             Ok(Primitive::Float(InnerFloat {
                 value: f32::from_be_bytes(buf),
             }))
         }
         FormatCode::Double => {
             let mut buf = [0u8; 8];
-            buf_reader.read_exact(&mut buf).await.unwrap_or_else(|_| 0);
+            buf_reader
+                .read_exact(&mut buf)
+                .await
+                .map_err(|_| "Unexpected end of data")?;
             Ok(Primitive::Double(InnerDouble {
                 value: f64::from_be_bytes(buf),
             }))
         }
         FormatCode::Decimal32 => {
             let mut buf = [0u8; 4];
-            buf_reader.read_exact(&mut buf).await.unwrap_or_else(|_| 0);
+            buf_reader
+                .read_exact(&mut buf)
+                .await
+                .map_err(|_| "Unexpected end of data")?;
             Ok(Primitive::Decimal32(buf))
         }
         FormatCode::Decimal64 => {
             let mut buf = [0u8; 8];
-            buf_reader.read_exact(&mut buf).await.unwrap_or_else(|_| 0);
+            buf_reader
+                .read_exact(&mut buf)
+                .await
+                .map_err(|_| "Unexpected end of data")?;
             Ok(Primitive::Decimal64(buf))
         }
         FormatCode::Decimal128 => {
             let mut buf = [0u8; 16];
-            buf_reader.read_exact(&mut buf).await.unwrap_or_else(|_| 0);
+            buf_reader
+                .read_exact(&mut buf)
+                .await
+                .map_err(|_| "Unexpected end of data")?;
             Ok(Primitive::Decimal128(buf))
         }
         FormatCode::Char => {
             let mut buf = [0u8; 4];
-            buf_reader.read_exact(&mut buf).await.unwrap_or_else(|_| 0);
+            buf_reader
+                .read_exact(&mut buf)
+                .await
+                .map_err(|_| "Unexpected end of data")?;
             Ok(Primitive::Char(buf))
         }
         FormatCode::Timestamp => {
             let mut buf = [0u8; 8];
-            buf_reader.read_exact(&mut buf).await.unwrap_or_else(|_| 0);
+            buf_reader
+                .read_exact(&mut buf)
+                .await
+                .map_err(|_| "Unexpected end of data")?;
             Ok(Primitive::Timestamp(i64::from_be_bytes(buf)))
         }
         FormatCode::Uuid => {
             let mut buf = [0u8; 16];
-            buf_reader.read_exact(&mut buf).await.unwrap_or_else(|_| 0);
+            buf_reader
+                .read_exact(&mut buf)
+                .await
+                .map_err(|_| "Unexpected end of data")?;
             Ok(Primitive::UUID(buf))
         }
         FormatCode::OneByteBinary => {
@@ -192,7 +236,7 @@ async fn read_primitive(
             buf_reader
                 .read_exact(&mut read_buf)
                 .await
-                .unwrap_or_else(|_| 0);
+                .map_err(|_| "Unexpected end of data")?;
             let len = read_buf[0];
 
             let mut buf = Vec::<u8>::with_capacity(len as usize);
@@ -201,7 +245,7 @@ async fn read_primitive(
                 buf_reader
                     .read_exact(&mut read_buf)
                     .await
-                    .unwrap_or_else(|_| 0);
+                    .map_err(|_| "Unexpected end of data")?;
                 buf.push(read_buf[0]);
             }
             Ok(Primitive::Binary(buf))
@@ -211,7 +255,7 @@ async fn read_primitive(
             buf_reader
                 .read_exact(&mut read_buf)
                 .await
-                .unwrap_or_else(|_| 0);
+                .map_err(|_| "Unexpected end of data")?;
             let len = u32::from_be_bytes(read_buf);
 
             let mut buf = Vec::<u8>::with_capacity(len as usize);
@@ -220,7 +264,7 @@ async fn read_primitive(
                 buf_reader
                     .read_exact(&mut read_buf)
                     .await
-                    .unwrap_or_else(|_| 0);
+                    .map_err(|_| "Unexpected end of data")?;
                 buf.push(read_buf[0]);
             }
             Ok(Primitive::Binary(buf))
@@ -230,7 +274,7 @@ async fn read_primitive(
             buf_reader
                 .read_exact(&mut read_buf)
                 .await
-                .unwrap_or_else(|_| 0);
+                .map_err(|_| "Unexpected end of data")?;
             let len = read_buf[0];
 
             let mut buf = Vec::<u8>::with_capacity(len as usize);
@@ -239,14 +283,12 @@ async fn read_primitive(
                 buf_reader
                     .read_exact(&mut read_buf)
                     .await
-                    .unwrap_or_else(|_| 0);
+                    .map_err(|_| "Unexpected end of data")?;
                 buf.push(read_buf[0]);
             }
             match String::from_utf8(buf) {
                 Ok(value) => Ok(Primitive::String(value)),
-                Err(_) => {
-                    return Err("Could not decode 1-byte string (UTF-8 error)");
-                }
+                Err(_) => Err("Could not decode 1-byte string (UTF-8 error)"),
             }
         }
         FormatCode::FourByteString => {
@@ -254,7 +296,7 @@ async fn read_primitive(
             buf_reader
                 .read_exact(&mut read_buf)
                 .await
-                .unwrap_or_else(|_| 0);
+                .map_err(|_| "Unexpected end of data")?;
             let len = u32::from_be_bytes(read_buf);
 
             let mut buf = Vec::<u8>::with_capacity(len as usize);
@@ -263,14 +305,12 @@ async fn read_primitive(
                 buf_reader
                     .read_exact(&mut read_buf)
                     .await
-                    .unwrap_or_else(|_| 0);
+                    .map_err(|_| "Unexpected end of data")?;
                 buf.push(read_buf[0]);
             }
             match String::from_utf8(buf) {
                 Ok(value) => Ok(Primitive::String(value)),
-                Err(_) => {
-                    return Err("Could not decode 4-byte string (UTF-8 error)");
-                }
+                Err(_) => Err("Could not decode 4-byte string (UTF-8 error)"),
             }
         }
         FormatCode::OneByteSymbol => {
@@ -278,7 +318,7 @@ async fn read_primitive(
             buf_reader
                 .read_exact(&mut read_buf)
                 .await
-                .unwrap_or_else(|_| 0);
+                .map_err(|_| "Unexpected end of data")?;
             let len = read_buf[0];
 
             let mut buf = Vec::<u8>::with_capacity(len as usize);
@@ -287,7 +327,7 @@ async fn read_primitive(
                 buf_reader
                     .read_exact(&mut read_buf)
                     .await
-                    .unwrap_or_else(|_| 0);
+                    .map_err(|_| "Unexpected end of data")?;
                 buf.push(read_buf[0]);
             }
             Ok(Primitive::Symbol(buf))
@@ -297,7 +337,7 @@ async fn read_primitive(
             buf_reader
                 .read_exact(&mut read_buf)
                 .await
-                .unwrap_or_else(|_| 0);
+                .map_err(|_| "Unexpected end of data")?;
             let len = u32::from_be_bytes(read_buf);
 
             let mut buf = Vec::<u8>::with_capacity(len as usize);
@@ -306,7 +346,7 @@ async fn read_primitive(
                 buf_reader
                     .read_exact(&mut read_buf)
                     .await
-                    .unwrap_or_else(|_| 0);
+                    .map_err(|_| "Unexpected end of data")?;
                 buf.push(read_buf[0]);
             }
             Ok(Primitive::Symbol(buf))
@@ -317,19 +357,14 @@ async fn read_primitive(
             buf_reader
                 .read_exact(&mut read_buf)
                 .await
-                .unwrap_or_else(|_| 0);
+                .map_err(|_| "Unexpected end of data")?;
             let _size = read_buf[0];
             let count = read_buf[1];
 
             let len = count as usize;
             let mut buf = Vec::with_capacity(len);
             for _ in 0..len {
-                let mut read_buf = [0u8; 2];
-                buf_reader
-                    .read_exact(&mut read_buf)
-                    .await
-                    .unwrap_or_else(|_| 0);
-                let elt_fcode = FormatCode::try_from(u16::from_be_bytes(read_buf))?;
+                let elt_fcode = FormatCode::read(buf_reader).await?;
                 let elt = Box::pin(Constructor::new(elt_fcode, buf_reader)).await?;
                 buf.push(elt);
             }
@@ -340,149 +375,665 @@ async fn read_primitive(
             buf_reader
                 .read_exact(&mut read_buf)
                 .await
-                .unwrap_or_else(|_| 0);
+                .map_err(|_| "Unexpected end of data")?;
             let _size = u32::from_be_bytes([read_buf[0], read_buf[1], read_buf[2], read_buf[3]]);
             let count = u32::from_be_bytes([read_buf[4], read_buf[5], read_buf[6], read_buf[7]]);
 
             let len = count as usize;
             let mut buf = Vec::with_capacity(len);
             for _ in 0..len {
-                let mut read_buf = [0u8; 2];
-                buf_reader
-                    .read_exact(&mut read_buf)
-                    .await
-                    .unwrap_or_else(|_| 0);
-                let elt_fcode = FormatCode::try_from(u16::from_be_bytes(read_buf))?;
+                let elt_fcode = FormatCode::read(buf_reader).await?;
                 let elt = Box::pin(Constructor::new(elt_fcode, buf_reader)).await?;
                 buf.push(elt);
             }
             Ok(Primitive::List(buf))
         }
         FormatCode::Map8 => {
-            let mut read_buf = [0u8; 1];
+            let mut read_buf = [0u8; 2];
             buf_reader
                 .read_exact(&mut read_buf)
                 .await
-                .unwrap_or_else(|_| 0);
-            let len = read_buf[0];
-            if len % 2 != 0 {
-                return Err("Map8 length found to be odd");
+                .map_err(|_| "Unexpected end of data")?;
+            let _size = read_buf[0];
+            // The count is the number of elements, i.e. keys and values together.
+            let count = read_buf[1];
+            if count % 2 != 0 {
+                return Err("Map8 element count found to be odd");
             }
-            let mut buf = HashMap::with_capacity(len as usize);
+            let len = (count / 2) as usize;
+            let mut buf = HashMap::with_capacity(len);
             for _ in 0..len {
-                let mut read_buf = [0u8; 2];
-                buf_reader
-                    .read_exact(&mut read_buf)
-                    .await
-                    .unwrap_or_else(|_| 0);
-                let key_fcode = FormatCode::try_from(u16::from_be_bytes(read_buf))?;
+                let key_fcode = FormatCode::read(buf_reader).await?;
                 let key = Box::pin(Constructor::new(key_fcode, buf_reader)).await?;
-
-                buf_reader
-                    .read_exact(&mut read_buf)
-                    .await
-                    .unwrap_or_else(|_| 0);
-                let val_fcode = FormatCode::try_from(u16::from_be_bytes(read_buf))?;
+                let val_fcode = FormatCode::read(buf_reader).await?;
                 let val = Box::pin(Constructor::new(val_fcode, buf_reader)).await?;
                 buf.insert(key, val);
             }
             Ok(Primitive::Map(InnerMap { value: buf }))
         }
         FormatCode::Map32 => {
-            let mut read_buf = [0u8; 4];
+            let mut read_buf = [0u8; 8];
             buf_reader
                 .read_exact(&mut read_buf)
                 .await
-                .unwrap_or_else(|_| 0);
-            let len = u32::from_be_bytes(read_buf);
-            let mut buf = HashMap::with_capacity(len as usize);
+                .map_err(|_| "Unexpected end of data")?;
+            let _size = u32::from_be_bytes([read_buf[0], read_buf[1], read_buf[2], read_buf[3]]);
+            let count = u32::from_be_bytes([read_buf[4], read_buf[5], read_buf[6], read_buf[7]]);
+            if count % 2 != 0 {
+                return Err("Map32 element count found to be odd");
+            }
+            let len = (count / 2) as usize;
+            let mut buf = HashMap::with_capacity(len);
             for _ in 0..len {
-                let mut read_buf = [0u8; 2];
-                buf_reader
-                    .read_exact(&mut read_buf)
-                    .await
-                    .unwrap_or_else(|_| 0);
-                let key_fcode = FormatCode::try_from(u16::from_be_bytes(read_buf))?;
+                let key_fcode = FormatCode::read(buf_reader).await?;
                 let key = Box::pin(Constructor::new(key_fcode, buf_reader)).await?;
-
-                buf_reader
-                    .read_exact(&mut read_buf)
-                    .await
-                    .unwrap_or_else(|_| 0);
-                let val_fcode = FormatCode::try_from(u16::from_be_bytes(read_buf))?;
+                let val_fcode = FormatCode::read(buf_reader).await?;
                 let val = Box::pin(Constructor::new(val_fcode, buf_reader)).await?;
                 buf.insert(key, val);
             }
             Ok(Primitive::Map(InnerMap { value: buf }))
         }
         FormatCode::Array8 => {
-            let mut read_buf = [0u8; 1];
+            let mut read_buf = [0u8; 2];
             buf_reader
                 .read_exact(&mut read_buf)
                 .await
-                .unwrap_or_else(|_| 0);
+                .map_err(|_| "Unexpected end of data")?;
             let _size = read_buf[0];
+            let count = read_buf[1];
+            Box::pin(read_array_elements(buf_reader, count as usize)).await
+        }
+        FormatCode::Array32 => {
+            let mut read_buf = [0u8; 8];
             buf_reader
                 .read_exact(&mut read_buf)
                 .await
-                .unwrap_or_else(|_| 0);
-            let count = read_buf[0];
+                .map_err(|_| "Unexpected end of data")?;
+            let _size = u32::from_be_bytes([read_buf[0], read_buf[1], read_buf[2], read_buf[3]]);
+            let count = u32::from_be_bytes([read_buf[4], read_buf[5], read_buf[6], read_buf[7]]);
+            Box::pin(read_array_elements(buf_reader, count as usize)).await
+        }
+    }
+}
 
-            let read_buf = [0u8; 2];
-            let elt_constructor_code = FormatCode::try_from(u16::from_be_bytes(read_buf))?;
-            let len = count as usize;
+// All elements of an array share a single element constructor which is written once,
+// right before the first element's data (see 1.6 Encodings, array8/array32).
+async fn read_array_elements(
+    buf_reader: &mut (impl AsyncReadExt + Unpin),
+    len: usize,
+) -> Result<Primitive, &'static str> {
+    let mut elt_constructor_code = FormatCode::read(buf_reader).await?;
+    let descriptor = match elt_constructor_code {
+        FormatCode::NonPrimitive => {
+            let descriptor_code = FormatCode::read(buf_reader).await?;
+            let descriptor = Box::pin(Constructor::new(descriptor_code, buf_reader)).await?;
+            elt_constructor_code = FormatCode::read(buf_reader).await?;
+            if let FormatCode::NonPrimitive = elt_constructor_code {
+                return Err("Non-primitive used as a described constructor primitive");
+            }
+            Some(Box::pin(descriptor))
+        }
+        _ => None,
+    };
 
-            let mut buf = Vec::with_capacity(len);
-            let first_elt = Box::pin(Constructor::new(elt_constructor_code, buf_reader)).await?;
-            for _ in 0..len {
-                let next_elt = match first_elt {
-                    Constructor::PrimitiveType(_) => Constructor::PrimitiveType(
-                        Box::pin(read_primitive(buf_reader, elt_constructor_code)).await?,
-                    ),
-                    Constructor::DescribedType(ref descriptor, _) => {
-                        Constructor::DescribedType(
-                            descriptor.clone(),
-                            Box::pin(read_primitive(buf_reader, elt_constructor_code)).await?,
-                        )
-                    }
-                };
-                buf.push(next_elt);
+    let mut buf = Vec::with_capacity(len);
+    for _ in 0..len {
+        let primitive = Box::pin(read_primitive(buf_reader, elt_constructor_code)).await?;
+        let next_elt = match descriptor {
+            Some(ref descriptor) => Constructor::DescribedType(descriptor.clone(), primitive),
+            None => Constructor::PrimitiveType(primitive),
+        };
+        buf.push(next_elt);
+    }
+    Ok(Primitive::Array(buf))
+}
+
+impl Constructor {
+    pub fn encode(&self, buf: &mut Vec<u8>) -> Result<(), &'static str> {
+        match self {
+            Constructor::PrimitiveType(primitive) => primitive.encode(buf),
+            Constructor::DescribedType(descriptor, primitive) => {
+                buf.push(FormatCode::NonPrimitive as u8);
+                descriptor.encode(buf)?;
+                primitive.encode(buf)
             }
-            Ok(Primitive::Array(buf))
         }
-        FormatCode::Array32 => {
-            let mut read_buf = [0u8; 4];
-            buf_reader
-                .read_exact(&mut read_buf)
-                .await
-                .unwrap_or_else(|_| 0);
-            let _size = u32::from_be_bytes(read_buf);
-            buf_reader
-                .read_exact(&mut read_buf)
-                .await
-                .unwrap_or_else(|_| 0);
-            let count = u32::from_be_bytes(read_buf);
+    }
 
-            let read_buf = [0u8; 2];
-            let elt_constructor_code = FormatCode::try_from(u16::from_be_bytes(read_buf))?;
-            let len = count as usize;
-            let mut buf = Vec::with_capacity(len);
-            let first_elt = Box::pin(Constructor::new(elt_constructor_code, buf_reader).await?);
-            for _ in 0..len {
-                let next_elt = match *first_elt {
-                    Constructor::PrimitiveType(_) => Constructor::PrimitiveType(
-                        Box::pin(read_primitive(buf_reader, elt_constructor_code)).await?,
-                    ),
-                    Constructor::DescribedType(ref descriptor, _) => {
-                        Constructor::DescribedType(
-                            descriptor.clone(),
-                            Box::pin(read_primitive(buf_reader, elt_constructor_code)).await?,
-                        )
+    pub fn as_bytes(&self) -> Result<Vec<u8>, &'static str> {
+        let mut buf = vec![];
+        self.encode(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+pub(super) fn write_primitive(
+    buf: &mut Vec<u8>,
+    primitive: &Primitive,
+) -> Result<(), &'static str> {
+    let code = compact_format_code(primitive);
+    buf.push(code as u8);
+    write_primitive_value(buf, primitive, code)
+}
+
+// Picks the smallest encoding able to represent the given value.
+fn compact_format_code(primitive: &Primitive) -> FormatCode {
+    match primitive {
+        Primitive::Boolean(true) => FormatCode::BooleanTrue,
+        Primitive::Boolean(false) => FormatCode::BooleanFalse,
+        Primitive::UInt(0) => FormatCode::Uint0,
+        Primitive::ULong(0) => FormatCode::Ulong0,
+        Primitive::List(elements) if elements.is_empty() => FormatCode::List0,
+        Primitive::EmptyList => FormatCode::List0,
+        _ => array_format_code(primitive),
+    }
+}
+
+// Picks the smallest encoding for the value that still carries data, which is
+// what the elements of an array need since they share a single constructor.
+fn array_format_code(primitive: &Primitive) -> FormatCode {
+    match primitive {
+        Primitive::Null => FormatCode::Null,
+        Primitive::Boolean(_) => FormatCode::Boolean,
+        Primitive::UByte(_) => FormatCode::Ubyte,
+        Primitive::UShort(_) => FormatCode::Ushort,
+        Primitive::UInt(value) => {
+            if *value <= u8::MAX as u32 {
+                FormatCode::Smalluint
+            } else {
+                FormatCode::Uint
+            }
+        }
+        Primitive::ULong(value) => {
+            if *value <= u8::MAX as u64 {
+                FormatCode::Smallulong
+            } else {
+                FormatCode::Ulong
+            }
+        }
+        Primitive::Byte(_) => FormatCode::Byte,
+        Primitive::Short(_) => FormatCode::Short,
+        Primitive::Int(value) => {
+            if i8::try_from(*value).is_ok() {
+                FormatCode::Smallint
+            } else {
+                FormatCode::Int
+            }
+        }
+        Primitive::Long(value) => {
+            if i8::try_from(*value).is_ok() {
+                FormatCode::Smalllong
+            } else {
+                FormatCode::Long
+            }
+        }
+        Primitive::Float(_) => FormatCode::Float,
+        Primitive::Double(_) => FormatCode::Double,
+        Primitive::Decimal32(_) => FormatCode::Decimal32,
+        Primitive::Decimal64(_) => FormatCode::Decimal64,
+        Primitive::Decimal128(_) => FormatCode::Decimal128,
+        Primitive::Char(_) => FormatCode::Char,
+        Primitive::Timestamp(_) => FormatCode::Timestamp,
+        Primitive::UUID(_) => FormatCode::Uuid,
+        Primitive::Binary(value) => {
+            if value.len() <= u8::MAX as usize {
+                FormatCode::OneByteBinary
+            } else {
+                FormatCode::FourByteBinary
+            }
+        }
+        Primitive::String(value) => {
+            if value.len() <= u8::MAX as usize {
+                FormatCode::OneByteString
+            } else {
+                FormatCode::FourByteString
+            }
+        }
+        Primitive::Symbol(value) => {
+            if value.len() <= u8::MAX as usize {
+                FormatCode::OneByteSymbol
+            } else {
+                FormatCode::FourByteSymbol
+            }
+        }
+        Primitive::EmptyList => FormatCode::List8,
+        Primitive::List(elements) => {
+            if elements.len() < u8::MAX as usize {
+                FormatCode::List8
+            } else {
+                FormatCode::List32
+            }
+        }
+        Primitive::Map(map) => {
+            if map.value.len() * 2 < u8::MAX as usize {
+                FormatCode::Map8
+            } else {
+                FormatCode::Map32
+            }
+        }
+        Primitive::Array(elements) => {
+            if elements.len() < u8::MAX as usize {
+                FormatCode::Array8
+            } else {
+                FormatCode::Array32
+            }
+        }
+    }
+}
+
+// Writes the value of a primitive (everything that follows the format code) using
+// the given encoding. Compound values that turn out to be too large for their
+// one-octet encoding are promoted to the four-octet one when the code allows it.
+fn write_primitive_value(
+    buf: &mut Vec<u8>,
+    primitive: &Primitive,
+    code: FormatCode,
+) -> Result<(), &'static str> {
+    match (primitive, code) {
+        (Primitive::Null, FormatCode::Null) => {}
+        (Primitive::Boolean(_), FormatCode::BooleanTrue | FormatCode::BooleanFalse) => {}
+        (Primitive::Boolean(value), FormatCode::Boolean) => buf.push(*value as u8),
+        (Primitive::UByte(value), FormatCode::Ubyte) => buf.push(*value),
+        (Primitive::UShort(value), FormatCode::Ushort) => buf.extend(value.to_be_bytes()),
+        (Primitive::UInt(_), FormatCode::Uint0) => {}
+        (Primitive::UInt(value), FormatCode::Smalluint) => buf.push(*value as u8),
+        (Primitive::UInt(value), FormatCode::Uint) => buf.extend(value.to_be_bytes()),
+        (Primitive::ULong(_), FormatCode::Ulong0) => {}
+        (Primitive::ULong(value), FormatCode::Smallulong) => buf.push(*value as u8),
+        (Primitive::ULong(value), FormatCode::Ulong) => buf.extend(value.to_be_bytes()),
+        (Primitive::Byte(value), FormatCode::Byte) => buf.extend(value.to_be_bytes()),
+        (Primitive::Short(value), FormatCode::Short) => buf.extend(value.to_be_bytes()),
+        (Primitive::Int(value), FormatCode::Smallint) => buf.extend((*value as i8).to_be_bytes()),
+        (Primitive::Int(value), FormatCode::Int) => buf.extend(value.to_be_bytes()),
+        (Primitive::Long(value), FormatCode::Smalllong) => buf.extend((*value as i8).to_be_bytes()),
+        (Primitive::Long(value), FormatCode::Long) => buf.extend(value.to_be_bytes()),
+        (Primitive::Float(value), FormatCode::Float) => buf.extend(value.value.to_be_bytes()),
+        (Primitive::Double(value), FormatCode::Double) => buf.extend(value.value.to_be_bytes()),
+        (Primitive::Decimal32(value), FormatCode::Decimal32) => buf.extend(value),
+        (Primitive::Decimal64(value), FormatCode::Decimal64) => buf.extend(value),
+        (Primitive::Decimal128(value), FormatCode::Decimal128) => buf.extend(value),
+        (Primitive::Char(value), FormatCode::Char) => buf.extend(value),
+        (Primitive::Timestamp(value), FormatCode::Timestamp) => buf.extend(value.to_be_bytes()),
+        (Primitive::UUID(value), FormatCode::Uuid) => buf.extend(value),
+        (Primitive::Binary(value), FormatCode::OneByteBinary)
+        | (Primitive::Symbol(value), FormatCode::OneByteSymbol) => {
+            buf.push(u8::try_from(value.len()).map_err(|_| "Value too long for a 1-byte size")?);
+            buf.extend(value);
+        }
+        (Primitive::Binary(value), FormatCode::FourByteBinary)
+        | (Primitive::Symbol(value), FormatCode::FourByteSymbol) => {
+            buf.extend(write_size_u32(value.len())?);
+            buf.extend(value);
+        }
+        (Primitive::String(value), FormatCode::OneByteString) => {
+            buf.push(u8::try_from(value.len()).map_err(|_| "Value too long for a 1-byte size")?);
+            buf.extend(value.as_bytes());
+        }
+        (Primitive::String(value), FormatCode::FourByteString) => {
+            buf.extend(write_size_u32(value.len())?);
+            buf.extend(value.as_bytes());
+        }
+        (Primitive::EmptyList, FormatCode::List0) => {}
+        (Primitive::List(elements), FormatCode::List0) if elements.is_empty() => {}
+        (Primitive::EmptyList, FormatCode::List8 | FormatCode::List32) => {
+            write_compound(buf, code == FormatCode::List8, 0, &[]);
+        }
+        (Primitive::List(elements), FormatCode::List8 | FormatCode::List32) => {
+            let mut data = vec![];
+            for elt in elements.iter() {
+                elt.encode(&mut data)?;
+            }
+            write_compound(buf, code == FormatCode::List8, elements.len(), &data);
+        }
+        (Primitive::Map(map), FormatCode::Map8 | FormatCode::Map32) => {
+            let mut data = vec![];
+            for (key, val) in map.value.iter() {
+                key.encode(&mut data)?;
+                val.encode(&mut data)?;
+            }
+            write_compound(buf, code == FormatCode::Map8, map.value.len() * 2, &data);
+        }
+        (Primitive::Array(elements), FormatCode::Array8 | FormatCode::Array32) => {
+            let data = write_array_elements(elements)?;
+            write_compound(buf, code == FormatCode::Array8, elements.len(), &data);
+        }
+        _ => return Err("Primitive value does not match the format code"),
+    }
+    Ok(())
+}
+
+fn write_size_u32(len: usize) -> Result<[u8; 4], &'static str> {
+    Ok(u32::try_from(len)
+        .map_err(|_| "Value too long for a 4-byte size")?
+        .to_be_bytes())
+}
+
+// Writes the size and count of a compound or array value followed by its data.
+// The size covers the count and the data. The one-octet width is only used if
+// both the size and the count fit into it.
+fn write_compound(buf: &mut Vec<u8>, try_narrow: bool, count: usize, data: &[u8]) {
+    let fits_narrow = data.len() < u8::MAX as usize && count <= u8::MAX as usize;
+    // The format code has already been written by the caller, so it is patched in place
+    // if the value has to be promoted to the wider encoding.
+    if try_narrow && fits_narrow {
+        buf.push((data.len() + 1) as u8);
+        buf.push(count as u8);
+    } else {
+        if try_narrow {
+            promote_format_code(buf);
+        }
+        buf.extend(((data.len() + 4) as u32).to_be_bytes());
+        buf.extend((count as u32).to_be_bytes());
+    }
+    buf.extend(data);
+}
+
+fn promote_format_code(buf: &mut [u8]) {
+    if let Some(code) = buf.last_mut() {
+        *code = match FormatCode::try_from(*code as u16) {
+            Ok(FormatCode::List8) => FormatCode::List32 as u8,
+            Ok(FormatCode::Map8) => FormatCode::Map32 as u8,
+            Ok(FormatCode::Array8) => FormatCode::Array32 as u8,
+            _ => *code,
+        };
+    }
+}
+
+fn write_array_elements(elements: &[Constructor]) -> Result<Vec<u8>, &'static str> {
+    let mut data = vec![];
+    let Some(first) = elements.first() else {
+        // An empty array still needs an element constructor; null is as good as any.
+        data.push(FormatCode::Null as u8);
+        return Ok(data);
+    };
+
+    let (descriptor, primitives) = match first {
+        Constructor::PrimitiveType(_) => {
+            let mut primitives = Vec::with_capacity(elements.len());
+            for elt in elements.iter() {
+                match elt {
+                    Constructor::PrimitiveType(primitive) => primitives.push(primitive),
+                    _ => return Err("Array elements have different constructors"),
+                }
+            }
+            (None, primitives)
+        }
+        Constructor::DescribedType(first_descriptor, _) => {
+            let mut primitives = Vec::with_capacity(elements.len());
+            for elt in elements.iter() {
+                match elt {
+                    Constructor::DescribedType(descriptor, primitive)
+                        if descriptor == first_descriptor =>
+                    {
+                        primitives.push(primitive)
                     }
-                };
-                buf.push(next_elt);
+                    _ => return Err("Array elements have different constructors"),
+                }
             }
-            Ok(Primitive::Array(buf))
+            (Some(first_descriptor), primitives)
+        }
+    };
+
+    let mut code = array_format_code(primitives[0]);
+    for primitive in primitives.iter().skip(1) {
+        code = widen_format_code(code, array_format_code(primitive))?;
+    }
+
+    if let Some(descriptor) = descriptor {
+        data.push(FormatCode::NonPrimitive as u8);
+        descriptor.encode(&mut data)?;
+    }
+    // Compound elements are always written in their four-octet form since the
+    // element constructor can't change from one element to the next.
+    let code = match code {
+        FormatCode::List8 | FormatCode::Map8 | FormatCode::Array8 => wide_format_code(code),
+        _ => code,
+    };
+    data.push(code as u8);
+    for primitive in primitives {
+        write_primitive_value(&mut data, primitive, code)?;
+    }
+    Ok(data)
+}
+
+// Finds an encoding that can hold the values of both encodings, which is the
+// wider one of the two if they belong to the same type.
+fn widen_format_code(left: FormatCode, right: FormatCode) -> Result<FormatCode, &'static str> {
+    if left == right {
+        Ok(left)
+    } else if wide_format_code(left) == wide_format_code(right) {
+        Ok(wide_format_code(left))
+    } else {
+        Err("Array elements have different types")
+    }
+}
+
+fn wide_format_code(code: FormatCode) -> FormatCode {
+    match code {
+        FormatCode::Smalluint => FormatCode::Uint,
+        FormatCode::Smallulong => FormatCode::Ulong,
+        FormatCode::Smallint => FormatCode::Int,
+        FormatCode::Smalllong => FormatCode::Long,
+        FormatCode::OneByteBinary => FormatCode::FourByteBinary,
+        FormatCode::OneByteString => FormatCode::FourByteString,
+        FormatCode::OneByteSymbol => FormatCode::FourByteSymbol,
+        FormatCode::List8 => FormatCode::List32,
+        FormatCode::Map8 => FormatCode::Map32,
+        FormatCode::Array8 => FormatCode::Array32,
+        code => code,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn decode(mut bytes: &[u8]) -> Constructor {
+        let code = FormatCode::read(&mut bytes).await.unwrap();
+        let constructor = Constructor::new(code, &mut bytes).await.unwrap();
+        assert!(bytes.is_empty(), "{} octets left over", bytes.len());
+        constructor
+    }
+
+    // Encodes the primitive, checks the encoding it was given and decodes it again.
+    async fn round_trip(primitive: Primitive, code: FormatCode) -> Primitive {
+        let bytes = Constructor::PrimitiveType(primitive).as_bytes().unwrap();
+        assert_eq!(bytes[0], code as u8);
+        match decode(&bytes).await {
+            Constructor::PrimitiveType(primitive) => primitive,
+            described => panic!("decoded {:?}", described),
+        }
+    }
+
+    fn int(value: i32) -> Constructor {
+        Constructor::PrimitiveType(Primitive::Int(value))
+    }
+
+    #[tokio::test]
+    async fn fixed_width_round_trips() {
+        let cases = [
+            (Primitive::Null, FormatCode::Null),
+            (Primitive::Boolean(true), FormatCode::BooleanTrue),
+            (Primitive::Boolean(false), FormatCode::BooleanFalse),
+            (Primitive::UByte(200), FormatCode::Ubyte),
+            (Primitive::UShort(0x1234), FormatCode::Ushort),
+            (Primitive::UInt(0), FormatCode::Uint0),
+            (Primitive::UInt(255), FormatCode::Smalluint),
+            (Primitive::UInt(70_000), FormatCode::Uint),
+            (Primitive::ULong(0), FormatCode::Ulong0),
+            (Primitive::ULong(255), FormatCode::Smallulong),
+            (Primitive::ULong(1 << 40), FormatCode::Ulong),
+            (Primitive::Byte(-5), FormatCode::Byte),
+            (Primitive::Short(-300), FormatCode::Short),
+            (Primitive::Int(127), FormatCode::Smallint),
+            (Primitive::Int(-129), FormatCode::Int),
+            (Primitive::Long(127), FormatCode::Smalllong),
+            (Primitive::Long(-1 << 40), FormatCode::Long),
+            (
+                Primitive::Float(InnerFloat { value: 1.5 }),
+                FormatCode::Float,
+            ),
+            (
+                Primitive::Double(InnerDouble { value: -2.25 }),
+                FormatCode::Double,
+            ),
+            (Primitive::Decimal32([1, 2, 3, 4]), FormatCode::Decimal32),
+            (Primitive::Decimal64([1; 8]), FormatCode::Decimal64),
+            (Primitive::Decimal128([2; 16]), FormatCode::Decimal128),
+            (Primitive::Char([0, 0, 0, b'a']), FormatCode::Char),
+            (Primitive::Timestamp(-1_000), FormatCode::Timestamp),
+            (Primitive::UUID([9; 16]), FormatCode::Uuid),
+        ];
+        for (primitive, code) in cases {
+            assert_eq!(round_trip(primitive.clone(), code).await, primitive);
+        }
+    }
+
+    #[tokio::test]
+    async fn small_signed_values_are_sign_extended() {
+        for value in [-1, -128, 0, 1] {
+            assert_eq!(
+                round_trip(Primitive::Int(value), FormatCode::Smallint).await,
+                Primitive::Int(value)
+            );
+            assert_eq!(
+                round_trip(Primitive::Long(value.into()), FormatCode::Smalllong).await,
+                Primitive::Long(value.into())
+            );
+        }
+        assert_eq!(decode(&[0x54, 0xff]).await, int(-1));
+        assert_eq!(
+            decode(&[0x55, 0x80]).await,
+            Constructor::PrimitiveType(Primitive::Long(-128))
+        );
+    }
+
+    #[tokio::test]
+    async fn boolean_with_a_value_octet() {
+        for (octet, value) in [(0x00, false), (0x01, true)] {
+            assert_eq!(
+                decode(&[0x56, octet]).await,
+                Constructor::PrimitiveType(Primitive::Boolean(value))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn variable_width_round_trips() {
+        let long = "x".repeat(300);
+        let cases = [
+            (Primitive::Binary(vec![1, 2, 3]), FormatCode::OneByteBinary),
+            (Primitive::Binary(vec![0; 300]), FormatCode::FourByteBinary),
+            (
+                Primitive::String(String::from("héllo")),
+                FormatCode::OneByteString,
+            ),
+            (Primitive::String(long.clone()), FormatCode::FourByteString),
+            (
+                Primitive::Symbol(b"amqp:accepted:list".to_vec()),
+                FormatCode::OneByteSymbol,
+            ),
+            (
+                Primitive::Symbol(long.into_bytes()),
+                FormatCode::FourByteSymbol,
+            ),
+        ];
+        for (primitive, code) in cases {
+            assert_eq!(round_trip(primitive.clone(), code).await, primitive);
+        }
+    }
+
+    #[tokio::test]
+    async fn compound_round_trips() {
+        assert_eq!(
+            round_trip(Primitive::EmptyList, FormatCode::List0).await,
+            Primitive::EmptyList
+        );
+        let list = Primitive::List(vec![
+            int(1),
+            Constructor::PrimitiveType(Primitive::String(String::from("two"))),
+            Constructor::PrimitiveType(Primitive::Null),
+        ]);
+        assert_eq!(round_trip(list.clone(), FormatCode::List8).await, list);
+        let list = Primitive::List((0..300).map(int).collect());
+        assert_eq!(round_trip(list.clone(), FormatCode::List32).await, list);
+        // Too many octets for the one-octet size, though few enough elements.
+        let list = Primitive::List(vec![Constructor::PrimitiveType(Primitive::Binary(vec![
+            0;
+            255
+        ]))]);
+        assert_eq!(round_trip(list.clone(), FormatCode::List32).await, list);
+
+        let map = |len: i32| {
+            Primitive::Map(InnerMap {
+                value: (0..len).map(|key| (int(key), int(-key))).collect(),
+            })
+        };
+        assert_eq!(round_trip(map(3), FormatCode::Map8).await, map(3));
+        assert_eq!(round_trip(map(200), FormatCode::Map32).await, map(200));
+    }
+
+    #[tokio::test]
+    async fn array_round_trips() {
+        // The elements share the smallest encoding all of them fit into.
+        let array = Primitive::Array(vec![int(-1), int(-128), int(5)]);
+        let bytes = Constructor::PrimitiveType(array.clone())
+            .as_bytes()
+            .unwrap();
+        assert_eq!(bytes[3], FormatCode::Smallint as u8);
+        assert_eq!(
+            round_trip(array, FormatCode::Array8).await,
+            Primitive::Array(vec![int(-1), int(-128), int(5)])
+        );
+        let array = Primitive::Array(vec![int(1), int(1_000)]);
+        let bytes = Constructor::PrimitiveType(array.clone())
+            .as_bytes()
+            .unwrap();
+        assert_eq!(bytes[3], FormatCode::Int as u8);
+        assert_eq!(round_trip(array.clone(), FormatCode::Array8).await, array);
+        let array = Primitive::Array((0..300).map(int).collect());
+        assert_eq!(round_trip(array.clone(), FormatCode::Array32).await, array);
+
+        let mixed = Primitive::Array(vec![
+            int(1),
+            Constructor::PrimitiveType(Primitive::String(String::from("one"))),
+        ]);
+        assert!(Constructor::PrimitiveType(mixed).as_bytes().is_err());
+    }
+
+    #[tokio::test]
+    async fn described_round_trips() {
+        let described = Constructor::DescribedType(
+            Box::pin(Constructor::PrimitiveType(Primitive::ULong(0x24))),
+            Primitive::EmptyList,
+        );
+        let bytes = described.as_bytes().unwrap();
+        assert_eq!(bytes, [0x00, 0x53, 0x24, 0x45]);
+        assert_eq!(decode(&bytes).await, described);
+
+        // Array elements share the constructor, descriptor included.
+        let described = |value| {
+            Constructor::DescribedType(
+                Box::pin(Constructor::PrimitiveType(Primitive::Symbol(
+                    b"amqp:data:binary".to_vec(),
+                ))),
+                Primitive::Binary(vec![value]),
+            )
+        };
+        let elements = vec![described(1), described(2)];
+        let array = Primitive::Array(elements.clone());
+        assert_eq!(
+            round_trip(array, FormatCode::Array8).await,
+            Primitive::Array(elements)
+        );
+    }
+
+    #[tokio::test]
+    async fn truncated_values_are_errors() {
+        for bytes in [&[0x71, 0, 0][..], &[0xa1, 5, b'a'], &[0xc0, 3, 1]] {
+            let mut bytes = bytes;
+            let code = FormatCode::read(&mut bytes).await.unwrap();
+            assert!(Constructor::new(code, &mut bytes).await.is_err());
         }
     }
 }
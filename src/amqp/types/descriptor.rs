@@ -0,0 +1,245 @@
+use super::constructor::Constructor;
+use super::primitive::Primitive;
+
+// Every described type defined by the specification can be identified either by its symbolic
+// descriptor (e.g. "amqp:open:list") or by its numeric one (e.g. 0x00000000:0x00000010,
+// where the high half is the domain-id and the low half is the descriptor-id).
+// Peers are free to use either form, so both are accepted when decoding; numeric
+// descriptors are used when encoding since they are what most implementations send.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Descriptor {
+    // 2.7 Performatives
+    Open,
+    Begin,
+    Attach,
+    Flow,
+    Transfer,
+    Disposition,
+    Detach,
+    End,
+    Close,
+    // 2.8.14 Error
+    Error,
+    // 3.2 Message Format
+    Header,
+    DeliveryAnnotations,
+    MessageAnnotations,
+    Properties,
+    ApplicationProperties,
+    Data,
+    AmqpSequence,
+    AmqpValue,
+    Footer,
+    // 3.4 Delivery State
+    Received,
+    Accepted,
+    Rejected,
+    Released,
+    Modified,
+    // 3.5 Source and Target
+    Source,
+    Target,
+    DeleteOnClose,
+    DeleteOnNoLinks,
+    DeleteOnNoMessages,
+    DeleteOnNoLinksOrMessages,
+    // Filters of the AMQP filter registry, see
+    // https://svn.apache.org/repos/asf/qpid/trunk/qpid/specs/apache-filters.xml
+    LegacyDirectBinding,
+    LegacyHeadersBinding,
+    SelectorFilter,
+    // 5.3 SASL
+    SaslMechanisms,
+    SaslInit,
+    SaslChallenge,
+    SaslResponse,
+    SaslOutcome,
+}
+
+const DESCRIPTORS: &[(Descriptor, &str, u64)] = &[
+    (Descriptor::Open, "amqp:open:list", 0x00000000_00000010),
+    (Descriptor::Begin, "amqp:begin:list", 0x00000000_00000011),
+    (Descriptor::Attach, "amqp:attach:list", 0x00000000_00000012),
+    (Descriptor::Flow, "amqp:flow:list", 0x00000000_00000013),
+    (
+        Descriptor::Transfer,
+        "amqp:transfer:list",
+        0x00000000_00000014,
+    ),
+    (
+        Descriptor::Disposition,
+        "amqp:disposition:list",
+        0x00000000_00000015,
+    ),
+    (Descriptor::Detach, "amqp:detach:list", 0x00000000_00000016),
+    (Descriptor::End, "amqp:end:list", 0x00000000_00000017),
+    (Descriptor::Close, "amqp:close:list", 0x00000000_00000018),
+    (Descriptor::Error, "amqp:error:list", 0x00000000_0000001d),
+    (Descriptor::Header, "amqp:header:list", 0x00000000_00000070),
+    (
+        Descriptor::DeliveryAnnotations,
+        "amqp:delivery-annotations:map",
+        0x00000000_00000071,
+    ),
+    (
+        Descriptor::MessageAnnotations,
+        "amqp:message-annotations:map",
+        0x00000000_00000072,
+    ),
+    (
+        Descriptor::Properties,
+        "amqp:properties:list",
+        0x00000000_00000073,
+    ),
+    (
+        Descriptor::ApplicationProperties,
+        "amqp:application-properties:map",
+        0x00000000_00000074,
+    ),
+    (Descriptor::Data, "amqp:data:binary", 0x00000000_00000075),
+    (
+        Descriptor::AmqpSequence,
+        "amqp:amqp-sequence:list",
+        0x00000000_00000076,
+    ),
+    (
+        Descriptor::AmqpValue,
+        "amqp:amqp-value:*",
+        0x00000000_00000077,
+    ),
+    (Descriptor::Footer, "amqp:footer:map", 0x00000000_00000078),
+    (
+        Descriptor::Received,
+        "amqp:received:list",
+        0x00000000_00000023,
+    ),
+    (
+        Descriptor::Accepted,
+        "amqp:accepted:list",
+        0x00000000_00000024,
+    ),
+    (
+        Descriptor::Rejected,
+        "amqp:rejected:list",
+        0x00000000_00000025,
+    ),
+    (
+        Descriptor::Released,
+        "amqp:released:list",
+        0x00000000_00000026,
+    ),
+    (
+        Descriptor::Modified,
+        "amqp:modified:list",
+        0x00000000_00000027,
+    ),
+    (Descriptor::Source, "amqp:source:list", 0x00000000_00000028),
+    (Descriptor::Target, "amqp:target:list", 0x00000000_00000029),
+    (
+        Descriptor::DeleteOnClose,
+        "amqp:delete-on-close:list",
+        0x00000000_0000002b,
+    ),
+    (
+        Descriptor::DeleteOnNoLinks,
+        "amqp:delete-on-no-links:list",
+        0x00000000_0000002c,
+    ),
+    (
+        Descriptor::DeleteOnNoMessages,
+        "amqp:delete-on-no-messages:list",
+        0x00000000_0000002d,
+    ),
+    (
+        Descriptor::DeleteOnNoLinksOrMessages,
+        "amqp:delete-on-no-links-or-messages:list",
+        0x00000000_0000002e,
+    ),
+    (
+        Descriptor::LegacyDirectBinding,
+        "apache.org:legacy-amqp-direct-binding:string",
+        0x0000468c_00000000,
+    ),
+    (
+        Descriptor::LegacyHeadersBinding,
+        "apache.org:legacy-amqp-headers-binding:map",
+        0x0000468c_00000002,
+    ),
+    (
+        Descriptor::SelectorFilter,
+        "apache.org:selector-filter:string",
+        0x0000468c_00000004,
+    ),
+    (
+        Descriptor::SaslMechanisms,
+        "amqp:sasl-mechanisms:list",
+        0x00000000_00000040,
+    ),
+    (
+        Descriptor::SaslInit,
+        "amqp:sasl-init:list",
+        0x00000000_00000041,
+    ),
+    (
+        Descriptor::SaslChallenge,
+        "amqp:sasl-challenge:list",
+        0x00000000_00000042,
+    ),
+    (
+        Descriptor::SaslResponse,
+        "amqp:sasl-response:list",
+        0x00000000_00000043,
+    ),
+    (
+        Descriptor::SaslOutcome,
+        "amqp:sasl-outcome:list",
+        0x00000000_00000044,
+    ),
+];
+
+impl Descriptor {
+    pub fn code(self) -> u64 {
+        DESCRIPTORS
+            .iter()
+            .find(|(descriptor, _, _)| *descriptor == self)
+            .map(|(_, _, code)| *code)
+            .unwrap_or_default()
+    }
+
+    pub fn from_name(name: &[u8]) -> Result<Self, &'static str> {
+        DESCRIPTORS
+            .iter()
+            .find(|(_, descriptor_name, _)| descriptor_name.as_bytes() == name)
+            .map(|(descriptor, _, _)| *descriptor)
+            .ok_or("Unknown descriptor name")
+    }
+
+    pub fn from_code(code: u64) -> Result<Self, &'static str> {
+        DESCRIPTORS
+            .iter()
+            .find(|(_, _, descriptor_code)| *descriptor_code == code)
+            .map(|(descriptor, _, _)| *descriptor)
+            .ok_or("Unknown descriptor code")
+    }
+
+    // Symbolic descriptors are symbols per the specification, but strings are
+    // accepted as well since some clients send those instead.
+    pub fn from_constructor(constructor: &Constructor) -> Result<Self, &'static str> {
+        match constructor {
+            Constructor::PrimitiveType(Primitive::ULong(code)) => Self::from_code(*code),
+            Constructor::PrimitiveType(Primitive::Symbol(name)) => Self::from_name(name),
+            Constructor::PrimitiveType(Primitive::String(name)) => Self::from_name(name.as_bytes()),
+            Constructor::PrimitiveType(_) => Err("Descriptor is neither a ulong nor a symbol"),
+            Constructor::DescribedType(_, _) => Err("Descriptor is not a primitive type"),
+        }
+    }
+
+    pub fn to_constructor(self) -> Constructor {
+        Constructor::PrimitiveType(Primitive::ULong(self.code()))
+    }
+
+    // Wraps the value into a described type carrying this descriptor.
+    pub fn describe(self, value: Primitive) -> Constructor {
+        Constructor::DescribedType(Box::pin(self.to_constructor()), value)
+    }
+}
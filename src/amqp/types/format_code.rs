@@ -1,4 +1,6 @@
-#[derive(Clone, Copy, Debug)]
+use tokio::io::AsyncReadExt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum FormatCode {
     NonPrimitive = 0x00,
     Null = 0x40,           // fixed/0 the null value
@@ -91,3 +93,15 @@ impl TryFrom<u16> for FormatCode {
         }
     }
 }
+
+impl FormatCode {
+    // A constructor is a single octet on the wire, except for the 0x00 of a described type
+    // which is followed by the descriptor and then by another constructor.
+    pub async fn read(buf_reader: &mut (impl AsyncReadExt + Unpin)) -> Result<Self, &'static str> {
+        let code = buf_reader
+            .read_u8()
+            .await
+            .map_err(|_| "Could not read format code")?;
+        FormatCode::try_from(code as u16)
+    }
+}
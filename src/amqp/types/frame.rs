@@ -1,13 +1,17 @@
-use tokio::io::AsyncReadExt;
+use std::fmt;
 
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FrameType {
     AMQP = 0x00,
-    SASL = 0x05,
+    SASL = 0x01,
 }
 
+#[derive(Debug, Clone)]
 pub struct Frame {
-    pub size: u32,
-    pub doff: u8,
     pub frame_type: FrameType,
     pub type_specific: [u8; 2],
     pub extended_header: Vec<u8>,
@@ -15,58 +19,284 @@ pub struct Frame {
 }
 
 impl Frame {
-    pub async fn new(buf_reader: &mut (impl AsyncReadExt + Unpin)) -> Result<Self, &'static str> {
-        let mut buffer = [0u8; 4];
-        buf_reader
-            .read_exact(&mut buffer)
-            .await
-            .unwrap_or_else(|_| 0);
-        let frame_size = u32::from_be_bytes(buffer);
-        let mut buffer = [0u8; 1];
-        buf_reader
-            .read_exact(&mut buffer)
-            .await
-            .unwrap_or_else(|_| 0);
-        let doff = buffer[0];
-        let mut buffer = [0u8; 1];
-        buf_reader
-            .read_exact(&mut buffer)
-            .await
-            .unwrap_or_else(|_| 0);
-        let frame_type = match buffer[0] {
+    // Frames we build ourselves never carry an extended header, so the body
+    // starts right after the 8-byte frame header.
+    pub fn amqp(channel: u16, frame_body: Vec<u8>) -> Self {
+        Frame {
+            frame_type: FrameType::AMQP,
+            type_specific: channel.to_be_bytes(),
+            extended_header: vec![],
+            frame_body,
+        }
+    }
+
+    // An AMQP frame with no body is used to keep the connection alive (see 2.4.5 Idle Timeout).
+    // SASL frames ignore the type-specific bytes of the header (see 5.3.1 SASL Frames).
+    pub fn sasl(frame_body: Vec<u8>) -> Self {
+        Frame {
+            frame_type: FrameType::SASL,
+            type_specific: [0, 0],
+            extended_header: vec![],
+            frame_body,
+        }
+    }
+
+    // An empty frame only tells the peer we are still there (see 2.4.5 Idle Timeout Of A Connection).
+    pub fn heartbeat() -> Self {
+        Frame::amqp(0, vec![])
+    }
+
+    pub fn is_heartbeat(&self) -> bool {
+        self.frame_type == FrameType::AMQP && self.frame_body.is_empty()
+    }
+
+    // Bytes 6 and 7 of an AMQP Frame contain the Channel number (see section 2.1 Transport).
+    pub fn channel(&self) -> u16 {
+        u16::from_be_bytes(self.type_specific)
+    }
+}
+
+// The smallest max-frame-size a peer may advertise, which is also the limit
+// in effect until the Open frames have been exchanged (see 2.7.1 Open).
+pub const MIN_MAX_FRAME_SIZE: u32 = 512;
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(std::io::Error),
+    // The size field is smaller than the frame header itself.
+    InvalidSize(u32),
+    // The data offset points inside the frame header or past the end of the frame.
+    InvalidDataOffset(u8),
+    FrameTooLarge { size: u32, max_frame_size: u32 },
+    UnknownFrameType(u8),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Io(err) => write!(f, "I/O error while reading frame: {}", err),
+            FrameError::InvalidSize(size) => write!(f, "Invalid frame size {}", size),
+            FrameError::InvalidDataOffset(doff) => write!(f, "Invalid data offset {}", doff),
+            FrameError::FrameTooLarge {
+                size,
+                max_frame_size,
+            } => write!(
+                f,
+                "Frame of {} octets exceeds the max frame size of {} octets",
+                size, max_frame_size
+            ),
+            FrameError::UnknownFrameType(frame_type) => {
+                write!(f, "Unknown frame type {:#04x}", frame_type)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<std::io::Error> for FrameError {
+    fn from(err: std::io::Error) -> Self {
+        FrameError::Io(err)
+    }
+}
+
+// Splits a byte stream into frames using the 4-byte size at the start of each frame
+// (see 2.3 Framing) and writes frames back out.
+pub struct FrameCodec {
+    max_frame_size: u32,
+}
+
+impl FrameCodec {
+    // Incoming frames are checked against the max-frame-size we advertise in our Open.
+    pub fn new(max_frame_size: u32) -> Self {
+        Self {
+            max_frame_size: max_frame_size.max(MIN_MAX_FRAME_SIZE),
+        }
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = FrameError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let size = u32::from_be_bytes([src[0], src[1], src[2], src[3]]);
+        if size < 8 {
+            return Err(FrameError::InvalidSize(size));
+        }
+        if size > self.max_frame_size {
+            return Err(FrameError::FrameTooLarge {
+                size,
+                max_frame_size: self.max_frame_size,
+            });
+        }
+        if src.len() < size as usize {
+            src.reserve(size as usize - src.len());
+            return Ok(None);
+        }
+
+        let mut frame_buf = src.split_to(size as usize);
+        frame_buf.advance(4);
+        let doff = frame_buf.get_u8();
+        if doff < 2 || doff as u32 * 4 > size {
+            return Err(FrameError::InvalidDataOffset(doff));
+        }
+        let frame_type = match frame_buf.get_u8() {
             0x00 => FrameType::AMQP,
-            0x05 => FrameType::SASL,
-            _ => return Err("Unexpected frame type"),
+            0x01 => FrameType::SASL,
+            frame_type => return Err(FrameError::UnknownFrameType(frame_type)),
         };
-        let mut buffer = [0u8; 2];
-        buf_reader
-            .read_exact(&mut buffer)
-            .await
-            .unwrap_or_else(|_| 0);
-        let mut frame = Frame {
-            size: frame_size,
-            doff,
+        let type_specific = [frame_buf.get_u8(), frame_buf.get_u8()];
+        let extended_header = frame_buf.split_to(doff as usize * 4 - 8).to_vec();
+        Ok(Some(Frame {
             frame_type,
-            type_specific: buffer,
-            extended_header: vec![],
-            frame_body: vec![],
-        };
-        for _ in 8..frame.doff * 4 {
-            let mut buffer = [0u8; 1];
-            buf_reader
-                .read_exact(&mut buffer)
-                .await
-                .unwrap_or_else(|_| 0);
-            frame.extended_header.push(buffer[0]);
-        }
-        for _ in (frame.doff as u32) * 4..frame.size {
-            let mut buffer = [0u8; 1];
-            buf_reader
-                .read_exact(&mut buffer)
-                .await
-                .unwrap_or_else(|_| 0);
-            frame.frame_body.push(buffer[0]);
+            type_specific,
+            extended_header,
+            frame_body: frame_buf.to_vec(),
+        }))
+    }
+}
+
+// The size and data offset are derived from the actual header and body,
+// so a frame assembled by hand can't be written out inconsistently.
+impl Encoder<Frame> for FrameCodec {
+    type Error = FrameError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        // The extended header is padded so that the body starts at a 4-byte boundary.
+        let header_size = (8 + frame.extended_header.len()).div_ceil(4) * 4;
+        let size = header_size + frame.frame_body.len();
+        dst.reserve(size);
+        dst.put_u32(size as u32);
+        dst.put_u8((header_size / 4) as u8);
+        dst.put_u8(frame.frame_type as u8);
+        dst.put_slice(&frame.type_specific);
+        dst.put_slice(&frame.extended_header);
+        dst.put_bytes(0, header_size - 8 - frame.extended_header.len());
+        dst.put_slice(&frame.frame_body);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(frame: Frame) -> BytesMut {
+        let mut buf = BytesMut::new();
+        FrameCodec::new(MIN_MAX_FRAME_SIZE)
+            .encode(frame, &mut buf)
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut buf = encode(Frame::amqp(7, vec![1, 2, 3]));
+        assert_eq!(&buf[..8], [0, 0, 0, 11, 2, 0, 0, 7]);
+        let frame = FrameCodec::new(MIN_MAX_FRAME_SIZE)
+            .decode(&mut buf)
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.frame_type, FrameType::AMQP);
+        assert_eq!(frame.channel(), 7);
+        assert_eq!(frame.frame_body, [1, 2, 3]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn extended_header_is_skipped() {
+        let mut frame = Frame::sasl(vec![9]);
+        frame.extended_header = vec![1, 2, 3];
+        let mut buf = encode(frame);
+        assert_eq!(buf[4], 3);
+        let frame = FrameCodec::new(MIN_MAX_FRAME_SIZE)
+            .decode(&mut buf)
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.frame_type, FrameType::SASL);
+        assert_eq!(frame.extended_header, [1, 2, 3, 0]);
+        assert_eq!(frame.frame_body, [9]);
+    }
+
+    #[test]
+    fn partial_frames_wait_for_the_rest() {
+        let whole = encode(Frame::amqp(1, vec![5; 20]));
+        let mut codec = FrameCodec::new(MIN_MAX_FRAME_SIZE);
+        let mut buf = BytesMut::new();
+        // Not even the size yet, then the header but not the whole body.
+        for end in [3, 10, whole.len() - 1] {
+            buf.extend_from_slice(&whole[buf.len()..end]);
+            assert!(codec.decode(&mut buf).unwrap().is_none());
+            assert_eq!(buf.len(), end);
         }
-        Ok(frame)
+        buf.extend_from_slice(&whole[buf.len()..]);
+        // The start of the next frame stays in the buffer.
+        buf.extend_from_slice(&whole[..2]);
+        let frame = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(frame.frame_body, [5; 20]);
+        assert_eq!(&buf[..], &whole[..2]);
+    }
+
+    #[test]
+    fn heartbeats_have_no_body() {
+        let mut buf = encode(Frame::heartbeat());
+        assert_eq!(buf.len(), 8);
+        let frame = FrameCodec::new(MIN_MAX_FRAME_SIZE)
+            .decode(&mut buf)
+            .unwrap()
+            .unwrap();
+        assert!(frame.is_heartbeat());
+    }
+
+    #[test]
+    fn oversize_frames_are_rejected_from_their_header() {
+        let mut codec = FrameCodec::new(1024);
+        // Only the size is known so far, which is enough to give up on the frame.
+        let mut buf = BytesMut::from(&1025u32.to_be_bytes()[..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(FrameError::FrameTooLarge {
+                size: 1025,
+                max_frame_size: 1024
+            })
+        ));
+        let mut buf = BytesMut::from(&1024u32.to_be_bytes()[..]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn max_frame_size_is_at_least_the_minimum() {
+        let mut codec = FrameCodec::new(8);
+        let mut buf = encode(Frame::amqp(0, vec![0; 100]));
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        let mut codec = FrameCodec::new(MIN_MAX_FRAME_SIZE);
+        let mut buf = BytesMut::from(&[0, 0, 0, 7][..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(FrameError::InvalidSize(7))
+        ));
+        let mut buf = BytesMut::from(&[0, 0, 0, 8, 1, 0, 0, 0][..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(FrameError::InvalidDataOffset(1))
+        ));
+        let mut buf = BytesMut::from(&[0, 0, 0, 8, 3, 0, 0, 0][..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(FrameError::InvalidDataOffset(3))
+        ));
+        let mut buf = BytesMut::from(&[0, 0, 0, 8, 2, 2, 0, 0][..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(FrameError::UnknownFrameType(2))
+        ));
     }
 }
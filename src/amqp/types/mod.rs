@@ -1,4 +1,5 @@
 pub mod constructor;
+pub mod descriptor;
 pub mod format_code;
 pub mod frame;
 pub mod primitive;
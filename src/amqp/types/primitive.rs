@@ -1,6 +1,6 @@
-use std::{collections::HashMap, hash::Hash}; 
+use std::{collections::HashMap, hash::Hash};
 
-use super::constructor::Constructor;
+use super::constructor::{Constructor, write_primitive};
 
 #[derive(Debug, Clone)]
 pub struct InnerFloat {
@@ -17,6 +17,7 @@ pub struct InnerMap {
     pub value: HashMap<Constructor, Constructor>,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub enum Primitive {
     // Fixed width
@@ -82,7 +83,7 @@ impl PartialEq for InnerMap {
                 }
             }
         }
-        return true;
+        true
     }
 }
 
@@ -116,3 +117,9 @@ impl Hash for InnerMap {
         }
     }
 }
+
+impl Primitive {
+    pub fn encode(&self, buf: &mut Vec<u8>) -> Result<(), &'static str> {
+        write_primitive(buf, self)
+    }
+}
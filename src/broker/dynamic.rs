@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+
+use crate::amqp::messaging::terminus::LifetimePolicy;
+use crate::amqp::transport::link::DYNAMIC_ADDRESS_PREFIX;
+use crate::broker::LinkId;
+
+// A queue created for the link that asked for a dynamic source.
+struct DynamicNode {
+    lifetime_policy: LifetimePolicy,
+    // The name of the link that created the node, which is where it is attached
+    // while it is, and which is gone once it is closed or its terminus expired.
+    creator: String,
+    attached: Option<LinkId>,
+    creator_gone: bool,
+}
+
+// The nodes created on demand (see 3.5.3 Source), deleted once their creator is gone
+// and their lifetime policy says so. The addresses made up for them are never used
+// again, so whatever is sent to a deleted one goes nowhere.
+#[derive(Default)]
+pub struct DynamicNodes {
+    nodes: HashMap<String, DynamicNode>,
+}
+
+impl DynamicNodes {
+    pub fn create(
+        &mut self,
+        address: &str,
+        lifetime_policy: LifetimePolicy,
+        name: &str,
+        link: LinkId,
+    ) {
+        self.nodes.insert(
+            address.to_string(),
+            DynamicNode {
+                lifetime_policy,
+                creator: name.to_string(),
+                attached: Some(link),
+                creator_gone: false,
+            },
+        );
+    }
+
+    pub fn contains(&self, address: &str) -> bool {
+        self.nodes.contains_key(address)
+    }
+
+    // Whether the address is that of a dynamic node that was deleted.
+    pub fn is_deleted(&self, address: &str) -> bool {
+        address.starts_with(DYNAMIC_ADDRESS_PREFIX) && !self.contains(address)
+    }
+
+    // A link attached to the node, which may be its creator resuming.
+    pub fn attached(&mut self, address: &str, name: &str, link: LinkId) {
+        if let Some(node) = self.nodes.get_mut(address)
+            && node.creator == name
+            && !node.creator_gone
+        {
+            node.attached = Some(link);
+        }
+    }
+
+    // A link detached, which is gone unless it is suspended.
+    pub fn detached(&mut self, link: LinkId, suspended: bool) {
+        for node in self.nodes.values_mut() {
+            if node.attached == Some(link) {
+                node.attached = None;
+                node.creator_gone |= !suspended;
+            }
+        }
+    }
+
+    // The terminus of a suspended link expired.
+    pub fn expired(&mut self, address: &str, name: &str) {
+        if let Some(node) = self.nodes.get_mut(address)
+            && node.creator == name
+            && node.attached.is_none()
+        {
+            node.creator_gone = true;
+        }
+    }
+
+    // Forgets the nodes due for deletion, given whether each has links attached and
+    // messages queued, returning their addresses (see 3.5.10 Lifetime Policies).
+    pub fn delete(&mut self, in_use: impl Fn(&str) -> (bool, bool)) -> Vec<String> {
+        let deleted: Vec<String> = self
+            .nodes
+            .iter()
+            .filter(|(address, node)| {
+                let (links, messages) = in_use(address);
+                node.creator_gone
+                    && match node.lifetime_policy {
+                        LifetimePolicy::DeleteOnClose => true,
+                        LifetimePolicy::DeleteOnNoLinks => !links,
+                        LifetimePolicy::DeleteOnNoMessages => !messages,
+                        LifetimePolicy::DeleteOnNoLinksOrMessages => !links && !messages,
+                    }
+            })
+            .map(|(address, _)| address.clone())
+            .collect();
+        for address in deleted.iter() {
+            self.nodes.remove(address);
+        }
+        deleted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICIES: [LifetimePolicy; 4] = [
+        LifetimePolicy::DeleteOnClose,
+        LifetimePolicy::DeleteOnNoLinks,
+        LifetimePolicy::DeleteOnNoMessages,
+        LifetimePolicy::DeleteOnNoLinksOrMessages,
+    ];
+
+    fn link(handle: u32) -> LinkId {
+        LinkId {
+            connection_id: 1,
+            channel: 0,
+            handle,
+        }
+    }
+
+    fn address(policy: LifetimePolicy) -> String {
+        format!("{}{:?}", DYNAMIC_ADDRESS_PREFIX, policy)
+    }
+
+    // A node of every policy, created by the link of the same handle.
+    fn nodes() -> DynamicNodes {
+        let mut nodes = DynamicNodes::default();
+        for (handle, policy) in POLICIES.into_iter().enumerate() {
+            nodes.create(&address(policy), policy, "creator", link(handle as u32));
+        }
+        nodes
+    }
+
+    fn deleted(nodes: &mut DynamicNodes, links: bool, messages: bool) -> Vec<LifetimePolicy> {
+        let deleted = nodes.delete(|_| (links, messages));
+        POLICIES
+            .into_iter()
+            .filter(|policy| deleted.contains(&address(*policy)))
+            .collect()
+    }
+
+    #[test]
+    fn nodes_outlive_their_creator_as_their_policy_says() {
+        let mut nodes = nodes();
+        assert!(deleted(&mut nodes, false, false).is_empty());
+        for handle in 0..4 {
+            nodes.detached(link(handle), false);
+        }
+        assert_eq!(
+            deleted(&mut nodes, true, true),
+            [LifetimePolicy::DeleteOnClose]
+        );
+        assert_eq!(
+            deleted(&mut nodes, true, false),
+            [LifetimePolicy::DeleteOnNoMessages]
+        );
+        assert_eq!(
+            deleted(&mut nodes, false, true),
+            [LifetimePolicy::DeleteOnNoLinks]
+        );
+        assert_eq!(
+            deleted(&mut nodes, false, false),
+            [LifetimePolicy::DeleteOnNoLinksOrMessages]
+        );
+        assert!(nodes.is_deleted(&address(LifetimePolicy::DeleteOnClose)));
+        assert!(!nodes.is_deleted("queue"));
+    }
+
+    #[test]
+    fn suspended_creators_are_gone_once_their_terminus_expires() {
+        let mut nodes = nodes();
+        let address = address(LifetimePolicy::DeleteOnClose);
+        nodes.detached(link(0), true);
+        assert!(deleted(&mut nodes, false, false).is_empty());
+
+        // Resumed under another handle, the creator is attached again.
+        nodes.attached(&address, "creator", link(4));
+        nodes.expired(&address, "creator");
+        assert!(deleted(&mut nodes, false, false).is_empty());
+        nodes.detached(link(4), true);
+        // Another link attached to the node doesn't stand in for its creator.
+        nodes.attached(&address, "other", link(5));
+        nodes.detached(link(5), false);
+        assert!(deleted(&mut nodes, false, false).is_empty());
+
+        nodes.expired(&address, "creator");
+        assert_eq!(
+            deleted(&mut nodes, true, true),
+            [LifetimePolicy::DeleteOnClose]
+        );
+        assert!(!nodes.contains(&address));
+    }
+}
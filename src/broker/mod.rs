@@ -0,0 +1,557 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::amqp::messaging::delivery_state::DeliveryState;
+use crate::amqp::messaging::message::{Header, Message};
+use crate::amqp::transport::condition;
+use crate::amqp::transport::connection::Connection;
+use crate::amqp::transport::link::LinkEvent;
+use crate::amqp::transport::performative::PerformativeError;
+use crate::amqp::transport::suspended::Expired;
+use crate::config::Config;
+use crate::frame_bus::ConnectionId;
+
+pub mod dynamic;
+pub mod queue;
+pub mod replica;
+pub mod rpc;
+pub mod store;
+pub mod topic;
+
+use dynamic::DynamicNodes;
+use queue::Queue;
+use replica::{Replica, ReplicaLog};
+use rpc::{Call, Services};
+use store::{Store, Stored};
+use topic::{SubscriptionId, Topics};
+
+// One of our links on any of the connections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LinkId {
+    pub connection_id: ConnectionId,
+    pub channel: u16,
+    pub handle: u32,
+}
+
+// The node a link sending to a receiver takes its messages from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Node {
+    Queue(String),
+    Replica(String),
+    Subscription(SubscriptionId),
+}
+
+// The nodes messages are sent to and received from (see 2.1 Transport). Every address
+// names a queue, which comes into existence as soon as a link or a message refers to it,
+// except for the replicated addresses, which name a replica, and topics. The queues of
+// services have their requests tracked until they are answered. Durable messages in
+// the queues are kept in the store, if there is one, until they are settled, except
+// in the queues of dynamic nodes, which don't outlive their links anyway. The sender
+// of a stored message only hears it was accepted once it is on the disk.
+pub struct Broker {
+    queues: HashMap<String, Queue>,
+    replicas: HashMap<String, Replica>,
+    topics: Topics,
+    services: Services,
+    dynamic: DynamicNodes,
+    // The node each of the links sending to receivers is attached to, and the address
+    // each of the links receiving from senders is attached to.
+    consumers: HashMap<LinkId, Node>,
+    producers: HashMap<LinkId, String>,
+    dead_letter_address: Option<String>,
+    store: Option<Store>,
+    // The outcomes of deliveries held back until the store is synced.
+    unsynced_outcomes: Vec<(ConnectionId, u16, u32, DeliveryState)>,
+}
+
+impl Broker {
+    pub fn new(config: &Config, store: Option<Store>) -> Self {
+        Self {
+            queues: HashMap::new(),
+            replicas: config
+                .replicated_addresses
+                .iter()
+                .map(|address| (address.clone(), Replica::new(config.replica_retention)))
+                .collect(),
+            topics: Topics::new(config.topic_prefix.clone()),
+            services: Services::new(&config.service_addresses, config.call_timeout),
+            dynamic: DynamicNodes::default(),
+            consumers: HashMap::new(),
+            producers: HashMap::new(),
+            dead_letter_address: config.dead_letter_address.clone(),
+            store,
+            unsynced_outcomes: vec![],
+        }
+    }
+
+    // Queues the messages the store kept, as they were when the node stopped.
+    pub async fn recover(&mut self, messages: Vec<Stored>) {
+        for stored in messages {
+            match Message::new(&stored.payload).await {
+                Ok(mut message) => {
+                    message.store_id = Some(stored.id);
+                    self.queue(&stored.address).push(message);
+                }
+                Err(err) => {
+                    println!("Could not recover a stored message, dropping it: {}", err);
+                    self.store_io(|store| store.remove(stored.id));
+                }
+            }
+        }
+    }
+
+    // Called once the frame bus handled an event.
+    pub async fn event_handled(&mut self, connections: &mut HashMap<ConnectionId, Connection>) {
+        self.store_io(|store| store.event_handled());
+        self.settle_synced(connections).await;
+    }
+
+    // Called periodically by the frame bus.
+    pub async fn store_tick(
+        &mut self,
+        now: Instant,
+        connections: &mut HashMap<ConnectionId, Connection>,
+    ) {
+        self.store_io(|store| store.tick(now));
+        self.settle_synced(connections).await;
+    }
+
+    // Sends the outcomes held back once what they are about is on the disk.
+    async fn settle_synced(&mut self, connections: &mut HashMap<ConnectionId, Connection>) {
+        if self.store.as_ref().is_some_and(|store| !store.is_synced()) {
+            return;
+        }
+        for (connection_id, channel, delivery_id, state) in self.unsynced_outcomes.drain(..) {
+            if let Some(connection) = connections.get_mut(&connection_id) {
+                connection.settle(channel, delivery_id, state).await;
+            }
+        }
+    }
+
+    // The logs of the replicated addresses, for the peers they are replicated to.
+    pub fn replica_logs(&self) -> Vec<(String, Arc<Mutex<ReplicaLog>>)> {
+        self.replicas
+            .iter()
+            .map(|(address, replica)| (address.clone(), replica.log()))
+            .collect()
+    }
+
+    // Answers the calls no worker replied to in time with an error, making sure
+    // no worker gets their request any more.
+    pub fn expire_calls(&mut self, now: Instant) {
+        for (service, call, timeout) in self.services.expire(now) {
+            let withdrawn = self
+                .queue(&service)
+                .retain(|message| Call::of_request(message).as_ref() != Some(&call));
+            for message in withdrawn {
+                self.forget(&message);
+            }
+            let reply = call.timed_out(&service, timeout);
+            self.push(&call.reply_to, reply);
+        }
+    }
+
+    // Gives the node of a suspended link that expired what the peer never settled,
+    // the link being gone for good.
+    pub fn expired(&mut self, link: Expired) {
+        self.dynamic.expired(&link.address, &link.name);
+        let node = match self.topics.topic(&link.address) {
+            Some(_) => Node::Subscription(SubscriptionId::Durable {
+                container_id: link.container_id,
+                name: link.name,
+            }),
+            None => self.node(&link.address),
+        };
+        self.requeue_unsettled(&node, link.unsettled);
+    }
+
+    pub async fn handle_event(
+        &mut self,
+        connection_id: ConnectionId,
+        connection: &mut Connection,
+        channel: u16,
+        event: LinkEvent,
+    ) {
+        let link = |handle| LinkId {
+            connection_id,
+            channel,
+            handle,
+        };
+        match event {
+            LinkEvent::Attached {
+                handle,
+                address,
+                name,
+                durable,
+                dynamic,
+                filter,
+            } => {
+                match dynamic {
+                    Some(lifetime_policy) => {
+                        self.dynamic
+                            .create(&address, lifetime_policy, &name, link(handle))
+                    }
+                    None if self.dynamic.is_deleted(&address) => {
+                        let error = PerformativeError::new(
+                            condition::NOT_FOUND,
+                            "The dynamic node is deleted",
+                        );
+                        connection.detach_link(channel, handle, error).await;
+                        return;
+                    }
+                    None => self.dynamic.attached(&address, &name, link(handle)),
+                }
+                let node = match self.topics.topic(&address) {
+                    Some(pattern) => {
+                        let id = match connection.remote_container_id() {
+                            Some(container_id) if durable => SubscriptionId::Durable {
+                                container_id: container_id.to_string(),
+                                name,
+                            },
+                            _ => SubscriptionId::Link(link(handle)),
+                        };
+                        self.topics
+                            .subscribe(id.clone(), pattern, link(handle), filter);
+                        Node::Subscription(id)
+                    }
+                    None => {
+                        let node = self.node(&address);
+                        match node {
+                            Node::Replica(ref address) => {
+                                if let Some(replica) = self.replicas.get_mut(address) {
+                                    replica.attach(link(handle), filter);
+                                }
+                            }
+                            _ => self.queue(&address).attach(link(handle), filter),
+                        }
+                        node
+                    }
+                };
+                self.consumers.insert(link(handle), node);
+            }
+            LinkEvent::TargetAttached { handle, address } => {
+                if self.dynamic.is_deleted(&address) {
+                    let error =
+                        PerformativeError::new(condition::NOT_FOUND, "The dynamic node is deleted");
+                    connection.detach_link(channel, handle, error).await;
+                    return;
+                }
+                self.producers.insert(link(handle), address);
+            }
+            LinkEvent::Detached {
+                handle,
+                closed,
+                suspended,
+                unsettled,
+            } => {
+                self.dynamic.detached(link(handle), suspended);
+                self.producers.remove(&link(handle));
+                // The node may have been deleted meanwhile.
+                let Some(node) = self.consumers.remove(&link(handle)) else {
+                    for message in unsettled {
+                        self.forget(&message);
+                    }
+                    return;
+                };
+                let requeue = match node {
+                    Node::Queue(ref address) => {
+                        self.queue(address).detach(link(handle));
+                        true
+                    }
+                    // A replica has them still.
+                    Node::Replica(ref address) => {
+                        if let Some(replica) = self.replicas.get_mut(address) {
+                            replica.detach(link(handle));
+                        }
+                        false
+                    }
+                    Node::Subscription(ref id) => self.topics.unsubscribe(id, link(handle), closed),
+                };
+                if requeue {
+                    self.requeue_unsettled(&node, unsettled);
+                }
+            }
+            LinkEvent::Delivery {
+                delivery_id,
+                settled,
+                address,
+                payload,
+            } => {
+                let state = match Message::new(&payload).await {
+                    Ok(message) => self.route(address, message),
+                    Err(err) => DeliveryState::Rejected {
+                        error: Some(PerformativeError::new(condition::DECODE_ERROR, err)),
+                    },
+                };
+                if settled {
+                    return;
+                }
+                if self.store.as_ref().is_some_and(|store| !store.is_synced()) {
+                    self.unsynced_outcomes
+                        .push((connection_id, channel, delivery_id, state));
+                } else {
+                    connection.settle(channel, delivery_id, state).await;
+                }
+            }
+            LinkEvent::Outcome {
+                handle,
+                message,
+                state,
+            } => match self.consumers.get(&link(handle)).cloned() {
+                Some(node) => self.outcome(link(handle), &node, *message, state),
+                None => self.forget(&message),
+            },
+        }
+    }
+
+    // Sends the queued messages to whichever links have credit for them.
+    pub async fn dispatch(&mut self, connections: &mut HashMap<ConnectionId, Connection>) {
+        self.delete_dynamic_nodes(connections).await;
+        for queue in self.queues.values_mut() {
+            queue.dispatch(connections).await;
+        }
+        for replica in self.replicas.values_mut() {
+            replica.dispatch(connections).await;
+        }
+        self.topics.dispatch(connections).await;
+    }
+
+    // Links without an address of their own send messages to the address in their
+    // to field, i.e. to whichever node the message asks for.
+    fn route(&mut self, address: Option<String>, message: Message) -> DeliveryState {
+        let Some(address) = address.or_else(|| {
+            message
+                .properties
+                .as_ref()
+                .and_then(|properties| properties.to.clone())
+        }) else {
+            return DeliveryState::Rejected {
+                error: Some(PerformativeError::new(
+                    condition::NOT_FOUND,
+                    "The message has no address to be routed to",
+                )),
+            };
+        };
+        if self.dynamic.is_deleted(&address) {
+            return DeliveryState::Rejected {
+                error: Some(PerformativeError::new(
+                    condition::NOT_FOUND,
+                    "The dynamic node is deleted",
+                )),
+            };
+        }
+        // The caller got an answer already when its call timed out.
+        if self.services.routed(&address, &message, Instant::now()) {
+            self.push(&address, message);
+        } else {
+            self.forget(&message);
+        }
+        DeliveryState::Accepted
+    }
+
+    // Deletes the dynamic nodes whose lifetime is over, closing the links still
+    // attached to them, whether they receive from the node or send to it.
+    async fn delete_dynamic_nodes(&mut self, connections: &mut HashMap<ConnectionId, Connection>) {
+        let deleted = self.dynamic.delete(|address| {
+            let node = Node::Queue(address.to_string());
+            (
+                self.consumers.values().any(|consumer| *consumer == node)
+                    || self.producers.values().any(|producer| producer == address),
+                self.queues
+                    .get(address)
+                    .is_some_and(|queue| !queue.is_empty()),
+            )
+        });
+        for address in deleted {
+            if let Some(mut queue) = self.queues.remove(&address) {
+                for message in queue.retain(|_| false) {
+                    self.forget(&message);
+                }
+            }
+            let node = Node::Queue(address.clone());
+            let mut links: Vec<LinkId> = self
+                .consumers
+                .iter()
+                .filter(|(_, consumer)| **consumer == node)
+                .map(|(link, _)| *link)
+                .collect();
+            links.extend(
+                self.producers
+                    .iter()
+                    .filter(|(_, producer)| **producer == address)
+                    .map(|(link, _)| *link),
+            );
+            for link in links {
+                self.consumers.remove(&link);
+                self.producers.remove(&link);
+                if let Some(connection) = connections.get_mut(&link.connection_id) {
+                    let error = PerformativeError::new(
+                        condition::RESOURCE_DELETED,
+                        "The dynamic node is deleted",
+                    );
+                    connection
+                        .detach_link(link.channel, link.handle, error)
+                        .await;
+                }
+            }
+        }
+    }
+
+    // Applies the outcome a receiver settled a message from the node with
+    // (see 3.4 Delivery State). Every receiver of a replica gets a copy of its own,
+    // whatever becomes of it.
+    fn outcome(&mut self, link: LinkId, node: &Node, mut message: Message, state: DeliveryState) {
+        if let Node::Replica(_) = node {
+            return;
+        }
+        match state {
+            DeliveryState::Accepted | DeliveryState::Received { .. } => self.forget(&message),
+            // Released messages were never processed, so nothing about them changes.
+            DeliveryState::Released => self.requeue(node, message, None),
+            DeliveryState::Modified {
+                delivery_failed,
+                undeliverable_here,
+                message_annotations,
+            } => {
+                if delivery_failed {
+                    message
+                        .header
+                        .get_or_insert_with(Header::default)
+                        .delivery_count += 1;
+                }
+                message.message_annotations.extend(message_annotations);
+                self.requeue(node, message, undeliverable_here.then_some(link));
+            }
+            DeliveryState::Rejected { error } => {
+                if let Some(error) = error {
+                    println!(
+                        "Message from {:?} rejected with {}: {:?}",
+                        node,
+                        String::from_utf8_lossy(&error.condition),
+                        error.description
+                    );
+                }
+                // The message is stored again at the dead letter queue, if it goes there.
+                self.forget(&message);
+                message.store_id = None;
+                // Messages rejected from the dead letter queue itself are discarded.
+                match self.dead_letter_address.clone() {
+                    Some(dead_letter_address)
+                        if *node != Node::Queue(dead_letter_address.clone()) =>
+                    {
+                        self.push(&dead_letter_address, message)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // Messages sent to a topic go to its subscriptions, those sent to a deleted
+    // dynamic node nowhere.
+    fn push(&mut self, address: &str, mut message: Message) {
+        if self.dynamic.is_deleted(address) {
+            self.forget(&message);
+            return;
+        }
+        if let Some(topic) = self.topics.topic(address) {
+            self.topics.publish(topic, message);
+            return;
+        }
+        match self.replicas.get_mut(address) {
+            Some(replica) => replica.push(message),
+            None => {
+                self.store(address, &mut message);
+                self.queue(address).push(message);
+            }
+        }
+    }
+
+    // Keeps a durable message queued at the address in the store, if it isn't yet.
+    fn store(&mut self, address: &str, message: &mut Message) {
+        if message.store_id.is_some()
+            || !message.header.as_ref().is_some_and(|header| header.durable)
+            || self.store.is_none()
+            || self.dynamic.contains(address)
+        {
+            return;
+        }
+        let payload = match message.encode() {
+            Ok(payload) => payload,
+            Err(err) => {
+                println!("Could not encode a message, not storing it: {}", err);
+                return;
+            }
+        };
+        self.store_io(|store| {
+            message.store_id = Some(store.append(address, &payload)?);
+            Ok(())
+        });
+    }
+
+    // The message is done with, so it isn't recovered any more.
+    fn forget(&mut self, message: &Message) {
+        if let Some(id) = message.store_id {
+            self.store_io(|store| store.remove(id));
+        }
+    }
+
+    // Store failures don't stop the node, which keeps the messages in memory anyway.
+    fn store_io(&mut self, io: impl FnOnce(&mut Store) -> std::io::Result<()>) {
+        if let Some(store) = self.store.as_mut()
+            && let Err(err) = io(store)
+        {
+            println!("Store failed: {}", err);
+        }
+    }
+
+    // The queue or replica at an address that doesn't name a topic.
+    fn node(&self, address: &str) -> Node {
+        if self.replicas.contains_key(address) {
+            Node::Replica(address.to_string())
+        } else {
+            Node::Queue(address.to_string())
+        }
+    }
+
+    // The queue messages given back to the node go to, if it has one.
+    fn node_queue(&mut self, node: &Node) -> Option<&mut Queue> {
+        match node {
+            Node::Queue(address) => Some(self.queue(address)),
+            Node::Replica(_) => None,
+            Node::Subscription(id) => self.topics.queue(id),
+        }
+    }
+
+    // Puts a message a receiver gave back in front of the others in the queue of its
+    // node, unless it is a request whose call timed out meanwhile.
+    fn requeue(&mut self, node: &Node, message: Message, undeliverable: Option<LinkId>) {
+        if let Node::Queue(address) = node
+            && self.services.withdrawn(address, &message)
+        {
+            self.forget(&message);
+            return;
+        }
+        if let Some(queue) = self.node_queue(node) {
+            queue.requeue(message, undeliverable);
+        }
+    }
+
+    // Gives the node back the messages a receiver never settled. The receiver may or
+    // may not have processed them, so they count as failed delivery attempts.
+    // Requeueing them in reverse keeps them in order.
+    fn requeue_unsettled(&mut self, node: &Node, messages: Vec<Message>) {
+        for mut message in messages.into_iter().rev() {
+            message
+                .header
+                .get_or_insert_with(Header::default)
+                .delivery_count += 1;
+            self.requeue(node, message, None);
+        }
+    }
+
+    fn queue(&mut self, address: &str) -> &mut Queue {
+        self.queues.entry(address.to_string()).or_default()
+    }
+}
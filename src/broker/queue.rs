@@ -0,0 +1,367 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::amqp::messaging::filter::Filter;
+use crate::amqp::messaging::message::Message;
+use crate::amqp::transport::connection::Connection;
+use crate::broker::LinkId;
+use crate::frame_bus::ConnectionId;
+
+// A queued message, along with the links whose receivers declared it undeliverable
+// there (see 3.4.5 Modified).
+struct Entry {
+    message: Message,
+    undeliverable: Vec<LinkId>,
+}
+
+// Buffers the messages sent to an address until a link attached to it has credit
+// for them, each message going to one link only, with the links taking turns.
+// A link whose source has a filter only gets the messages passing it, the others
+// waiting for a link they pass at.
+#[derive(Default)]
+pub struct Queue {
+    messages: VecDeque<Entry>,
+    consumers: Vec<(LinkId, Filter)>,
+    next_consumer: usize,
+}
+
+impl Queue {
+    pub fn attach(&mut self, link: LinkId, filter: Filter) {
+        self.consumers.push((link, filter));
+    }
+
+    // The links after the one leaving move up a place, and so does whose turn it is.
+    pub fn detach(&mut self, link: LinkId) {
+        let Some(position) = self
+            .consumers
+            .iter()
+            .position(|(consumer, _)| *consumer == link)
+        else {
+            return;
+        };
+        self.consumers.remove(position);
+        if position < self.next_consumer {
+            self.next_consumer -= 1;
+        }
+    }
+
+    pub fn push(&mut self, message: Message) {
+        self.messages.push_back(Entry {
+            message,
+            undeliverable: vec![],
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    // Puts a message a receiver gave back in front of the others, where it came from.
+    pub fn requeue(&mut self, message: Message, undeliverable: Option<LinkId>) {
+        self.messages.push_front(Entry {
+            message,
+            undeliverable: undeliverable.into_iter().collect(),
+        });
+    }
+
+    // Drops the queued messages that aren't wanted any more, returning them.
+    pub fn retain(&mut self, keep: impl Fn(&Message) -> bool) -> Vec<Message> {
+        let (kept, dropped) = std::mem::take(&mut self.messages)
+            .into_iter()
+            .partition(|entry| keep(&entry.message));
+        self.messages = kept;
+        dropped
+            .into_iter()
+            .map(|entry: Entry| entry.message)
+            .collect()
+    }
+
+    // Sends the queued messages for as long as the links have credit for them,
+    // then uses up the credit of draining links.
+    pub async fn dispatch(&mut self, connections: &mut HashMap<ConnectionId, Connection>) {
+        let mut index = 0;
+        while index < self.messages.len() && !self.consumers.is_empty() {
+            let consumers = self.consumers.len();
+            let consumer = (0..consumers)
+                .map(|offset| (self.next_consumer + offset) % consumers)
+                .find(|consumer| {
+                    let (link, filter) = &self.consumers[*consumer];
+                    let entry = &self.messages[index];
+                    !entry.undeliverable.contains(link)
+                        && filter.matches(&entry.message)
+                        && can_send(connections, *link)
+                });
+            let Some(consumer) = consumer else {
+                // Later messages may still go to the links this one is undeliverable at,
+                // or doesn't pass the filter of.
+                if self
+                    .consumers
+                    .iter()
+                    .any(|(link, _)| can_send(connections, *link))
+                {
+                    index += 1;
+                    continue;
+                }
+                break;
+            };
+            let link = self.consumers[consumer].0;
+            self.next_consumer = (consumer + 1) % consumers;
+            let Some(entry) = self.messages.remove(index) else {
+                break;
+            };
+            let Some(connection) = connections.get_mut(&link.connection_id) else {
+                self.messages.insert(index, entry);
+                break;
+            };
+            if let Err(message) = connection
+                .send_message(link.channel, link.handle, entry.message)
+                .await
+            {
+                self.messages.insert(
+                    index,
+                    Entry {
+                        message,
+                        undeliverable: entry.undeliverable,
+                    },
+                );
+                break;
+            }
+        }
+        for (link, _) in self.consumers.iter() {
+            if let Some(connection) = connections.get_mut(&link.connection_id) {
+                connection.drain_credit(link.channel, link.handle).await;
+            }
+        }
+    }
+}
+
+fn can_send(connections: &HashMap<ConnectionId, Connection>, link: LinkId) -> bool {
+    connections
+        .get(&link.connection_id)
+        .is_some_and(|connection| connection.can_send(link.channel, link.handle))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::sync::mpsc::{self, Receiver};
+
+    use super::*;
+    use crate::amqp::messaging::message::Properties;
+    use crate::amqp::messaging::terminus::Source;
+    use crate::amqp::transport::connection::get_performative_and_payload;
+    use crate::amqp::transport::performative::Performative;
+    use crate::amqp::transport::suspended::SuspendedLinks;
+    use crate::amqp::types::constructor::Constructor;
+    use crate::amqp::types::descriptor::Descriptor;
+    use crate::amqp::types::frame::Frame;
+    use crate::amqp::types::primitive::Primitive;
+    use crate::config::Config;
+
+    // An open connection with a link we send on, which has credit for ten messages.
+    async fn consumer(
+        connections: &mut HashMap<ConnectionId, Connection>,
+        connection_id: ConnectionId,
+    ) -> (LinkId, Receiver<Frame>) {
+        let (client_tx, mut client_rx) = mpsc::channel(64);
+        let mut connection = Connection::new(
+            connection_id,
+            client_tx,
+            Arc::new(Config::default()),
+            Arc::new(Mutex::new(SuspendedLinks::default())),
+        );
+        connection.header_received().unwrap();
+        connection.header_sent().unwrap();
+        for performative in [
+            Performative::Open {
+                container_id: String::from("client"),
+                hostname: None,
+                max_frame_size: 4096,
+                channel_max: 0,
+                idle_time_out: None,
+                outgoing_locales: vec![],
+                incoming_locales: vec![],
+                offered_capabilities: vec![],
+                desired_capabilities: vec![],
+                properties: HashMap::new(),
+            },
+            Performative::Begin {
+                remote_channel: None,
+                next_outgoing_id: 0,
+                incoming_window: 100,
+                outgoing_window: 100,
+                handle_max: 0,
+                offered_capabilities: vec![],
+                desired_capabilities: vec![],
+                properties: HashMap::new(),
+            },
+            Performative::Attach {
+                name: String::from("receiver"),
+                handle: 0,
+                role: true,
+                snd_settle_mode: 1,
+                rcv_settle_mode: 0,
+                source: Some(Box::new(Source {
+                    address: Some(String::from("queue")),
+                    ..Source::default()
+                })),
+                target: None,
+                unsettled: HashMap::new(),
+                incomplete_unsettled: false,
+                initial_delivery_count: None,
+                max_message_size: None,
+                offered_capabilities: vec![],
+                desired_capabilities: vec![],
+                properties: HashMap::new(),
+            },
+            Performative::Flow {
+                next_incoming_id: Some(0),
+                incoming_window: 100,
+                next_outgoing_id: 0,
+                outgoing_window: 100,
+                handle: Some(0),
+                delivery_count: Some(0),
+                link_credit: Some(10),
+                available: None,
+                drain: false,
+                echo: false,
+                properties: HashMap::new(),
+            },
+        ] {
+            connection
+                .handle_frame(performative.to_frame(0, &[]).unwrap())
+                .await;
+        }
+        while client_rx.try_recv().is_ok() {}
+        connections.insert(connection_id, connection);
+        let link = LinkId {
+            connection_id,
+            channel: 0,
+            handle: 0,
+        };
+        (link, client_rx)
+    }
+
+    fn message(subject: &str) -> Message {
+        Message {
+            properties: Some(Properties {
+                subject: Some(subject.to_string()),
+                ..Properties::default()
+            }),
+            ..Message::default()
+        }
+    }
+
+    fn selector(selector: &str) -> Filter {
+        let mut filter_set = HashMap::from([(
+            Constructor::PrimitiveType(Primitive::Symbol(b"selector".to_vec())),
+            Descriptor::SelectorFilter.describe(Primitive::String(selector.to_string())),
+        )]);
+        Filter::from_filter_set(&mut filter_set).unwrap()
+    }
+
+    // The subjects of the messages sent to the client, in order.
+    async fn received(client_rx: &mut Receiver<Frame>) -> Vec<String> {
+        let mut subjects = vec![];
+        while let Ok(frame) = client_rx.try_recv() {
+            let (performative, payload) = get_performative_and_payload(&frame.frame_body)
+                .await
+                .unwrap();
+            if let Performative::Transfer { .. } = performative {
+                let message = Message::new(&payload).await.unwrap();
+                subjects.extend(message.properties.and_then(|properties| properties.subject));
+            }
+        }
+        subjects
+    }
+
+    #[tokio::test]
+    async fn links_take_turns() {
+        let mut connections = HashMap::new();
+        let (first, mut first_rx) = consumer(&mut connections, 1).await;
+        let (second, mut second_rx) = consumer(&mut connections, 2).await;
+        let mut queue = Queue::default();
+        queue.attach(first, Filter::default());
+        queue.attach(second, Filter::default());
+        for subject in ["a", "b", "c", "d", "e"] {
+            queue.push(message(subject));
+        }
+        queue.dispatch(&mut connections).await;
+        assert!(queue.is_empty());
+        assert_eq!(received(&mut first_rx).await, ["a", "c", "e"]);
+        assert_eq!(received(&mut second_rx).await, ["b", "d"]);
+    }
+
+    #[tokio::test]
+    async fn filtered_links_only_get_what_passes() {
+        let mut connections = HashMap::new();
+        let (first, mut first_rx) = consumer(&mut connections, 1).await;
+        let (second, mut second_rx) = consumer(&mut connections, 2).await;
+        let mut queue = Queue::default();
+        queue.attach(first, selector("JMSType = 'a'"));
+        queue.attach(second, Filter::default());
+        for subject in ["b", "a", "a", "b"] {
+            queue.push(message(subject));
+        }
+        queue.dispatch(&mut connections).await;
+        assert!(queue.is_empty());
+        // The b skips the first link, whose turn it then still is for the a.
+        assert_eq!(received(&mut first_rx).await, ["a"]);
+        assert_eq!(received(&mut second_rx).await, ["b", "a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn messages_no_filter_passes_wait() {
+        let mut connections = HashMap::new();
+        let (link, mut client_rx) = consumer(&mut connections, 1).await;
+        let mut queue = Queue::default();
+        queue.attach(link, selector("JMSType = 'a'"));
+        for subject in ["b", "a", "c", "a"] {
+            queue.push(message(subject));
+        }
+        queue.dispatch(&mut connections).await;
+        assert_eq!(received(&mut client_rx).await, ["a", "a"]);
+        let waiting = queue.retain(|_| false);
+        let subjects: Vec<_> = waiting
+            .into_iter()
+            .filter_map(|message| message.properties?.subject)
+            .collect();
+        assert_eq!(subjects, ["b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn undeliverable_messages_go_to_other_links() {
+        let mut connections = HashMap::new();
+        let (first, mut first_rx) = consumer(&mut connections, 1).await;
+        let (second, mut second_rx) = consumer(&mut connections, 2).await;
+        let mut queue = Queue::default();
+        queue.attach(first, Filter::default());
+        queue.attach(second, Filter::default());
+        queue.requeue(message("a"), Some(first));
+        queue.dispatch(&mut connections).await;
+        assert!(received(&mut first_rx).await.is_empty());
+        assert_eq!(received(&mut second_rx).await, ["a"]);
+    }
+
+    #[tokio::test]
+    async fn detaching_keeps_the_turn() {
+        let mut connections = HashMap::new();
+        let (first, mut first_rx) = consumer(&mut connections, 1).await;
+        let (second, mut second_rx) = consumer(&mut connections, 2).await;
+        let (third, mut third_rx) = consumer(&mut connections, 3).await;
+        let mut queue = Queue::default();
+        for link in [first, second, third] {
+            queue.attach(link, Filter::default());
+        }
+        queue.push(message("a"));
+        queue.dispatch(&mut connections).await;
+        // It is the second link's turn, which stays so as the first one leaves.
+        queue.detach(first);
+        queue.push(message("b"));
+        queue.push(message("c"));
+        queue.dispatch(&mut connections).await;
+        assert_eq!(received(&mut first_rx).await, ["a"]);
+        assert_eq!(received(&mut second_rx).await, ["b"]);
+        assert_eq!(received(&mut third_rx).await, ["c"]);
+    }
+}
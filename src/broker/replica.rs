@@ -0,0 +1,137 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+use crate::amqp::messaging::filter::Filter;
+use crate::amqp::messaging::message::Message;
+use crate::amqp::transport::connection::Connection;
+use crate::broker::LinkId;
+use crate::frame_bus::ConnectionId;
+
+// The messages accepted on a replicated address, numbered in the order they came in.
+// Only the last of them are retained, for the receivers and peers that fell behind to
+// catch up on.
+pub struct ReplicaLog {
+    // The number of the oldest message retained.
+    first: u64,
+    messages: VecDeque<Message>,
+    retention: usize,
+    appended: Arc<Notify>,
+}
+
+impl ReplicaLog {
+    pub fn new(retention: usize) -> Self {
+        Self {
+            first: 0,
+            messages: VecDeque::new(),
+            retention: retention.max(1),
+            appended: Arc::new(Notify::new()),
+        }
+    }
+
+    // The number the next message will get.
+    pub fn end(&self) -> u64 {
+        self.first + self.messages.len() as u64
+    }
+
+    pub fn push(&mut self, message: Message) {
+        self.messages.push_back(message);
+        if self.messages.len() > self.retention {
+            self.messages.pop_front();
+            self.first += 1;
+        }
+        self.appended.notify_waiters();
+    }
+
+    // The message with the number, or the oldest one retained if it is gone already,
+    // along with its number.
+    pub fn get(&self, number: u64) -> Option<(u64, &Message)> {
+        let number = number.max(self.first);
+        self.messages
+            .get((number - self.first) as usize)
+            .map(|message| (number, message))
+    }
+
+    // Woken up whenever a message is appended.
+    pub fn appended(&self) -> Arc<Notify> {
+        self.appended.clone()
+    }
+}
+
+// A node copying every message sent to its address to every link attached to it,
+// each link going through the log at its own pace.
+pub struct Replica {
+    log: Arc<Mutex<ReplicaLog>>,
+    // The number of the next message for each link, and the filter of its source.
+    consumers: HashMap<LinkId, (u64, Filter)>,
+}
+
+impl Replica {
+    pub fn new(retention: usize) -> Self {
+        Self {
+            log: Arc::new(Mutex::new(ReplicaLog::new(retention))),
+            consumers: HashMap::new(),
+        }
+    }
+
+    // The log, shared with the links replicating it to the peers.
+    pub fn log(&self) -> Arc<Mutex<ReplicaLog>> {
+        self.log.clone()
+    }
+
+    // Receivers get the messages sent after they attached.
+    pub fn attach(&mut self, link: LinkId, filter: Filter) {
+        let end = self.log.lock().unwrap().end();
+        self.consumers.insert(link, (end, filter));
+    }
+
+    pub fn detach(&mut self, link: LinkId) {
+        self.consumers.remove(&link);
+    }
+
+    pub fn push(&mut self, message: Message) {
+        self.log.lock().unwrap().push(message);
+    }
+
+    // Sends every link the messages it hasn't had yet for as long as it has credit,
+    // passing over those its filter leaves out, then uses up the credit of draining links.
+    pub async fn dispatch(&mut self, connections: &mut HashMap<ConnectionId, Connection>) {
+        for (link, (next, filter)) in self.consumers.iter_mut() {
+            let Some(connection) = connections.get_mut(&link.connection_id) else {
+                continue;
+            };
+            while connection.can_send(link.channel, link.handle) {
+                let Some((number, message)) = self
+                    .log
+                    .lock()
+                    .unwrap()
+                    .get(*next)
+                    .map(|(number, message)| (number, message.clone()))
+                else {
+                    break;
+                };
+                if number > *next {
+                    println!(
+                        "Link {:?} fell behind, missing {} replicated messages",
+                        link,
+                        number - *next
+                    );
+                }
+                if !filter.matches(&message) {
+                    *next = number + 1;
+                    continue;
+                }
+                if connection
+                    .send_message(link.channel, link.handle, message)
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+                *next = number + 1;
+            }
+            connection.drain_credit(link.channel, link.handle).await;
+        }
+    }
+}
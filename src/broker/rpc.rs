@@ -0,0 +1,292 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::amqp::messaging::message::{Body, Message, Properties};
+use crate::amqp::types::constructor::Constructor;
+use crate::amqp::types::primitive::Primitive;
+
+// The error-condition of the reply a caller gets when no worker answered in time.
+const CALL_TIMED_OUT: &[u8] = b"uexrs:call-timed-out";
+
+// A request a caller waits on the reply to, which goes to its reply-to address with
+// the correlation-id of the request, or its message-id if it has none.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Call {
+    pub reply_to: String,
+    pub correlation_id: Constructor,
+}
+
+impl Call {
+    pub fn of_request(message: &Message) -> Option<Self> {
+        let properties = message.properties.as_ref()?;
+        let correlation_id = match properties.correlation_id {
+            Constructor::PrimitiveType(Primitive::Null) => properties.message_id.clone(),
+            ref correlation_id => correlation_id.clone(),
+        };
+        if correlation_id == Constructor::PrimitiveType(Primitive::Null) {
+            return None;
+        }
+        Some(Call {
+            reply_to: properties.reply_to.clone()?,
+            correlation_id,
+        })
+    }
+
+    // How long the caller waits on the reply: until the request expires by its ttl or
+    // absolute-expiry-time, whichever comes first, or for the default timeout.
+    fn timeout(message: &Message, default: Duration) -> Duration {
+        let ttl = message.header.as_ref().and_then(|header| header.ttl);
+        let expiry = message
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.absolute_expiry_time)
+            .map(|expiry| {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as i64;
+                Duration::from_millis(expiry.saturating_sub(now).max(0) as u64)
+            });
+        match (ttl, expiry) {
+            (Some(ttl), Some(expiry)) => ttl.min(expiry),
+            (ttl, expiry) => ttl.or(expiry).unwrap_or(default),
+        }
+    }
+
+    fn of_reply(address: &str, message: &Message) -> Option<Self> {
+        Some(Call {
+            reply_to: address.to_string(),
+            correlation_id: message.properties.as_ref()?.correlation_id.clone(),
+        })
+    }
+
+    // What the caller gets when no worker answered in time.
+    pub fn timed_out(&self, service: &str, timeout: Duration) -> Message {
+        Message {
+            properties: Some(Properties {
+                to: Some(self.reply_to.clone()),
+                subject: Some(String::from("error")),
+                correlation_id: self.correlation_id.clone(),
+                ..Properties::default()
+            }),
+            application_properties: HashMap::from([(
+                String::from("error-condition"),
+                Constructor::PrimitiveType(Primitive::Symbol(CALL_TIMED_OUT.to_vec())),
+            )]),
+            body: Body::AmqpValue(Primitive::String(format!(
+                "No worker of {} answered within {} ms",
+                service,
+                timeout.as_millis()
+            ))),
+            ..Message::default()
+        }
+    }
+}
+
+// The addresses of the services called with requests that are load-balanced over the
+// workers attached to them like any queue, and the calls waiting on their reply.
+pub struct Services {
+    addresses: HashSet<String>,
+    // How long a caller waits on a request that doesn't say when it expires.
+    timeout: Duration,
+    // The service, deadline and timeout of every call by the reply it waits on.
+    pending: HashMap<Call, (String, Instant, Duration)>,
+    // The calls that timed out, until when a late reply to them is dropped, since
+    // their caller got the timeout error as its answer already.
+    timed_out: HashMap<Call, Instant>,
+}
+
+impl Services {
+    pub fn new(addresses: &[String], timeout: Duration) -> Self {
+        Self {
+            addresses: addresses.iter().cloned().collect(),
+            timeout,
+            pending: HashMap::new(),
+            timed_out: HashMap::new(),
+        }
+    }
+
+    // Keeps track of a message sent to the address, which may be a request to a service
+    // or the reply to one, returning whether it goes on to the address. A reply to
+    // a call that timed out doesn't.
+    pub fn routed(&mut self, address: &str, message: &Message, now: Instant) -> bool {
+        if self.addresses.contains(address) {
+            if let Some(call) = Call::of_request(message) {
+                let timeout = Call::timeout(message, self.timeout);
+                // A caller asking again waits on the reply to its new request.
+                self.timed_out.remove(&call);
+                self.pending
+                    .insert(call, (address.to_string(), now + timeout, timeout));
+            }
+        } else if let Some(call) = Call::of_reply(address, message) {
+            if self.timed_out.remove(&call).is_some() {
+                return false;
+            }
+            self.pending.remove(&call);
+        }
+        true
+    }
+
+    // Whether the message is a request to the service whose call timed out already.
+    pub fn withdrawn(&self, address: &str, message: &Message) -> bool {
+        self.addresses.contains(address)
+            && Call::of_request(message).is_some_and(|call| !self.pending.contains_key(&call))
+    }
+
+    // The calls that weren't answered in time, along with their service and timeout.
+    // A worker still busy with one has as long as the default timeout again to reply
+    // before its reply is no longer recognised as late.
+    pub fn expire(&mut self, now: Instant) -> Vec<(String, Call, Duration)> {
+        self.timed_out.retain(|_, until| *until > now);
+        let expired: Vec<Call> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, deadline, _))| *deadline <= now)
+            .map(|(call, _)| call.clone())
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|call| {
+                let (service, _, timeout) = self.pending.remove(&call)?;
+                self.timed_out.insert(call.clone(), now + self.timeout);
+                Some((service, call, timeout))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amqp::messaging::message::Header;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn id(id: u64) -> Constructor {
+        Constructor::PrimitiveType(Primitive::ULong(id))
+    }
+
+    fn request(message_id: u64) -> Message {
+        Message {
+            properties: Some(Properties {
+                message_id: id(message_id),
+                reply_to: Some(String::from("reply")),
+                ..Properties::default()
+            }),
+            ..Message::default()
+        }
+    }
+
+    fn reply(correlation_id: u64) -> Message {
+        Message {
+            properties: Some(Properties {
+                correlation_id: id(correlation_id),
+                ..Properties::default()
+            }),
+            ..Message::default()
+        }
+    }
+
+    fn call(correlation_id: u64) -> Call {
+        Call {
+            reply_to: String::from("reply"),
+            correlation_id: id(correlation_id),
+        }
+    }
+
+    fn services() -> Services {
+        Services::new(&[String::from("service")], 30 * SECOND)
+    }
+
+    #[test]
+    fn replies_answer_the_call_they_correlate_to() {
+        let mut services = services();
+        let now = Instant::now();
+        assert!(services.routed("service", &request(1), now));
+        // The correlation-id of a request is what its reply is matched by, if it has one.
+        let mut correlated = request(2);
+        correlated.properties.as_mut().unwrap().correlation_id = id(3);
+        assert!(services.routed("service", &correlated, now));
+        // Requests without a reply-to and messages to other addresses aren't calls.
+        let mut no_reply_to = request(4);
+        no_reply_to.properties.as_mut().unwrap().reply_to = None;
+        assert!(services.routed("service", &no_reply_to, now));
+        assert!(services.routed("queue", &request(5), now));
+        assert_eq!(
+            services.pending.keys().collect::<HashSet<_>>(),
+            HashSet::from([&call(1), &call(3)])
+        );
+        assert!(!services.withdrawn("service", &request(1)));
+
+        assert!(services.routed("reply", &reply(1), now));
+        assert!(services.routed("reply", &reply(3), now));
+        assert!(services.pending.is_empty());
+        assert!(services.expire(now + 60 * SECOND).is_empty());
+    }
+
+    #[test]
+    fn unanswered_calls_expire_and_their_late_replies_are_dropped() {
+        let mut services = services();
+        let now = Instant::now();
+        services.routed("service", &request(1), now);
+        services.routed("service", &request(2), now + 10 * SECOND);
+        assert!(services.expire(now + 29 * SECOND).is_empty());
+        assert_eq!(
+            services.expire(now + 30 * SECOND),
+            [(String::from("service"), call(1), 30 * SECOND)]
+        );
+        // The request a worker hasn't taken yet is withdrawn from the service.
+        assert!(services.withdrawn("service", &request(1)));
+        assert!(!services.withdrawn("service", &request(2)));
+        assert!(!services.routed("reply", &reply(1), now + 31 * SECOND));
+
+        let expired = services.expire(now + 40 * SECOND);
+        assert_eq!(expired, [(String::from("service"), call(2), 30 * SECOND)]);
+        // As long again as the default timeout later, a reply is no longer taken for late.
+        services.expire(now + 70 * SECOND);
+        assert!(services.routed("reply", &reply(2), now + 70 * SECOND));
+
+        // A caller asking again waits on the reply to its new request.
+        services.routed("service", &request(1), now + 80 * SECOND);
+        services.expire(now + 80 * SECOND);
+        assert!(!services.withdrawn("service", &request(1)));
+        assert!(services.routed("reply", &reply(1), now + 81 * SECOND));
+        assert!(services.pending.is_empty());
+    }
+
+    #[test]
+    fn requests_expiring_sooner_are_waited_on_for_less() {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let expiring = |ttl: Option<u64>, absolute_expiry_time: Option<i64>| {
+            let mut message = request(1);
+            message.header = Some(Header {
+                ttl: ttl.map(Duration::from_secs),
+                ..Header::default()
+            });
+            message.properties.as_mut().unwrap().absolute_expiry_time = absolute_expiry_time;
+            Call::timeout(&message, 30 * SECOND)
+        };
+        assert_eq!(expiring(None, None), 30 * SECOND);
+        assert_eq!(expiring(Some(5), None), 5 * SECOND);
+        assert_eq!(expiring(Some(5), Some(now_ms + 10_000)), 5 * SECOND);
+        let timeout = expiring(None, Some(now_ms + 10_000));
+        assert!(timeout <= 10 * SECOND && timeout > 9 * SECOND);
+        assert_eq!(expiring(Some(5), Some(now_ms - 1_000)), Duration::ZERO);
+
+        let mut services = services();
+        let now = Instant::now();
+        let mut message = request(1);
+        message.header = Some(Header {
+            ttl: Some(5 * SECOND),
+            ..Header::default()
+        });
+        services.routed("service", &message, now);
+        assert_eq!(
+            services.expire(now + 5 * SECOND),
+            [(String::from("service"), call(1), 5 * SECOND)]
+        );
+    }
+}
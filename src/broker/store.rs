@@ -0,0 +1,465 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+
+// Record kinds: a message queued at an address, and a message that is done with.
+const ENQUEUE: u8 = 1;
+const REMOVE: u8 = 2;
+// The length and checksum in front of every record.
+const RECORD_HEADER_SIZE: usize = 8;
+
+// When the records written reach the disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SyncPolicy {
+    // Every record is synced as soon as it is written.
+    Always,
+    // The records are synced together once the frame bus handled the event that wrote them.
+    Batched,
+    // The records are synced every so often, which is how long the outcomes of the
+    // messages that came in meanwhile wait.
+    Interval(Duration),
+}
+
+// A record as read back from a segment.
+struct Record {
+    kind: u8,
+    id: u64,
+    size: u64,
+    body: Vec<u8>,
+}
+
+// A durable message found in the store at startup.
+pub struct Stored {
+    pub id: u64,
+    pub address: String,
+    pub payload: Vec<u8>,
+}
+
+// An append-only write-ahead log of the durable messages in the queues, split into
+// numbered segment files. Every message queued is appended to the last segment and
+// every message settled is recorded as removed, so replaying the segments in order
+// gives back the queued messages, including those delivered but never settled.
+// A segment mostly settled is dropped after rewriting the messages still queued in it
+// into the last one, but only once the older segments holding messages its removals
+// settled are gone, so a removal is never lost while the message it removes isn't.
+pub struct Store {
+    dir: PathBuf,
+    sync_policy: SyncPolicy,
+    segment_size: u64,
+    active: File,
+    active_number: u64,
+    active_size: u64,
+    // The bytes of the records of queued messages in every segment.
+    segments: BTreeMap<u64, u64>,
+    // The segment and record size of every queued message.
+    messages: HashMap<u64, (u64, u64)>,
+    // The older segments holding the messages the removals in each segment settled.
+    removals: HashMap<u64, BTreeSet<u64>>,
+    next_id: u64,
+    unsynced: bool,
+    last_sync: Instant,
+}
+
+impl Store {
+    // Replays the segments in the directory, then starts a new one to write to.
+    pub fn open(dir: &str, config: &Config) -> io::Result<(Self, Vec<Stored>)> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir)?;
+        let mut numbers: Vec<u64> = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                entry
+                    .file_name()
+                    .to_str()?
+                    .strip_suffix(".wal")?
+                    .parse()
+                    .ok()
+            })
+            .collect();
+        numbers.sort_unstable();
+        // A message rewritten by a compaction cut short shows up twice, the last copy
+        // being the current one.
+        let mut stored: BTreeMap<u64, (u64, u64, Stored)> = BTreeMap::new();
+        let mut copies: HashMap<u64, Vec<u64>> = HashMap::new();
+        let mut removals: HashMap<u64, BTreeSet<u64>> = HashMap::new();
+        let mut next_id = 0;
+        for number in numbers.iter() {
+            for record in read_segment(&segment_path(&dir, *number))? {
+                next_id = next_id.max(record.id + 1);
+                if record.kind != ENQUEUE {
+                    stored.remove(&record.id);
+                    let older = copies.remove(&record.id).unwrap_or_default();
+                    removals
+                        .entry(*number)
+                        .or_default()
+                        .extend(older.into_iter().filter(|older| older != number));
+                } else if let Some((address, payload)) = decode_enqueue(&record.body) {
+                    copies.entry(record.id).or_default().push(*number);
+                    let message = Stored {
+                        id: record.id,
+                        address,
+                        payload,
+                    };
+                    stored.insert(record.id, (*number, record.size, message));
+                }
+            }
+        }
+        let active_number = numbers.last().map_or(0, |number| number + 1);
+        let mut store = Store {
+            sync_policy: sync_policy(config)?,
+            active: create_segment(&dir, active_number)?,
+            dir,
+            segment_size: config.store_segment_size,
+            active_number,
+            active_size: 0,
+            segments: numbers.iter().map(|number| (*number, 0)).collect(),
+            messages: HashMap::new(),
+            removals,
+            next_id,
+            unsynced: false,
+            last_sync: Instant::now(),
+        };
+        store.segments.insert(active_number, 0);
+        let mut recovered = vec![];
+        for (id, (number, record_size, message)) in stored {
+            store.messages.insert(id, (number, record_size));
+            *store.segments.entry(number).or_default() += record_size;
+            recovered.push(message);
+        }
+        // Segments without any queued message left go right away.
+        store.compact()?;
+        Ok((store, recovered))
+    }
+
+    // Writes a message queued at the address, returning the id to remove it by.
+    pub fn append(&mut self, address: &str, payload: &[u8]) -> io::Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let record_size = self.write(&enqueue_body(id, address, payload))?;
+        self.messages.insert(id, (self.active_number, record_size));
+        *self.segments.entry(self.active_number).or_default() += record_size;
+        Ok(id)
+    }
+
+    // Records that the message is done with, so it isn't recovered any more.
+    pub fn remove(&mut self, id: u64) -> io::Result<()> {
+        let Some((number, record_size)) = self.messages.remove(&id) else {
+            return Ok(());
+        };
+        if let Some(live) = self.segments.get_mut(&number) {
+            *live -= record_size;
+        }
+        let mut body = vec![REMOVE];
+        body.extend_from_slice(&id.to_be_bytes());
+        self.write(&body)?;
+        if number != self.active_number {
+            self.removals
+                .entry(self.active_number)
+                .or_default()
+                .insert(number);
+        }
+        Ok(())
+    }
+
+    // Called once the frame bus handled an event.
+    pub fn event_handled(&mut self) -> io::Result<()> {
+        if self.sync_policy == SyncPolicy::Batched {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    // Called periodically by the frame bus to sync on an interval and compact the log.
+    pub fn tick(&mut self, now: Instant) -> io::Result<()> {
+        if let SyncPolicy::Interval(interval) = self.sync_policy
+            && now.duration_since(self.last_sync) >= interval
+        {
+            self.sync()?;
+        }
+        self.compact()
+    }
+
+    // Whether every record written is on the disk.
+    pub fn is_synced(&self) -> bool {
+        !self.unsynced
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced {
+            self.active.sync_data()?;
+            self.unsynced = false;
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    // Appends a record, moving on to a new segment once the last one is full.
+    fn write(&mut self, body: &[u8]) -> io::Result<u64> {
+        if self.active_size >= self.segment_size {
+            self.sync()?;
+            self.active_number += 1;
+            self.active = create_segment(&self.dir, self.active_number)?;
+            self.active_size = 0;
+            self.segments.insert(self.active_number, 0);
+        }
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + body.len());
+        record.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        record.extend_from_slice(&crc32(body).to_be_bytes());
+        record.extend_from_slice(body);
+        self.active.write_all(&record)?;
+        self.active_size += record.len() as u64;
+        self.unsynced = true;
+        if self.sync_policy == SyncPolicy::Always {
+            self.sync()?;
+        }
+        Ok(record.len() as u64)
+    }
+
+    // Drops the segments at most half of which is still queued, rewriting what is into
+    // the last segment, unless the messages their removals settled are still on disk.
+    fn compact(&mut self) -> io::Result<()> {
+        let numbers: Vec<u64> = self.segments.keys().copied().collect();
+        for number in numbers {
+            let live = self.segments.get(&number).copied().unwrap_or(0);
+            let pinned = self
+                .removals
+                .get(&number)
+                .is_some_and(|older| older.iter().any(|older| self.segments.contains_key(older)));
+            if number == self.active_number || live * 2 > self.segment_size || pinned {
+                continue;
+            }
+            if live > 0 {
+                let path = segment_path(&self.dir, number);
+                for record in read_segment(&path)? {
+                    let segment = self.messages.get(&record.id).map(|(segment, _)| *segment);
+                    if record.kind != ENQUEUE || segment != Some(number) {
+                        continue;
+                    }
+                    let record_size = self.write(&record.body)?;
+                    self.messages
+                        .insert(record.id, (self.active_number, record_size));
+                    *self.segments.entry(self.active_number).or_default() += record_size;
+                }
+                // The copies have to be on disk before the originals go.
+                self.sync()?;
+            }
+            self.segments.remove(&number);
+            self.removals.remove(&number);
+            fs::remove_file(segment_path(&self.dir, number))?;
+        }
+        Ok(())
+    }
+}
+
+fn sync_policy(config: &Config) -> io::Result<SyncPolicy> {
+    match config.store_sync.as_str() {
+        "always" => Ok(SyncPolicy::Always),
+        "batched" => Ok(SyncPolicy::Batched),
+        "interval" => Ok(SyncPolicy::Interval(config.store_sync_interval)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "UEXRS_STORE_SYNC must be always, batched or interval",
+        )),
+    }
+}
+
+fn segment_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:020}.wal", number))
+}
+
+fn create_segment(dir: &Path, number: u64) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, number))
+}
+
+fn enqueue_body(id: u64, address: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(11 + address.len() + payload.len());
+    body.push(ENQUEUE);
+    body.extend_from_slice(&id.to_be_bytes());
+    body.extend_from_slice(&(address.len() as u16).to_be_bytes());
+    body.extend_from_slice(address.as_bytes());
+    body.extend_from_slice(payload);
+    body
+}
+
+fn decode_enqueue(body: &[u8]) -> Option<(String, Vec<u8>)> {
+    let address_size = u16::from_be_bytes(body.get(9..11)?.try_into().ok()?) as usize;
+    let address = String::from_utf8(body.get(11..11 + address_size)?.to_vec()).ok()?;
+    Some((address, body[11 + address_size..].to_vec()))
+}
+
+// The records in the segment. A record cut short or damaged by a crash ends the
+// segment, since nothing was written to it after that.
+fn read_segment(path: &Path) -> io::Result<Vec<Record>> {
+    let data = fs::read(path)?;
+    let mut records = vec![];
+    let mut offset = 0;
+    while let Some(header) = data.get(offset..offset + RECORD_HEADER_SIZE) {
+        let size = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_be_bytes(header[4..].try_into().unwrap());
+        let start = offset + RECORD_HEADER_SIZE;
+        let Some(body) = data.get(start..start + size) else {
+            break;
+        };
+        if crc32(body) != checksum || size < 9 {
+            break;
+        }
+        records.push(Record {
+            kind: body[0],
+            id: u64::from_be_bytes(body[1..9].try_into().unwrap()),
+            size: (RECORD_HEADER_SIZE + size) as u64,
+            body: body.to_vec(),
+        });
+        offset = start + size;
+    }
+    Ok(records)
+}
+
+// CRC-32 (IEEE 802.3), to tell records written in full from those a crash cut short.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(segment_size: u64) -> Config {
+        Config {
+            store_sync: String::from("batched"),
+            store_segment_size: segment_size,
+            ..Config::default()
+        }
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("uexrs-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn open(dir: &Path, segment_size: u64) -> (Store, Vec<Stored>) {
+        Store::open(dir.to_str().unwrap(), &config(segment_size)).unwrap()
+    }
+
+    fn segment_count(dir: &Path) -> usize {
+        fs::read_dir(dir).unwrap().count()
+    }
+
+    #[test]
+    fn recovers_the_messages_not_removed() {
+        let dir = test_dir("recover");
+        let (mut store, stored) = open(&dir, 1024);
+        assert!(stored.is_empty());
+        let first = store.append("q1", b"one").unwrap();
+        let second = store.append("q2", b"two").unwrap();
+        let third = store.append("q1", b"three").unwrap();
+        store.remove(second).unwrap();
+        store.event_handled().unwrap();
+        drop(store);
+
+        let (mut store, stored) = open(&dir, 1024);
+        let recovered: Vec<(u64, &str, &[u8])> = stored
+            .iter()
+            .map(|message| (message.id, message.address.as_str(), &message.payload[..]))
+            .collect();
+        assert_eq!(
+            recovered,
+            vec![(first, "q1", &b"one"[..]), (third, "q1", &b"three"[..])]
+        );
+        assert!(store.append("q1", b"four").unwrap() > third);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stops_at_a_record_cut_short() {
+        let dir = test_dir("cut-short");
+        let (mut store, _) = open(&dir, 1024);
+        store.append("q", b"whole").unwrap();
+        store.event_handled().unwrap();
+        drop(store);
+        let mut segment = OpenOptions::new()
+            .append(true)
+            .open(segment_path(&dir, 0))
+            .unwrap();
+        segment
+            .write_all(&[0, 0, 0, 40, 1, 2, 3, 4, ENQUEUE])
+            .unwrap();
+        drop(segment);
+
+        let (_, stored) = open(&dir, 1024);
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].payload, b"whole");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_an_unknown_sync_policy() {
+        let dir = test_dir("sync-policy");
+        let mut config = config(1024);
+        config.store_sync = String::from("sometimes");
+        let err = Store::open(dir.to_str().unwrap(), &config).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reclaims_segments_behind_a_long_lived_message() {
+        let dir = test_dir("long-lived");
+        let (mut store, _) = open(&dir, 100);
+        let long_lived = store.append("q", b"long-lived").unwrap();
+        for _ in 0..1000 {
+            let id = store.append("q", &[0; 40]).unwrap();
+            store.remove(id).unwrap();
+            store.tick(Instant::now()).unwrap();
+        }
+        assert!(segment_count(&dir) <= 3);
+        drop(store);
+
+        let (_, stored) = open(&dir, 100);
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].id, long_lived);
+        assert_eq!(stored[0].payload, b"long-lived");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_removals_while_the_messages_they_remove_are_on_disk() {
+        let dir = test_dir("removals");
+        let (mut store, _) = open(&dir, 200);
+        // The first segment stays more than half queued, so it is never compacted.
+        let kept = store.append("q", &[1; 120]).unwrap();
+        let removed = store.append("q", &[2; 10]).unwrap();
+        let filler = store.append("q", &[3; 40]).unwrap();
+        store.remove(removed).unwrap();
+        store.remove(filler).unwrap();
+        for _ in 0..20 {
+            let id = store.append("q", &[4; 40]).unwrap();
+            store.remove(id).unwrap();
+            store.tick(Instant::now()).unwrap();
+        }
+        drop(store);
+
+        let (_, stored) = open(&dir, 200);
+        let ids: Vec<u64> = stored.iter().map(|message| message.id).collect();
+        assert_eq!(ids, vec![kept]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+
+use crate::amqp::messaging::filter::Filter;
+use crate::amqp::messaging::message::Message;
+use crate::amqp::transport::connection::Connection;
+use crate::broker::LinkId;
+use crate::broker::queue::Queue;
+use crate::frame_bus::ConnectionId;
+
+// A durable subscription belongs to the link name of the container that made it,
+// and lives on until that link is closed. Any other ends with its link.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SubscriptionId {
+    Durable { container_id: String, name: String },
+    Link(LinkId),
+}
+
+// The messages of the topics matching the pattern, queued for the subscriber alone.
+struct Subscription {
+    pattern: String,
+    queue: Queue,
+}
+
+// Every address starting with the prefix is a topic. Messages sent to a topic are
+// copied to every subscription whose pattern matches it, patterns being topics in
+// which * stands for any one word and # for any number of words, words being
+// separated by dots, e.g. orders.*.created or orders.#.
+pub struct Topics {
+    prefix: Option<String>,
+    subscriptions: HashMap<SubscriptionId, Subscription>,
+}
+
+impl Topics {
+    pub fn new(prefix: Option<String>) -> Self {
+        Self {
+            prefix,
+            subscriptions: HashMap::new(),
+        }
+    }
+
+    // The topic or pattern the address names, if it names one.
+    pub fn topic<'a>(&self, address: &'a str) -> Option<&'a str> {
+        address.strip_prefix(self.prefix.as_deref()?)
+    }
+
+    // Attaches the link to its subscription, which a durable one may have been
+    // queueing messages for meanwhile.
+    pub fn subscribe(&mut self, id: SubscriptionId, pattern: &str, link: LinkId, filter: Filter) {
+        let subscription = self
+            .subscriptions
+            .entry(id)
+            .or_insert_with(|| Subscription {
+                pattern: pattern.to_string(),
+                queue: Queue::default(),
+            });
+        pattern.clone_into(&mut subscription.pattern);
+        subscription.queue.attach(link, filter);
+    }
+
+    // Detaches the link from its subscription, returning whether the subscription
+    // lives on.
+    pub fn unsubscribe(&mut self, id: &SubscriptionId, link: LinkId, closed: bool) -> bool {
+        if closed || matches!(id, SubscriptionId::Link(_)) {
+            self.subscriptions.remove(id);
+            return false;
+        }
+        match self.queue(id) {
+            Some(queue) => {
+                queue.detach(link);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn queue(&mut self, id: &SubscriptionId) -> Option<&mut Queue> {
+        self.subscriptions
+            .get_mut(id)
+            .map(|subscription| &mut subscription.queue)
+    }
+
+    pub fn publish(&mut self, topic: &str, message: Message) {
+        let topic: Vec<&str> = topic.split('.').collect();
+        for subscription in self.subscriptions.values_mut() {
+            let pattern: Vec<&str> = subscription.pattern.split('.').collect();
+            if pattern_matches(&pattern, &topic) {
+                subscription.queue.push(message.clone());
+            }
+        }
+    }
+
+    pub async fn dispatch(&mut self, connections: &mut HashMap<ConnectionId, Connection>) {
+        for subscription in self.subscriptions.values_mut() {
+            subscription.queue.dispatch(connections).await;
+        }
+    }
+}
+
+// Runs through the topic a word at a time, keeping track of how many segments of
+// the pattern the words so far can be matched by, so that a pattern with many #s
+// takes no longer than the pattern times the topic.
+fn pattern_matches(pattern: &[&str], topic: &[&str]) -> bool {
+    // Consecutive #s match what a single one does.
+    let mut pattern = pattern.to_vec();
+    pattern.dedup_by(|word, previous| *word == "#" && *previous == "#");
+    let segments = pattern.len();
+    let mut matched = vec![false; segments + 1];
+    matched[0] = true;
+    skip_hashes(&pattern, &mut matched);
+    for topic_word in topic {
+        let mut next = vec![false; segments + 1];
+        for (segment, word) in pattern.iter().enumerate() {
+            if !matched[segment] {
+                continue;
+            }
+            match *word {
+                "#" => next[segment] = true,
+                "*" => next[segment + 1] = true,
+                word => next[segment + 1] |= word == *topic_word,
+            }
+        }
+        skip_hashes(&pattern, &mut next);
+        matched = next;
+    }
+    matched[segments]
+}
+
+// A # may stand for no words at all.
+fn skip_hashes(pattern: &[&str], matched: &mut [bool]) {
+    for (segment, word) in pattern.iter().enumerate() {
+        if *word == "#" && matched[segment] {
+            matched[segment + 1] = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pattern_matches;
+
+    fn matches(pattern: &str, topic: &str) -> bool {
+        let pattern: Vec<&str> = pattern.split('.').collect();
+        let topic: Vec<&str> = topic.split('.').collect();
+        pattern_matches(&pattern, &topic)
+    }
+
+    #[test]
+    fn exact_words() {
+        assert!(matches("orders.eu.created", "orders.eu.created"));
+        assert!(!matches("orders.eu.created", "orders.us.created"));
+        assert!(!matches("orders.eu", "orders.eu.created"));
+    }
+
+    #[test]
+    fn star_matches_one_word() {
+        assert!(matches("orders.*.created", "orders.eu.created"));
+        assert!(!matches("orders.*.created", "orders.created"));
+        assert!(!matches("orders.*.created", "orders.eu.west.created"));
+        assert!(matches("*", "orders"));
+        assert!(!matches("*", "orders.eu"));
+    }
+
+    #[test]
+    fn hash_matches_any_number_of_words() {
+        assert!(matches("orders.#", "orders"));
+        assert!(matches("orders.#", "orders.eu.created"));
+        assert!(matches("orders.#.created", "orders.created"));
+        assert!(matches("orders.#.created", "orders.eu.west.created"));
+        assert!(!matches("orders.#.created", "orders.eu.shipped"));
+        assert!(matches("#.#.created", "orders.created"));
+        assert!(matches("#.*", "orders"));
+        assert!(matches("#", "anything.at.all"));
+    }
+
+    #[test]
+    fn empty_pattern() {
+        assert!(pattern_matches(&[], &[]));
+        assert!(!pattern_matches(&[], &["orders"]));
+        assert!(pattern_matches(&["#"], &[]));
+        assert!(!pattern_matches(&["*"], &[]));
+        assert!(!pattern_matches(&["#", "*"], &[]));
+    }
+
+    #[test]
+    fn many_hashes_against_a_long_topic() {
+        let pattern = ["#.#.#.#.#.#.#.#.#.#.#.#.x", "#.a.#.a.#.a.#.a.#.a.#.a.#.x"];
+        let topic = vec!["a"; 10_000].join(".");
+        for pattern in pattern {
+            assert!(!matches(pattern, &topic));
+            assert!(matches(pattern, &format!("{}.x", topic)));
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+
+// Node configuration. Every value can be overridden with the UEXRS_* environment
+// variable named next to it; unset or unparseable variables fall back to the default.
+pub struct Config {
+    // UEXRS_CONTAINER_ID
+    pub container_id: String,
+    // UEXRS_AMQP_ADDRESS
+    pub amqp_address: String,
+    // UEXRS_AMQPS_ADDRESS, for clients starting with TLS right away; needs TLS set up.
+    pub amqps_address: Option<String>,
+    // UEXRS_PANEL_ADDRESS
+    pub panel_address: String,
+    // UEXRS_MAX_FRAME_SIZE
+    pub max_frame_size: u32,
+    // UEXRS_CHANNEL_MAX
+    pub channel_max: u16,
+    // UEXRS_INCOMING_WINDOW
+    pub incoming_window: u32,
+    // UEXRS_OUTGOING_WINDOW
+    pub outgoing_window: u32,
+    // UEXRS_HANDLE_MAX
+    pub handle_max: u32,
+    // UEXRS_LINK_CREDIT
+    pub link_credit: u32,
+    // UEXRS_MAX_MESSAGE_SIZE, in octets, the largest message a link takes in.
+    pub max_message_size: u64,
+    // UEXRS_IDLE_TIMEOUT, in milliseconds; 0 disables it.
+    pub idle_time_out: Option<Duration>,
+    // UEXRS_DEAD_LETTER_ADDRESS, the queue rejected messages go to; empty discards them.
+    pub dead_letter_address: Option<String>,
+    // UEXRS_REPLICATED_ADDRESSES, comma separated, the addresses every receiver gets
+    // a copy of every message of, as do the replica peers.
+    pub replicated_addresses: Vec<String>,
+    // UEXRS_REPLICA_PEERS, comma separated host:port of the nodes replicated to.
+    pub replica_peers: Vec<String>,
+    // UEXRS_REPLICA_RETENTION, how many messages of each replicated address are kept
+    // for the receivers and peers catching up on them.
+    pub replica_retention: usize,
+    // UEXRS_TOPIC_PREFIX, what the addresses of topics start with; empty disables topics.
+    pub topic_prefix: Option<String>,
+    // UEXRS_SERVICE_ADDRESSES, comma separated, the addresses requests are sent to with
+    // a reply-to, each going to one of the workers attached.
+    pub service_addresses: Vec<String>,
+    // UEXRS_CALL_TIMEOUT, in milliseconds, how long a caller waits for a worker to reply
+    // to a request without a ttl or absolute-expiry-time of its own.
+    pub call_timeout: Duration,
+    // UEXRS_STORE_DIR, where durable messages are kept across restarts; unset keeps
+    // them in memory only.
+    pub store_dir: Option<String>,
+    // UEXRS_STORE_SYNC, when stored messages reach the disk: always, batched or interval.
+    pub store_sync: String,
+    // UEXRS_STORE_SYNC_INTERVAL, in milliseconds, for the interval sync policy.
+    pub store_sync_interval: Duration,
+    // UEXRS_STORE_SEGMENT_SIZE, in octets, how large a store segment file grows.
+    pub store_segment_size: u64,
+    // UEXRS_USERS_FILE, see Users; SCRAM and, over TLS, PLAIN are only offered if set.
+    pub users_file: Option<String>,
+    // UEXRS_SCRAM_ITERATIONS, for the credentials of users added with add-user.
+    pub scram_iterations: u32,
+    // UEXRS_EXTERNAL_RULES, how client certificates map to user names, see MappingRule.
+    pub external_rules: String,
+    // UEXRS_ALLOW_ANONYMOUS
+    pub allow_anonymous: bool,
+    // UEXRS_REQUIRE_SASL
+    pub require_sasl: bool,
+    // UEXRS_TLS_CERT_FILE and UEXRS_TLS_KEY_FILE, PEM files enabling TLS.
+    pub tls_cert_file: Option<String>,
+    pub tls_key_file: Option<String>,
+    // UEXRS_TLS_CLIENT_CA_FILE, PEM file with the CAs client certificates are verified against.
+    pub tls_client_ca_file: Option<String>,
+    // UEXRS_TLS_REQUIRE_CLIENT_CERT, only valid along with UEXRS_TLS_CLIENT_CA_FILE.
+    pub tls_require_client_cert: bool,
+    // UEXRS_REQUIRE_TLS
+    pub require_tls: bool,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self::from_vars(|name| env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        Self {
+            container_id: var_or(&var, "UEXRS_CONTAINER_ID", String::from("uexrs")),
+            amqp_address: var_or(&var, "UEXRS_AMQP_ADDRESS", String::from("127.0.0.1:6142")),
+            amqps_address: var("UEXRS_AMQPS_ADDRESS"),
+            panel_address: var_or(&var, "UEXRS_PANEL_ADDRESS", String::from("0.0.0.0:3000")),
+            max_frame_size: var_or(&var, "UEXRS_MAX_FRAME_SIZE", 64 * 1024),
+            channel_max: var_or(&var, "UEXRS_CHANNEL_MAX", 255),
+            incoming_window: var_or(&var, "UEXRS_INCOMING_WINDOW", 2048),
+            outgoing_window: var_or(&var, "UEXRS_OUTGOING_WINDOW", 2048),
+            handle_max: var_or(&var, "UEXRS_HANDLE_MAX", 255),
+            link_credit: var_or(&var, "UEXRS_LINK_CREDIT", 100),
+            max_message_size: var_or(&var, "UEXRS_MAX_MESSAGE_SIZE", 16 * 1024 * 1024),
+            idle_time_out: Some(var_or(&var, "UEXRS_IDLE_TIMEOUT", 60_000))
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            dead_letter_address: Some(var_or(
+                &var,
+                "UEXRS_DEAD_LETTER_ADDRESS",
+                String::from("dead-letter"),
+            ))
+            .filter(|address| !address.is_empty()),
+            replicated_addresses: var_list(&var, "UEXRS_REPLICATED_ADDRESSES"),
+            replica_peers: var_list(&var, "UEXRS_REPLICA_PEERS"),
+            replica_retention: var_or(&var, "UEXRS_REPLICA_RETENTION", 10_000),
+            topic_prefix: Some(var_or(&var, "UEXRS_TOPIC_PREFIX", String::from("topic/")))
+                .filter(|prefix| !prefix.is_empty()),
+            service_addresses: var_list(&var, "UEXRS_SERVICE_ADDRESSES"),
+            call_timeout: Duration::from_millis(var_or(&var, "UEXRS_CALL_TIMEOUT", 30_000)),
+            store_dir: var("UEXRS_STORE_DIR"),
+            store_sync: var_or(&var, "UEXRS_STORE_SYNC", String::from("batched")),
+            store_sync_interval: Duration::from_millis(var_or(
+                &var,
+                "UEXRS_STORE_SYNC_INTERVAL",
+                1000,
+            )),
+            store_segment_size: var_or(&var, "UEXRS_STORE_SEGMENT_SIZE", 64 * 1024 * 1024),
+            users_file: var("UEXRS_USERS_FILE"),
+            scram_iterations: var_or(&var, "UEXRS_SCRAM_ITERATIONS", 4096),
+            external_rules: var_or(&var, "UEXRS_EXTERNAL_RULES", String::from("cn")),
+            allow_anonymous: var_or(&var, "UEXRS_ALLOW_ANONYMOUS", true),
+            require_sasl: var_or(&var, "UEXRS_REQUIRE_SASL", false),
+            tls_cert_file: var("UEXRS_TLS_CERT_FILE"),
+            tls_key_file: var("UEXRS_TLS_KEY_FILE"),
+            tls_client_ca_file: var("UEXRS_TLS_CLIENT_CA_FILE"),
+            tls_require_client_cert: var_or(&var, "UEXRS_TLS_REQUIRE_CLIENT_CERT", false),
+            require_tls: var_or(&var, "UEXRS_REQUIRE_TLS", false),
+        }
+    }
+}
+
+// The defaults, whatever the environment says.
+impl Default for Config {
+    fn default() -> Self {
+        Self::from_vars(|_| None)
+    }
+}
+
+fn var_or<T: FromStr>(var: &impl Fn(&str) -> Option<String>, name: &str, default: T) -> T {
+    var(name)
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+fn var_list(var: &impl Fn(&str) -> Option<String>, name: &str) -> Vec<String> {
+    var(name)
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
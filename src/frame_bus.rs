@@ -1,62 +1,172 @@
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use tokio::io::AsyncReadExt;
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::time::{self, Instant, MissedTickBehavior};
 
-use crate::amqp::transport::performative::Performative;
-use crate::amqp::types::frame::Frame;
+use crate::amqp::transport::connection::Connection;
+use crate::amqp::transport::suspended::SuspendedLinks;
+use crate::amqp::types::frame::{Frame, FrameError};
+use crate::broker::Broker;
+use crate::broker::store::{Store, Stored};
+use crate::config::Config;
+use crate::replicator;
 
+pub type ConnectionId = u64;
+
+// How often connections get to send heartbeats and check for idle peers.
+const TICK_INTERVAL: Duration = Duration::from_millis(500);
+
+// Everything the terminus handlers of a client connection tell the frame bus.
+pub enum BusEvent {
+    // The protocol header has been exchanged; frames for the client go to the sender.
+    Connected(ConnectionId, Sender<Frame>),
+    Frame(ConnectionId, Frame),
+    FramingError(ConnectionId, FrameError),
+    Disconnected(ConnectionId),
+}
+
+// The store, if any, and the messages recovered from it go to the broker.
 pub async fn process_frames(
-    mut frame_bus_rx: Receiver<Frame>,
-    source_map: Arc<Mutex<HashMap<u16, Sender<Frame>>>>,
+    mut frame_bus_rx: Receiver<BusEvent>,
+    config: Arc<Config>,
+    store: Option<Store>,
+    stored: Vec<Stored>,
 ) {
-    while let Some(frame) = frame_bus_rx.recv().await {
-        // Bytes 6 and 7 of an AMQP Frame contain the Channel number (see section 2.1 Transport).
-        let channel_number = u16::from_be_bytes(frame.type_specific);
-
-        // The frame body is defined as a performative followed by an opaque payload.
-        // The performative MUST be one of those defined in section 2.7 Performatives
-        // and is encoded as a described type in the AMQP type system.
-        // The remaining bytes in the frame body form the payload for that frame.
-        // The presence and format of the payload is defined by the semantics
-        // of the given performative.
-        let mut perf_body = frame.frame_body.as_slice();
-        match get_performative_and_payload(&mut perf_body).await {
-            Ok((performative, payload)) => {
-                // TODO: match on frame type, parse the frame body and create/update/delete the necessary
-                // Connection, Sessions and Links as per the frame type and body (see 2.4-2.7).
-                // - needs a separate function likely
-                match performative {
-                    Performative::Open {
-                        container_id,
-                        hostname,
-                        max_frame_size,
-                        channel_max,
-                        idle_time_out,
-                        outgoing_locales,
-                        incoming_locales,
-                        offered_capabilities,
-                        desired_capabilities,
-                        properties,
-                    } => {
-                        dbg!(container_id, hostname);
-                    }
+    let mut connections: HashMap<ConnectionId, Connection> = HashMap::new();
+    let mut broker = Broker::new(&config, store);
+    broker.recover(stored).await;
+    let suspended = Arc::new(Mutex::new(SuspendedLinks::default()));
+    for peer in config.replica_peers.iter() {
+        tokio::spawn(replicator::replicate(
+            peer.clone(),
+            config.clone(),
+            broker.replica_logs(),
+        ));
+    }
+    let mut ticks = time::interval(TICK_INTERVAL);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        let event = tokio::select! {
+            event = frame_bus_rx.recv() => match event {
+                Some(event) => event,
+                None => break,
+            },
+            now = ticks.tick() => {
+                let now = Instant::into_std(now);
+                for connection in connections.values_mut() {
+                    connection.tick(now).await;
                 }
+                let ended: Vec<ConnectionId> = connections
+                    .iter()
+                    .filter(|(_, connection)| connection.is_ended())
+                    .map(|(connection_id, _)| *connection_id)
+                    .collect();
+                for connection_id in ended {
+                    remove_connection(&mut broker, &mut connections, connection_id).await;
+                }
+                expire_suspended(&mut broker, &suspended, now);
+                broker.expire_calls(now);
+                dispatch(&mut broker, &mut connections).await;
+                broker.store_tick(now, &mut connections).await;
+                continue;
             }
-            Err(_) => {
-                println!("Could not read performative or payload");
+        };
+        let connection_id = match event {
+            BusEvent::Connected(connection_id, client_tx) => {
+                let mut connection =
+                    Connection::new(connection_id, client_tx, config.clone(), suspended.clone());
+                // negotiate_amqp_version reads the client's header before sending ours.
+                if connection.header_received().is_err() || connection.header_sent().is_err() {
+                    continue;
+                }
+                connections.insert(connection_id, connection);
+                connection_id
+            }
+            BusEvent::Frame(connection_id, frame) => {
+                if let Some(connection) = connections.get_mut(&connection_id) {
+                    connection.handle_frame(frame).await;
+                }
+                connection_id
+            }
+            BusEvent::FramingError(connection_id, err) => {
+                if let Some(connection) = connections.get_mut(&connection_id) {
+                    connection.framing_error(err).await;
+                }
+                connection_id
+            }
+            BusEvent::Disconnected(connection_id) => {
+                remove_connection(&mut broker, &mut connections, connection_id).await;
+                expire_suspended(&mut broker, &suspended, std::time::Instant::now());
+                dispatch(&mut broker, &mut connections).await;
+                broker.event_handled(&mut connections).await;
                 continue;
             }
+        };
+
+        if let Some(connection) = connections.get_mut(&connection_id) {
+            pass_link_events(&mut broker, connection_id, connection).await;
         }
+        // Dropping the connection drops the client sender, which stops the source handler.
+        if connections
+            .get(&connection_id)
+            .is_some_and(|connection| connection.is_ended())
+        {
+            remove_connection(&mut broker, &mut connections, connection_id).await;
+        }
+        expire_suspended(&mut broker, &suspended, std::time::Instant::now());
+        dispatch(&mut broker, &mut connections).await;
+        broker.event_handled(&mut connections).await;
+    }
+}
+
+// Sending messages settled to a receiver settles them at their node right away.
+async fn dispatch(broker: &mut Broker, connections: &mut HashMap<ConnectionId, Connection>) {
+    broker.dispatch(connections).await;
+    for (connection_id, connection) in connections.iter_mut() {
+        pass_link_events(broker, *connection_id, connection).await;
+    }
+}
+
+async fn pass_link_events(
+    broker: &mut Broker,
+    connection_id: ConnectionId,
+    connection: &mut Connection,
+) {
+    for (channel, event) in connection.take_events() {
+        broker
+            .handle_event(connection_id, connection, channel, event)
+            .await;
     }
 }
 
-async fn get_performative_and_payload(
-    buf_reader: &mut (impl AsyncReadExt + Unpin),
-) -> Result<(Performative, Vec<u8>), &'static str> {
-    let performative = Performative::new(buf_reader).await?;
-    let mut payload = vec![];
-    buf_reader.read_to_end(&mut payload);
-    Ok((performative, payload))
+// The links of a connection that is gone are detached, so their nodes can take back
+// whatever the peer never settled.
+async fn remove_connection(
+    broker: &mut Broker,
+    connections: &mut HashMap<ConnectionId, Connection>,
+    connection_id: ConnectionId,
+) {
+    if let Some(mut connection) = connections.remove(&connection_id) {
+        connection.detach_links();
+        pass_link_events(broker, connection_id, &mut connection).await;
+    }
+}
+
+// The nodes take back what the peer never settled on links whose terminus expired.
+fn expire_suspended(
+    broker: &mut Broker,
+    suspended: &Mutex<SuspendedLinks>,
+    now: std::time::Instant,
+) {
+    let expired = {
+        let mut suspended = suspended.lock().unwrap();
+        suspended.expire(now);
+        suspended.take_expired()
+    };
+    for expired in expired {
+        broker.expired(expired);
+    }
 }
@@ -1,5 +1,5 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use axum::Router;
 use tokio::io;
@@ -7,58 +7,190 @@ use tokio::net::TcpListener;
 use tokio::sync::mpsc;
 
 use amqp::transport::negotiate_amqp_version;
+use amqp::transport::sasl::{Sasl, users};
+use amqp::transport::tls::{Tls, Transport};
+use broker::store::Store;
+use config::Config;
+use frame_bus::{BusEvent, ConnectionId};
 
 mod amqp;
+mod broker;
+mod config;
 mod frame_bus;
 mod panel;
+mod replicator;
 mod terminus_handler;
 
 // TODO: proper logging
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
+    let config = Arc::new(Config::from_env());
+    // uexrs add-user <name> reads the password from stdin and stores its credentials.
+    let args: Vec<String> = std::env::args().collect();
+    if let [_, command, user] = args.as_slice()
+        && command == "add-user"
+    {
+        return add_user(&config, user);
+    }
+
     // web server stuff
     let app = Router::new();
 
-    // run our app, listening globally on port 3000
-    // TODO: get from config
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    // run our app, listening globally on port 3000 by default
+    let listener = tokio::net::TcpListener::bind(&config.panel_address)
+        .await
+        .unwrap();
     tokio::spawn(async move {
         axum::serve(listener, app).await.unwrap();
     });
 
     // AMQP stuff
-    // we need to get these params from a config
-    let listener = TcpListener::bind("127.0.0.1:6142").await?;
     let (frame_bus_tx, frame_bus_rx) = mpsc::channel(1024);
-    let source_map = Arc::new(Mutex::new(HashMap::new()));
 
-    let frame_bus_source_map = source_map.clone();
+    let (store, stored) = match config.store_dir {
+        Some(ref store_dir) => {
+            let (store, stored) = Store::open(store_dir, &config)?;
+            println!("recovered {} messages from {}", stored.len(), store_dir);
+            (Some(store), stored)
+        }
+        None => (None, vec![]),
+    };
+    let frame_bus_config = config.clone();
     tokio::spawn(async move {
-        frame_bus::process_frames(frame_bus_rx, frame_bus_source_map).await;
+        frame_bus::process_frames(frame_bus_rx, frame_bus_config, store, stored).await;
     });
 
-    loop {
-        let (mut socket, _) = listener.accept().await?;
-        match negotiate_amqp_version(&mut socket).await {
-            Ok(version) => println!("negotiation successful: version is {}", version),
-            Err(_) => {
-                println!("negotiation failed");
-                continue;
+    let acceptor = Arc::new(Acceptor {
+        max_frame_size: config.max_frame_size,
+        tls: Tls::from_config(&config)?,
+        sasl: Sasl::from_config(&config)?,
+        frame_bus_tx,
+        next_connection_id: AtomicU64::new(0),
+    });
+    if let Some(ref amqps_address) = config.amqps_address {
+        if !acceptor.tls.is_enabled() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "UEXRS_AMQPS_ADDRESS needs UEXRS_TLS_CERT_FILE and UEXRS_TLS_KEY_FILE",
+            ));
+        }
+        let listener = TcpListener::bind(amqps_address).await?;
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            if let Err(err) = acceptor.listen(listener, true).await {
+                println!("amqps listener failed: {}", err);
             }
+        });
+    }
+    let listener = TcpListener::bind(&config.amqp_address).await?;
+    acceptor.listen(listener, false).await
+}
+
+// Takes accepted sockets through the protocol negotiation and hands them over to the frame bus.
+struct Acceptor {
+    max_frame_size: u32,
+    tls: Tls,
+    sasl: Sasl,
+    frame_bus_tx: mpsc::Sender<BusEvent>,
+    next_connection_id: AtomicU64,
+}
+
+impl Acceptor {
+    // With implicit TLS the handshake starts right away, without a TLS protocol header first.
+    async fn listen(self: Arc<Self>, listener: TcpListener, implicit_tls: bool) -> io::Result<()> {
+        loop {
+            let (socket, _) = listener.accept().await?;
+            // Negotiation happens off the accept loop, so a client taking its time
+            // to authenticate doesn't hold up everyone else.
+            tokio::spawn(self.clone().serve(Transport::Plain(socket), implicit_tls));
         }
-        let frame_bus_tx = frame_bus_tx.clone();
-        let (amqp_client_tx, amqp_client_rx) = mpsc::channel(1024);
+    }
 
-        tokio::spawn(async move {
-            // TODO: socket timeouts and configurable connection timeouts
-            let (socket_rcv, socket_snd) = socket.into_split();
-            tokio::spawn(async move {
-                terminus_handler::source_handler(socket_snd, amqp_client_rx).await;
-            });
-            tokio::spawn(async move {
-                terminus_handler::target_handler(socket_rcv, frame_bus_tx).await;
-            });
+    async fn serve(self: Arc<Self>, stream: Transport, implicit_tls: bool) {
+        let stream = if implicit_tls {
+            match self.tls.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    println!("negotiation failed: {}", err);
+                    return;
+                }
+            }
+        } else {
+            stream
+        };
+        let stream = match negotiate_amqp_version(stream, &self.tls, &self.sasl).await {
+            Ok((stream, Some(user))) => {
+                println!("negotiation successful: authenticated as {}", user);
+                stream
+            }
+            Ok((stream, None)) => {
+                println!("negotiation successful: no authentication");
+                stream
+            }
+            Err(err) => {
+                println!("negotiation failed: {}", err);
+                return;
+            }
+        };
+
+        let connection_id: ConnectionId = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let (amqp_client_tx, amqp_client_rx) = mpsc::channel(1024);
+        if self
+            .frame_bus_tx
+            .send(BusEvent::Connected(connection_id, amqp_client_tx))
+            .await
+            .is_err()
+        {
+            println!("frame bus is gone");
+            return;
+        }
+        let (socket_rcv, socket_snd) = io::split(stream);
+        // The reader tells the frame bus once the peer is gone or sends something we
+        // can't decode, and the frame bus then drops the connection along with its sender.
+        let frame_bus_tx = self.frame_bus_tx.clone();
+        let max_frame_size = self.max_frame_size;
+        let reader = tokio::spawn(async move {
+            terminus_handler::target_handler(
+                socket_rcv,
+                connection_id,
+                frame_bus_tx.clone(),
+                max_frame_size,
+            )
+            .await;
+            frame_bus_tx
+                .send(BusEvent::Disconnected(connection_id))
+                .await
+                .unwrap_or(());
         });
+        // The writer keeps going until that sender is dropped, so whatever the connection
+        // queued last, such as the Close after a framing error, still reaches the peer.
+        terminus_handler::source_handler(socket_snd, amqp_client_rx, self.max_frame_size).await;
+        // The frame bus may drop the connection on its own, e.g. after an idle timeout,
+        // and the peer may never send anything again, so the reader is stopped here
+        // and the socket released. If the writer gave up first, the frame bus still
+        // has to hear that the connection is gone.
+        reader.abort();
+        if reader.await.is_err() {
+            self.frame_bus_tx
+                .send(BusEvent::Disconnected(connection_id))
+                .await
+                .unwrap_or(());
+        }
     }
 }
+
+fn add_user(config: &Config, user: &str) -> io::Result<()> {
+    let Some(ref users_file) = config.users_file else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "UEXRS_USERS_FILE is not set",
+        ));
+    };
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    users::add_user(users_file, user, password, config.scram_iterations)?;
+    println!("added user {} to {}", user, users_file);
+    Ok(())
+}
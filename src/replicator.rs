@@ -0,0 +1,697 @@
+// Replicates the messages accepted on the replicated addresses to the downstream peers,
+// connecting to each of them as a client with a sending link per address.
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::select_all;
+use futures::{SinkExt, StreamExt};
+use tokio::io::{self, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::time::{self, Interval, MissedTickBehavior};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::amqp::messaging::delivery_state::DeliveryState;
+use crate::amqp::messaging::terminus::{Source, Target};
+use crate::amqp::transport::connection::get_performative_and_payload;
+use crate::amqp::transport::performative::Performative;
+use crate::amqp::transport::request_amqp_version;
+use crate::amqp::types::constructor::Constructor;
+use crate::amqp::types::frame::{Frame, FrameCodec};
+use crate::amqp::types::primitive::Primitive;
+use crate::broker::replica::ReplicaLog;
+use crate::config::Config;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// How often we let a peer without an idle timeout know we are still there.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+// The message annotation naming the container a replicated message was first accepted
+// by, so that it is never replicated back to it.
+const ORIGIN: &[u8] = b"x-opt-uexrs-origin";
+
+type Frames = FramedRead<ReadHalf<TcpStream>, FrameCodec>;
+
+// A replicated address as far as one peer is concerned, the link of the same handle
+// sending it the messages of the log in order.
+struct Replication {
+    address: String,
+    log: Arc<Mutex<ReplicaLog>>,
+    appended: Arc<Notify>,
+    // The number of the next message to send.
+    next: u64,
+    // The messages sent that the peer hasn't acknowledged yet, and those it gave back
+    // to be sent again.
+    unacknowledged: BTreeSet<u64>,
+    resend: BTreeSet<u64>,
+    // The flow state of the link (see 2.6.7 Flow Control).
+    delivery_count: u32,
+    link_credit: u32,
+}
+
+impl Replication {
+    // The peer acknowledged every message before this one.
+    fn acknowledged(&self) -> u64 {
+        [self.unacknowledged.first(), self.resend.first()]
+            .into_iter()
+            .flatten()
+            .copied()
+            .fold(self.next, u64::min)
+    }
+
+    // What the peer didn't acknowledge is sent again once it is back.
+    fn reset(&mut self) {
+        self.next = self.acknowledged();
+        self.unacknowledged.clear();
+        self.resend.clear();
+        self.delivery_count = 0;
+        self.link_credit = 0;
+    }
+}
+
+// Keeps replicating to the peer for as long as we run, catching it up on whatever
+// it missed while it was unreachable as far as the logs retain it.
+pub async fn replicate(
+    peer: String,
+    config: Arc<Config>,
+    logs: Vec<(String, Arc<Mutex<ReplicaLog>>)>,
+) {
+    let mut replications: Vec<Replication> = logs
+        .into_iter()
+        .map(|(address, log)| {
+            let (appended, next) = {
+                let log = log.lock().unwrap();
+                (log.appended(), log.end())
+            };
+            Replication {
+                address,
+                log,
+                appended,
+                next,
+                unacknowledged: BTreeSet::new(),
+                resend: BTreeSet::new(),
+                delivery_count: 0,
+                link_credit: 0,
+            }
+        })
+        .collect();
+    if replications.is_empty() {
+        return;
+    }
+    loop {
+        if let Err(err) = replicate_to(&peer, &config, &mut replications).await {
+            println!("Replication to {} stopped: {}", peer, err);
+        }
+        for replication in replications.iter_mut() {
+            replication.reset();
+        }
+        time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn replicate_to(
+    peer: &str,
+    config: &Config,
+    replications: &mut [Replication],
+) -> Result<(), &'static str> {
+    let mut stream = TcpStream::connect(peer)
+        .await
+        .map_err(|_| "Could not connect")?;
+    request_amqp_version(&mut stream).await?;
+    let (socket_rcv, socket_snd) = io::split(stream);
+    let mut frames = FramedRead::new(socket_rcv, FrameCodec::new(config.max_frame_size));
+    let mut downstream = Downstream {
+        frames: FramedWrite::new(socket_snd, FrameCodec::new(config.max_frame_size)),
+        container_id: config.container_id.clone(),
+        remote_container_id: None,
+        max_frame_size: config.max_frame_size,
+        next_outgoing_id: 0,
+        remote_incoming_window: 0,
+        outgoing: VecDeque::new(),
+        next_delivery_id: 0,
+        deliveries: HashMap::new(),
+        heartbeats: heartbeats(HEARTBEAT_INTERVAL),
+    };
+    downstream.open(config, replications).await?;
+    let appended: Vec<Arc<Notify>> = replications
+        .iter()
+        .map(|replication| replication.appended.clone())
+        .collect();
+    loop {
+        // Created before anything is sent, so nothing appended meanwhile goes unnoticed.
+        let notified: Vec<_> = appended
+            .iter()
+            .map(|appended| Box::pin(appended.notified()))
+            .collect();
+        downstream.send_messages(replications).await?;
+        tokio::select! {
+            performative = next_performative(&mut frames) => {
+                downstream.handle(performative?, replications).await?;
+            }
+            _ = select_all(notified) => {}
+            _ = downstream.heartbeats.tick() => {
+                downstream.send_frame(Frame::heartbeat()).await?;
+            }
+        }
+    }
+}
+
+// The next performative the peer sends, skipping its heartbeats.
+async fn next_performative(frames: &mut Frames) -> Result<Performative, &'static str> {
+    loop {
+        let frame = match frames.next().await {
+            Some(Ok(frame)) => frame,
+            Some(Err(_)) => return Err("Could not read a frame"),
+            None => return Err("The peer closed the connection"),
+        };
+        if !frame.is_heartbeat() {
+            return Ok(get_performative_and_payload(&frame.frame_body).await?.0);
+        }
+    }
+}
+
+fn heartbeats(period: Duration) -> Interval {
+    let mut heartbeats = time::interval_at(time::Instant::now() + period, period);
+    heartbeats.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    heartbeats
+}
+
+// Our end of the connection to a peer, with a single session on channel 0.
+struct Downstream {
+    frames: FramedWrite<WriteHalf<TcpStream>, FrameCodec>,
+    container_id: String,
+    remote_container_id: Option<String>,
+    max_frame_size: u32,
+    // The session flow state (see 2.5.6 Session Flow Control).
+    next_outgoing_id: u32,
+    remote_incoming_window: u32,
+    // The frames of a delivery that didn't fit in the peer's incoming window yet.
+    outgoing: VecDeque<Frame>,
+    next_delivery_id: u32,
+    // The replication and message number of every delivery the peer hasn't settled.
+    deliveries: HashMap<u32, (usize, u64)>,
+    heartbeats: Interval,
+}
+
+impl Downstream {
+    async fn open(
+        &mut self,
+        config: &Config,
+        replications: &[Replication],
+    ) -> Result<(), &'static str> {
+        self.send(Performative::Open {
+            container_id: config.container_id.clone(),
+            hostname: None,
+            max_frame_size: config.max_frame_size,
+            channel_max: 0,
+            idle_time_out: None,
+            outgoing_locales: vec![],
+            incoming_locales: vec![],
+            offered_capabilities: vec![],
+            desired_capabilities: vec![],
+            properties: HashMap::new(),
+        })
+        .await?;
+        self.send(Performative::Begin {
+            remote_channel: None,
+            next_outgoing_id: self.next_outgoing_id,
+            incoming_window: config.incoming_window,
+            outgoing_window: config.outgoing_window,
+            handle_max: replications.len() as u32 - 1,
+            offered_capabilities: vec![],
+            desired_capabilities: vec![],
+            properties: HashMap::new(),
+        })
+        .await?;
+        for (handle, replication) in replications.iter().enumerate() {
+            let source = Source {
+                address: Some(replication.address.clone()),
+                ..Default::default()
+            };
+            let target = Target {
+                address: Some(replication.address.clone()),
+                ..Default::default()
+            };
+            self.send(Performative::Attach {
+                name: format!("{}-replica-{}", config.container_id, replication.address),
+                handle: handle as u32,
+                role: false,
+                // Unsettled, for the peer to acknowledge every message.
+                snd_settle_mode: 0,
+                rcv_settle_mode: 0,
+                source: Some(Box::new(source)),
+                target: Some(Box::new(target)),
+                unsettled: HashMap::new(),
+                incomplete_unsettled: false,
+                initial_delivery_count: Some(0),
+                max_message_size: None,
+                offered_capabilities: vec![],
+                desired_capabilities: vec![],
+                properties: HashMap::new(),
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn handle(
+        &mut self,
+        performative: Performative,
+        replications: &mut [Replication],
+    ) -> Result<(), &'static str> {
+        match performative {
+            Performative::Open {
+                container_id,
+                max_frame_size,
+                idle_time_out,
+                ..
+            } => {
+                self.remote_container_id = Some(container_id);
+                self.max_frame_size = self.max_frame_size.min(max_frame_size);
+                // Heartbeats go out at half the peer's idle timeout (see 2.4.5).
+                if let Some(idle_time_out) = idle_time_out.filter(|timeout| !timeout.is_zero()) {
+                    self.heartbeats = heartbeats(idle_time_out / 2);
+                }
+            }
+            Performative::Begin {
+                incoming_window, ..
+            } => self.remote_incoming_window = incoming_window,
+            Performative::Attach { target: None, .. } => {
+                return Err("The peer refused a replicated address");
+            }
+            Performative::Flow {
+                next_incoming_id,
+                incoming_window,
+                handle,
+                delivery_count,
+                link_credit,
+                ..
+            } => {
+                // remote-incoming-window = next-incoming-id + incoming-window - next-outgoing-id
+                self.remote_incoming_window = next_incoming_id
+                    .unwrap_or(0)
+                    .wrapping_add(incoming_window)
+                    .wrapping_sub(self.next_outgoing_id);
+                if let Some(replication) =
+                    handle.and_then(|handle| replications.get_mut(handle as usize))
+                {
+                    // link-credit = delivery-count(receiver) + link-credit(receiver)
+                    //               - delivery-count(sender)
+                    replication.link_credit = delivery_count
+                        .unwrap_or(0)
+                        .wrapping_add(link_credit.unwrap_or(0))
+                        .wrapping_sub(replication.delivery_count);
+                }
+            }
+            Performative::Disposition {
+                role: true,
+                first,
+                last,
+                settled,
+                state,
+                ..
+            } => {
+                let last = last.unwrap_or(first);
+                let mut delivery_id = first;
+                loop {
+                    self.acknowledge(delivery_id, settled, state.as_ref(), replications);
+                    if delivery_id == last {
+                        break;
+                    }
+                    delivery_id = delivery_id.wrapping_add(1);
+                }
+                if !settled {
+                    self.send(Performative::Disposition {
+                        role: false,
+                        first,
+                        last: Some(last),
+                        settled: true,
+                        state,
+                        batchable: false,
+                    })
+                    .await?;
+                }
+            }
+            Performative::Detach { .. } => return Err("The peer detached a replicated address"),
+            Performative::End { .. } => return Err("The peer ended the session"),
+            Performative::Close { .. } => return Err("The peer closed the connection"),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Accepted and rejected messages are done with, as are those the peer settled
+    // without an outcome, while released and modified ones were never processed by the
+    // peer, so they are sent again.
+    fn acknowledge(
+        &mut self,
+        delivery_id: u32,
+        settled: bool,
+        state: Option<&DeliveryState>,
+        replications: &mut [Replication],
+    ) {
+        let state = state.filter(|state| state.is_terminal());
+        if state.is_none() && !settled {
+            return;
+        }
+        let Some((index, number)) = self.deliveries.remove(&delivery_id) else {
+            return;
+        };
+        let replication = &mut replications[index];
+        replication.unacknowledged.remove(&number);
+        match state {
+            Some(DeliveryState::Released | DeliveryState::Modified { .. }) => {
+                replication.resend.insert(number);
+            }
+            Some(DeliveryState::Rejected { error }) => println!(
+                "Replicated message {} of {} rejected by {:?}: {:?}",
+                number, replication.address, self.remote_container_id, error
+            ),
+            _ => {}
+        }
+    }
+
+    // Sends every link the messages it has credit for, the ones to send again first,
+    // once the rest of any delivery held back by the peer's incoming window is out.
+    async fn send_messages(
+        &mut self,
+        replications: &mut [Replication],
+    ) -> Result<(), &'static str> {
+        // Nothing goes out before the peer told us who it is.
+        let Some(remote_container_id) = self.remote_container_id.clone() else {
+            return Ok(());
+        };
+        self.send_transfers().await?;
+        for (index, replication) in replications.iter_mut().enumerate() {
+            while replication.link_credit > 0
+                && self.remote_incoming_window > 0
+                && self.outgoing.is_empty()
+            {
+                let resend = replication.resend.pop_first();
+                let wanted = resend.unwrap_or(replication.next);
+                let Some((number, mut message)) = replication
+                    .log
+                    .lock()
+                    .unwrap()
+                    .get(wanted)
+                    .map(|(number, message)| (number, message.clone()))
+                else {
+                    break;
+                };
+                if number > wanted {
+                    println!(
+                        "Replication of {} to {} fell behind, missing {} messages",
+                        replication.address,
+                        remote_container_id,
+                        number - wanted
+                    );
+                }
+                match resend {
+                    // The message to send again is gone from the log already.
+                    Some(_) if number > wanted => continue,
+                    Some(_) => {}
+                    None => replication.next = number + 1,
+                }
+                let origin = message
+                    .message_annotations
+                    .entry(Constructor::PrimitiveType(Primitive::Symbol(
+                        ORIGIN.to_vec(),
+                    )))
+                    .or_insert_with(|| {
+                        Constructor::PrimitiveType(Primitive::String(self.container_id.clone()))
+                    });
+                if *origin
+                    == Constructor::PrimitiveType(Primitive::String(remote_container_id.clone()))
+                {
+                    continue;
+                }
+                let payload = match message.encode() {
+                    Ok(payload) => payload,
+                    Err(err) => {
+                        println!(
+                            "Could not encode a replicated message, skipping it: {}",
+                            err
+                        );
+                        continue;
+                    }
+                };
+                let delivery_id = self.next_delivery_id;
+                self.transfer(index as u32, replication, &payload).await?;
+                replication.unacknowledged.insert(number);
+                self.deliveries.insert(delivery_id, (index, number));
+            }
+        }
+        Ok(())
+    }
+
+    // Sends a message in as many Transfers as the max-frame-size asks for, as far as
+    // the peer's incoming window goes.
+    async fn transfer(
+        &mut self,
+        handle: u32,
+        replication: &mut Replication,
+        payload: &[u8],
+    ) -> Result<(), &'static str> {
+        let delivery_id = self.next_delivery_id;
+        let delivery_tag = replication.delivery_count.to_be_bytes().to_vec();
+        let transfer = |more| Performative::Transfer {
+            handle,
+            delivery_id: Some(delivery_id),
+            delivery_tag: delivery_tag.clone(),
+            message_format: Some(0),
+            settled: Some(false),
+            more,
+            rcv_settle_mode: None,
+            state: None,
+            resume: false,
+            aborted: false,
+            batchable: false,
+        };
+        let overhead = 8 + transfer(true).to_constructor().as_bytes()?.len();
+        let chunk_size = (self.max_frame_size as usize)
+            .checked_sub(overhead)
+            .filter(|size| *size > 0)
+            .unwrap_or(1);
+        let chunks: Vec<&[u8]> = payload.chunks(chunk_size).collect();
+        for (index, chunk) in chunks.iter().enumerate() {
+            let frame = transfer(index + 1 < chunks.len()).to_frame(0, chunk)?;
+            self.outgoing.push_back(frame);
+        }
+        self.next_delivery_id = self.next_delivery_id.wrapping_add(1);
+        replication.delivery_count = replication.delivery_count.wrapping_add(1);
+        replication.link_credit -= 1;
+        self.send_transfers().await
+    }
+
+    // Sends the held back frames that fit in the peer's incoming window, each taking
+    // up one transfer-id of it (see 2.5.6 Session Flow Control).
+    async fn send_transfers(&mut self) -> Result<(), &'static str> {
+        while self.remote_incoming_window > 0
+            && let Some(frame) = self.outgoing.pop_front()
+        {
+            self.send_frame(frame).await?;
+            self.next_outgoing_id = self.next_outgoing_id.wrapping_add(1);
+            self.remote_incoming_window -= 1;
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, performative: Performative) -> Result<(), &'static str> {
+        let frame = performative.to_frame(0, &[])?;
+        self.send_frame(frame).await
+    }
+
+    async fn send_frame(&mut self, frame: Frame) -> Result<(), &'static str> {
+        self.frames
+            .send(frame)
+            .await
+            .map_err(|_| "Could not write to socket")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amqp::messaging::message::{Body, Message};
+    use tokio::net::TcpListener;
+
+    // Our end of a connection to a peer that told us who it is, and the peer's end.
+    async fn downstream() -> (Downstream, Frames) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (stream, accepted) = tokio::join!(TcpStream::connect(address), listener.accept());
+        let (_, socket_snd) = io::split(stream.unwrap());
+        let (socket_rcv, _) = io::split(accepted.unwrap().0);
+        let downstream = Downstream {
+            frames: FramedWrite::new(socket_snd, FrameCodec::new(512)),
+            container_id: String::from("here"),
+            remote_container_id: Some(String::from("peer")),
+            max_frame_size: 512,
+            next_outgoing_id: 0,
+            remote_incoming_window: 100,
+            outgoing: VecDeque::new(),
+            next_delivery_id: 0,
+            deliveries: HashMap::new(),
+            heartbeats: heartbeats(HEARTBEAT_INTERVAL),
+        };
+        (
+            downstream,
+            FramedRead::new(socket_rcv, FrameCodec::new(512)),
+        )
+    }
+
+    fn replication(log: &Arc<Mutex<ReplicaLog>>, next: u64, link_credit: u32) -> Replication {
+        Replication {
+            address: String::from("replicated"),
+            log: log.clone(),
+            appended: log.lock().unwrap().appended(),
+            next,
+            unacknowledged: BTreeSet::new(),
+            resend: BTreeSet::new(),
+            delivery_count: 0,
+            link_credit,
+        }
+    }
+
+    fn log(retention: usize, messages: u64) -> Arc<Mutex<ReplicaLog>> {
+        let mut log = ReplicaLog::new(retention);
+        for number in 0..messages {
+            log.push(Message {
+                body: Body::AmqpValue(Primitive::String(number.to_string())),
+                ..Message::default()
+            });
+        }
+        Arc::new(Mutex::new(log))
+    }
+
+    // The delivery id and body of the next message the peer receives.
+    async fn received(frames: &mut Frames) -> (u32, String) {
+        let frame = frames.next().await.unwrap().unwrap();
+        let (performative, payload) = get_performative_and_payload(&frame.frame_body)
+            .await
+            .unwrap();
+        let Performative::Transfer {
+            delivery_id: Some(delivery_id),
+            ..
+        } = performative
+        else {
+            panic!("expected a transfer");
+        };
+        let Body::AmqpValue(Primitive::String(body)) = Message::new(&payload).await.unwrap().body
+        else {
+            panic!("expected a string body");
+        };
+        (delivery_id, body)
+    }
+
+    fn disposition(first: u32, settled: bool, state: Option<DeliveryState>) -> Performative {
+        Performative::Disposition {
+            role: true,
+            first,
+            last: None,
+            settled,
+            state,
+            batchable: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn every_peer_acknowledges_on_its_own() {
+        let log = log(10, 3);
+        let (mut a, mut a_frames) = downstream().await;
+        let (mut b, _b_frames) = downstream().await;
+        let mut a_replications = [replication(&log, 0, 10)];
+        let mut b_replications = [replication(&log, 0, 10)];
+        a.send_messages(&mut a_replications).await.unwrap();
+        b.send_messages(&mut b_replications).await.unwrap();
+        for number in 0..3 {
+            assert_eq!(received(&mut a_frames).await, (number, number.to_string()));
+        }
+
+        // Receiving a message without settling it acknowledges nothing.
+        let received_state = DeliveryState::Received {
+            section_number: 0,
+            section_offset: 0,
+        };
+        a.handle(
+            disposition(0, false, Some(received_state)),
+            &mut a_replications,
+        )
+        .await
+        .unwrap();
+        a.handle(
+            disposition(0, false, Some(DeliveryState::Accepted)),
+            &mut a_replications,
+        )
+        .await
+        .unwrap();
+        // Settling without an outcome leaves the message to the peer all the same.
+        a.handle(disposition(1, true, None), &mut a_replications)
+            .await
+            .unwrap();
+        assert_eq!(a_replications[0].unacknowledged, BTreeSet::from([2]));
+        assert_eq!(a.deliveries.len(), 1);
+        assert_eq!(a_replications[0].acknowledged(), 2);
+        assert_eq!(b_replications[0].unacknowledged, BTreeSet::from([0, 1, 2]));
+        assert_eq!(b_replications[0].acknowledged(), 0);
+
+        // Once reconnected each peer starts over from what it didn't acknowledge.
+        a_replications[0].reset();
+        b_replications[0].reset();
+        assert_eq!(a_replications[0].next, 2);
+        assert_eq!(b_replications[0].next, 0);
+    }
+
+    #[tokio::test]
+    async fn released_and_modified_messages_are_sent_again_first() {
+        let log = log(10, 4);
+        let (mut downstream, mut frames) = downstream().await;
+        let mut replications = [replication(&log, 0, 3)];
+        downstream.send_messages(&mut replications).await.unwrap();
+        for number in 0..3 {
+            received(&mut frames).await;
+            downstream.acknowledge(
+                number,
+                true,
+                Some(&match number {
+                    0 => DeliveryState::Released,
+                    1 => DeliveryState::Accepted,
+                    _ => DeliveryState::Modified {
+                        delivery_failed: true,
+                        undeliverable_here: false,
+                        message_annotations: HashMap::new(),
+                    },
+                }),
+                &mut replications,
+            );
+        }
+        assert_eq!(replications[0].resend, BTreeSet::from([0, 2]));
+        assert!(replications[0].unacknowledged.is_empty());
+        assert_eq!(replications[0].acknowledged(), 0);
+
+        replications[0].link_credit = 3;
+        downstream.send_messages(&mut replications).await.unwrap();
+        assert_eq!(received(&mut frames).await, (3, String::from("0")));
+        assert_eq!(received(&mut frames).await, (4, String::from("2")));
+        assert_eq!(received(&mut frames).await, (5, String::from("3")));
+        assert!(replications[0].resend.is_empty());
+        assert_eq!(replications[0].unacknowledged, BTreeSet::from([0, 2, 3]));
+        assert_eq!(replications[0].next, 4);
+    }
+
+    #[tokio::test]
+    async fn peers_catch_up_on_what_the_log_still_retains() {
+        // Only the last two of the five messages are left.
+        let log = log(2, 5);
+        let (mut downstream, mut frames) = downstream().await;
+        let mut replications = [replication(&log, 0, 10)];
+        replications[0].resend.insert(1);
+        downstream.send_messages(&mut replications).await.unwrap();
+        assert_eq!(received(&mut frames).await, (0, String::from("3")));
+        assert_eq!(received(&mut frames).await, (1, String::from("4")));
+        assert!(replications[0].resend.is_empty());
+        assert_eq!(replications[0].unacknowledged, BTreeSet::from([3, 4]));
+        assert_eq!(replications[0].next, 5);
+        assert_eq!(replications[0].link_credit, 8);
+    }
+}
@@ -1,70 +1,76 @@
 // A Terminus is responsible for tracking the state of a particular stream of incoming or outgoing messages.
+use std::io;
+
+use futures::{SinkExt, StreamExt};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio_util::codec::{FramedRead, FramedWrite};
 
-use crate::amqp::types::frame::{Frame, FrameType};
+use crate::amqp::types::frame::{Frame, FrameCodec, FrameError, FrameType};
+use crate::frame_bus::{BusEvent, ConnectionId};
 
 // Sources track outgoing messages.
 // Messages may only travel along a Link if they meet the entry criteria at the Source.
 // TODO: logging
-pub async fn source_handler<W>(mut socket_writer: W, mut client_rx: Receiver<Frame>)
-where
+pub async fn source_handler<W>(
+    socket_writer: W,
+    mut client_rx: Receiver<Frame>,
+    max_frame_size: u32,
+) where
     W: AsyncWriteExt + Unpin + Send + 'static,
 {
-    let mut buf = vec![0; 8192];
-    let open_frame = Frame::open();
-    socket_writer.write_buf(&mut open_frame.as_bytes());
-
-    loop {
-        match client_rx.recv().await {
-            Some(frame) => {
-                match frame.frame_type {
-                    FrameType::AMQP => {}
-                    _ => {
-                        continue;
-                    }
-                }
-                socket_writer.write_buf(&mut frame.as_bytes());
-            }
-            None => {
-                break;
+    let mut frames = FramedWrite::new(socket_writer, FrameCodec::new(max_frame_size));
+    while let Some(frame) = client_rx.recv().await {
+        match frame.frame_type {
+            FrameType::AMQP => {}
+            _ => {
+                continue;
             }
         }
+        if frames.send(frame).await.is_err() {
+            break;
+        }
     }
 }
 
 // Targets track incoming messages.
 // TODO: logging
-pub async fn target_handler<R>(mut socket_reader: R, frame_bus_tx: Sender<Frame>)
-where
+pub async fn target_handler<R>(
+    socket_reader: R,
+    connection_id: ConnectionId,
+    frame_bus_tx: Sender<BusEvent>,
+    max_frame_size: u32,
+) where
     R: AsyncReadExt + Unpin + Send + 'static,
 {
+    let mut frames = FramedRead::new(socket_reader, FrameCodec::new(max_frame_size));
     loop {
-        let mut buf = [0u8; 8192];
-        match socket_reader.read(&mut buf).await {
+        match frames.next().await {
             // closed connection
-            Ok(0) => {
+            None => {
                 break;
             }
-            Ok(_) => {
-                let frame = Frame::new(&mut socket_reader).await;
-                match frame {
-                    Ok(frame) => match frame_bus_tx.send(frame).await {
-                        Ok(_) => {}
-                        Err(_) => {
-                            break;
-                        }
-                    },
-                    Err(_) => {
-                        break;
-                    }
+            Some(Ok(frame)) => match frame_bus_tx
+                .send(BusEvent::Frame(connection_id, frame))
+                .await
+            {
+                Ok(_) => {}
+                Err(_) => {
+                    break;
                 }
+            },
+            // TLS peers that go away without a close_notify look no different
+            // from a closed connection to us.
+            Some(Err(FrameError::Io(err))) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                break;
             }
-            Err(_) => {
+            Some(Err(err)) => {
+                frame_bus_tx
+                    .send(BusEvent::FramingError(connection_id, err))
+                    .await
+                    .unwrap_or(());
                 break;
             }
         }
     }
-    let close_frame = Frame::close();
-    frame_bus_tx.send(close_frame).await.unwrap_or(());
 }